@@ -0,0 +1,415 @@
+//! Procedural macros for [futures-cache].
+//!
+//! This crate shouldn't be used directly. Enable the `derive` feature of
+//! [futures-cache] instead, which re-exports the macros.
+//!
+//! [futures-cache]: https://docs.rs/futures-cache
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+
+/// Derive `CacheKey` for a struct or an enum.
+///
+/// See the documentation of `futures_cache::CacheKey` for the supported
+/// attributes.
+#[proc_macro_derive(CacheKey, attributes(cache_key))]
+pub fn derive_cache_key(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = syn::parse_macro_input!(input as syn::DeriveInput);
+
+    match expand(input) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Cache the result of an async function.
+///
+/// See the documentation of `futures_cache::cached` for the supported
+/// arguments.
+#[proc_macro_attribute]
+pub fn cached(
+    args: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let item = syn::parse_macro_input!(item as syn::ItemFn);
+    let mut cached = Cached::default();
+
+    let parser = syn::meta::parser(|meta| cached.parse(meta));
+    syn::parse_macro_input!(args with parser);
+
+    match expand_cached(cached, item) {
+        Ok(output) => output.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Arguments of the `cached` attribute.
+#[derive(Default)]
+struct Cached {
+    krate: Option<syn::Path>,
+    cache: Option<syn::Expr>,
+    ttl: Option<(i64, &'static str)>,
+    ns: Option<String>,
+    version: u32,
+}
+
+impl Cached {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta<'_>) -> syn::Result<()> {
+        if meta.path.is_ident("cache") {
+            self.cache = Some(meta.value()?.parse::<syn::LitStr>()?.parse()?);
+        } else if meta.path.is_ident("ttl") {
+            let lit = meta.value()?.parse::<syn::LitStr>()?;
+            self.ttl = Some(parse_ttl(&lit.value()).ok_or_else(|| {
+                syn::Error::new(lit.span(), "expected a number followed by s, m, h or d")
+            })?);
+        } else if meta.path.is_ident("ns") {
+            self.ns = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+        } else if meta.path.is_ident("version") {
+            self.version = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+        } else if meta.path.is_ident("crate") {
+            self.krate = Some(meta.value()?.parse::<syn::LitStr>()?.parse()?);
+        } else {
+            return Err(meta.error("unsupported cached argument"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a time to live like `5m` into a number and the constructor of
+/// `Duration` to use for its unit.
+fn parse_ttl(ttl: &str) -> Option<(i64, &'static str)> {
+    let unit = match ttl.chars().last()? {
+        's' => "seconds",
+        'm' => "minutes",
+        'h' => "hours",
+        'd' => "days",
+        _ => return None,
+    };
+
+    Some((ttl[..ttl.len() - 1].parse().ok()?, unit))
+}
+
+fn expand_cached(cached: Cached, mut item: syn::ItemFn) -> syn::Result<TokenStream> {
+    if item.sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            item.sig.fn_token.span(),
+            "only async functions can be cached",
+        ));
+    }
+
+    let span = Span::call_site();
+    // Locals of the generated code are resolved at the definition site so
+    // that they can't shadow the arguments of the function.
+    let site = Span::mixed_site();
+
+    let cache = cached
+        .cache
+        .ok_or_else(|| syn::Error::new(span, "missing `cache` argument"))?;
+    let (ttl, unit) = cached
+        .ttl
+        .ok_or_else(|| syn::Error::new(span, "missing `ttl` argument"))?;
+    let krate = cached
+        .krate
+        .unwrap_or_else(|| syn::parse_quote!(::futures_cache));
+
+    let mut fields = Vec::new();
+
+    for input in &item.sig.inputs {
+        let input = match input {
+            syn::FnArg::Receiver(..) => continue,
+            syn::FnArg::Typed(input) => input,
+        };
+
+        let ident = match &*input.pat {
+            syn::Pat::Ident(pat) => &pat.ident,
+            pat => {
+                return Err(syn::Error::new(
+                    pat.span(),
+                    "arguments of cached functions have to be identifiers",
+                ));
+            }
+        };
+
+        let name = ident.to_string();
+        fields.push(quote_spanned!(site=> record.field(#name, &#ident)?;));
+    }
+
+    let name = item.sig.ident.to_string();
+    let version = cached.version;
+    let unit = syn::Ident::new(unit, span);
+
+    let namespaced = cached.ns.map(|ns| {
+        quote_spanned! {site=>
+            let cache = #krate::Cache::namespaced(cache, &#ns)?;
+            let cache = &cache;
+        }
+    });
+
+    let block = &item.block;
+
+    let body = quote_spanned! {site=> {
+        let cache: &#krate::Cache = &#cache;
+        #namespaced
+
+        let key = #krate::__private::EncodedKey::new(|encoder| {
+            #[allow(unused_mut)]
+            let mut record = encoder.record(#name, #version)?;
+            #(#fields)*
+            record.end()
+        })
+        .map_err(#krate::Error::from)?;
+
+        let age = #krate::Duration::#unit(#ttl);
+        #krate::Cache::wrap(cache, key, age, async move #block).await
+    }};
+
+    item.block = syn::parse2(body)?;
+    Ok(quote!(#item))
+}
+
+/// Attributes of the type deriving `CacheKey`.
+struct Container {
+    krate: syn::Path,
+    name: String,
+    version: u32,
+}
+
+/// Attributes of a variant.
+struct Variant {
+    name: Option<String>,
+}
+
+/// A field which is part of the key.
+struct Field {
+    name: String,
+    binding: syn::Ident,
+    default: bool,
+}
+
+fn expand(input: syn::DeriveInput) -> syn::Result<TokenStream> {
+    let container = container(&input)?;
+
+    let arms = match &input.data {
+        syn::Data::Struct(data) => {
+            let body = record(&container, &container.name, &data.fields)?;
+            let pattern = pattern(quote!(Self), &data.fields);
+            vec![quote!(#pattern => { #body })]
+        }
+        syn::Data::Enum(data) => {
+            let mut arms = Vec::with_capacity(data.variants.len());
+
+            for variant in &data.variants {
+                let ident = &variant.ident;
+
+                let name = match parse_variant(&variant.attrs)?.name {
+                    Some(name) => name,
+                    None => ident.to_string(),
+                };
+
+                let name = format!("{}::{}", container.name, name);
+                let body = record(&container, &name, &variant.fields)?;
+                let pattern = pattern(quote!(Self::#ident), &variant.fields);
+                arms.push(quote!(#pattern => { #body }));
+            }
+
+            arms
+        }
+        syn::Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span(),
+                "CacheKey can't be derived for unions",
+            ));
+        }
+    };
+
+    let krate = &container.krate;
+    let ident = &input.ident;
+
+    let mut generics = input.generics.clone();
+
+    for param in generics.type_params_mut() {
+        param
+            .bounds
+            .push(syn::parse_quote!(#krate::__private::Serialize));
+    }
+
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics #krate::CacheKey for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_key(
+                &self,
+                encoder: #krate::KeyEncoder<'_>,
+            ) -> ::core::result::Result<(), #krate::KeyError> {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+    })
+}
+
+/// Generate the code which encodes the given fields as a record.
+fn record(container: &Container, name: &str, fields: &syn::Fields) -> syn::Result<TokenStream> {
+    let mut out = Vec::new();
+
+    for (index, field) in fields.iter().enumerate() {
+        let mut rename = None;
+        let mut skip = false;
+        let mut default = false;
+
+        for attr in &field.attrs {
+            if !attr.path().is_ident("cache_key") {
+                continue;
+            }
+
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    rename = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else if meta.path.is_ident("default") {
+                    default = true;
+                } else {
+                    return Err(meta.error("unsupported cache_key field attribute"));
+                }
+
+                Ok(())
+            })?;
+        }
+
+        if skip {
+            continue;
+        }
+
+        let name = match (rename, &field.ident) {
+            (Some(name), _) => name,
+            (None, Some(ident)) => ident.to_string(),
+            (None, None) => index.to_string(),
+        };
+
+        if out.iter().any(|f: &Field| f.name == name) {
+            return Err(syn::Error::new(
+                field.span(),
+                format!("duplicate cache key field `{}`", name),
+            ));
+        }
+
+        out.push(Field {
+            name,
+            binding: binding(index),
+            default,
+        });
+    }
+
+    // Fields are encoded in the order of their names, so that reordering them
+    // doesn't change the key.
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let krate = &container.krate;
+    let version = container.version;
+
+    let fields = out.iter().map(|field| {
+        let name = &field.name;
+        let binding = &field.binding;
+
+        if field.default {
+            quote! {
+                if !#krate::__private::is_default(#binding) {
+                    record.field(#name, #binding)?;
+                }
+            }
+        } else {
+            quote!(record.field(#name, #binding)?;)
+        }
+    });
+
+    Ok(quote! {
+        #[allow(unused_mut)]
+        let mut record = #krate::KeyEncoder::record(encoder, #name, #version)?;
+        #(#fields)*
+        record.end()
+    })
+}
+
+/// Construct a pattern binding every field of a struct or variant.
+fn pattern(path: TokenStream, fields: &syn::Fields) -> TokenStream {
+    match fields {
+        syn::Fields::Named(fields) => {
+            let bindings = fields.named.iter().enumerate().map(|(index, field)| {
+                let ident = &field.ident;
+                let binding = binding(index);
+                quote!(#ident: #binding)
+            });
+
+            quote!(#path { #(#bindings,)* })
+        }
+        syn::Fields::Unnamed(fields) => {
+            let bindings = (0..fields.unnamed.len()).map(binding);
+            quote!(#path(#(#bindings,)*))
+        }
+        syn::Fields::Unit => path,
+    }
+}
+
+/// The variable a field is bound to.
+fn binding(index: usize) -> syn::Ident {
+    syn::Ident::new(&format!("field{}", index), Span::call_site())
+}
+
+fn container(input: &syn::DeriveInput) -> syn::Result<Container> {
+    let mut container = Container {
+        krate: syn::parse_quote!(::futures_cache),
+        name: input.ident.to_string(),
+        version: 0,
+    };
+
+    for attr in &input.attrs {
+        if !attr.path().is_ident("cache_key") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("version") {
+                container.version = meta.value()?.parse::<syn::LitInt>()?.base10_parse()?;
+            } else if meta.path.is_ident("rename") {
+                container.name = meta.value()?.parse::<syn::LitStr>()?.value();
+            } else if meta.path.is_ident("crate") {
+                container.krate = meta.value()?.parse::<syn::LitStr>()?.parse()?;
+            } else {
+                return Err(meta.error("unsupported cache_key container attribute"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(container)
+}
+
+fn parse_variant(attrs: &[syn::Attribute]) -> syn::Result<Variant> {
+    let mut variant = Variant { name: None };
+
+    for attr in attrs {
+        if !attr.path().is_ident("cache_key") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                variant.name = Some(meta.value()?.parse::<syn::LitStr>()?.value());
+            } else {
+                return Err(meta.error("unsupported cache_key variant attribute"));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(variant)
+}
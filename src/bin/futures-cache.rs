@@ -0,0 +1,448 @@
+//! Command line tool to inspect and maintain a cache stored on disk.
+//!
+//! Requires the `cli` feature. Run `futures-cache --help` for usage.
+//!
+//! sled only allows one process to open a database at a time, so a cache
+//! which is in use by another process can't be opened. Stop the process, or
+//! inspect a copy of the database taken while it was stopped. Use
+//! `--read-only` to make sure the entries of the cache aren't modified, which
+//! refuses commands which modify the cache and loads it with
+//! [Builder::read_only][futures_cache::Builder::read_only]. sled itself may
+//! still write to the files of the database when it opens them, so inspect a
+//! copy if they have to stay untouched.
+
+use futures_cache::dump::{ConflictPolicy, DumpFormat, ExportOptions};
+use futures_cache::{verify, Cache, ExpiryFilter, ListFilter, State};
+use serde_json as json;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "\
+Usage: futures-cache [OPTIONS] <PATH> <COMMAND> [ARGS]
+
+Options:
+    --tree <NAME>     The tree the cache is stored in [default: cache]
+    --read-only       Refuse commands which modify the cache, and don't clean
+                      up, convert, or extend entries when reading it
+    -h, --help        Print this help
+
+Commands:
+    list [--ns <NS>] [--default-ns] [--prefix <KEY>] [--fresh | --expired] [--limit <N>]
+                      List entries as JSON lines
+    get <KEY> [--ns <NS>]
+                      Print an entry as JSON
+    delete <KEY> [--ns <NS>]
+                      Delete an entry
+    cleanup           Remove expired entries
+    stats             Print the number of entries and bytes used by each namespace
+    export [--format json|cbor] [--ns <NS>]... [--output <FILE>]
+                      Write a dump of the cache to a file or stdout
+    import [--policy skip|overwrite|keep-newer] [<FILE>]
+                      Load a dump from a file or stdin
+    flush             Flush pending writes to disk
+    verify [--repair] Check the integrity of the cache, and repair the problems
+                      found with --repair
+    serve <ADDR>      Serve the cache over HTTP, requires the `server` feature
+
+Keys, key prefixes and namespaces are given as JSON, like `[\"users\", 42]`.
+Anything which isn't valid JSON is used as a string.
+";
+
+/// An error in the arguments, which is reported with the usage.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+impl Error for UsageError {}
+
+fn usage<T>(message: impl Into<String>) -> Result<T, Box<dyn Error>> {
+    Err(Box::new(UsageError(message.into())))
+}
+
+/// Command line arguments which haven't been consumed yet.
+struct Args {
+    args: Vec<String>,
+}
+
+impl Args {
+    /// Take the given flag if it's present.
+    fn flag(&mut self, name: &str) -> bool {
+        match self.args.iter().position(|arg| arg == name) {
+            Some(index) => {
+                self.args.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Take the value of the given option if it's present.
+    fn option(&mut self, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let index = match self.args.iter().position(|arg| arg == name) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        if index + 1 >= self.args.len() {
+            return usage(format!("missing value for {}", name));
+        }
+
+        self.args.remove(index);
+        Ok(Some(self.args.remove(index)))
+    }
+
+    /// Take every value of the given option.
+    fn options(&mut self, name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut values = Vec::new();
+
+        while let Some(value) = self.option(name)? {
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+
+    /// Take the next positional argument if there is one.
+    fn next(&mut self) -> Option<String> {
+        if self.args.is_empty() {
+            None
+        } else {
+            Some(self.args.remove(0))
+        }
+    }
+
+    /// Take the next positional argument, which is required.
+    fn required(&mut self, what: &str) -> Result<String, Box<dyn Error>> {
+        match self.next() {
+            Some(arg) => Ok(arg),
+            None => usage(format!("missing {}", what)),
+        }
+    }
+
+    /// Fail if there are any arguments left.
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self.args.first() {
+            Some(arg) => usage(format!("unexpected argument: {}", arg)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse a key or namespace given as JSON, falling back to a string.
+fn parse_json(arg: &str) -> json::Value {
+    json::from_str(arg).unwrap_or_else(|_| json::Value::String(arg.to_owned()))
+}
+
+/// Construct the handle for the namespace given with `--ns`.
+fn namespaced(cache: &Cache, ns: Option<String>) -> Result<Cache, Box<dyn Error>> {
+    Ok(match ns {
+        Some(ns) => cache.namespaced(&parse_json(&ns))?,
+        None => cache.clone(),
+    })
+}
+
+fn main() {
+    let args = Args {
+        args: std::env::args().skip(1).collect(),
+    };
+
+    if let Err(error) = run(args) {
+        eprintln!("error: {}", error);
+
+        if error.is::<UsageError>() {
+            eprint!("\n{}", USAGE);
+            process::exit(2);
+        }
+
+        process::exit(1);
+    }
+}
+
+fn run(mut args: Args) -> Result<(), Box<dyn Error>> {
+    if args.flag("-h") || args.flag("--help") {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+
+    let tree = args
+        .option("--tree")?
+        .unwrap_or_else(|| String::from("cache"));
+    let read_only = args.flag("--read-only");
+    let path = args.required("path")?;
+    let command = args.required("command")?;
+
+    let mutates = matches!(command.as_str(), "delete" | "cleanup" | "import" | "flush");
+
+    if read_only && mutates {
+        return usage(format!("`{}` modifies the cache", command));
+    }
+
+    // opening a database which doesn't exist creates it.
+    if read_only && !Path::new(&path).exists() {
+        return Err(format!("{}: no such database", path).into());
+    }
+
+    let db = sled::open(&path)?;
+
+    // and so does opening a tree which doesn't exist.
+    if read_only
+        && !db
+            .tree_names()
+            .iter()
+            .any(|name| **name == *tree.as_bytes())
+    {
+        return Err(format!("{}: no such tree", tree).into());
+    }
+
+    let cache = Cache::builder(db.open_tree(&tree)?)
+        .read_only(read_only)
+        .load()?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    match command.as_str() {
+        "list" => {
+            let options = ListOptions {
+                ns: args.option("--ns")?.map(|ns| parse_json(&ns)),
+                default_ns: args.flag("--default-ns"),
+                prefix: args.option("--prefix")?.map(|prefix| parse_json(&prefix)),
+                expiry: match (args.flag("--fresh"), args.flag("--expired")) {
+                    (true, true) => return usage("--fresh and --expired are exclusive"),
+                    (true, false) => Some(ExpiryFilter::Fresh),
+                    (false, true) => Some(ExpiryFilter::Expired),
+                    (false, false) => None,
+                },
+            };
+
+            let limit = match args.option("--limit")? {
+                Some(limit) => limit.parse()?,
+                None => usize::MAX,
+            };
+
+            args.finish()?;
+            list(&cache, &options, limit, &mut out)?;
+        }
+        "get" => {
+            let key = parse_json(&args.required("key")?);
+            let cache = namespaced(&cache, args.option("--ns")?)?;
+            args.finish()?;
+
+            let (state, entry) = match cache.get::<_, json::Value>(&key)? {
+                State::Fresh(entry) => ("fresh", entry),
+                State::Expired(entry) => ("expired", entry),
+                State::Missing => return Err("entry is missing".into()),
+                State::Corrupt => return Err("entry is corrupt".into()),
+            };
+
+            let mut entry = json::to_value(&entry)?;
+
+            if let Some(entry) = entry.as_object_mut() {
+                entry.insert(String::from("state"), json::Value::from(state));
+            }
+
+            writeln!(out, "{}", entry)?;
+        }
+        "delete" => {
+            let key = parse_json(&args.required("key")?);
+            let ns = args.option("--ns")?.map(|ns| parse_json(&ns));
+            args.finish()?;
+            cache.delete_with_ns(ns.as_ref(), &key)?;
+        }
+        "verify" => {
+            let repair = args.flag("--repair");
+            args.finish()?;
+
+            if read_only && repair {
+                return usage("`verify --repair` modifies the cache");
+            }
+
+            // sled locks the database, so no other process uses it.
+            let report = if repair {
+                cache.verify_and_repair(verify::Exclusive::assert())?
+            } else {
+                cache.verify()?
+            };
+
+            for issue in &report.issues {
+                writeln!(out, "{}", issue)?;
+            }
+
+            writeln!(
+                out,
+                "checked {} entries, found {} problems, repaired {}",
+                report.entries,
+                report.issues.len(),
+                report.repaired()
+            )?;
+
+            if report.repaired() < report.issues.len() {
+                return Err("the cache has problems".into());
+            }
+        }
+        "cleanup" => {
+            args.finish()?;
+            writeln!(out, "removed {} entries", cache.cleanup()?)?;
+        }
+        "stats" => {
+            args.finish()?;
+
+            for usage in cache.namespace_usage()? {
+                writeln!(out, "{}", json::to_string(&usage)?)?;
+            }
+        }
+        "export" => {
+            let mut options = ExportOptions::new();
+
+            match args.option("--format")?.as_deref() {
+                None | Some("json") => (),
+                Some("cbor") => options = options.format(DumpFormat::Cbor),
+                Some(format) => return usage(format!("unsupported format: {}", format)),
+            }
+
+            for ns in args.options("--ns")? {
+                options = options.ns(&parse_json(&ns));
+            }
+
+            let output = args.option("--output")?;
+            args.finish()?;
+
+            let report = match output {
+                Some(output) => {
+                    let file = io::BufWriter::new(fs::File::create(output)?);
+                    cache.export(file, options)?
+                }
+                None => cache.export(&mut out, options)?,
+            };
+
+            for failure in &report.failed {
+                let key = failure.key.as_ref().map(ToString::to_string);
+                eprintln!("{}: {}", key.as_deref().unwrap_or("?"), failure.error);
+            }
+
+            eprintln!(
+                "exported {} entries, failed {}",
+                report.exported,
+                report.failed.len()
+            );
+
+            if !report.failed.is_empty() {
+                return Err("some entries couldn't be exported".into());
+            }
+        }
+        "import" => {
+            let policy = match args.option("--policy")?.as_deref() {
+                None | Some("skip") => ConflictPolicy::Skip,
+                Some("overwrite") => ConflictPolicy::Overwrite,
+                Some("keep-newer") => ConflictPolicy::KeepNewer,
+                Some(policy) => return usage(format!("unsupported policy: {}", policy)),
+            };
+
+            let input = args.next();
+            args.finish()?;
+
+            let report = match input {
+                Some(input) => cache.import(fs::File::open(input)?, policy)?,
+                None => cache.import(io::stdin().lock(), policy)?,
+            };
+
+            writeln!(
+                out,
+                "imported {}, expired {}, conflicts {}, failed {}",
+                report.imported, report.expired, report.conflicts, report.failed
+            )?;
+        }
+        "flush" => {
+            args.finish()?;
+            writeln!(out, "flushed {} bytes", cache.flush()?)?;
+        }
+        #[cfg(feature = "server")]
+        "serve" => {
+            let addr = args.required("address")?.parse()?;
+            args.finish()?;
+
+            let server = futures_cache::server::Server::new(cache).read_only(read_only);
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+
+            eprintln!("serving on {}", addr);
+            runtime.block_on(server.serve(addr))?;
+        }
+        command => return usage(format!("unsupported command: {}", command)),
+    }
+
+    db.flush()?;
+    Ok(())
+}
+
+/// The filter of the `list` command.
+struct ListOptions {
+    ns: Option<json::Value>,
+    default_ns: bool,
+    prefix: Option<json::Value>,
+    expiry: Option<ExpiryFilter>,
+}
+
+impl ListOptions {
+    fn filter(&self) -> ListFilter {
+        let mut filter = ListFilter::new();
+
+        if let Some(ns) = &self.ns {
+            filter = filter.ns(ns);
+        }
+
+        if self.default_ns {
+            filter = filter.default_ns();
+        }
+
+        if let Some(prefix) = &self.prefix {
+            filter = filter.key_prefix(prefix);
+        }
+
+        if let Some(expiry) = self.expiry {
+            filter = filter.expiry(expiry);
+        }
+
+        filter
+    }
+}
+
+/// List entries matching the filter, one page at a time.
+fn list(
+    cache: &Cache,
+    options: &ListOptions,
+    limit: usize,
+    out: &mut impl io::Write,
+) -> Result<(), Box<dyn Error>> {
+    const PAGE_SIZE: usize = 256;
+
+    let mut cursor = None;
+    let mut remaining = limit;
+
+    while remaining > 0 {
+        let limit = PAGE_SIZE.min(remaining);
+        let page = cache.list_json_page_with(cursor.as_ref(), limit, options.filter())?;
+
+        for entry in &page.entries {
+            writeln!(out, "{}", json::to_string(entry)?)?;
+        }
+
+        remaining -= page.entries.len();
+
+        match page.next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! The operations on entries shared by every kind of cache.
+
+use crate::{Cache, CacheKey, Duration, Error, State};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+
+/// The operations on entries shared by [Cache], the stand-ins of the `mock`
+/// module with the `testing` feature, and the `Client` of a remote cache with
+/// the `client` feature.
+///
+/// Code which is generic over it can be passed a [Cache] in production, and a
+/// stand-in in tests. The trait has generic methods, so it can't be used as a
+/// trait object. It returns `impl Future` from [wrap][CacheLike::wrap], which
+/// is why this crate requires Rust 1.75.
+pub trait CacheLike: Clone + Send + Sync + Sized {
+    /// Load the entry with the given key.
+    ///
+    /// See [Cache::get].
+    fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + DeserializeOwned;
+
+    /// Insert a value which expires after the given duration.
+    ///
+    /// See [Cache::insert].
+    fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize;
+
+    /// Delete the entry with the given key.
+    ///
+    /// See [Cache::delete].
+    fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey;
+
+    /// Create a handle for the given namespace, nested in the namespace of
+    /// this handle.
+    ///
+    /// See [Cache::namespaced].
+    fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize;
+
+    /// Wrap the result of the given future to load and store it.
+    ///
+    /// The returned future is [Send] as long as the given future is, so it
+    /// can be spawned on a multi-threaded executor. See [Cache::wrap].
+    fn wrap<K, F, T, E>(
+        &self,
+        key: K,
+        age: Duration,
+        future: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        K: CacheKey + Send,
+        F: Future<Output = Result<T, E>> + Send,
+        T: 'static + Serialize + DeserializeOwned + Send,
+        E: From<Error> + Send;
+}
+
+impl CacheLike for Cache {
+    fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + DeserializeOwned,
+    {
+        Cache::get(self, key)
+    }
+
+    fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        Cache::insert(self, key, age, value)
+    }
+
+    fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        Cache::delete(self, key)
+    }
+
+    fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        Cache::namespaced(self, ns)
+    }
+
+    fn wrap<K, F, T, E>(
+        &self,
+        key: K,
+        age: Duration,
+        future: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        K: CacheKey + Send,
+        F: Future<Output = Result<T, E>> + Send,
+        T: 'static + Serialize + DeserializeOwned + Send,
+        E: From<Error> + Send,
+    {
+        Cache::wrap(self, key, age, future)
+    }
+}
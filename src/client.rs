@@ -0,0 +1,492 @@
+//! A client for a cache exposed by a [server][crate::server], which lets
+//! several processes share one persistent cache. Requires the `client`
+//! feature.
+//!
+//! A [Client] has the same methods to access entries as a
+//! [Cache][crate::Cache] and implements [CacheLike], so code using a cache can
+//! switch to a remote one by replacing its handle. Like with a cache, reads and
+//! writes block the calling thread until they're done, while
+//! [wrap][Client::wrap] waits for its requests without blocking. The storage of
+//! a [Cache][crate::Cache] itself can't be replaced, since it's always stored
+//! in sled.
+//!
+//! Keys are encoded the same way as by a [Cache][crate::Cache] and sent to the
+//! server encoded, so they're stored the same way as if they were inserted
+//! into the served cache directly. Namespaces are sent as JSON, so they have to
+//! be encoded as JSON values the same way as they are by the server's users.
+//!
+//! Requests run on a runtime of the client, which is driven by a background
+//! thread until the client and all of its clones are dropped, so a client can
+//! be used from any executor or from none at all. Connections are pooled and
+//! reused between requests, and requests which take longer than the
+//! configured [timeout][Builder::timeout] fail with an error of the kind
+//! [ErrorKind::Timeout].
+//!
+//! ```rust,no_run
+//! use futures_cache::client::Client;
+//! use futures_cache::Duration;
+//!
+//! # async fn client() -> Result<(), futures_cache::Error> {
+//! let client = Client::builder("http://127.0.0.1:8080")
+//!     .timeout(std::time::Duration::from_secs(1))
+//!     .build()?;
+//!
+//! let users = client.namespaced(&"users")?;
+//!
+//! let name = users
+//!     .wrap(1u32, Duration::hours(1), async {
+//!         Ok::<_, futures_cache::Error>(String::from("Alice"))
+//!     })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::key;
+use crate::{CacheKey, CacheLike, Duration, Error, ErrorKind, RemoteError, State, StoredEntry};
+use futures_channel::oneshot;
+use http_body_util::{BodyExt as _, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, StatusCode, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json as json;
+use std::future::Future;
+use std::sync::{mpsc, Arc};
+use std::{thread, time};
+
+/// Builder for a [Client].
+///
+/// Constructed through [Client::builder].
+pub struct Builder {
+    base: String,
+    timeout: Option<time::Duration>,
+    client: hyper_util::client::legacy::Builder,
+}
+
+impl Builder {
+    /// Fail requests which take longer than `timeout` with an error of the
+    /// kind [ErrorKind::Timeout]. Requests never time out by default.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Close pooled connections which have been idle for longer than
+    /// `timeout`. Defaults to 90 seconds.
+    pub fn pool_idle_timeout(mut self, timeout: time::Duration) -> Self {
+        self.client.pool_idle_timeout(timeout);
+        self
+    }
+
+    /// The largest number of idle connections to keep in the pool.
+    pub fn pool_max_idle(mut self, max_idle: usize) -> Self {
+        self.client.pool_max_idle_per_host(max_idle);
+        self
+    }
+
+    /// Construct the client, and start the thread its requests run on.
+    ///
+    /// This fails if the base URL isn't valid.
+    pub fn build(self) -> Result<Client, Error> {
+        let base = self.base.trim_end_matches('/').to_owned();
+
+        // check the URL up front, so that requests only fail because of
+        // their keys.
+        base.parse::<Uri>()
+            .map_err(|e| remote(format!("invalid URL: {}", e)))?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+
+        thread::Builder::new()
+            .name(String::from("futures-cache-client"))
+            .spawn(move || {
+                runtime.block_on(async {
+                    let _ = stopped.await;
+                })
+            })?;
+
+        Ok(Client {
+            client: self.client.build_http(),
+            runtime: Arc::new(Runtime {
+                handle,
+                _shutdown: shutdown,
+            }),
+            base,
+            ns: Vec::new(),
+            timeout: self.timeout,
+        })
+    }
+}
+
+/// A client for a remote cache.
+///
+/// Can be cheaply cloned and namespaced, and clones share their pool of
+/// connections. See the [module level documentation][self].
+#[derive(Clone)]
+pub struct Client {
+    client: hyper_util::client::legacy::Client<HttpConnector, Full<Bytes>>,
+    runtime: Arc<Runtime>,
+    base: String,
+    ns: Vec<json::Value>,
+    timeout: Option<time::Duration>,
+}
+
+/// The runtime requests run on, which runs until it's dropped along with the
+/// last clone of a client.
+struct Runtime {
+    handle: tokio::runtime::Handle,
+    _shutdown: oneshot::Sender<()>,
+}
+
+/// Query of the `/entry` endpoint.
+#[derive(Serialize)]
+struct EntryQuery {
+    raw_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ns_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<i64>,
+}
+
+/// An entry as returned by the server, with its state.
+#[derive(Deserialize)]
+struct RemoteEntry<T> {
+    state: String,
+    #[serde(flatten)]
+    stored: StoredEntry<T>,
+}
+
+/// An error as returned by the server.
+#[derive(Deserialize)]
+struct ServerError {
+    error: String,
+}
+
+impl Client {
+    /// Construct a builder for a client of the server at the given base URL,
+    /// like `http://127.0.0.1:8080`.
+    pub fn builder(base: &str) -> Builder {
+        let mut client = hyper_util::client::legacy::Client::builder(TokioExecutor::new());
+        client.pool_timer(TokioTimer::new());
+
+        Builder {
+            base: base.to_owned(),
+            timeout: None,
+            client,
+        }
+    }
+
+    /// Create a client for the given namespace, nested in the namespace of
+    /// this client.
+    ///
+    /// See [Cache::namespaced][crate::Cache::namespaced].
+    pub fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let mut client = self.clone();
+        client.ns.push(json::to_value(ns)?);
+        Ok(client)
+    }
+
+    /// Load an entry from the remote cache.
+    ///
+    /// See [Cache::get][crate::Cache::get].
+    pub fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: DeserializeOwned,
+    {
+        let key = key::encode(&key)?;
+        let request = self.entry_request(Method::GET, &key, None, Full::default())?;
+        decode_entry(self.send_blocking(request)?)
+    }
+
+    /// Insert a value into the remote cache.
+    ///
+    /// See [Cache::insert][crate::Cache::insert].
+    pub fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let request = self.put_request(&key::encode(&key)?, Some(age), value)?;
+        self.send_blocking(request)?;
+        Ok(())
+    }
+
+    /// Insert a value into the remote cache which never expires.
+    ///
+    /// See [Cache::insert_forever][crate::Cache::insert_forever].
+    pub fn insert_forever<K, T>(&self, key: K, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let request = self.put_request(&key::encode(&key)?, None, value)?;
+        self.send_blocking(request)?;
+        Ok(())
+    }
+
+    /// Delete an entry from the remote cache.
+    ///
+    /// See [Cache::delete][crate::Cache::delete].
+    pub fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        let key = key::encode(&key)?;
+        let request = self.entry_request(Method::DELETE, &key, None, Full::default())?;
+        self.send_blocking(request)?;
+        Ok(())
+    }
+
+    /// Wrap the result of the given future to load and store it in the remote
+    /// cache.
+    ///
+    /// Unlike with [Cache::wrap][crate::Cache::wrap], concurrent calls for the
+    /// same key aren't coalesced, since they might be made by different
+    /// processes.
+    pub async fn wrap<K, F, T, E>(&self, key: K, age: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey,
+        F: Future<Output = Result<T, E>>,
+        T: Serialize + DeserializeOwned,
+        E: From<Error>,
+    {
+        let key = key::encode(&key).map_err(Error::from)?;
+        let request = self.entry_request(Method::GET, &key, None, Full::default())?;
+
+        if let State::Fresh(entry) = decode_entry::<T>(self.send(request).await?)? {
+            return Ok(entry.into_value());
+        }
+
+        let value = future.await?;
+        let request = self.put_request(&key, Some(age), &value)?;
+        self.send(request).await?;
+        Ok(value)
+    }
+
+    fn put_request<T>(
+        &self,
+        key: &[u8],
+        age: Option<Duration>,
+        value: &T,
+    ) -> Result<Request<Full<Bytes>>, Error>
+    where
+        T: Serialize,
+    {
+        let body = Full::from(json::to_vec(value)?);
+        self.entry_request(Method::PUT, key, age, body)
+    }
+
+    /// Construct a request of the entry with the given encoded key.
+    fn entry_request(
+        &self,
+        method: Method,
+        key: &[u8],
+        age: Option<Duration>,
+        body: Full<Bytes>,
+    ) -> Result<Request<Full<Bytes>>, Error> {
+        let ns_path = if self.ns.is_empty() {
+            None
+        } else {
+            Some(json::to_string(&self.ns)?)
+        };
+
+        let query = EntryQuery {
+            raw_key: hex::encode(key),
+            ns_path,
+            ttl_ms: age.map(|age| age.num_milliseconds()),
+        };
+
+        let query = serde_urlencoded::to_string(&query)
+            .map_err(|e| remote(format!("invalid query: {}", e)))?;
+
+        let uri = format!("{}/entry?{}", self.base, query)
+            .parse::<Uri>()
+            .map_err(|e| remote(format!("invalid URL: {}", e)))?;
+
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body)
+            .map_err(|e| remote(e.to_string()))
+    }
+
+    /// Send a request and wait for its response, blocking the current thread.
+    fn send_blocking(&self, request: Request<Full<Bytes>>) -> Result<Option<Bytes>, Error> {
+        let (tx, rx) = mpsc::sync_channel(1);
+
+        self.spawn(request, move |response| {
+            let _ = tx.send(response);
+        });
+
+        rx.recv().map_err(|_| remote("request was cancelled"))?
+    }
+
+    /// Send a request and wait for its response.
+    async fn send(&self, request: Request<Full<Bytes>>) -> Result<Option<Bytes>, Error> {
+        let (tx, rx) = oneshot::channel();
+
+        self.spawn(request, move |response| {
+            let _ = tx.send(response);
+        });
+
+        rx.await.map_err(|_| remote("request was cancelled"))?
+    }
+
+    /// Send a request on the runtime of the client, and pass its body or
+    /// `None` if the entry wasn't found to `reply`.
+    fn spawn<F>(&self, request: Request<Full<Bytes>>, reply: F)
+    where
+        F: 'static + Send + FnOnce(Result<Option<Bytes>, Error>),
+    {
+        let client = self.client.clone();
+        let timeout = self.timeout;
+
+        self.runtime.handle.spawn(async move {
+            let response = async {
+                let response = client
+                    .request(request)
+                    .await
+                    .map_err(|e| http("request failed", e))?;
+                let status = response.status();
+                let body = response
+                    .into_body()
+                    .collect()
+                    .await
+                    .map_err(|e| http("reading response failed", e))?;
+                Ok::<_, Error>((status, body.to_bytes()))
+            };
+
+            let response = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, response).await {
+                    Ok(response) => response,
+                    Err(..) => Err(Error::Remote(Box::new(RemoteError {
+                        kind: ErrorKind::Timeout,
+                        message: String::from("request timed out"),
+                        source: None,
+                    }))),
+                },
+                None => response.await,
+            };
+
+            reply(response.and_then(|(status, body)| check(status, body)));
+        });
+    }
+}
+
+impl CacheLike for Client {
+    fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + DeserializeOwned,
+    {
+        Client::get(self, key)
+    }
+
+    fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        Client::insert(self, key, age, value)
+    }
+
+    fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        Client::delete(self, key)
+    }
+
+    fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        Client::namespaced(self, ns)
+    }
+
+    fn wrap<K, F, T, E>(
+        &self,
+        key: K,
+        age: Duration,
+        future: F,
+    ) -> impl Future<Output = Result<T, E>> + Send
+    where
+        K: CacheKey + Send,
+        F: Future<Output = Result<T, E>> + Send,
+        T: 'static + Serialize + DeserializeOwned + Send,
+        E: From<Error> + Send,
+    {
+        Client::wrap(self, key, age, future)
+    }
+}
+
+/// Decode the entry returned by the server, if any.
+fn decode_entry<T>(body: Option<Bytes>) -> Result<State<T>, Error>
+where
+    T: DeserializeOwned,
+{
+    let body = match body {
+        Some(body) => body,
+        None => return Ok(State::Missing),
+    };
+
+    let entry = json::from_slice::<RemoteEntry<T>>(&body)?;
+
+    Ok(match entry.state.as_str() {
+        "fresh" => State::Fresh(entry.stored),
+        _ => State::Expired(entry.stored),
+    })
+}
+
+/// Check the status of a response, returning its body or `None` if the entry
+/// wasn't found.
+fn check(status: StatusCode, body: Bytes) -> Result<Option<Bytes>, Error> {
+    if status == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    if !status.is_success() {
+        let message = match json::from_slice::<ServerError>(&body) {
+            Ok(error) => error.error,
+            Err(..) => String::from_utf8_lossy(&body).into_owned(),
+        };
+
+        return Err(remote(format!("{}: {}", status, message)));
+    }
+
+    Ok(Some(body))
+}
+
+fn remote<M>(message: M) -> Error
+where
+    M: Into<String>,
+{
+    Error::Remote(Box::new(RemoteError {
+        kind: ErrorKind::Storage,
+        message: message.into(),
+        source: None,
+    }))
+}
+
+fn http<E>(message: &str, error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let error = error.into();
+
+    Error::Remote(Box::new(RemoteError {
+        kind: ErrorKind::Storage,
+        message: format!("{}: {}", message, error),
+        source: Some(error),
+    }))
+}
@@ -0,0 +1,291 @@
+//! Formats used to serialize cached values.
+//!
+//! The format used to store new entries is selected with
+//! [Builder::format][crate::Builder::format], or for a specific namespace with
+//! [Builder::namespace_format][crate::Builder::namespace_format]. Every entry records the format
+//! it was stored with, so entries stored with a different format remain
+//! readable.
+//!
+//! Formats other than the built-in ones are plugged in by implementing
+//! [Codec] and selecting it with [Format::Custom].
+//!
+//! ```rust
+//! use futures_cache::codec::{Codec, Format};
+//! use futures_cache::{Cache, Duration, Error};
+//! use serde_cbor::Value;
+//! use std::sync::Arc;
+//!
+//! /// Stores values as pretty-printed JSON.
+//! struct PrettyJson;
+//!
+//! impl Codec for PrettyJson {
+//!     fn id(&self) -> u8 {
+//!         128
+//!     }
+//!
+//!     fn encode(&self, value: &Value) -> Result<Vec<u8>, Error> {
+//!         Ok(serde_json::to_vec_pretty(value)?)
+//!     }
+//!
+//!     fn decode(&self, bytes: &[u8]) -> Result<Value, Error> {
+//!         Ok(serde_json::from_slice(bytes)?)
+//!     }
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//!
+//! let cache = Cache::builder(db.open_tree("cache")?)
+//!     .format(Format::Custom(Arc::new(PrettyJson)))
+//!     .load()?;
+//!
+//! cache.insert("a", Duration::hours(1), &vec![1u32, 2, 3])?;
+//! assert_eq!(Some(vec![1u32, 2, 3]), cache.get::<_, Vec<u32>>("a")?.get());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::Error;
+use serde::de::{DeserializeOwned, DeserializeSeed};
+use serde::{Deserialize, Serialize};
+use serde_cbor as cbor;
+use serde_json as json;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// The smallest identifier of a custom [Codec].
+///
+/// Smaller identifiers are reserved for the built-in formats.
+pub const MIN_CUSTOM_ID: u8 = 128;
+
+/// A serialization format which can be plugged into a cache through
+/// [Format::Custom].
+///
+/// Codecs are used as trait objects, so values are converted to and from a
+/// [serde_cbor::Value] on their way in and out of a codec instead of being
+/// serialized by it directly. Values stored with a codec can therefore always
+/// be decoded without knowing their type, which means that they can be
+/// converted to other formats and listed as JSON. See the
+/// [module level documentation][self].
+pub trait Codec: Send + Sync {
+    /// The identifier recorded in entries stored with the codec, which is
+    /// used to find the codec to read them with.
+    ///
+    /// It must be at least [MIN_CUSTOM_ID], must differ from the identifiers
+    /// of other codecs used with the same database, and must never change.
+    fn id(&self) -> u8;
+
+    /// Serialize the given value.
+    fn encode(&self, value: &cbor::Value) -> Result<Vec<u8>, Error>;
+
+    /// Deserialize a value.
+    fn decode(&self, bytes: &[u8]) -> Result<cbor::Value, Error>;
+}
+
+/// The formats which can be selected for storing values.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum Format {
+    /// Values are stored as [CBOR](https://cbor.io).
+    #[default]
+    Cbor,
+    /// Values are stored as JSON.
+    Json,
+    /// Values are stored as [MessagePack](https://msgpack.org).
+    ///
+    /// Structs are encoded as maps with named fields, so that stored values can
+    /// be read by tooling which doesn't know their layout.
+    ///
+    /// Requires the `msgpack` feature.
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// Values are stored as [postcard](https://docs.rs/postcard).
+    ///
+    /// This produces very compact encodings, but isn't self-describing. Values
+    /// stored with it can only be read back as the type they were stored as,
+    /// so they are skipped by [Cache::list_json][crate::Cache::list_json].
+    ///
+    /// Requires the `postcard` feature.
+    #[cfg(feature = "postcard")]
+    Postcard,
+    /// Values are stored with a custom [Codec].
+    ///
+    /// A cache can read entries stored with any codec which is selected for it
+    /// or for one of its namespaces, or which is registered with
+    /// [Builder::codec][crate::Builder::codec].
+    Custom(Arc<dyn Codec>),
+}
+
+impl Format {
+    /// The tag recorded in stored entries to identify the format.
+    pub(crate) fn id(&self) -> u8 {
+        match self {
+            Format::Cbor => 0,
+            Format::Json => 1,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => 2,
+            #[cfg(feature = "postcard")]
+            Format::Postcard => 3,
+            Format::Custom(codec) => codec.id(),
+        }
+    }
+
+    /// Test if values can be decoded without knowing their type.
+    ///
+    /// Only values stored in self-describing formats can be converted between
+    /// formats.
+    pub(crate) fn is_self_describing(&self) -> bool {
+        match self {
+            #[cfg(feature = "postcard")]
+            Format::Postcard => false,
+            _ => true,
+        }
+    }
+
+    /// Serialize the given value.
+    pub(crate) fn encode<T>(&self, value: &T) -> Result<Vec<u8>, Error>
+    where
+        T: Serialize,
+    {
+        match self {
+            Format::Cbor => Ok(cbor::to_vec(value)?),
+            Format::Json => Ok(json::to_vec(value)?),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|e| Error::Codec(e.into()))
+            }
+            #[cfg(feature = "postcard")]
+            Format::Postcard => postcard::to_allocvec(value).map_err(|e| Error::Codec(e.into())),
+            Format::Custom(codec) => codec.encode(&cbor::value::to_value(value)?),
+        }
+    }
+
+    /// Deserialize a value.
+    pub(crate) fn decode<T>(&self, bytes: &[u8]) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        match self {
+            Format::Custom(codec) => Ok(cbor::value::from_value(codec.decode(bytes)?)?),
+            format => format.decode_borrowed(bytes),
+        }
+    }
+
+    /// Convert a value stored with a custom codec to CBOR, so that it can be
+    /// deserialized while borrowing from it or with a seed.
+    ///
+    /// Values stored in other formats are returned as they are.
+    pub(crate) fn transcode<'a>(
+        &self,
+        bytes: Cow<'a, [u8]>,
+    ) -> Result<(Format, Cow<'a, [u8]>), Error> {
+        match self {
+            Format::Custom(codec) => {
+                let bytes = cbor::to_vec(&codec.decode(&bytes)?)?;
+                Ok((Format::Cbor, Cow::Owned(bytes)))
+            }
+            format => Ok((format.clone(), bytes)),
+        }
+    }
+
+    /// Deserialize a value which may borrow from the given bytes.
+    ///
+    /// Values stored with a custom codec must be [transcoded][Format::transcode]
+    /// first.
+    pub(crate) fn decode_borrowed<'de, T>(&self, bytes: &'de [u8]) -> Result<T, Error>
+    where
+        T: Deserialize<'de>,
+    {
+        match self {
+            Format::Cbor => Ok(cbor::from_slice(bytes)?),
+            Format::Json => Ok(json::from_slice(bytes)?),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| Error::Codec(e.into())),
+            #[cfg(feature = "postcard")]
+            Format::Postcard => postcard::from_bytes(bytes).map_err(|e| Error::Codec(e.into())),
+            Format::Custom(..) => Err(not_transcoded()),
+        }
+    }
+
+    /// Deserialize a value with the given seed.
+    ///
+    /// Values stored with a custom codec must be [transcoded][Format::transcode]
+    /// first.
+    pub(crate) fn decode_seed<'de, S>(&self, bytes: &'de [u8], seed: S) -> Result<S::Value, Error>
+    where
+        S: DeserializeSeed<'de>,
+    {
+        match self {
+            Format::Cbor => {
+                let mut deserializer = cbor::Deserializer::from_slice(bytes);
+                let value = seed.deserialize(&mut deserializer)?;
+                deserializer.end()?;
+                Ok(value)
+            }
+            Format::Json => {
+                let mut deserializer = json::Deserializer::from_slice(bytes);
+                let value = seed.deserialize(&mut deserializer)?;
+                deserializer.end()?;
+                Ok(value)
+            }
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => {
+                let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes);
+                seed.deserialize(&mut deserializer)
+                    .map_err(|e| Error::Codec(e.into()))
+            }
+            #[cfg(feature = "postcard")]
+            Format::Postcard => {
+                let mut deserializer = postcard::Deserializer::from_bytes(bytes);
+                seed.deserialize(&mut deserializer)
+                    .map_err(|e| Error::Codec(e.into()))
+            }
+            Format::Custom(..) => Err(not_transcoded()),
+        }
+    }
+
+    /// Look up a built-in format by the tag recorded in a stored entry.
+    ///
+    /// Custom codecs are looked up in the configuration of the cache.
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Format::Cbor),
+            1 => Some(Format::Json),
+            #[cfg(feature = "msgpack")]
+            2 => Some(Format::MessagePack),
+            #[cfg(feature = "postcard")]
+            3 => Some(Format::Postcard),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for Format {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Cbor => fmt.write_str("Cbor"),
+            Format::Json => fmt.write_str("Json"),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => fmt.write_str("MessagePack"),
+            #[cfg(feature = "postcard")]
+            Format::Postcard => fmt.write_str("Postcard"),
+            Format::Custom(codec) => fmt.debug_tuple("Custom").field(&codec.id()).finish(),
+        }
+    }
+}
+
+/// Formats are equal if they're recorded with the same tag.
+impl PartialEq for Format {
+    fn eq(&self, other: &Self) -> bool {
+        self.id() == other.id()
+    }
+}
+
+impl Eq for Format {}
+
+/// Error raised when a value stored with a custom codec is deserialized
+/// without being transcoded.
+fn not_transcoded() -> Error {
+    Error::Codec("values stored with a custom codec must be transcoded first".into())
+}
@@ -0,0 +1,718 @@
+//! Portable dumps of cached entries.
+//!
+//! A dump is written with [Cache::export][crate::Cache::export] and loaded
+//! with [Cache::import][crate::Cache::import]. It starts
+//! with a header recording the version of the dump format, followed by one
+//! record for each entry with its namespace, key, expiration, dependencies,
+//! and value. Keys and namespaces are stored decoded, with the type of every
+//! value they're made of, like `{"tuple": [{"str": "a"}, {"u64": 1}]}` for the
+//! key `("a", 1u32)`. This means that they're restored exactly, without
+//! depending on how keys are encoded. Values are stored as JSON or CBOR values
+//! so that they don't depend on the [format][crate::codec::Format],
+//! compression, or encryption configured for the cache they were exported
+//! from.
+//!
+//! ```rust
+//! use futures_cache::dump::{ConflictPolicy, DumpFormat, ExportOptions};
+//! use futures_cache::{Cache, Duration};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//! let users = cache.namespaced(&"users")?;
+//! users.insert(1u32, Duration::hours(1), &"Alice")?;
+//! cache.insert("other", Duration::hours(1), &42u32)?;
+//!
+//! let mut dump = Vec::new();
+//! let options = ExportOptions::new().format(DumpFormat::JsonLines).ns(&"users");
+//! let report = cache.export(&mut dump, options)?;
+//! assert_eq!(1, report.exported);
+//! assert!(report.failed.is_empty());
+//! assert_eq!(2, String::from_utf8(dump.clone())?.lines().count());
+//!
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let seeded = Cache::load(db.open_tree("cache")?)?;
+//! let report = seeded.import(&dump[..], ConflictPolicy::Skip)?;
+//! assert_eq!(1, report.imported);
+//!
+//! let users = seeded.namespaced(&"users")?;
+//! assert_eq!(Some("Alice".to_owned()), users.get::<_, String>(1u32)?.get());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::key::Tree;
+use crate::{
+    compress, decode_meta, decode_payload, decompress, encode_value, join_key, key, split_key,
+    Cache, Condition, Duration, Error, InsertOptions, Metadata, StoredEntry, Written, FLAG_RAW,
+};
+use chrono::{DateTime, Utc};
+use hex::ToHex as _;
+use serde::{de, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor as cbor;
+use serde_hashkey as hashkey;
+use serde_json as json;
+use std::borrow::Cow;
+use std::io::{self, BufRead as _};
+
+/// The version of the dump format written by
+/// [Cache::export][crate::Cache::export].
+pub const DUMP_VERSION: u32 = 2;
+
+/// The format of a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DumpFormat {
+    /// One JSON value per line, with byte strings encoded as hex.
+    #[default]
+    JsonLines,
+    /// A sequence of CBOR values.
+    Cbor,
+}
+
+/// Options of [Cache::export][crate::Cache::export].
+#[derive(Default)]
+pub struct ExportOptions {
+    pub(crate) format: DumpFormat,
+    pub(crate) namespaces: Vec<Result<Option<Vec<u8>>, Error>>,
+}
+
+impl ExportOptions {
+    /// Construct options which export every entry as JSON lines.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The format of the dump.
+    pub fn format(mut self, format: DumpFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Export the entries in the given namespace, including namespaces nested
+    /// inside of it.
+    ///
+    /// The namespace is nested inside of the namespace of the handle which
+    /// exports the entries.
+    ///
+    /// This can be called multiple times to export several namespaces. Every
+    /// namespace is exported if it isn't called.
+    pub fn ns<N>(mut self, ns: &N) -> Self
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)
+            .map_err(Error::from)
+            .and_then(|ns| Ok(key::namespace_to_vec(Some(&ns.normalize()))?));
+
+        self.namespaces.push(ns.map(Some));
+        self
+    }
+
+    /// Export the entries in the namespace of the handle which exports them.
+    pub fn default_ns(mut self) -> Self {
+        self.namespaces.push(Ok(None));
+        self
+    }
+}
+
+/// What [Cache::import][crate::Cache::import] does with entries which are
+/// already in the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the entry in the cache.
+    Skip,
+    /// Replace the entry in the cache.
+    Overwrite,
+    /// Replace the entry in the cache if the entry in the dump was stored
+    /// after it. Entries which don't record when they were stored are
+    /// considered older than any other entry.
+    KeepNewer,
+}
+
+/// What was imported by [Cache::import][crate::Cache::import].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImportReport {
+    /// The number of entries imported.
+    pub imported: usize,
+    /// The number of entries which were skipped because they expired since
+    /// they were exported.
+    pub expired: usize,
+    /// The number of entries which were skipped because of the
+    /// [ConflictPolicy].
+    pub conflicts: usize,
+    /// The number of records which couldn't be decoded or imported.
+    pub failed: usize,
+}
+
+/// What was exported by [Cache::export][crate::Cache::export].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct ExportReport {
+    /// The number of entries exported.
+    pub exported: usize,
+    /// The entries which couldn't be exported, and are missing from the dump.
+    pub failed: Vec<ExportFailure>,
+}
+
+/// An entry which [Cache::export][crate::Cache::export] failed to export.
+#[derive(Debug)]
+pub struct ExportFailure {
+    /// The key of the entry as a JSON array of its namespace and its key, or
+    /// `None` if the key is malformed.
+    pub key: Option<json::Value>,
+    /// Why the entry couldn't be exported.
+    pub error: Error,
+}
+
+/// The first record of a dump.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    #[serde(rename = "futures-cache-dump")]
+    version: u32,
+}
+
+/// A record of an entry in a dump.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "V: Deserialize<'de>"))]
+struct Record<V> {
+    /// The levels of the namespace of the entry, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ns: Vec<Tree>,
+    /// The key of the entry, before it's hashed.
+    key: Tree,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "crate::is_zero")]
+    sliding: i64,
+    #[serde(default, skip_serializing_if = "crate::is_false")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    indexes: Vec<(String, Bytes)>,
+    #[serde(default, skip_serializing_if = "crate::is_zero")]
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deps: Vec<Dependency>,
+    /// The value of the entry, unless it was inserted as raw bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<V>,
+    /// The value of an entry inserted as raw bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw: Option<Bytes>,
+}
+
+impl<V> Record<V> {
+    /// Construct the record of a stored entry.
+    fn new(
+        ns: Vec<Tree>,
+        key: Tree,
+        indexes: Vec<(String, Bytes)>,
+        schema: u32,
+        deps: Vec<Dependency>,
+        stored: StoredEntry<Option<V>>,
+    ) -> Self {
+        Self {
+            ns,
+            key,
+            expires_at: stored.expires_at,
+            stored_at: stored.stored_at,
+            sliding: stored.sliding,
+            pinned: stored.pinned,
+            tags: stored.tags,
+            indexes,
+            schema,
+            metadata: stored.metadata,
+            deps,
+            value: stored.value,
+            raw: None,
+        }
+    }
+}
+
+/// An entry which an entry depends on.
+#[derive(Serialize, Deserialize)]
+struct Dependency {
+    /// The levels of the namespace of the entry, outermost first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ns: Vec<Tree>,
+    /// The key of the entry, or its hash if the entry doesn't exist.
+    key: Tree,
+}
+
+/// Bytes which are encoded as hex in human readable formats.
+#[derive(Serialize, Deserialize)]
+struct Bytes(#[serde(with = "bytes")] Vec<u8>);
+
+/// Serialize bytes as hex in human readable formats, and as a byte string
+/// otherwise.
+pub(crate) mod bytes {
+    use super::*;
+
+    pub(crate) fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&bytes.encode_hex::<String>())
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let hex = Cow::<str>::deserialize(deserializer)?;
+            hex::decode(&*hex).map_err(de::Error::custom)
+        } else {
+            Ok(ByteBuf::deserialize(deserializer)?.into_vec())
+        }
+    }
+}
+
+/// Write a dump of the entries of the cache, reporting what was written.
+pub(crate) fn export<W>(
+    cache: &Cache,
+    mut writer: W,
+    options: ExportOptions,
+) -> Result<ExportReport, Error>
+where
+    W: io::Write,
+{
+    let ns_key = cache.ns_key()?;
+
+    let namespaces = options
+        .namespaces
+        .into_iter()
+        .map(|ns| {
+            let mut nested = ns_key.to_vec();
+            let ns = ns?;
+            nested.extend(ns.as_deref().unwrap_or_default());
+            Ok((nested, ns.is_some()))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let header = Header {
+        version: DUMP_VERSION,
+    };
+
+    match options.format {
+        DumpFormat::JsonLines => {
+            write_json(&mut writer, &header)?;
+            export_with::<json::Value>(cache, &namespaces, |record| {
+                write_json(&mut writer, &record)
+            })
+        }
+        DumpFormat::Cbor => {
+            cbor::to_writer(&mut writer, &header)?;
+            export_with::<cbor::Value>(cache, &namespaces, |record| {
+                Ok(cbor::to_writer(&mut writer, &record)?)
+            })
+        }
+    }
+}
+
+/// Write a record as a line of JSON.
+fn write_json<W, T>(writer: &mut W, record: &T) -> Result<(), Error>
+where
+    W: io::Write,
+    T: Serialize,
+{
+    json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Scan the entries to export, passing a record of each one to `write`.
+fn export_with<V>(
+    cache: &Cache,
+    namespaces: &[(Vec<u8>, bool)],
+    mut write: impl FnMut(Record<V>) -> Result<(), Error>,
+) -> Result<ExportReport, Error>
+where
+    V: 'static + de::DeserializeOwned,
+{
+    let now = cache.now();
+    let epoch = cache.epoch();
+    let mut report = ExportReport::default();
+
+    for result in cache.entries() {
+        let (key, value) = result?;
+
+        let ns = match split_key(&key) {
+            Ok((ns, _)) => ns,
+            Err(error) => {
+                log::warn!("{}: failed to export: {}", cache.key_format(&key), error);
+                report.failed.push(ExportFailure { key: None, error });
+                continue;
+            }
+        };
+
+        let included = namespaces.is_empty()
+            || namespaces.iter().any(|(filter, nested)| {
+                if *nested {
+                    ns.starts_with(filter)
+                } else {
+                    ns == filter.as_slice()
+                }
+            });
+
+        if !included {
+            continue;
+        }
+
+        let record = match record::<V>(cache, &key, &value, now, epoch) {
+            Ok(Some(record)) => record,
+            Ok(None) => continue,
+            Err(error) => {
+                log::warn!("{}: failed to export: {}", cache.key_format(&key), error);
+
+                let key = decode_meta(&value).ok().and_then(|meta| {
+                    let original_key = meta.original_key.as_ref().map(|key| key.as_slice());
+                    cache.json_key(&key, original_key).ok()
+                });
+
+                report.failed.push(ExportFailure { key, error });
+                continue;
+            }
+        };
+
+        write(record)?;
+        report.exported += 1;
+    }
+
+    log::trace!(
+        "export -> {} (failed: {})",
+        report.exported,
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// Construct the record of an entry, or `None` if it isn't exported.
+///
+/// Deleted entries, lists, and expired entries which aren't pinned aren't
+/// exported, and neither are entries which were modified while they were
+/// being read. Entries whose hashed key can't be recovered fail to export.
+fn record<V>(
+    cache: &Cache,
+    key: &[u8],
+    value: &[u8],
+    now: DateTime<Utc>,
+    epoch: u64,
+) -> Result<Option<Record<V>>, Error>
+where
+    V: 'static + de::DeserializeOwned,
+{
+    let meta = decode_meta(value)?;
+
+    if meta.deleted_at.is_some()
+        || meta.list.is_some()
+        || (meta.is_expired(now, epoch) && !meta.pinned)
+    {
+        return Ok(None);
+    }
+
+    let (meta, payload) = match cache.load_payload(key, value)? {
+        Some(loaded) => loaded,
+        None => return Ok(None),
+    };
+
+    let (ns, _) = split_key(key)?;
+    let ns = key::namespace_to_trees(ns)?;
+
+    let plain_key = match cache.encoded_plain_key(key, &meta)? {
+        Some(plain_key) => key::to_tree(&plain_key)?,
+        None => return Err(key::KeyError::new("original key isn't stored").into()),
+    };
+
+    let deps = meta
+        .deps
+        .iter()
+        .map(|dep| dependency(cache, dep))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let indexes = meta
+        .indexes
+        .iter()
+        .map(|(name, indexed)| (name.clone(), Bytes(indexed.to_vec())))
+        .collect();
+
+    let schema = meta.schema;
+
+    let record = if meta.flags & FLAG_RAW != 0 {
+        let config = &cache.inner.config;
+        let raw = decompress(meta.flags, payload)?.into_owned();
+        let stored = meta.into_stored_entry(config, None);
+
+        Record {
+            raw: Some(Bytes(raw)),
+            ..Record::new(ns, plain_key, indexes, schema, deps, stored)
+        }
+    } else {
+        let stored = decode_payload::<V>(&cache.inner.config, meta, payload)?;
+        let stored = stored.try_map(|value| Ok(Some(value)))?;
+        Record::new(ns, plain_key, indexes, schema, deps, stored)
+    };
+
+    Ok(Some(record))
+}
+
+/// Construct the record of a dependency.
+///
+/// Hashed keys are replaced with the original key of the entry they depend on
+/// if it exists, so that they're hashed again when they're imported.
+fn dependency(cache: &Cache, dep: &[u8]) -> Result<Dependency, Error> {
+    let (ns, dep_key) = split_key(dep)?;
+    let mut key = key::to_tree(dep_key)?;
+
+    if let Tree::Hashed(..) = key {
+        let meta = cache.inner.db.get(dep)?;
+        let meta = meta.as_deref().and_then(|meta| decode_meta(meta).ok());
+
+        if let Some(plain_key) = meta.and_then(|meta| cache.encoded_plain_key(dep, &meta).ok()?) {
+            key = key::to_tree(&plain_key)?;
+        }
+    }
+
+    Ok(Dependency {
+        ns: key::namespace_to_trees(ns)?,
+        key,
+    })
+}
+
+/// Construct the key a namespace and a key are stored under in the cache.
+///
+/// Keys which are already hashed are stored as they are.
+fn storage_key(cache: &Cache, ns: &[Tree], key: &Tree) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let ns = key::namespace_from_trees(ns)?;
+    let plain_key = key::from_tree(key)?;
+
+    let key = match key {
+        Tree::Hashed(..) => join_key(&ns, &plain_key),
+        _ => cache.storage_key_with(&ns, &plain_key),
+    };
+
+    Ok((key, plain_key))
+}
+
+/// Load a dump into the cache.
+pub(crate) fn import<R>(
+    cache: &Cache,
+    reader: R,
+    policy: ConflictPolicy,
+) -> Result<ImportReport, Error>
+where
+    R: io::Read,
+{
+    let mut reader = io::BufReader::new(reader);
+
+    // JSON lines start with the header object, while CBOR starts with a map.
+    let is_json = match reader.fill_buf()?.first() {
+        Some(b) => *b == b'{' || b.is_ascii_whitespace(),
+        None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    };
+
+    let now = cache.now();
+    let mut report = ImportReport::default();
+
+    if is_json {
+        let mut lines = reader.lines();
+
+        let header = match lines.next() {
+            Some(line) => json::from_str::<Header>(&line?)?,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+
+        check_version(&header)?;
+
+        for line in lines {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match json::from_str::<Record<json::Value>>(&line) {
+                Ok(record) => import_record(cache, record, policy, now, &mut report),
+                Err(e) => {
+                    log::warn!("failed to decode record: {}", e);
+                    report.failed += 1;
+                }
+            }
+        }
+    } else {
+        let mut values = cbor::Deserializer::from_reader(reader).into_iter::<cbor::Value>();
+
+        let header = match values.next() {
+            Some(header) => cbor::value::from_value::<Header>(header?)?,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+
+        check_version(&header)?;
+
+        for value in values {
+            match cbor::value::from_value::<Record<cbor::Value>>(value?) {
+                Ok(record) => import_record(cache, record, policy, now, &mut report),
+                Err(e) => {
+                    log::warn!("failed to decode record: {}", e);
+                    report.failed += 1;
+                }
+            }
+        }
+    }
+
+    log::trace!("import -> {:?}", report);
+    Ok(report)
+}
+
+/// Test that the dump has a version which can be imported.
+///
+/// Dumps of the first version stored keys in their encoded form, so they can't
+/// be imported.
+fn check_version(header: &Header) -> Result<(), Error> {
+    if header.version != DUMP_VERSION {
+        return Err(Error::UnsupportedDumpVersion(header.version));
+    }
+
+    Ok(())
+}
+
+/// Import a single record, counting it in the report.
+fn import_record<V>(
+    cache: &Cache,
+    record: Record<V>,
+    policy: ConflictPolicy,
+    now: DateTime<Utc>,
+    report: &mut ImportReport,
+) where
+    V: Serialize,
+{
+    let key = match record.key {
+        Tree::Hashed(..) => Err(key::KeyError::new("record has a hashed key").into()),
+        _ => storage_key(cache, &record.ns, &record.key),
+    };
+
+    let (key, plain_key) = match key {
+        Ok(key) => key,
+        Err(e) => {
+            log::warn!("failed to import record: {}", e);
+            report.failed += 1;
+            return;
+        }
+    };
+
+    match write_record(cache, &key, &plain_key, record, policy, now) {
+        Ok(Outcome::Imported) => report.imported += 1,
+        Ok(Outcome::Expired) => report.expired += 1,
+        Ok(Outcome::Conflict) => report.conflicts += 1,
+        Err(e) => {
+            log::warn!("{}: failed to import: {}", cache.key_format(&key), e);
+            report.failed += 1;
+        }
+    }
+}
+
+/// The outcome of importing a record.
+enum Outcome {
+    Imported,
+    Expired,
+    Conflict,
+}
+
+/// Write the entry of a record under the given key.
+fn write_record<V>(
+    cache: &Cache,
+    key: &[u8],
+    plain_key: &[u8],
+    record: Record<V>,
+    policy: ConflictPolicy,
+    now: DateTime<Utc>,
+) -> Result<Outcome, Error>
+where
+    V: Serialize,
+{
+    if record.expires_at.is_some_and(|expires_at| expires_at < now) && !record.pinned {
+        return Ok(Outcome::Expired);
+    }
+
+    let existing = cache.inner.db.get(key)?;
+
+    // only fresh entries conflict with the imported entry.
+    let conflict = existing
+        .as_deref()
+        .and_then(|existing| decode_meta(existing).ok())
+        .filter(|meta| meta.deleted_at.is_none() && !meta.is_expired(now, cache.epoch()));
+
+    if let Some(meta) = conflict {
+        let replace = match policy {
+            ConflictPolicy::Skip => false,
+            ConflictPolicy::Overwrite => true,
+            ConflictPolicy::KeepNewer => record.stored_at > meta.stored_at,
+        };
+
+        if !replace {
+            return Ok(Outcome::Conflict);
+        }
+    }
+
+    let (payload, flags) = match (&record.value, record.raw) {
+        (_, Some(Bytes(raw))) => {
+            let (payload, flags) = compress(cache.inner.compression_threshold, raw)?;
+            (payload, flags | FLAG_RAW)
+        }
+        (Some(value), None) => encode_value(
+            cache.inner.compression_threshold,
+            &cache.inner.format,
+            value,
+        )?,
+        (None, None) => return Err(Error::Codec("record has no value".into())),
+    };
+
+    let tags = record.tags.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let indexes = record
+        .indexes
+        .into_iter()
+        .map(|(name, Bytes(indexed))| (name, ByteBuf::from(indexed)))
+        .collect::<Vec<_>>();
+
+    let deps = record
+        .deps
+        .iter()
+        .map(|dep| Ok(ByteBuf::from(storage_key(cache, &dep.ns, &dep.key)?.0)))
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let options = InsertOptions {
+        pinned: record.pinned,
+        sliding: record.sliding != 0,
+        tags: &tags,
+        indexes: &indexes,
+        deps: &deps,
+        plain_key: Some(plain_key),
+        schema: record.schema,
+        expires_at: record.expires_at,
+        forever: record.expires_at.is_none(),
+        condition: Condition::Unchanged(existing.as_deref()),
+        metadata: Some(&record.metadata),
+    };
+
+    let age = Duration::milliseconds(record.sliding);
+
+    match cache.write_payload(key, age, payload, flags, options)? {
+        Written::Replaced(previous) => cache.remove_replaced(key, previous, options)?,
+        // the entry was modified while it was being imported.
+        Written::Rejected(..) => return Ok(Outcome::Conflict),
+    }
+
+    Ok(Outcome::Imported)
+}
@@ -0,0 +1,130 @@
+//! Deserialization of values of any type as JSON.
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde_json as json;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// A value deserialized directly into a [json::Value], from whichever format
+/// it was stored in.
+///
+/// Unlike [json::Value] itself, this accepts maps with keys which aren't
+/// strings, which are formatted as strings, and byte strings, which are
+/// converted into arrays of numbers.
+pub(crate) struct Dynamic(pub(crate) json::Value);
+
+impl<'de> Deserialize<'de> for Dynamic {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(DynamicVisitor).map(Dynamic)
+    }
+}
+
+struct DynamicVisitor;
+
+impl<'de> Visitor<'de> for DynamicVisitor {
+    type Value = json::Value;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E> {
+        Ok(json::Value::Bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(json::Value::from(value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(json::Value::from(value))
+    }
+
+    fn visit_i128<E>(self, value: i128) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if let Ok(value) = i64::try_from(value) {
+            return Ok(json::Value::from(value));
+        }
+
+        match u64::try_from(value) {
+            Ok(value) => Ok(json::Value::from(value)),
+            Err(_) => Err(E::custom("integer out of range")),
+        }
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E> {
+        Ok(json::Value::from(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(json::Value::from(value))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+        Ok(json::Value::String(value))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E> {
+        Ok(json::Value::from(value))
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(json::Value::Null)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(json::Value::Null)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Dynamic::deserialize(deserializer).map(|value| value.0)
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Dynamic::deserialize(deserializer).map(|value| value.0)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+
+        while let Some(Dynamic(value)) = seq.next_element()? {
+            values.push(value);
+        }
+
+        Ok(json::Value::Array(values))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut values = json::Map::new();
+
+        while let Some(Dynamic(key)) = map.next_key()? {
+            let key = match key {
+                json::Value::String(key) => key,
+                json::Value::Bool(..) | json::Value::Number(..) => key.to_string(),
+                _ => return Err(de::Error::custom("map keys must be strings or scalars")),
+            };
+
+            let Dynamic(value) = map.next_value()?;
+            values.insert(key, value);
+        }
+
+        Ok(json::Value::Object(values))
+    }
+}
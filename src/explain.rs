@@ -0,0 +1,177 @@
+//! Explanations of the decisions made by a cache, to diagnose why an entry
+//! keeps missing.
+//!
+//! A cache configured with [Builder::explain][crate::Builder::explain] passes
+//! an [Explanation] to its callback whenever it decides how to treat an entry
+//! which is read, like that it expired or that it was stored before the
+//! [epoch][crate::Cache::bump_epoch] was bumped, whenever
+//! [Cache::wrap][crate::Cache::wrap] decides how to load a value, and
+//! whenever a write is rejected. Nothing is explained unless a callback is
+//! configured.
+//!
+//! ```rust
+//! use futures_cache::explain::Reason;
+//! use futures_cache::{Cache, Duration};
+//! use std::sync::{Arc, Mutex};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let reasons = Arc::new(Mutex::new(Vec::new()));
+//!
+//! let cache = Cache::builder(db.open_tree("cache")?)
+//!     .explain({
+//!         let reasons = reasons.clone();
+//!         move |explanation| reasons.lock().unwrap().push(explanation.reason.clone())
+//!     })
+//!     .load()?;
+//!
+//! cache.insert("a", Duration::seconds(-32), &1u32)?;
+//! cache.get::<_, u32>("a")?;
+//!
+//! let reasons = reasons.lock().unwrap();
+//! assert!(matches!(reasons[0], Reason::Expired { .. }));
+//! assert!(reasons[0].to_string().starts_with("expired 32"));
+//! # Ok(())
+//! # }
+//! ```
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json as json;
+use std::fmt;
+use std::time;
+
+/// A decision made about an entry, passed to the callback registered with
+/// [Builder::explain][crate::Builder::explain].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Explanation {
+    /// The namespace of the entry as JSON, or `null` for the default
+    /// namespace.
+    pub ns: json::Value,
+    /// The key of the entry as JSON, redacted according to
+    /// [Builder::key_redaction][crate::Builder::key_redaction].
+    pub key: json::Value,
+    /// Why the entry was treated the way it was.
+    pub reason: Reason,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}/{}: {}", self.ns, self.key, self.reason)
+    }
+}
+
+/// Why an entry was treated the way it was.
+///
+/// Formatting a reason with [Display][fmt::Display] describes it, like
+/// `expired 32s ago`.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Reason {
+    /// The entry is fresh, and expires after the given duration unless it
+    /// never expires.
+    Fresh {
+        /// How long until the entry expires.
+        expires_in: Option<Duration>,
+    },
+    /// There's no entry.
+    Missing,
+    /// The entry was soft deleted.
+    Deleted {
+        /// When the entry was deleted.
+        at: DateTime<Utc>,
+    },
+    /// The entry expired.
+    Expired {
+        /// How long ago the entry expired.
+        ago: Duration,
+    },
+    /// The entry was stored before the epoch was bumped, see
+    /// [Cache::bump_epoch][crate::Cache::bump_epoch].
+    Invalidated {
+        /// The epoch the entry was stored in.
+        epoch: u64,
+        /// The current epoch.
+        current: u64,
+    },
+    /// The entry is corrupt.
+    Corrupt,
+    /// The entry couldn't be deserialized, and is treated as missing.
+    Undecodable {
+        /// The error deserializing the entry.
+        error: String,
+    },
+    /// [Cache::wrap][crate::Cache::wrap] loaded the value and stored it.
+    Loaded {
+        /// How long the loader took.
+        took: time::Duration,
+    },
+    /// [Cache::wrap][crate::Cache::wrap] failed, since its loader failed.
+    LoaderFailed {
+        /// How long the loader took before it failed.
+        took: time::Duration,
+    },
+    /// [Cache::wrap][crate::Cache::wrap] waited for a concurrent call for the
+    /// same key, which loaded the value.
+    Coalesced,
+    /// [Cache::wrap][crate::Cache::wrap] waited for a concurrent call for the
+    /// same key, which failed to load the value.
+    CoalescedFailed,
+    /// A write was rejected, since the condition it was written with didn't
+    /// hold. Like when [Cache::insert_if_absent][crate::Cache::insert_if_absent]
+    /// finds a fresh entry, or when an entry being modified was modified
+    /// concurrently, in which case the modification is retried.
+    Rejected,
+}
+
+impl Reason {
+    /// The reason an entry stored with the given expiration and epoch is
+    /// expired, or fresh.
+    pub(crate) fn expiry(
+        expires_at: Option<DateTime<Utc>>,
+        epoch: u64,
+        now: DateTime<Utc>,
+        current: u64,
+    ) -> Self {
+        if epoch < current {
+            return Reason::Invalidated { epoch, current };
+        }
+
+        match expires_at {
+            Some(expires_at) if expires_at < now => Reason::Expired {
+                ago: now - expires_at,
+            },
+            expires_at => Reason::Fresh {
+                expires_in: expires_at.map(|expires_at| expires_at - now),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::Fresh {
+                expires_in: Some(expires_in),
+            } => write!(fmt, "fresh, expires in {}s", expires_in.num_seconds()),
+            Reason::Fresh { expires_in: None } => write!(fmt, "fresh, never expires"),
+            Reason::Missing => write!(fmt, "missing"),
+            Reason::Deleted { at } => write!(fmt, "deleted at {}", at),
+            Reason::Expired { ago } => write!(fmt, "expired {}s ago", ago.num_seconds()),
+            Reason::Invalidated { epoch, current } => write!(
+                fmt,
+                "stored in epoch {} before it was bumped to {}",
+                epoch, current
+            ),
+            Reason::Corrupt => write!(fmt, "corrupt"),
+            Reason::Undecodable { error } => write!(fmt, "failed to deserialize: {}", error),
+            Reason::Loaded { took } => write!(fmt, "loaded in {}ms", took.as_millis()),
+            Reason::LoaderFailed { took } => {
+                write!(fmt, "loader failed after {}ms", took.as_millis())
+            }
+            Reason::Coalesced => write!(fmt, "loaded by a concurrent call"),
+            Reason::CoalescedFailed => write!(fmt, "a concurrent call failed to load it"),
+            Reason::Rejected => write!(fmt, "write rejected by its condition"),
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Faults injected into the storage of a cache, to test how an application
+//! behaves when the cache fails.
+//!
+//! A cache configured with [Builder::faults][crate::Builder::faults] fails,
+//! delays or corrupts a fraction of the entries it reads from and writes to
+//! sled. Failures are reported as [Error::Sled][crate::Error::Sled] with an
+//! [ErrorKind::Storage][crate::ErrorKind::Storage] like real storage errors,
+//! and corrupted entries fail their checksum so that they're read as
+//! [State::Corrupt][crate::State::Corrupt].
+//!
+//! Faults are injected where [Cache::get][crate::Cache::get] and friends read
+//! entries and where [Cache::insert][crate::Cache::insert] and friends write
+//! them. Maintenance like cleanups, scans and imports isn't affected. Which
+//! operations are affected is decided by a pseudo random generator, which
+//! produces the same sequence for the same [seed][Faults::seed].
+//!
+//! ```rust
+//! use futures_cache::faults::Faults;
+//! use futures_cache::{Cache, Duration, ErrorKind};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//!
+//! let cache = Cache::builder(db.open_tree("cache")?)
+//!     .faults(Faults::new().fail_writes(1.0))
+//!     .load()?;
+//!
+//! let error = cache.insert("a", Duration::hours(1), &1u32).unwrap_err();
+//! assert_eq!(ErrorKind::Storage, error.kind());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time;
+
+/// The seed used unless one is configured.
+const DEFAULT_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Faults to inject into the reads and writes of a cache.
+///
+/// Rates are fractions between `0.0`, which never injects the fault, and
+/// `1.0`, which always does. See the [module level documentation][self].
+#[derive(Debug)]
+pub struct Faults {
+    reads: Fault,
+    writes: Fault,
+    state: AtomicU64,
+}
+
+/// The faults injected into one kind of operation.
+#[derive(Debug, Default, Clone, Copy)]
+struct Fault {
+    fail: f64,
+    corrupt: f64,
+    delay: f64,
+    delay_by: time::Duration,
+}
+
+/// What to do with an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Perform the operation.
+    Pass,
+    /// Fail the operation.
+    Fail,
+    /// Corrupt the entry read or written.
+    Corrupt,
+}
+
+impl Faults {
+    /// Construct a configuration which doesn't inject any faults.
+    pub fn new() -> Self {
+        Self {
+            reads: Fault::default(),
+            writes: Fault::default(),
+            state: AtomicU64::new(DEFAULT_SEED),
+        }
+    }
+
+    /// Fail the given fraction of reads.
+    pub fn fail_reads(mut self, rate: f64) -> Self {
+        self.reads.fail = rate;
+        self
+    }
+
+    /// Fail the given fraction of writes.
+    pub fn fail_writes(mut self, rate: f64) -> Self {
+        self.writes.fail = rate;
+        self
+    }
+
+    /// Corrupt the given fraction of the entries which are read, without
+    /// modifying what's stored.
+    pub fn corrupt_reads(mut self, rate: f64) -> Self {
+        self.reads.corrupt = rate;
+        self
+    }
+
+    /// Corrupt the given fraction of the entries which are written, so that
+    /// they're stored corrupt.
+    pub fn corrupt_writes(mut self, rate: f64) -> Self {
+        self.writes.corrupt = rate;
+        self
+    }
+
+    /// Block the given fraction of reads for `delay` before they're performed.
+    pub fn delay_reads(mut self, rate: f64, delay: time::Duration) -> Self {
+        self.reads.delay = rate;
+        self.reads.delay_by = delay;
+        self
+    }
+
+    /// Block the given fraction of writes for `delay` before they're
+    /// performed.
+    pub fn delay_writes(mut self, rate: f64, delay: time::Duration) -> Self {
+        self.writes.delay = rate;
+        self.writes.delay_by = delay;
+        self
+    }
+
+    /// Seed the generator deciding which operations are affected.
+    pub fn seed(self, seed: u64) -> Self {
+        // zero is a fixed point of the generator.
+        self.state.store(seed.max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// Decide what to do with a read, blocking if it's delayed.
+    pub(crate) fn on_read(&self) -> Action {
+        self.apply(&self.reads)
+    }
+
+    /// Decide what to do with a write, blocking if it's delayed.
+    pub(crate) fn on_write(&self) -> Action {
+        self.apply(&self.writes)
+    }
+
+    fn apply(&self, fault: &Fault) -> Action {
+        if self.roll(fault.delay) {
+            thread::sleep(fault.delay_by);
+        }
+
+        if self.roll(fault.fail) {
+            return Action::Fail;
+        }
+
+        if self.roll(fault.corrupt) {
+            return Action::Corrupt;
+        }
+
+        Action::Pass
+    }
+
+    /// Test if an event with the given rate happens.
+    fn roll(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+
+        // xorshift64, which is good enough to pick operations.
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+
+        let mut current = self.state.load(Ordering::Relaxed);
+
+        let next = loop {
+            let next = step(current);
+
+            match self.state.compare_exchange_weak(
+                current,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(..) => break next,
+                Err(actual) => current = actual,
+            }
+        };
+
+        ((next >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The error an operation fails with.
+pub(crate) fn error() -> sled::Error {
+    sled::Error::Io(io::Error::other("injected fault"))
+}
+
+/// Corrupt a serialized entry, by flipping the bits of its last byte.
+///
+/// Entries end with their checksum, so they fail it once corrupted.
+pub(crate) fn corrupt(value: &mut [u8]) {
+    if let Some(last) = value.last_mut() {
+        *last ^= 0xff;
+    }
+}
+
+/// Apply the action decided for a read to the entry which was read.
+pub(crate) fn apply(action: Action, value: Option<sled::IVec>) -> Option<sled::IVec> {
+    match (action, value) {
+        (Action::Corrupt, Some(value)) => {
+            let mut value = value.to_vec();
+            corrupt(&mut value);
+            Some(value.into())
+        }
+        (_, value) => value,
+    }
+}
@@ -0,0 +1,392 @@
+//! Buses which broadcast invalidations between caches.
+//!
+//! A cache configured with
+//! [Builder::invalidation_bus][crate::Builder::invalidation_bus] publishes an
+//! [Invalidation] whenever one of its entries is modified or removed, and
+//! whenever its epoch or the generation of a namespace is bumped.
+//! Caches sharing the bus apply bumped epochs and generations as they're
+//! received, and [Cache::invalidations][crate::Cache::invalidations] lets an
+//! application drop the copies of entries it holds in memory.
+//!
+//! Every subscriber receives every message published on the bus, including
+//! the ones published by its own cache. A cache itself only
+//! [subscribes to bumps][Bus::subscribe_bumps], so that it doesn't queue
+//! messages about entries which it has no use for.
+//!
+//! ```rust
+//! use futures_cache::invalidation::{Invalidation, LocalBus};
+//! use futures_cache::{Cache, Duration};
+//! use futures_util::stream::StreamExt as _;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let bus = LocalBus::new();
+//!
+//! let cache = Cache::builder(db.open_tree("cache")?)
+//!     .invalidation_bus(bus.clone())
+//!     .load()?;
+//!
+//! let mut invalidations = cache.invalidations()?;
+//! cache.insert("a", Duration::hours(1), &1u32)?;
+//!
+//! let invalidation = futures::executor::block_on(invalidations.next());
+//! assert!(matches!(invalidation, Some(Invalidation::Entry { .. })));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{AuditOp, Error};
+use futures_channel::mpsc;
+use futures_util::stream::Stream;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_json as json;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// A message published on an invalidation bus.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum Invalidation {
+    /// An entry was modified or removed.
+    Entry {
+        /// The kind of mutation.
+        op: AuditOp,
+        /// The namespace of the entry as JSON, or `null` for the default
+        /// namespace.
+        ns: json::Value,
+        /// The key of the entry as JSON, redacted according to
+        /// [Builder::key_redaction][crate::Builder::key_redaction].
+        key: json::Value,
+    },
+    /// The epoch was bumped with [Cache::bump_epoch][crate::Cache::bump_epoch].
+    Epoch(u64),
+    /// The generation of a namespace was bumped with
+    /// [Cache::bump_generation][crate::Cache::bump_generation].
+    Generation {
+        /// The encoded namespace.
+        ns: ByteBuf,
+        /// The new generation.
+        generation: u64,
+    },
+}
+
+/// A bus which broadcasts invalidations to every subscriber.
+pub trait Bus: Send + Sync + 'static {
+    /// Publish a message to every subscriber of the bus.
+    fn publish(&self, message: &Invalidation) -> Result<(), Error>;
+
+    /// Subscribe to the messages published from now on.
+    fn subscribe(&self) -> Result<Subscriber, Error>;
+
+    /// Subscribe to the epochs and generations bumped from now on, leaving out
+    /// the messages about entries.
+    ///
+    /// This is what a cache subscribes to, and the messages are only taken
+    /// when the cache is used, so a bus should implement it to not queue
+    /// messages about entries which are never taken. Defaults to
+    /// [Bus::subscribe].
+    fn subscribe_bumps(&self) -> Result<Subscriber, Error> {
+        self.subscribe()
+    }
+}
+
+/// A stream of the messages published on a bus.
+pub struct Subscriber {
+    rx: mpsc::UnboundedReceiver<Invalidation>,
+}
+
+impl Subscriber {
+    /// Take the next message which has already been received, without
+    /// waiting for one.
+    pub fn try_next(&mut self) -> Option<Invalidation> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Stream for Subscriber {
+    type Item = Invalidation;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().rx).poll_next(cx)
+    }
+}
+
+/// A bus which broadcasts invalidations between caches in the same process.
+///
+/// Clones of the bus publish to the same subscribers.
+#[derive(Clone, Default)]
+pub struct LocalBus {
+    subscribers: Arc<Mutex<Vec<Subscription>>>,
+}
+
+/// A subscriber of a [LocalBus].
+struct Subscription {
+    tx: mpsc::UnboundedSender<Invalidation>,
+    bumps_only: bool,
+}
+
+impl LocalBus {
+    /// Construct a new bus without any subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver a message to every subscriber, dropping the ones which are
+    /// gone.
+    fn deliver(&self, message: &Invalidation) {
+        let is_entry = matches!(message, Invalidation::Entry { .. });
+
+        self.subscribers.lock().retain(|subscription| {
+            if is_entry && subscription.bumps_only {
+                return !subscription.tx.is_closed();
+            }
+
+            subscription.tx.unbounded_send(message.clone()).is_ok()
+        });
+    }
+
+    fn add_subscriber(&self, bumps_only: bool) -> Subscriber {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers
+            .lock()
+            .push(Subscription { tx, bumps_only });
+        Subscriber { rx }
+    }
+}
+
+impl Bus for LocalBus {
+    fn publish(&self, message: &Invalidation) -> Result<(), Error> {
+        self.deliver(message);
+        Ok(())
+    }
+
+    fn subscribe(&self) -> Result<Subscriber, Error> {
+        Ok(self.add_subscriber(false))
+    }
+
+    fn subscribe_bumps(&self) -> Result<Subscriber, Error> {
+        Ok(self.add_subscriber(true))
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::UnixBus;
+
+#[cfg(unix)]
+mod unix {
+    use super::{Bus, Invalidation, LocalBus, Subscriber};
+    use crate::Error;
+    use crossbeam::channel;
+    use serde_cbor as cbor;
+    use std::fs;
+    use std::io;
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// The largest message which can be received.
+    const MAX_MESSAGE: usize = 64 * 1024;
+
+    /// The number of messages waiting to be sent to other processes, after
+    /// which further messages are dropped.
+    const MAX_QUEUED: usize = 1024;
+
+    /// Used to give every bus in a process its own socket.
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    /// A bus which broadcasts invalidations between processes on the same
+    /// host through unix sockets.
+    ///
+    /// Every bus binds a datagram socket in a shared directory, and publishes
+    /// by sending each message to every other socket in it. The socket is
+    /// removed once every clone of the bus is dropped, and sockets left
+    /// behind by processes which exited are removed when they're found.
+    ///
+    /// Messages are sent by a background thread, so publishing never blocks.
+    /// Messages are dropped rather than waited on if more than 1024 are
+    /// waiting to be sent, or if the socket of another process is full because
+    /// it doesn't keep up.
+    #[derive(Clone)]
+    pub struct UnixBus {
+        inner: Arc<Inner>,
+    }
+
+    struct Inner {
+        path: PathBuf,
+        socket: UnixDatagram,
+        closed: Arc<AtomicBool>,
+        outgoing: channel::Sender<Vec<u8>>,
+        local: LocalBus,
+    }
+
+    impl UnixBus {
+        /// Bind a new bus in the given directory, which is created if it
+        /// doesn't exist.
+        pub fn bind<P>(dir: P) -> Result<Self, Error>
+        where
+            P: AsRef<Path>,
+        {
+            let dir = dir.as_ref().to_owned();
+            fs::create_dir_all(&dir)?;
+
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("{}-{}.sock", process::id(), id));
+
+            // a socket left behind by an earlier process with the same id.
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+
+            let socket = UnixDatagram::bind(&path)?;
+            let local = LocalBus::new();
+
+            let closed = Arc::new(AtomicBool::new(false));
+
+            let receiver = socket.try_clone()?;
+            let forward = local.clone();
+            let stop = closed.clone();
+
+            thread::Builder::new()
+                .name(String::from("futures-cache-invalidation"))
+                .spawn(move || receive(receiver, forward, &stop))?;
+
+            // sending uses a socket of its own, since a socket shares whether
+            // it's non-blocking with its clones.
+            let sender = UnixDatagram::unbound()?;
+            sender.set_nonblocking(true)?;
+
+            let (outgoing, queued) = channel::bounded(MAX_QUEUED);
+            let own = path.clone();
+
+            thread::Builder::new()
+                .name(String::from("futures-cache-invalidation-publish"))
+                .spawn(move || send(sender, &dir, &own, queued))?;
+
+            Ok(Self {
+                inner: Arc::new(Inner {
+                    path,
+                    socket,
+                    closed,
+                    outgoing,
+                    local,
+                }),
+            })
+        }
+    }
+
+    impl Bus for UnixBus {
+        fn publish(&self, message: &Invalidation) -> Result<(), Error> {
+            let bytes = cbor::to_vec(message)?;
+
+            if self.inner.outgoing.try_send(bytes).is_err() {
+                log::warn!("dropping invalidation, too many are waiting to be sent");
+            }
+
+            self.inner.local.deliver(message);
+            Ok(())
+        }
+
+        fn subscribe(&self) -> Result<Subscriber, Error> {
+            self.inner.local.subscribe()
+        }
+
+        fn subscribe_bumps(&self) -> Result<Subscriber, Error> {
+            self.inner.local.subscribe_bumps()
+        }
+    }
+
+    impl Drop for Inner {
+        fn drop(&mut self) {
+            // wakes up the receiving thread so that it exits. The sending
+            // thread exits once `outgoing` is dropped.
+            self.closed.store(true, Ordering::Release);
+            let _ = self.socket.shutdown(Shutdown::Both);
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    /// Send queued messages to every other socket in the directory until the
+    /// bus is dropped.
+    fn send(socket: UnixDatagram, dir: &Path, own: &Path, queued: channel::Receiver<Vec<u8>>) {
+        for bytes in queued {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("{}: failed to publish: {}", dir.display(), e);
+                    continue;
+                }
+            };
+
+            for entry in entries {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        log::warn!("{}: failed to publish: {}", dir.display(), e);
+                        continue;
+                    }
+                };
+
+                if path == own || path.extension() != Some("sock".as_ref()) {
+                    continue;
+                }
+
+                match socket.send_to(&bytes, &path) {
+                    Ok(..) => (),
+                    Err(e)
+                        if e.kind() == io::ErrorKind::ConnectionRefused
+                            || e.kind() == io::ErrorKind::NotFound =>
+                    {
+                        log::trace!("{}: removing stale socket", path.display());
+                        let _ = fs::remove_file(&path);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        log::debug!("{}: dropping invalidation, socket is full", path.display());
+                    }
+                    Err(e) => {
+                        log::warn!("{}: failed to publish: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Forward messages received on the socket to local subscribers until the
+    /// bus is dropped.
+    fn receive(socket: UnixDatagram, local: LocalBus, closed: &AtomicBool) {
+        let mut buf = vec![0; MAX_MESSAGE];
+
+        loop {
+            let result = socket.recv(&mut buf);
+
+            if closed.load(Ordering::Acquire) {
+                break;
+            }
+
+            let len = match result {
+                // an empty datagram, which isn't a message.
+                Ok(0) => continue,
+                Ok(len) => len,
+                Err(e) => {
+                    log::warn!("failed to receive invalidation: {}", e);
+                    // don't spin on errors which persist.
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+
+            match cbor::from_slice::<Invalidation>(&buf[..len]) {
+                Ok(message) => local.deliver(&message),
+                Err(e) => log::warn!("failed to decode invalidation: {}", e),
+            }
+        }
+    }
+}
@@ -0,0 +1,1673 @@
+//! Binary encoding of cache keys.
+//!
+//! Every value is encoded as a one byte tag followed by its payload, which
+//! makes the encoding self-describing and self-delimiting:
+//!
+//! * Integers are stored big-endian in a fixed width, and strings and bytes are
+//!   prefixed with their length.
+//! * Numbers are encoded so that their encodings sort in the same order as the
+//!   numbers themselves, so ranges of keys can be scanned in order, see
+//!   [crate::Cache::scan].
+//! * The fields of the outermost tuple or struct are simply concatenated
+//!   without a length prefix. This means that the encoding of `(a,)` is a
+//!   prefix of the encoding of `(a, b)`. Nested tuples and structs are
+//!   prefixed with their number of fields.
+//! * Sequences and maps are prefixed with their number of elements. Map entries
+//!   are sorted by their encoded key so that the encoding is deterministic.
+//! * Enum variants are identified by name rather than by index.
+//!
+//! Keys encoded with [KeyEncoder::raw] are stored as a [RAW] tag followed by
+//! their bytes, which extend to the end of the key. They're decoded as bytes.
+//!
+//! Keys of caches which hash their keys are instead stored as a [HASHED] tag
+//! followed by the 32 byte hash of the encoded key. These can't be decoded.
+//!
+//! The generations of generational namespaces are stored as a level of their
+//! namespace with a [GENERATION] tag followed by the generation as a big-endian
+//! `u64`. No serialized value produces this tag, so a generation can't be
+//! confused with a namespace level which happens to be an integer. They're
+//! decoded as `{"generation": n}`.
+//!
+//! Namespaces are encoded like values inside of a compound value, except that
+//! integers are encoded by value rather than by type. Nested namespaces are
+//! encoded as the concatenation of their levels, outermost first, which can't
+//! be confused with a namespace which isn't nested since each level is
+//! self-delimiting.
+
+use serde::de::{self, Deserialize};
+use serde::ser::{self, Serialize};
+use serde_hashkey as hashkey;
+use serde_json as json;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+
+const UNIT: u8 = 0x01;
+const FALSE: u8 = 0x02;
+const TRUE: u8 = 0x03;
+const NONE: u8 = 0x04;
+const SOME: u8 = 0x05;
+const U64: u8 = 0x10;
+const U128: u8 = 0x11;
+const I64: u8 = 0x12;
+const I128: u8 = 0x13;
+const F64: u8 = 0x18;
+const STR: u8 = 0x20;
+const BYTES: u8 = 0x21;
+const SEQ: u8 = 0x30;
+const TUPLE: u8 = 0x31;
+const MAP: u8 = 0x32;
+const NESTED_TUPLE: u8 = 0x33;
+const UNIT_VARIANT: u8 = 0x40;
+const NEWTYPE_VARIANT: u8 = 0x41;
+const TUPLE_VARIANT: u8 = 0x42;
+const NESTED_TUPLE_VARIANT: u8 = 0x43;
+/// Tag of a raw key.
+const RAW: u8 = 0x50;
+/// Tag of the generation of a generational namespace.
+const GENERATION: u8 = 0x60;
+/// Tag of a hashed key.
+pub(crate) const HASHED: u8 = 0xf0;
+
+/// Error raised when encoding or decoding a key.
+#[derive(Debug)]
+pub struct KeyError {
+    message: String,
+}
+
+impl KeyError {
+    pub(crate) fn new<T>(message: T) -> Self
+    where
+        T: fmt::Display,
+    {
+        Self {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(fmt)
+    }
+}
+
+impl error::Error for KeyError {}
+
+impl ser::Error for KeyError {
+    fn custom<T>(msg: T) -> Self
+    where
+        T: fmt::Display,
+    {
+        Self::new(msg)
+    }
+}
+
+impl de::Error for KeyError {
+    fn custom<T>(msg: T) -> Self
+    where
+        T: fmt::Display,
+    {
+        Self::new(msg)
+    }
+}
+
+/// A type which can be used as the key of an entry.
+///
+/// This is implemented for every type which implements [Serialize], which is
+/// encoded with an order-preserving binary encoding of its serialized form. Types
+/// which already have a stable binary representation can implement it to
+/// control how they're encoded instead, see [RawKey].
+///
+/// Some keys are encoded directly instead of being serialized:
+///
+/// * `&[u8]` and `Vec<u8>` are stored as their bytes, like a [RawKey]. They
+///   would otherwise serialize as sequences of integers.
+/// * `&str` and `String` are written directly, with the same encoding as when
+///   they're serialized.
+/// * With the `uuid` feature, `uuid::Uuid` is stored as its 16 bytes, like a
+///   [RawKey].
+///
+/// # Deriving
+///
+/// With the `derive` feature, `#[derive(CacheKey)]` encodes a struct or an
+/// enum as a record, see [KeyEncoder::record]. Unlike serialized keys, the key
+/// doesn't depend on the order in which fields are declared, and it's tagged
+/// with a version which can be bumped to deliberately discard existing entries
+/// when the meaning of a key changes.
+///
+/// The following attributes are supported:
+///
+/// * `#[cache_key(version = 1)]` on the type sets the version of its keys,
+///   which defaults to `0`.
+/// * `#[cache_key(rename = "name")]` on the type, a variant, or a field
+///   encodes it under a different name, so it can be renamed without changing
+///   its keys.
+/// * `#[cache_key(skip)]` on a field leaves it out of the key.
+/// * `#[cache_key(default)]` on a field leaves it out of the key when it's
+///   equal to its default value, so a field can be added without changing the
+///   keys of existing entries.
+/// * `#[cache_key(crate = "path")]` on the type sets the path to this crate.
+///
+/// ```rust
+/// # #[cfg(feature = "derive")]
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use futures_cache::{Cache, CacheKey, Duration};
+///
+/// #[derive(CacheKey)]
+/// #[cache_key(version = 1)]
+/// struct Profile<'a> {
+///     user: &'a str,
+///     #[cache_key(default)]
+///     locale: Option<&'a str>,
+/// }
+///
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let cache = Cache::load(db.open_tree("cache")?)?;
+///
+/// cache.insert(Profile { user: "alice", locale: None }, Duration::hours(1), &42u32)?;
+/// # Ok(())
+/// # }
+/// # #[cfg(not(feature = "derive"))]
+/// # fn main() {}
+/// ```
+///
+/// ```rust
+/// use futures_cache::{CacheKey, KeyEncoder, KeyError};
+///
+/// struct Digest([u8; 32]);
+///
+/// impl CacheKey for Digest {
+///     fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+///         encoder.raw(&self.0)
+///     }
+/// }
+/// ```
+pub trait CacheKey {
+    /// Encode the key with the given encoder.
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError>;
+}
+
+impl<T> CacheKey for T
+where
+    T: ?Sized + Serialize,
+{
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        if let Some(bytes) = direct_bytes(self) {
+            return encoder.raw(bytes);
+        }
+
+        if let Some(string) = direct_str(self) {
+            return Encoder {
+                out: encoder.out,
+                nested: false,
+            }
+            .bytes(STR, string.as_bytes());
+        }
+
+        encoder.serialize(self)
+    }
+}
+
+/// The bytes of a key which is stored as they are, if it's one of the types
+/// which [CacheKey] stores directly.
+fn direct_bytes<'k, T>(key: &'k T) -> Option<&'k [u8]>
+where
+    T: ?Sized,
+{
+    if let Some(bytes) = downcast::<T, &'k [u8]>(key) {
+        return Some(bytes);
+    }
+
+    if let Some(bytes) = downcast::<T, Vec<u8>>(key) {
+        return Some(bytes);
+    }
+
+    if let Some(bytes) = downcast::<T, &'k Vec<u8>>(key) {
+        return Some(bytes);
+    }
+
+    #[cfg(feature = "uuid")]
+    {
+        if let Some(uuid) = downcast::<T, uuid::Uuid>(key) {
+            return Some(uuid.as_bytes());
+        }
+
+        if let Some(uuid) = downcast::<T, &'k uuid::Uuid>(key) {
+            return Some(uuid.as_bytes());
+        }
+    }
+
+    None
+}
+
+/// The string of a key which is written directly, if it's one of the types
+/// which [CacheKey] writes directly.
+fn direct_str<'k, T>(key: &'k T) -> Option<&'k str>
+where
+    T: ?Sized,
+{
+    if let Some(string) = downcast::<T, &'k str>(key) {
+        return Some(string);
+    }
+
+    if let Some(string) = downcast::<T, String>(key) {
+        return Some(string);
+    }
+
+    if let Some(string) = downcast::<T, &'k String>(key) {
+        return Some(string);
+    }
+
+    None
+}
+
+/// Downcast a reference to `U` if `T` is `U`.
+///
+/// Types are compared with [typeid::of], which ignores lifetimes. Every `U`
+/// used here only holds references which outlive the given reference, which
+/// `T` must do as well for the reference to exist.
+fn downcast<T, U>(value: &T) -> Option<&U>
+where
+    T: ?Sized,
+{
+    if typeid::of::<T>() != typeid::of::<U>() {
+        return None;
+    }
+
+    // SAFETY: `T` and `U` are the same type up to lifetimes, see above.
+    Some(unsafe { &*(value as *const T).cast::<U>() })
+}
+
+/// Encoder passed to [CacheKey::encode_key], which encodes a key in one of the
+/// supported ways.
+pub struct KeyEncoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl<'a> KeyEncoder<'a> {
+    /// Encode the key as a record with the given name and version, whose fields
+    /// are added with [RecordEncoder::field].
+    ///
+    /// Records are what `#[derive(CacheKey)]` encodes types as. Their fields
+    /// are encoded along with their names, so the same fields added in the
+    /// same order produce the same key regardless of how the type declares
+    /// them.
+    pub fn record(self, name: &str, version: u32) -> Result<RecordEncoder<'a>, KeyError> {
+        self.out.push(TUPLE);
+        let mut record = RecordEncoder { out: self.out };
+        record.nested(name)?;
+        record.nested(&version)?;
+        Ok(record)
+    }
+
+    /// Encode the key by serializing the given value.
+    pub fn serialize<T>(self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut Encoder {
+            out: self.out,
+            nested: false,
+        })
+    }
+
+    /// Encode the key as the given bytes, without serializing them.
+    ///
+    /// Raw keys never compare equal to serialized keys, and they sort by their
+    /// bytes. They're listed as bytes by [Cache::list_json][crate::Cache::list_json]
+    /// and can be decoded into types like
+    /// [serde_bytes::ByteBuf](https://docs.rs/serde_bytes).
+    pub fn raw(self, bytes: &[u8]) -> Result<(), KeyError> {
+        self.out.push(RAW);
+        self.out.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Encoder for the fields of a record, constructed with [KeyEncoder::record].
+pub struct RecordEncoder<'a> {
+    out: &'a mut Vec<u8>,
+}
+
+impl RecordEncoder<'_> {
+    /// Add a field to the record.
+    pub fn field<T>(&mut self, name: &str, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.nested(name)?;
+        self.nested(value)
+    }
+
+    /// Finish encoding the record.
+    pub fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+
+    fn nested<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut Encoder {
+            out: self.out,
+            nested: true,
+        })
+    }
+}
+
+/// A key which has already been encoded, used by `#[cached]` to encode the
+/// arguments of a function before they're moved into its body.
+#[doc(hidden)]
+#[cfg_attr(not(feature = "derive"), allow(dead_code))]
+pub struct EncodedKey(Vec<u8>);
+
+#[cfg_attr(not(feature = "derive"), allow(dead_code))]
+impl EncodedKey {
+    /// Encode a key with the given function.
+    pub fn new<F>(encode: F) -> Result<Self, KeyError>
+    where
+        F: FnOnce(KeyEncoder<'_>) -> Result<(), KeyError>,
+    {
+        let mut out = Vec::new();
+        encode(KeyEncoder { out: &mut out })?;
+        Ok(Self(out))
+    }
+}
+
+impl CacheKey for EncodedKey {
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        encoder.out.extend_from_slice(&self.0);
+        Ok(())
+    }
+}
+
+/// A key which is stored as the given bytes, like a digest or a UUID, rather
+/// than being serialized.
+///
+/// Byte slices and vectors are already stored as they are, see [CacheKey].
+/// Wrapping other types which can be viewed as bytes, like arrays, in a raw
+/// key stores them the same way, and lets the caller control the layout of
+/// the key.
+///
+/// ```rust
+/// use futures_cache::{Cache, Duration, RawKey};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let cache = Cache::load(db.open_tree("cache")?)?;
+///
+/// let digest = [0xab; 32];
+/// cache.insert(RawKey(&digest), Duration::hours(1), &"value")?;
+///
+/// assert_eq!(Some("value".to_owned()), cache.get::<_, String>(RawKey(&digest))?.get());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RawKey<B>(pub B);
+
+impl<B> CacheKey for RawKey<B>
+where
+    B: AsRef<[u8]>,
+{
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        encoder.raw(self.0.as_ref())
+    }
+}
+
+/// A key which has already been encoded, like the keys sent to a
+/// [server][crate::server] by its clients.
+#[cfg(feature = "server")]
+pub(crate) struct Encoded(Vec<u8>);
+
+#[cfg(feature = "server")]
+impl Encoded {
+    /// Check that the bytes are a key as encoded by [CacheKey::encode_key].
+    pub(crate) fn new(key: Vec<u8>) -> Result<Self, KeyError> {
+        if let Tree::Hashed(..) | Tree::Generation(..) = to_tree(&key)? {
+            return Err(KeyError::new("not a key which can be encoded"));
+        }
+
+        Ok(Self(key))
+    }
+}
+
+#[cfg(feature = "server")]
+impl CacheKey for Encoded {
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        encoder.out.extend_from_slice(&self.0);
+        Ok(())
+    }
+}
+
+/// Encode the given key.
+pub(crate) fn encode<T>(key: &T) -> Result<Vec<u8>, KeyError>
+where
+    T: ?Sized + CacheKey,
+{
+    let mut out = Vec::new();
+    key.encode_key(KeyEncoder { out: &mut out })?;
+    Ok(out)
+}
+
+/// Encode the given key.
+pub(crate) fn to_vec<T>(value: &T) -> Result<Vec<u8>, KeyError>
+where
+    T: ?Sized + Serialize,
+{
+    let mut out = Vec::new();
+    value.serialize(&mut Encoder {
+        out: &mut out,
+        nested: false,
+    })?;
+    Ok(out)
+}
+
+/// Decode an encoded key.
+pub(crate) fn from_slice<'de, T>(mut bytes: &'de [u8]) -> Result<T, KeyError>
+where
+    T: Deserialize<'de>,
+{
+    let value = T::deserialize(&mut Decoder { bytes: &mut bytes })?;
+
+    if !bytes.is_empty() {
+        return Err(KeyError::new("trailing bytes in key"));
+    }
+
+    Ok(value)
+}
+
+/// Decode an encoded key into JSON.
+///
+/// Tuples and structs are decoded as arrays, and enum variants other than unit
+/// variants as single-entry objects keyed by the name of the variant.
+pub(crate) fn to_json(mut bytes: &[u8]) -> Result<json::Value, KeyError> {
+    let value = Decoder { bytes: &mut bytes }.value()?;
+
+    if !bytes.is_empty() {
+        return Err(KeyError::new("trailing bytes in key"));
+    }
+
+    Ok(value)
+}
+
+/// Encode the namespace of a key.
+///
+/// The absence of a namespace is encoded as no bytes at all, and `1u32` and
+/// `1i64` name the same namespace.
+pub(crate) fn namespace_to_vec(ns: Option<&hashkey::Key>) -> Result<Vec<u8>, KeyError> {
+    match ns {
+        Some(ns) => to_nested_vec(&Namespace(ns)),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Decode an encoded namespace into JSON, with one value for each level of a
+/// nested namespace, outermost first.
+pub(crate) fn namespace_to_json(mut bytes: &[u8]) -> Result<Vec<json::Value>, KeyError> {
+    let mut levels = Vec::new();
+
+    while !bytes.is_empty() {
+        levels.push(Decoder { bytes: &mut bytes }.value()?);
+    }
+
+    Ok(levels)
+}
+
+/// Encode a generation as a level of a namespace.
+pub(crate) fn generation_level(generation: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(9);
+    out.push(GENERATION);
+    out.extend_from_slice(&generation.to_be_bytes());
+    out
+}
+
+/// Find the generation levels of an encoded namespace, returning the offset of
+/// each level along with its generation, outermost first.
+pub(crate) fn generations(ns: &[u8]) -> Result<Vec<(usize, u64)>, KeyError> {
+    let mut out = Vec::new();
+
+    if !ns.contains(&GENERATION) {
+        return Ok(out);
+    }
+
+    let mut bytes = ns;
+
+    while !bytes.is_empty() {
+        let offset = ns.len() - bytes.len();
+        let mut decoder = Decoder { bytes: &mut bytes };
+
+        if decoder.bytes.first() == Some(&GENERATION) {
+            decoder.take(1)?;
+            out.push((offset, u64::from_be_bytes(decoder.array()?)));
+        } else {
+            decoder.value()?;
+        }
+    }
+
+    Ok(out)
+}
+
+/// A decoded key or level of a namespace, which keeps the type of every value
+/// so that it can be encoded again exactly.
+///
+/// This is how [dumps][crate::dump] store keys, so that they don't depend on
+/// how keys are encoded. Tuples and structs are both decoded as tuples since
+/// they're encoded the same way.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Tree {
+    Unit,
+    Bool(bool),
+    None,
+    Some(Box<Tree>),
+    U64(u64),
+    U128(#[serde(with = "display")] u128),
+    I64(i64),
+    I128(#[serde(with = "display")] i128),
+    F64(#[serde(with = "display")] f64),
+    Str(String),
+    Bytes(#[serde(with = "crate::dump::bytes")] Vec<u8>),
+    Seq(Vec<Tree>),
+    Tuple(Vec<Tree>),
+    Map(Vec<(Tree, Tree)>),
+    UnitVariant(String),
+    NewtypeVariant(String, Box<Tree>),
+    TupleVariant(String, Vec<Tree>),
+    Raw(#[serde(with = "crate::dump::bytes")] Vec<u8>),
+    Hashed(#[serde(with = "crate::dump::bytes")] Vec<u8>),
+    Generation(u64),
+}
+
+impl Tree {
+    /// Encode the tree, like [Encoder] encodes the value it was decoded from.
+    fn encode(&self, encoder: &mut Encoder<'_>) -> Result<(), KeyError> {
+        use ser::Serializer as _;
+
+        match self {
+            Tree::Unit => encoder.serialize_unit(),
+            Tree::Bool(v) => encoder.serialize_bool(*v),
+            Tree::None => encoder.serialize_none(),
+            Tree::Some(value) => {
+                encoder.out.push(SOME);
+                value.encode(encoder)
+            }
+            Tree::U64(v) => encoder.serialize_u64(*v),
+            Tree::U128(v) => encoder.serialize_u128(*v),
+            Tree::I64(v) => encoder.serialize_i64(*v),
+            Tree::I128(v) => encoder.serialize_i128(*v),
+            Tree::F64(v) => encoder.serialize_f64(*v),
+            Tree::Str(v) => encoder.serialize_str(v),
+            Tree::Bytes(v) => encoder.serialize_bytes(v),
+            Tree::Seq(values) => {
+                encoder.out.push(SEQ);
+                encoder.len(values.len())?;
+                values
+                    .iter()
+                    .try_for_each(|v| v.encode(&mut encoder.nested()))
+            }
+            Tree::Tuple(values) => {
+                encoder.tuple(values.len())?;
+                values
+                    .iter()
+                    .try_for_each(|v| v.encode(&mut encoder.nested()))
+            }
+            Tree::Map(entries) => {
+                let mut encoded = entries
+                    .iter()
+                    .map(|(k, v)| Ok((k.to_nested_vec()?, v.to_nested_vec()?)))
+                    .collect::<Result<Vec<_>, KeyError>>()?;
+
+                encoded.sort();
+                encoder.out.push(MAP);
+                encoder.len(encoded.len())?;
+
+                for (k, v) in encoded {
+                    encoder.out.extend_from_slice(&k);
+                    encoder.out.extend_from_slice(&v);
+                }
+
+                Ok(())
+            }
+            Tree::UnitVariant(name) => encoder.name(UNIT_VARIANT, name),
+            Tree::NewtypeVariant(name, value) => {
+                encoder.name(NEWTYPE_VARIANT, name)?;
+                value.encode(encoder)
+            }
+            Tree::TupleVariant(name, values) => {
+                encoder.tuple_variant(name, values.len())?;
+                values
+                    .iter()
+                    .try_for_each(|v| v.encode(&mut encoder.nested()))
+            }
+            Tree::Raw(bytes) => {
+                encoder.out.push(RAW);
+                encoder.out.extend_from_slice(bytes);
+                Ok(())
+            }
+            Tree::Hashed(hash) => {
+                if hash.len() != 32 {
+                    return Err(KeyError::new("hashed keys are 32 bytes"));
+                }
+
+                encoder.out.push(HASHED);
+                encoder.out.extend_from_slice(hash);
+                Ok(())
+            }
+            Tree::Generation(generation) => {
+                encoder.out.extend(generation_level(*generation));
+                Ok(())
+            }
+        }
+    }
+
+    fn to_nested_vec(&self) -> Result<Vec<u8>, KeyError> {
+        let mut out = Vec::new();
+        self.encode(&mut Encoder {
+            out: &mut out,
+            nested: true,
+        })?;
+        Ok(out)
+    }
+}
+
+/// Decode an encoded key into a [Tree].
+///
+/// Fails if the tree doesn't encode to the same key again, which is the case
+/// for keys which weren't encoded by [Encoder].
+pub(crate) fn to_tree(key: &[u8]) -> Result<Tree, KeyError> {
+    let mut bytes = key;
+    let tree = Decoder { bytes: &mut bytes }.tree()?;
+
+    if !bytes.is_empty() {
+        return Err(KeyError::new("trailing bytes in key"));
+    }
+
+    if from_tree(&tree)? != key {
+        return Err(KeyError::new("key doesn't encode to itself"));
+    }
+
+    Ok(tree)
+}
+
+/// Encode a key from a [Tree].
+pub(crate) fn from_tree(tree: &Tree) -> Result<Vec<u8>, KeyError> {
+    let mut out = Vec::new();
+    tree.encode(&mut Encoder {
+        out: &mut out,
+        nested: false,
+    })?;
+    Ok(out)
+}
+
+/// Decode an encoded namespace into a [Tree] for each level, outermost first.
+///
+/// Fails unless the trees encode to the same namespace again.
+pub(crate) fn namespace_to_trees(ns: &[u8]) -> Result<Vec<Tree>, KeyError> {
+    let mut bytes = ns;
+    let mut levels = Vec::new();
+
+    while !bytes.is_empty() {
+        levels.push(Decoder { bytes: &mut bytes }.tree()?);
+    }
+
+    if namespace_from_trees(&levels)? != ns {
+        return Err(KeyError::new("namespace doesn't encode to itself"));
+    }
+
+    Ok(levels)
+}
+
+/// Encode a namespace from a [Tree] for each level, outermost first.
+pub(crate) fn namespace_from_trees(levels: &[Tree]) -> Result<Vec<u8>, KeyError> {
+    let mut out = Vec::new();
+
+    for level in levels {
+        out.extend(level.to_nested_vec()?);
+    }
+
+    Ok(out)
+}
+
+/// Serialize numbers which don't fit in every format as strings.
+mod display {
+    use serde::de::{self, Deserialize};
+    use std::borrow::Cow;
+    use std::fmt;
+    use std::str::FromStr;
+
+    pub(super) fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: fmt::Display,
+        S: serde::Serializer,
+    {
+        serializer.collect_str(value)
+    }
+
+    pub(super) fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+        D: serde::Deserializer<'de>,
+    {
+        Cow::<str>::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Encode a value inside of a compound value.
+fn to_nested_vec<T>(value: &T) -> Result<Vec<u8>, KeyError>
+where
+    T: ?Sized + Serialize,
+{
+    let mut out = Vec::new();
+    value.serialize(&mut Encoder {
+        out: &mut out,
+        nested: true,
+    })?;
+    Ok(out)
+}
+
+/// Serializes a namespace with integers widened by value.
+struct Namespace<'a>(&'a hashkey::Key);
+
+impl Serialize for Namespace<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match self.0 {
+            hashkey::Key::Integer(integer) => match widen(integer) {
+                Ok(v) => match u64::try_from(v) {
+                    Ok(v) => serializer.serialize_u64(v),
+                    Err(_) => serializer.serialize_u128(v),
+                },
+                Err(v) => match i64::try_from(v) {
+                    Ok(v) => serializer.serialize_i64(v),
+                    Err(_) => serializer.serialize_i128(v),
+                },
+            },
+            hashkey::Key::Vec(values) => serializer.collect_seq(values.iter().map(Namespace)),
+            hashkey::Key::Map(entries) => {
+                serializer.collect_map(entries.iter().map(|(k, v)| (Namespace(k), Namespace(v))))
+            }
+            key => key.serialize(serializer),
+        }
+    }
+}
+
+/// Widen an integer, returning it as unsigned if it's not negative.
+fn widen(integer: &hashkey::Integer) -> Result<u128, i128> {
+    let signed = |v: i128| u128::try_from(v).map_err(|_| v);
+
+    match *integer {
+        hashkey::Integer::U8(v) => Ok(v.into()),
+        hashkey::Integer::U16(v) => Ok(v.into()),
+        hashkey::Integer::U32(v) => Ok(v.into()),
+        hashkey::Integer::U64(v) => Ok(v.into()),
+        hashkey::Integer::U128(v) => Ok(v),
+        hashkey::Integer::I8(v) => signed(v.into()),
+        hashkey::Integer::I16(v) => signed(v.into()),
+        hashkey::Integer::I32(v) => signed(v.into()),
+        hashkey::Integer::I64(v) => signed(v.into()),
+        hashkey::Integer::I128(v) => signed(v),
+    }
+}
+
+struct Encoder<'a> {
+    out: &'a mut Vec<u8>,
+    /// If we're encoding a value inside of a compound value.
+    nested: bool,
+}
+
+impl Encoder<'_> {
+    /// Encoder for a value inside of a compound value.
+    fn nested(&mut self) -> Encoder<'_> {
+        Encoder {
+            out: &mut *self.out,
+            nested: true,
+        }
+    }
+
+    fn tuple(&mut self, len: usize) -> Result<(), KeyError> {
+        if self.nested {
+            self.out.push(NESTED_TUPLE);
+            self.len(len)
+        } else {
+            self.out.push(TUPLE);
+            Ok(())
+        }
+    }
+
+    fn tuple_variant(&mut self, name: &str, len: usize) -> Result<(), KeyError> {
+        if self.nested {
+            self.name(NESTED_TUPLE_VARIANT, name)?;
+            self.len(len)
+        } else {
+            self.name(TUPLE_VARIANT, name)
+        }
+    }
+
+    fn len(&mut self, len: usize) -> Result<(), KeyError> {
+        let len = u32::try_from(len).map_err(|_| KeyError::new("length out of range"))?;
+        self.out.extend_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+
+    fn bytes(&mut self, tag: u8, bytes: &[u8]) -> Result<(), KeyError> {
+        self.out.push(tag);
+        self.len(bytes.len())?;
+        self.out.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn name(&mut self, tag: u8, name: &str) -> Result<(), KeyError> {
+        self.bytes(tag, name.as_bytes())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Encoder<'b> {
+    type Ok = ();
+    type Error = KeyError;
+    type SerializeSeq = Collect<'a, 'b>;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = CollectMap<'a, 'b>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), KeyError> {
+        self.out.push(if v { TRUE } else { FALSE });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), KeyError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), KeyError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), KeyError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), KeyError> {
+        // Flip the sign bit so that negative numbers sort before positive.
+        self.out.push(I64);
+        self.out
+            .extend_from_slice(&((v as u64) ^ (1 << 63)).to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), KeyError> {
+        self.out.push(I128);
+        self.out
+            .extend_from_slice(&((v as u128) ^ (1 << 127)).to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), KeyError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), KeyError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), KeyError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), KeyError> {
+        self.out.push(U64);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), KeyError> {
+        self.out.push(U128);
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), KeyError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), KeyError> {
+        // Transform the bits so that they sort in the same order as the
+        // numbers they represent.
+        let bits = v.to_bits();
+
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 63)
+        };
+
+        self.out.push(F64);
+        self.out.extend_from_slice(&bits.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), KeyError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), KeyError> {
+        self.bytes(STR, v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), KeyError> {
+        self.bytes(BYTES, v)
+    }
+
+    fn serialize_none(self) -> Result<(), KeyError> {
+        self.out.push(NONE);
+        Ok(())
+    }
+
+    fn serialize_some<T>(self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.out.push(SOME);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), KeyError> {
+        self.out.push(UNIT);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), KeyError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<(), KeyError> {
+        self.name(UNIT_VARIANT, variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.name(NEWTYPE_VARIANT, variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, KeyError> {
+        Ok(Collect {
+            encoder: self,
+            buf: Vec::new(),
+            len: 0,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, KeyError> {
+        self.tuple(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Self, KeyError> {
+        self.tuple(len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, KeyError> {
+        self.tuple_variant(variant, len)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, KeyError> {
+        Ok(CollectMap {
+            encoder: self,
+            entries: Vec::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self, KeyError> {
+        self.tuple(len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, KeyError> {
+        self.tuple_variant(variant, len)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeTuple for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut self.nested())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut self.nested())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut self.nested())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_field<T>(&mut self, _: &'static str, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut self.nested())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder<'_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_field<T>(&mut self, _: &'static str, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut self.nested())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        Ok(())
+    }
+}
+
+/// Collects the elements of a sequence so that it can be prefixed with its
+/// length.
+pub(crate) struct Collect<'a, 'b> {
+    encoder: &'a mut Encoder<'b>,
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl ser::SerializeSeq for Collect<'_, '_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        value.serialize(&mut Encoder {
+            out: &mut self.buf,
+            nested: true,
+        })?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), KeyError> {
+        self.encoder.out.push(SEQ);
+        self.encoder.len(self.len)?;
+        self.encoder.out.extend_from_slice(&self.buf);
+        Ok(())
+    }
+}
+
+/// Collects the entries of a map so that they can be sorted.
+pub(crate) struct CollectMap<'a, 'b> {
+    encoder: &'a mut Encoder<'b>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    key: Option<Vec<u8>>,
+}
+
+impl ser::SerializeMap for CollectMap<'_, '_> {
+    type Ok = ();
+    type Error = KeyError;
+
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.key = Some(to_nested_vec(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), KeyError>
+    where
+        T: ?Sized + Serialize,
+    {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| KeyError::new("map value without a key"))?;
+
+        self.entries.push((key, to_nested_vec(value)?));
+        Ok(())
+    }
+
+    fn end(mut self) -> Result<(), KeyError> {
+        self.entries.sort();
+        self.encoder.out.push(MAP);
+        self.encoder.len(self.entries.len())?;
+
+        for (key, value) in self.entries {
+            self.encoder.out.extend_from_slice(&key);
+            self.encoder.out.extend_from_slice(&value);
+        }
+
+        Ok(())
+    }
+}
+
+struct Decoder<'a, 'de> {
+    bytes: &'a mut &'de [u8],
+}
+
+impl<'de> Decoder<'_, 'de> {
+    fn take(&mut self, n: usize) -> Result<&'de [u8], KeyError> {
+        if self.bytes.len() < n {
+            return Err(KeyError::new("unexpected end of key"));
+        }
+
+        let (head, tail) = self.bytes.split_at(n);
+        *self.bytes = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], KeyError> {
+        let mut out = [0; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    fn len(&mut self) -> Result<usize, KeyError> {
+        Ok(u32::from_be_bytes(self.array()?) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<&'de [u8], KeyError> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'de str, KeyError> {
+        std::str::from_utf8(self.bytes()?).map_err(KeyError::new)
+    }
+
+    fn tag(&mut self) -> Result<u8, KeyError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn f64(&mut self) -> Result<f64, KeyError> {
+        let bits = u64::from_be_bytes(self.array()?);
+
+        let bits = if bits >> 63 == 1 {
+            bits ^ (1 << 63)
+        } else {
+            !bits
+        };
+
+        Ok(f64::from_bits(bits))
+    }
+
+    /// Decode a single value into JSON.
+    fn value(&mut self) -> Result<json::Value, KeyError> {
+        Ok(match self.tag()? {
+            UNIT | NONE => json::Value::Null,
+            FALSE => json::Value::Bool(false),
+            TRUE => json::Value::Bool(true),
+            SOME => self.value()?,
+            U64 => u64::from_be_bytes(self.array()?).into(),
+            U128 => u128::from_be_bytes(self.array()?).to_string().into(),
+            I64 => ((u64::from_be_bytes(self.array()?) ^ (1 << 63)) as i64).into(),
+            I128 => ((u128::from_be_bytes(self.array()?) ^ (1 << 127)) as i128)
+                .to_string()
+                .into(),
+            F64 => json::Number::from_f64(self.f64()?)
+                .map(json::Value::Number)
+                .unwrap_or(json::Value::Null),
+            STR => self.str()?.into(),
+            BYTES => self.bytes()?.to_vec().into(),
+            SEQ => json::Value::Array(self.fields()?),
+            TUPLE => json::Value::Array(self.rest()?),
+            NESTED_TUPLE => json::Value::Array(self.fields()?),
+            MAP => {
+                let len = self.len()?;
+                let mut out = json::Map::new();
+
+                for _ in 0..len {
+                    let key = match self.value()? {
+                        json::Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+
+                    out.insert(key, self.value()?);
+                }
+
+                json::Value::Object(out)
+            }
+            UNIT_VARIANT => self.str()?.into(),
+            NEWTYPE_VARIANT => {
+                let name = self.str()?;
+                let mut out = json::Map::new();
+                out.insert(name.to_owned(), self.value()?);
+                json::Value::Object(out)
+            }
+            TUPLE_VARIANT => {
+                let name = self.str()?;
+                let mut out = json::Map::new();
+                out.insert(name.to_owned(), json::Value::Array(self.rest()?));
+                json::Value::Object(out)
+            }
+            NESTED_TUPLE_VARIANT => {
+                let name = self.str()?;
+                let mut out = json::Map::new();
+                out.insert(name.to_owned(), json::Value::Array(self.fields()?));
+                json::Value::Object(out)
+            }
+            RAW => {
+                let len = self.bytes.len();
+                self.take(len)?.to_vec().into()
+            }
+            HASHED => {
+                let mut out = json::Map::new();
+                out.insert(String::from("hashed"), hex::encode(self.take(32)?).into());
+                json::Value::Object(out)
+            }
+            GENERATION => {
+                let mut out = json::Map::new();
+                let generation = u64::from_be_bytes(self.array()?);
+                out.insert(String::from("generation"), generation.into());
+                json::Value::Object(out)
+            }
+            tag => return Err(KeyError::new(format!("unsupported tag 0x{:02x}", tag))),
+        })
+    }
+
+    /// Decode a single value into a [Tree].
+    fn tree(&mut self) -> Result<Tree, KeyError> {
+        Ok(match self.tag()? {
+            UNIT => Tree::Unit,
+            FALSE => Tree::Bool(false),
+            TRUE => Tree::Bool(true),
+            NONE => Tree::None,
+            SOME => Tree::Some(Box::new(self.tree()?)),
+            U64 => Tree::U64(u64::from_be_bytes(self.array()?)),
+            U128 => Tree::U128(u128::from_be_bytes(self.array()?)),
+            I64 => Tree::I64((u64::from_be_bytes(self.array()?) ^ (1 << 63)) as i64),
+            I128 => Tree::I128((u128::from_be_bytes(self.array()?) ^ (1 << 127)) as i128),
+            F64 => Tree::F64(self.f64()?),
+            STR => Tree::Str(self.str()?.to_owned()),
+            BYTES => Tree::Bytes(self.bytes()?.to_vec()),
+            SEQ => Tree::Seq(self.tree_fields()?),
+            TUPLE => Tree::Tuple(self.tree_rest()?),
+            NESTED_TUPLE => Tree::Tuple(self.tree_fields()?),
+            MAP => {
+                let len = self.len()?;
+                let mut out = Vec::with_capacity(len.min(1024));
+
+                for _ in 0..len {
+                    out.push((self.tree()?, self.tree()?));
+                }
+
+                Tree::Map(out)
+            }
+            UNIT_VARIANT => Tree::UnitVariant(self.str()?.to_owned()),
+            NEWTYPE_VARIANT => {
+                let name = self.str()?.to_owned();
+                Tree::NewtypeVariant(name, Box::new(self.tree()?))
+            }
+            TUPLE_VARIANT => {
+                let name = self.str()?.to_owned();
+                Tree::TupleVariant(name, self.tree_rest()?)
+            }
+            NESTED_TUPLE_VARIANT => {
+                let name = self.str()?.to_owned();
+                Tree::TupleVariant(name, self.tree_fields()?)
+            }
+            RAW => {
+                let len = self.bytes.len();
+                Tree::Raw(self.take(len)?.to_vec())
+            }
+            HASHED => Tree::Hashed(self.take(32)?.to_vec()),
+            GENERATION => Tree::Generation(u64::from_be_bytes(self.array()?)),
+            tag => return Err(KeyError::new(format!("unsupported tag 0x{:02x}", tag))),
+        })
+    }
+
+    /// Decode a number of trees prefixed with their count.
+    fn tree_fields(&mut self) -> Result<Vec<Tree>, KeyError> {
+        let len = self.len()?;
+        let mut out = Vec::with_capacity(len.min(1024));
+
+        for _ in 0..len {
+            out.push(self.tree()?);
+        }
+
+        Ok(out)
+    }
+
+    /// Decode the fields of the outermost tuple or struct into trees.
+    fn tree_rest(&mut self) -> Result<Vec<Tree>, KeyError> {
+        let mut out = Vec::new();
+
+        while !self.bytes.is_empty() {
+            out.push(self.tree()?);
+        }
+
+        Ok(out)
+    }
+
+    /// Decode a number of values prefixed with their count.
+    fn fields(&mut self) -> Result<Vec<json::Value>, KeyError> {
+        let len = self.len()?;
+        let mut out = Vec::with_capacity(len.min(1024));
+
+        for _ in 0..len {
+            out.push(self.value()?);
+        }
+
+        Ok(out)
+    }
+
+    /// Decode the fields of the outermost tuple or struct.
+    ///
+    /// Their number isn't encoded, so they extend to the end of the key.
+    fn rest(&mut self) -> Result<Vec<json::Value>, KeyError> {
+        let mut out = Vec::new();
+
+        while !self.bytes.is_empty() {
+            out.push(self.value()?);
+        }
+
+        Ok(out)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Decoder<'_, 'de> {
+    type Error = KeyError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, KeyError>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.tag()? {
+            UNIT => visitor.visit_unit(),
+            FALSE => visitor.visit_bool(false),
+            TRUE => visitor.visit_bool(true),
+            NONE => visitor.visit_none(),
+            SOME => visitor.visit_some(self),
+            U64 => visitor.visit_u64(u64::from_be_bytes(self.array()?)),
+            U128 => visitor.visit_u128(u128::from_be_bytes(self.array()?)),
+            I64 => visitor.visit_i64((u64::from_be_bytes(self.array()?) ^ (1 << 63)) as i64),
+            I128 => visitor.visit_i128((u128::from_be_bytes(self.array()?) ^ (1 << 127)) as i128),
+            F64 => visitor.visit_f64(self.f64()?),
+            STR => visitor.visit_borrowed_str(self.str()?),
+            BYTES => visitor.visit_borrowed_bytes(self.bytes()?),
+            SEQ | NESTED_TUPLE => {
+                let len = self.len()?;
+                visitor.visit_seq(Fields::new(self, Some(len)))
+            }
+            TUPLE => visitor.visit_seq(Fields::new(self, None)),
+            MAP => {
+                let len = self.len()?;
+                visitor.visit_map(Fields::new(self, Some(len)))
+            }
+            tag @ (UNIT_VARIANT | NEWTYPE_VARIANT | TUPLE_VARIANT | NESTED_TUPLE_VARIANT) => {
+                let name = self.str()?;
+                visitor.visit_enum(Variant {
+                    decoder: self,
+                    tag,
+                    name,
+                })
+            }
+            RAW => {
+                let len = self.bytes.len();
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            HASHED => Err(KeyError::new("hashed keys can't be decoded")),
+            GENERATION => Err(KeyError::new("generations can't be decoded")),
+            tag => Err(KeyError::new(format!("unsupported tag 0x{:02x}", tag))),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, KeyError>
+    where
+        V: de::Visitor<'de>,
+    {
+        // Newtype structs are encoded as the value they wrap.
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple tuple_struct map struct
+        enum identifier ignored_any
+    }
+}
+
+/// Access to the elements of a sequence or the entries of a map.
+struct Fields<'a, 'b, 'de> {
+    decoder: &'a mut Decoder<'b, 'de>,
+    /// The number of remaining elements, or `None` if they extend to the end
+    /// of the key.
+    remaining: Option<usize>,
+}
+
+impl<'a, 'b, 'de> Fields<'a, 'b, 'de> {
+    fn new(decoder: &'a mut Decoder<'b, 'de>, remaining: Option<usize>) -> Self {
+        Self { decoder, remaining }
+    }
+
+    /// Test if there is another element, counting it as decoded.
+    fn advance(&mut self) -> bool {
+        match &mut self.remaining {
+            Some(0) => false,
+            Some(remaining) => {
+                *remaining -= 1;
+                true
+            }
+            None => !self.decoder.bytes.is_empty(),
+        }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Fields<'_, '_, 'de> {
+    type Error = KeyError;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, KeyError>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if !self.advance() {
+            return Ok(None);
+        }
+
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Fields<'_, '_, 'de> {
+    type Error = KeyError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, KeyError>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        if !self.advance() {
+            return Ok(None);
+        }
+
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, KeyError>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.remaining
+    }
+}
+
+/// Access to an enum variant, identified by its name.
+struct Variant<'a, 'b, 'de> {
+    decoder: &'a mut Decoder<'b, 'de>,
+    tag: u8,
+    name: &'de str,
+}
+
+impl<'de> Variant<'_, '_, 'de> {
+    fn fields<V>(self, visitor: V) -> Result<V::Value, KeyError>
+    where
+        V: de::Visitor<'de>,
+    {
+        match self.tag {
+            TUPLE_VARIANT => visitor.visit_seq(Fields::new(self.decoder, None)),
+            NESTED_TUPLE_VARIANT => {
+                let len = self.decoder.len()?;
+                visitor.visit_seq(Fields::new(self.decoder, Some(len)))
+            }
+            _ => Err(KeyError::new(format!(
+                "expected a tuple or struct variant, but `{}` isn't one",
+                self.name
+            ))),
+        }
+    }
+}
+
+impl<'a, 'b, 'de> de::EnumAccess<'de> for Variant<'a, 'b, 'de> {
+    type Error = KeyError;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), KeyError>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let name = de::value::BorrowedStrDeserializer::new(self.name);
+        Ok((seed.deserialize(name)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_, '_, 'de> {
+    type Error = KeyError;
+
+    fn unit_variant(self) -> Result<(), KeyError> {
+        match self.tag {
+            UNIT_VARIANT => Ok(()),
+            _ => Err(KeyError::new(format!(
+                "expected a unit variant, but `{}` isn't one",
+                self.name
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, KeyError>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        match self.tag {
+            NEWTYPE_VARIANT => seed.deserialize(self.decoder),
+            _ => Err(KeyError::new(format!(
+                "expected a newtype variant, but `{}` isn't one",
+                self.name
+            ))),
+        }
+    }
+
+    fn tuple_variant<V>(self, _: usize, visitor: V) -> Result<V::Value, KeyError>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.fields(visitor)
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value, KeyError>
+    where
+        V: de::Visitor<'de>,
+    {
+        self.fields(visitor)
+    }
+}
@@ -39,6 +39,26 @@
 //! serde = {version = "1.0", features = ["derive"]}
 //! ```
 //!
+//! ## Key layout
+//!
+//! Entries are stored under keys with a stable layout, so that a database
+//! remains usable across versions of this library and its dependencies:
+//!
+//! ```text
+//! [version][namespace length][namespace][key]
+//! ```
+//!
+//! The version is currently `0x01`. The length of the namespace is a
+//! big-endian `u32`, which is zero for entries without a namespace. The
+//! namespace and the key are encoded with a binary encoding which doesn't
+//! depend on any serialization library. All keys in a namespace share the same
+//! prefix, so each namespace occupies a contiguous range of the database which
+//! can be iterated over or deleted without visiting other entries. Keys of
+//! internal metadata start with a zero byte.
+//!
+//! If the layout has to change, its version is bumped and [Builder::load]
+//! converts the keys of existing entries to the new layout.
+//!
 //! ## Examples
 //!
 //! Simple example showcasing fetching information on a github repository.
@@ -100,50 +120,395 @@
 //! }
 //! ```
 //!
+//! ## Command line tool
+//!
+//! A cache stored on disk can be inspected and maintained with the
+//! `futures-cache` tool, which is built with the `cli` feature. Serving the cache
+//! over HTTP also requires the `server` feature:
+//!
+//! ```sh
+//! cargo install futures-cache --features cli,server
+//! futures-cache --read-only cache list --ns '"users"'
+//! futures-cache cache export --output dump.jsonl
+//! futures-cache cache serve 127.0.0.1:8080
+//! ```
+//!
+//! Run `futures-cache --help` for every command. sled only lets one process open
+//! a database at a time, so the cache can't be in use while it's inspected.
+//!
+//! ## Tracing
+//!
+//! With the `tracing` feature, `get`, `insert` and `wrap` are recorded in
+//! [tracing] spans with the namespace of the handle, whether reads were a `hit`,
+//! `stale` or `miss`, the size of entries in bytes and how long the loader of
+//! `wrap` took in `loader_us`. The spans are at the `DEBUG` level.
+//!
 //! [serde]: https://docs.rs/serde
+//! [tracing]: https://docs.rs/tracing
 //! [Cache::namespaced]: https://docs.rs/futures-cache/0/futures_cache/struct.Cache.html#method.namespaced
 
-use chrono::{DateTime, Utc};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{self, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
 use crossbeam::queue::SegQueue;
 use futures_channel::oneshot;
-use hashbrown::HashMap;
+use futures_util::io::{AsyncRead, AsyncReadExt as _};
+use futures_util::stream::Stream;
+use hashbrown::{HashMap, HashSet};
 use hex::ToHex as _;
-use parking_lot::RwLock;
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "key-hashing")]
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use serde::{de, Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use serde_cbor as cbor;
 use serde_hashkey as hashkey;
 use serde_json as json;
+#[cfg(any(feature = "key-hashing", feature = "deduplicate"))]
+use sha2::{Digest as _, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionalTree};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::error;
 use std::fmt;
 use std::future::Future;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io;
+use std::marker::PhantomData;
+use std::ops::{Bound, Range, RangeBounds};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{borrow::Borrow, error};
+use std::task::{Context, Poll};
+use std::time::Instant;
 
-pub use chrono::Duration;
+pub use self::cache_like::CacheLike;
+pub use self::key::{CacheKey, KeyEncoder, KeyError, RawKey, RecordEncoder};
+pub use chrono::{DateTime, Duration, Utc};
+/// Cache the result of an async function with [Cache::wrap]. Requires the
+/// `derive` feature.
+///
+/// The key of an entry is the name of the function along with its arguments
+/// other than `self`, encoded like a record by [KeyEncoder::record], so every
+/// argument has to implement [Serialize]. The function has to return a
+/// [Result] whose value can be stored, and whose error can be constructed from
+/// an [Error].
+///
+/// The following arguments are supported:
+///
+/// * `cache = "expr"` is an expression evaluating to the [Cache] to use, like
+///   `"self.cache"`. This is required.
+/// * `ttl = "5m"` is how long entries are cached for, as a number followed by
+///   one of the units `s`, `m`, `h` or `d`. This is required.
+/// * `ns = "name"` stores entries in the given namespace, see
+///   [Cache::namespaced].
+/// * `version = 1` is the version of the key, which can be bumped to discard
+///   the entries cached by earlier versions of the function.
+/// * `crate = "path"` sets the path to this crate.
+///
+/// ```rust
+/// # #[cfg(feature = "derive")]
+/// # mod example {
+/// use futures_cache::{cached, Cache, Error};
+///
+/// struct Profiles {
+///     cache: Cache,
+/// }
+///
+/// impl Profiles {
+///     #[cached(cache = "self.cache", ttl = "5m", ns = "profile")]
+///     async fn name(&self, user: u64) -> Result<String, Error> {
+///         Ok(format!("user {}", user))
+///     }
+/// }
+/// # }
+/// ```
+#[cfg(feature = "derive")]
+pub use futures_cache_derive::cached;
+/// Derive [CacheKey] for a struct or an enum. Requires the `derive` feature.
+///
+/// See [CacheKey] for how types are encoded.
+#[cfg(feature = "derive")]
+pub use futures_cache_derive::CacheKey;
 pub use sled;
 
+use self::codec::{Codec, Format};
+use self::schema::Schema;
+
+mod cache_like;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod codec;
+pub mod dump;
+mod dynamic;
+pub mod explain;
+#[cfg(feature = "testing")]
+pub mod faults;
+pub mod invalidation;
+mod key;
+#[cfg(feature = "testing")]
+pub mod mock;
+pub mod namespace;
+mod path;
+mod pool;
+pub mod query;
+pub mod schema;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod trace;
+pub mod typed;
+pub mod verify;
+
+#[cfg(feature = "derive")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::key::EncodedKey;
+    pub use serde::Serialize;
+
+    /// Test if the value is equal to its default value.
+    pub fn is_default<T>(value: &T) -> bool
+    where
+        T: Default + PartialEq,
+    {
+        *value == T::default()
+    }
+}
+
 /// Error type for the cache.
+///
+/// Errors raised while reading or writing a specific entry are wrapped in
+/// [Error::Entry], which tells which entry failed. Use [Error::kind] to tell
+/// what failed regardless of how the error is wrapped.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
+    /// Reading or writing an entry failed.
+    Entry(Box<EntryError>),
     /// An underlying CBOR error.
     Cbor(cbor::error::Error),
     /// An underlying HashKey error.
     HashKey(hashkey::Error),
+    /// An I/O error raised while reading a value to insert.
+    Io(io::Error),
     /// An underlying JSON error.
     Json(json::error::Error),
+    /// Failed to encode or decode a key.
+    Key(KeyError),
     /// An underlying Sled error.
     Sled(sled::Error),
+    /// The requested time to live is outside of the configured bounds.
+    ///
+    /// See [Builder::ttl_strictness].
+    TtlOutOfRange(Duration),
+    /// The configuration passed to the [Builder] is invalid.
+    InvalidConfig(String),
+    /// The entry was stored in a format which is not supported.
+    UnsupportedFormat(u8),
+    /// The entry was stored with a version of the entry format which is not
+    /// supported.
+    UnsupportedVersion(u8),
+    /// The database uses a version of the key layout which is not supported.
+    UnsupportedKeyVersion(u8),
+    /// The value was stored with a schema version which can't be migrated to
+    /// the current version. See [schema].
+    UnsupportedSchema(u32),
+    /// The JSON pointer is malformed.
+    InvalidPointer(String),
+    /// The JSON path of a [query] is malformed.
+    InvalidPath(String),
+    /// The [Cursor] of a page of entries is malformed.
+    InvalidCursor(String),
+    /// A page of entries was requested with a limit of zero.
+    InvalidLimit,
+    /// The [dump] was written with a version of the dump format which is not
+    /// supported.
+    UnsupportedDumpVersion(u32),
+    /// The entry is corrupt, because it failed its checksum or is truncated.
+    Corrupt,
+    /// The entry was modified or removed while it was being streamed, or kept
+    /// being modified while it was being read.
+    Modified,
+    /// A read run on a background thread on behalf of a stream panicked.
+    Panicked,
+    /// The operation depends on the order of keys, which isn't preserved when
+    /// keys are hashed.
+    HashedKeys,
+    /// The operation requires an audit log, which isn't configured.
+    ///
+    /// See [Builder::audit_log].
+    NoAuditLog,
+    /// The operation requires an invalidation bus, which isn't configured.
+    ///
+    /// See [Builder::invalidation_bus].
+    NoInvalidationBus,
+    /// The namespace has no default time to live.
+    ///
+    /// See [Cache::namespace].
+    NoDefaultTtl,
+    /// An error raised by a value [codec].
+    Codec(Box<dyn error::Error + Send + Sync>),
+    /// A request to a remote cache failed or timed out.
+    Remote(Box<RemoteError>),
     /// The underlying future failed (with an unspecified error).
     Failed,
 }
 
+impl Error {
+    /// Classify what failed.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Entry(e) => e.kind,
+            Error::Key(..) | Error::HashKey(..) => ErrorKind::KeySerialization,
+            Error::Sled(sled::Error::CollectionNotFound(..)) => ErrorKind::Closed,
+            Error::Sled(..) => ErrorKind::Storage,
+            // values are serialized by writes, which wrap their errors in
+            // `Error::Entry` with the kind of what failed.
+            Error::Json(e) if !e.is_io() => ErrorKind::ValueDeserialization,
+            Error::Cbor(e) if !e.is_io() => ErrorKind::ValueDeserialization,
+            Error::Codec(..) => ErrorKind::ValueDeserialization,
+            Error::Remote(e) => e.kind,
+            Error::UnsupportedFormat(..)
+            | Error::UnsupportedVersion(..)
+            | Error::UnsupportedSchema(..) => ErrorKind::ValueDeserialization,
+            Error::Corrupt => ErrorKind::Corrupt,
+            Error::Failed => ErrorKind::Loader,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// The error which isn't wrapped in [Error::Entry].
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::Entry(e) => e.error.root(),
+            error => error,
+        }
+    }
+}
+
+/// What failed, as classified by [Error::kind].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A key or namespace couldn't be serialized.
+    KeySerialization,
+    /// A value couldn't be serialized.
+    ValueSerialization,
+    /// A stored value couldn't be deserialized, or was stored in a format,
+    /// version or schema which isn't supported.
+    ValueDeserialization,
+    /// The database, or the remote cache of a client, failed.
+    Storage,
+    /// A request to the remote cache of a client timed out.
+    Timeout,
+    /// The cache can't be used anymore, since its tree was dropped from the
+    /// database.
+    Closed,
+    /// An entry is corrupt.
+    Corrupt,
+    /// The future passed to [Cache::wrap] by a concurrent call for the same
+    /// key failed.
+    Loader,
+    /// Anything else, like invalid arguments or missing configuration.
+    Other,
+}
+
+/// An error raised while reading or writing an entry, see [Error::Entry].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct EntryError {
+    /// What failed.
+    pub kind: ErrorKind,
+    /// The namespace of the entry as JSON, or `null` for the default
+    /// namespace.
+    pub ns: json::Value,
+    /// The key of the entry as JSON, redacted according to
+    /// [Builder::key_redaction], or `None` if the key couldn't be serialized.
+    pub key: Option<json::Value>,
+    /// The underlying error.
+    pub error: Error,
+}
+
+impl fmt::Display for EntryError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(fmt, "{}/{}: {}", self.ns, key, self.error),
+            None => write!(fmt, "{}: {}", self.ns, self.error),
+        }
+    }
+}
+
+/// An error raised by a request to a remote cache, see [Error::Remote].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct RemoteError {
+    /// What failed, which is [ErrorKind::Timeout] if the request timed out and
+    /// [ErrorKind::Storage] otherwise.
+    pub kind: ErrorKind,
+    /// What went wrong, like the status and the error the remote cache
+    /// responded with.
+    pub message: String,
+    /// The underlying error of the HTTP client, if any.
+    pub source: Option<Box<dyn error::Error + Send + Sync>>,
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(fmt)
+    }
+}
+
+impl error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.source {
+            Some(e) => Some(&**e),
+            None => None,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Error::Entry(e) => e.fmt(fmt),
             Error::Cbor(e) => write!(fmt, "CBOR error: {}", e),
             Error::HashKey(e) => write!(fmt, "HashKey error: {}", e),
+            Error::Io(e) => write!(fmt, "I/O error: {}", e),
             Error::Json(e) => write!(fmt, "JSON error: {}", e),
+            Error::Key(e) => write!(fmt, "Key error: {}", e),
             Error::Sled(e) => write!(fmt, "Database error: {}", e),
+            Error::TtlOutOfRange(age) => write!(fmt, "Time to live out of range: {}", age),
+            Error::InvalidConfig(message) => write!(fmt, "Invalid configuration: {}", message),
+            Error::UnsupportedFormat(id) => write!(fmt, "Unsupported format: {}", id),
+            Error::UnsupportedVersion(version) => {
+                write!(fmt, "Unsupported entry version: {}", version)
+            }
+            Error::UnsupportedKeyVersion(version) => {
+                write!(fmt, "Unsupported key version: {}", version)
+            }
+            Error::UnsupportedSchema(version) => {
+                write!(fmt, "Unsupported schema version: {}", version)
+            }
+            Error::InvalidPointer(pointer) => write!(fmt, "Invalid JSON pointer: {}", pointer),
+            Error::InvalidPath(path) => write!(fmt, "Invalid JSON path: {}", path),
+            Error::InvalidCursor(cursor) => write!(fmt, "Invalid cursor: {}", cursor),
+            Error::InvalidLimit => write!(fmt, "Invalid limit: pages hold at least one entry"),
+            Error::UnsupportedDumpVersion(version) => {
+                write!(fmt, "Unsupported dump version: {}", version)
+            }
+            Error::Corrupt => write!(fmt, "Entry is corrupt"),
+            Error::Modified => write!(fmt, "Entry was modified while being read"),
+            Error::Panicked => write!(fmt, "Background read panicked"),
+            Error::HashedKeys => write!(fmt, "Keys are hashed and can't be ordered"),
+            Error::NoAuditLog => write!(fmt, "No audit log is configured"),
+            Error::NoInvalidationBus => write!(fmt, "No invalidation bus is configured"),
+            Error::NoDefaultTtl => write!(fmt, "Namespace has no default time to live"),
+            Error::Codec(e) => write!(fmt, "Codec error: {}", e),
+            Error::Remote(e) => write!(fmt, "Remote cache error: {}", e),
             Error::Failed => write!(fmt, "Operation failed"),
         }
     }
@@ -152,10 +517,15 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
+            Error::Entry(e) => Some(&e.error),
             Error::Cbor(e) => Some(e),
             Error::HashKey(e) => Some(e),
+            Error::Io(e) => Some(e),
             Error::Json(e) => Some(e),
+            Error::Key(e) => Some(e),
             Error::Sled(e) => Some(e),
+            Error::Codec(e) => Some(&**e),
+            Error::Remote(e) => Some(&**e),
             _ => None,
         }
     }
@@ -179,12 +549,33 @@ impl From<hashkey::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(error: KeyError) -> Self {
+        Error::Key(error)
+    }
+}
+
 impl From<sled::Error> for Error {
     fn from(error: sled::Error) -> Self {
         Error::Sled(error)
     }
 }
 
+impl From<TransactionError<Error>> for Error {
+    fn from(error: TransactionError<Error>) -> Self {
+        match error {
+            TransactionError::Abort(error) => error,
+            TransactionError::Storage(error) => Error::Sled(error),
+        }
+    }
+}
+
 /// Represents the state of an entry.
 pub enum State<T> {
     /// Entry is fresh and can be used.
@@ -194,6 +585,11 @@ pub enum State<T> {
     Expired(StoredEntry<T>),
     /// No entry.
     Missing,
+    /// Entry exists, but is corrupt and can't be used.
+    ///
+    /// This is distinct from [State::Missing] so that corruption, like bit rot
+    /// or partially written entries, can be noticed.
+    Corrupt,
 }
 
 impl<T> State<T> {
@@ -201,765 +597,15724 @@ impl<T> State<T> {
     pub fn get(self) -> Option<T> {
         match self {
             State::Fresh(e) | State::Expired(e) => Some(e.value),
-            State::Missing => None,
+            State::Missing | State::Corrupt => None,
         }
     }
 }
 
+/// Arbitrary metadata stored alongside the value of an entry.
+///
+/// See [Cache::insert_with_metadata].
+pub type Metadata = std::collections::HashMap<String, cbor::Value>;
+
 /// Entry which have had its type erased into a JSON representation for convenience.
 ///
 /// This is necessary in case you want to list all the entries in the database unless you want to deal with raw bytes.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonEntry {
-    /// The key of the entry.
+    /// The key of the entry, as a JSON array of its namespace and its key.
+    ///
+    /// The namespace is `null` for the default namespace, or an array with one
+    /// value for each level of a nested namespace, outermost first. So the key
+    /// `1` is `[["users"], 1]` in the namespace `users`, and
+    /// `[["users", "admins"], 1]` in `admins` nested inside of it.
     pub key: serde_json::Value,
     /// The stored entry.
     #[serde(flatten)]
     pub stored: StoredEntry<serde_json::Value>,
 }
 
-/// A complete stored entry with a type.
-#[derive(Debug, Serialize, Deserialize)]
-pub struct StoredEntry<T> {
-    expires_at: DateTime<Utc>,
-    value: T,
+/// An entry as listed by [Cache::list], with its value deserialized.
+#[derive(Debug)]
+pub struct ListedEntry<T> {
+    /// The key of the entry, as a JSON array of its namespace and its key.
+    pub key: serde_json::Value,
+    /// The stored entry.
+    pub stored: StoredEntry<T>,
 }
 
-/// A reference to a complete stored entry with a type.
+/// An entry which [Cache::list] failed to deserialize.
+#[derive(Debug)]
+pub struct ListFailure {
+    /// The key of the entry like in [ListedEntry::key], or `None` if the key
+    /// is malformed.
+    pub key: Option<serde_json::Value>,
+    /// Why the entry couldn't be deserialized.
+    pub error: Error,
+}
+
+/// The entries of a namespace as listed by [Cache::list].
+#[derive(Debug)]
+pub struct Listing<T> {
+    /// The entries which could be deserialized, in the order of their keys.
+    pub entries: Vec<ListedEntry<T>>,
+    /// The entries which couldn't be deserialized.
+    pub failed: Vec<ListFailure>,
+}
+
+/// A page of entries as listed by [Cache::list_json_page].
+#[derive(Debug)]
+pub struct JsonPage {
+    /// The entries in the page, in the order of their keys.
+    pub entries: Vec<JsonEntry>,
+    /// The cursor to pass to get the next page, or `None` if there are no
+    /// more entries.
+    ///
+    /// A filtered listing stops scanning after a fixed number of entries even
+    /// if the page isn't full, so a page might hold fewer entries than
+    /// requested, or none at all, while there are more to come. Only `None`
+    /// means that the listing is complete.
+    pub next: Option<Cursor>,
+}
+
+/// The position in the database of a page of entries listed by
+/// [Cache::list_json_page].
 ///
-/// This is used for serialization to avoid taking ownership of the value to serialize.
-#[derive(Debug, Serialize)]
-pub struct StoredEntryRef<'a, T> {
-    expires_at: DateTime<Utc>,
-    value: &'a T,
+/// Cursors are opaque, but they can be converted to and from a string with
+/// [ToString] and [str::parse] to pass them to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(Vec<u8>);
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str(&self.0.encode_hex::<String>())
+    }
 }
 
-impl<T> StoredEntry<T> {
-    /// Test if entry is expired.
-    fn is_expired(&self, now: DateTime<Utc>) -> bool {
-        self.expires_at < now
+impl std::str::FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .map(Cursor)
+            .map_err(|_| Error::InvalidCursor(s.to_owned()))
     }
 }
 
-/// Used to only deserialize part of the stored entry.
-#[derive(Debug, Serialize, Deserialize)]
-struct PartialStoredEntry {
-    expires_at: DateTime<Utc>,
+/// Whether entries listed by [Cache::list_json_page_with] have to be fresh or
+/// expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryFilter {
+    /// Only list entries which haven't expired.
+    Fresh,
+    /// Only list entries which have expired but haven't been cleaned up yet.
+    Expired,
 }
 
-impl PartialStoredEntry {
-    /// Test if entry is expired.
-    fn is_expired(&self, now: DateTime<Utc>) -> bool {
-        self.expires_at < now
+/// Filters of the entries listed by [Cache::list_json_page_with].
+///
+/// Every entry is listed by default, including deleted entries which haven't
+/// been cleaned up yet.
+#[derive(Default)]
+pub struct ListFilter {
+    ns: Option<Result<Option<hashkey::Key>, Error>>,
+    key_prefix: Option<Result<Vec<u8>, Error>>,
+    expiry: Option<ExpiryFilter>,
+}
+
+impl ListFilter {
+    /// Construct a filter which lists every entry.
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    /// Convert into a stored entry.
-    fn into_stored_entry(self) -> StoredEntry<()> {
-        StoredEntry {
-            expires_at: self.expires_at,
-            value: (),
-        }
+    /// Only list entries in the given namespace, and not in namespaces nested
+    /// inside of it.
+    ///
+    /// The namespace is nested inside of the namespace of the handle which
+    /// lists the entries.
+    pub fn ns<N>(mut self, ns: &N) -> Self
+    where
+        N: Serialize,
+    {
+        self.ns = Some(
+            hashkey::to_key(ns)
+                .map(|ns| Some(ns.normalize()))
+                .map_err(Error::from),
+        );
+        self
+    }
+
+    /// Only list entries in the namespace of the handle which lists them.
+    pub fn default_ns(mut self) -> Self {
+        self.ns = Some(Ok(None));
+        self
+    }
+
+    /// Only list entries whose keys start with the given prefix.
+    ///
+    /// The prefix is a tuple or struct holding the leading fields of the keys,
+    /// like with [Cache::delete_prefix]. Hashed keys have no prefixes, so
+    /// listing fails with [Error::HashedKeys] if keys are
+    /// [hashed][Builder::hash_keys].
+    pub fn key_prefix<P>(mut self, prefix: &P) -> Self
+    where
+        P: Serialize,
+    {
+        self.key_prefix = Some(key::to_vec(prefix).map_err(Error::from));
+        self
+    }
+
+    /// Only list entries which are fresh or expired. Deleted entries are never
+    /// listed when this is set.
+    pub fn expiry(mut self, expiry: ExpiryFilter) -> Self {
+        self.expiry = Some(expiry);
+        self
     }
 }
 
-#[derive(Default)]
-struct Waker {
-    /// Number of things waiting for a response.
-    pending: AtomicUsize,
-    /// Channels to use for notifying dependents.
-    channels: SegQueue<oneshot::Sender<bool>>,
+/// Disk usage of a single namespace as reported by [Cache::namespace_usage].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceUsage {
+    /// The namespace as JSON, or `None` for entries outside of any namespace.
+    pub namespace: Option<serde_json::Value>,
+    /// The number of entries stored in the namespace.
+    pub entries: usize,
+    /// The approximate number of bytes used by keys and values in the
+    /// namespace.
+    pub bytes: u64,
 }
 
-impl Waker {
-    /// Spin on performing cleanup, receiving channels to notify until we are in a stable state
-    /// where everything has been reset.
-    fn cleanup(&self, error: bool) {
-        let mut previous = self.pending.load(Ordering::Acquire);
+/// A namespace present in the database as reported by [Cache::namespaces].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceCount {
+    /// The namespace as JSON, or `None` for entries outside of any namespace.
+    ///
+    /// Nested namespaces are represented as an array of their levels,
+    /// outermost first.
+    pub namespace: Option<serde_json::Value>,
+    /// The number of entries stored in the namespace, including expired
+    /// entries and tombstones which haven't been cleaned up yet.
+    pub entries: usize,
+}
 
-        loop {
-            while previous > 1 {
-                let mut received = 0usize;
+/// How [Cache::merge_from] resolves conflicts between an entry in the cache
+/// and a fresh entry with the same key in the cache it merges from.
+///
+/// Entries which are missing, expired, or deleted in the cache are always
+/// replaced.
+#[derive(Default)]
+pub enum MergePolicy {
+    /// Keep the entry which expires last, where entries which never expire
+    /// are fresher than any other entry. The entry in the cache is kept if
+    /// they expire at the same time.
+    #[default]
+    FreshestExpiry,
+    /// Keep the entry in the cache.
+    KeepExisting,
+    /// Replace the entry in the cache.
+    Overwrite,
+    /// Replace the entry in the cache if the function returns `true` when
+    /// called with the entry in the cache and the entry to merge, in that
+    /// order. Entries which can't be decoded as JSON are never replaced.
+    Custom(ResolveFn),
+}
 
-                while let Some(waker) = self.channels.pop() {
-                    received += 1;
-                    let _ = waker.send(error);
-                }
+/// Resolves a conflict for [MergePolicy::Custom].
+type ResolveFn = Box<dyn FnMut(&JsonEntry, &JsonEntry) -> bool>;
 
-                // Subtract the number of notifications sent here. Setting this inside the wrap
-                // function would deadlock on singlethreaded executors since they can't make
-                // progress at the same time as this procedure.
-                previous = self.pending.fetch_sub(received, Ordering::AcqRel);
-            }
+/// What was merged by [Cache::merge_from].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MergeReport {
+    /// The number of entries which were merged.
+    pub merged: usize,
+    /// The number of entries which weren't merged because the entry in the
+    /// cache was kept by the [MergePolicy].
+    pub kept: usize,
+    /// The number of entries which couldn't be merged, like entries whose
+    /// value can't be converted to the format of the cache, or which were
+    /// modified while they were being merged.
+    pub failed: usize,
+}
 
-            previous = self.pending.compare_and_swap(1, 0, Ordering::AcqRel);
+/// The outcome of copying or merging a single entry.
+enum CopyOutcome {
+    /// The entry was copied.
+    Copied,
+    /// The entry in the destination was kept by the [MergePolicy].
+    Kept,
+    /// The entry has a hashed key whose original key can't be recovered.
+    UnrecoverableKey,
+    /// The value can't be converted to the format of the destination.
+    Unconvertible,
+    /// The entry, or the entry it would replace, was modified while it was
+    /// being copied.
+    Modified,
+}
 
-            if previous == 1 {
-                break;
-            }
+impl CopyOutcome {
+    /// Describe why the entry wasn't copied.
+    fn reason(&self) -> &'static str {
+        match self {
+            CopyOutcome::Copied => "copied",
+            CopyOutcome::Kept => "kept by the merge policy",
+            CopyOutcome::UnrecoverableKey => "original key can't be recovered",
+            CopyOutcome::Unconvertible => "value can't be converted to the format of the cache",
+            CopyOutcome::Modified => "entry was modified concurrently",
         }
     }
 }
 
-struct Inner {
-    /// The serialized namespace this cache belongs to.
-    ns: Option<hashkey::Key>,
-    /// Underlying storage.
-    db: sled::Tree,
-    /// Things to wake up.
-    /// TODO: clean up wakers that have been idle for a long time in future cleanup loop.
-    wakers: RwLock<HashMap<Vec<u8>, Arc<Waker>>>,
+/// A difference between two caches reported by [Cache::diff].
+///
+/// Keys are JSON arrays of the namespace and the key, like in [JsonEntry::key].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Difference {
+    /// The entry is only in the cache [Cache::diff] is called on.
+    OnlyInThis {
+        /// The key of the entry.
+        key: serde_json::Value,
+    },
+    /// The entry is only in the cache passed to [Cache::diff].
+    OnlyInOther {
+        /// The key of the entry.
+        key: serde_json::Value,
+    },
+    /// The entry is in both caches, but differs.
+    Changed {
+        /// The key of the entry.
+        key: serde_json::Value,
+        /// Whether the values differ.
+        value: bool,
+        /// Whether the entries expire at different times.
+        expires_at: bool,
+    },
 }
 
-/// Primary cache abstraction.
+/// Statistics of a namespace as reported by [Cache::namespace_stats].
 ///
-/// Can be cheaply cloned and namespaced.
-#[derive(Clone)]
-pub struct Cache {
-    inner: Arc<Inner>,
+/// Statistics are kept in memory by the process since the cache was loaded,
+/// and are shared by every handle of the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct NamespaceStats {
+    /// The namespace as JSON, or `None` for entries outside of any namespace.
+    ///
+    /// Nested namespaces are represented as an array of their levels,
+    /// outermost first.
+    pub namespace: Option<serde_json::Value>,
+    /// Whether these are the combined statistics of the namespaces beyond
+    /// [Builder::max_stats_namespaces], in which case `namespace` is `None`.
+    pub overflow: bool,
+    /// The number of reads which found a fresh entry.
+    pub hits: u64,
+    /// The number of reads which didn't find an entry.
+    pub misses: u64,
+    /// The number of reads which found an expired entry.
+    pub stale_hits: u64,
+    /// The number of entries inserted or replaced.
+    pub inserts: u64,
+    /// The number of entries deleted.
+    pub deletes: u64,
+    /// The number of entries invalidated.
+    pub invalidations: u64,
+    /// The number of expired entries removed.
+    pub expirations: u64,
+    /// The number of entries evicted to stay within a quota.
+    pub evictions: u64,
+    /// The number of futures passed to [Cache::wrap] which failed.
+    pub loader_errors: u64,
+    /// How long the futures passed to [Cache::wrap] took to complete.
+    pub loader_latency: Histogram,
+    /// The number of futures passed to [Cache::wrap] which took longer than
+    /// the [slow loader threshold][Builder::slow_loader_threshold].
+    pub slow_loads: u64,
+    /// The number of reads and writes of entries which took longer than the
+    /// [slow storage threshold][Builder::slow_storage_threshold].
+    pub slow_storage: u64,
+    /// How long ago the entries which were read had been stored, whether
+    /// they were fresh or expired.
+    ///
+    /// Together with [NamespaceStats::ttl_utilization] this tells whether
+    /// the time to live of entries in the namespace is too long or too short.
+    pub access_age: Histogram,
+    /// How much of their time to live entries had used when they were
+    /// replaced, where entries replaced after they expired are counted as
+    /// having used all of it.
+    ///
+    /// Entries which are mostly replaced early suggest that their time to
+    /// live is longer than it needs to be. Entries which never expire and
+    /// tombstones aren't counted.
+    pub ttl_utilization: FractionHistogram,
 }
 
-impl Cache {
-    /// Load the cache from the database.
-    pub fn load(db: sled::Tree) -> Result<Cache, Error> {
-        let cache = Cache {
-            inner: Arc::new(Inner {
-                ns: None,
-                db,
-                wakers: Default::default(),
-            }),
-        };
-        cache.cleanup()?;
-        Ok(cache)
+/// Statistics of every operation on a cache as reported by [Cache::stats].
+///
+/// Like [NamespaceStats], statistics are kept in memory by the process since
+/// the cache was loaded, and are shared by every handle of the cache.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// The number of entries read through [Cache::get], [Cache::get_many],
+    /// [Cache::get_path] and [Cache::get_ref].
+    pub gets: u64,
+    /// The number of calls to [Cache::wrap].
+    pub wraps: u64,
+    /// The number of reads which found a fresh entry, including the ones made
+    /// by [Cache::wrap].
+    pub hits: u64,
+    /// The number of reads which found an expired entry.
+    pub stale_hits: u64,
+    /// The number of reads which didn't find an entry.
+    pub misses: u64,
+    /// The number of entries inserted or replaced.
+    pub inserts: u64,
+    /// The number of entries deleted.
+    pub deletes: u64,
+    /// The number of reads and writes which failed, including the ones made
+    /// by [Cache::wrap]. Futures passed to [Cache::wrap] which failed are
+    /// counted separately.
+    pub errors: u64,
+    /// The number of futures passed to [Cache::wrap] which failed.
+    pub loader_errors: u64,
+    /// The number of futures passed to [Cache::wrap] which took longer than
+    /// the [slow loader threshold][Builder::slow_loader_threshold].
+    pub slow_loads: u64,
+    /// The number of reads and writes of entries which took longer than the
+    /// [slow storage threshold][Builder::slow_storage_threshold].
+    pub slow_storage: u64,
+    /// How long reading entries took.
+    pub get_latency: Histogram,
+    /// How long writing entries took.
+    pub insert_latency: Histogram,
+    /// How long the futures passed to [Cache::wrap] took to complete.
+    pub loader_latency: Histogram,
+}
+
+/// Time spent in the storage engine as reported by [Cache::storage_stats], as
+/// opposed to time spent encoding and decoding values.
+///
+/// Comparing these with the latencies in [CacheStats] tells whether a
+/// regression is caused by sled or by how values are serialized. sled doesn't
+/// expose statistics of its internals, like how often its page cache is hit,
+/// so only time is attributed.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct StorageStats {
+    /// How long reading entries from the database took.
+    pub read_latency: Histogram,
+    /// How long decoding the entries which were read took, including
+    /// decrypting, decompressing and deserializing their values.
+    pub decode_latency: Histogram,
+    /// How long writing entries to the database took, including their tags,
+    /// dependencies and indexes.
+    pub write_latency: Histogram,
+}
+
+/// The health of a cache as reported by [Cache::health].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Health {
+    /// The error which made the probe fail, or `None` if the cache is
+    /// healthy.
+    pub error: Option<String>,
+    /// How long writing the probe took, or `None` if it wasn't written.
+    pub write_latency: Option<std::time::Duration>,
+    /// How long flushing the probe to disk took, or `None` if it wasn't
+    /// flushed.
+    pub flush_latency: Option<std::time::Duration>,
+    /// How long reading the probe back took, or `None` if it wasn't read.
+    pub read_latency: Option<std::time::Duration>,
+    /// How long deleting the probe took, or `None` if it wasn't deleted.
+    pub delete_latency: Option<std::time::Duration>,
+    /// When the last [cleanup][Cache::cleanup] completed since the cache was
+    /// loaded.
+    pub last_cleanup: Option<DateTime<Utc>>,
+    /// The number of reads and writes which failed since the cache was
+    /// loaded, see [CacheStats::errors].
+    pub errors: u64,
+    /// The number of futures passed to [Cache::wrap] which failed since the
+    /// cache was loaded, see [CacheStats::loader_errors].
+    pub loader_errors: u64,
+}
+
+impl Health {
+    /// Test if the probe succeeded.
+    pub fn is_healthy(&self) -> bool {
+        self.error.is_none()
     }
+}
 
-    /// Delete the given key from the specified namespace.
-    pub fn delete_with_ns<N, K>(&self, ns: Option<&N>, key: &K) -> Result<(), Error>
-    where
-        N: Serialize,
-        K: Serialize,
-    {
-        let ns = match ns {
-            Some(ns) => Some(hashkey::to_key(ns)?.normalize()),
-            None => None,
-        };
+/// The number of buckets of a [Histogram], which is enough for any duration
+/// in microseconds.
+const HISTOGRAM_BUCKETS: usize = 64;
 
-        let key = self.key_with_ns(ns.as_ref(), key)?;
-        self.inner.db.remove(&key)?;
-        Ok(())
+/// A histogram of durations, as reported in [CacheStats] and
+/// [NamespaceStats].
+///
+/// Durations are counted in buckets whose bounds are powers of two
+/// microseconds, so quantiles are approximate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    total: std::time::Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            total: std::time::Duration::default(),
+        }
     }
+}
 
-    /// List all cache entries as JSON.
-    pub fn list_json(&self) -> Result<Vec<JsonEntry>, Error> {
-        let mut out = Vec::new();
+impl Histogram {
+    /// The number of durations counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
 
-        for result in self.inner.db.range::<&[u8], _>(..) {
-            let (key, value) = result?;
+    /// The sum of the durations counted.
+    pub fn total(&self) -> std::time::Duration {
+        self.total
+    }
 
-            let key: json::Value = match cbor::from_slice(&*key) {
-                Ok(key) => key,
-                // key is malformed.
-                Err(_) => continue,
-            };
+    /// The mean of the durations counted, or `None` if there aren't any.
+    pub fn mean(&self) -> Option<std::time::Duration> {
+        match u32::try_from(self.count()) {
+            Ok(0) => None,
+            Ok(count) => Some(self.total / count),
+            Err(..) => Some(self.total.div_f64(self.count() as f64)),
+        }
+    }
 
-            let stored = match cbor::from_slice(&*value) {
-                Ok(storage) => storage,
-                // something weird stored in there.
-                Err(_) => continue,
-            };
+    /// The upper bound of the bucket holding the duration at the given
+    /// quantile, like `0.99` for the 99th percentile, or `None` if no
+    /// durations have been counted.
+    pub fn quantile(&self, quantile: f64) -> Option<std::time::Duration> {
+        let count = self.count();
 
-            out.push(JsonEntry { key, stored });
+        if count == 0 {
+            return None;
         }
 
-        Ok(out)
-    }
+        let rank = ((count as f64 * quantile).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
 
-    /// Clean up stale entries.
-    ///
-    /// This could be called periodically if you want to reclaim space.
-    fn cleanup(&self) -> Result<(), Error> {
-        let now = Utc::now();
+        for (bound, n) in self.buckets() {
+            seen += n;
 
-        for result in self.inner.db.range::<&[u8], _>(..) {
-            let (key, value) = result?;
+            if seen >= rank {
+                return Some(bound);
+            }
+        }
 
-            let entry: PartialStoredEntry = match cbor::from_slice(&*value) {
-                Ok(entry) => entry,
-                Err(e) => {
-                    if log::log_enabled!(log::Level::Trace) {
-                        log::warn!(
+        None
+    }
+
+    /// Iterate over the buckets of the histogram, as their exclusive upper
+    /// bound and the number of durations counted in them. The last bucket
+    /// also counts every duration longer than its bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (std::time::Duration, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(n, count)| (std::time::Duration::from_micros(1 << n), *count))
+    }
+}
+
+/// The number of buckets of a [FractionHistogram], one for each tenth and one
+/// for fractions of one or more.
+const FRACTION_BUCKETS: usize = 11;
+
+/// A histogram of fractions, as reported in [NamespaceStats].
+///
+/// Fractions are counted in buckets of a tenth, so quantiles are approximate.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FractionHistogram {
+    buckets: [u64; FRACTION_BUCKETS],
+}
+
+impl FractionHistogram {
+    /// The number of fractions counted.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The upper bound of the bucket holding the fraction at the given
+    /// quantile, like `0.5` for the median, or `None` if no fractions have
+    /// been counted.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        let count = self.count();
+
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((count as f64 * quantile).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+
+        for (bound, n) in self.buckets() {
+            seen += n;
+
+            if seen >= rank {
+                return Some(bound);
+            }
+        }
+
+        None
+    }
+
+    /// Iterate over the buckets of the histogram, as their exclusive upper
+    /// bound and the number of fractions counted in them. The last bucket
+    /// counts every fraction of one or more, and its bound is infinite.
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.buckets.iter().enumerate().map(|(n, count)| {
+            let bound = if n + 1 < FRACTION_BUCKETS {
+                (n + 1) as f64 / 10.0
+            } else {
+                f64::INFINITY
+            };
+
+            (bound, *count)
+        })
+    }
+}
+
+/// In-memory state held by a cache as reported by [Cache::memory_usage].
+///
+/// Only the wakers deduplicating concurrent calls to [Cache::wrap] are
+/// reported. The cache doesn't keep decoded values in memory and doesn't
+/// buffer writes, so there's no front cache or pending write batch to report,
+/// and sled doesn't expose how much of its page cache or write buffers are in
+/// use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct MemoryUsage {
+    /// The number of keys which have a waker registered for deduplicating
+    /// concurrent calls to [Cache::wrap].
+    pub wakers: usize,
+    /// The number of calls to [Cache::wrap] which are currently in flight.
+    pub pending: usize,
+    /// The approximate number of bytes used by registered wakers, including
+    /// their keys.
+    pub bytes: usize,
+}
+
+/// The kind of mutation recorded by an [AuditRecord].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum AuditOp {
+    /// The entry was inserted, replaced, or restored.
+    Insert,
+    /// The entry was deleted, taken, or soft deleted.
+    Delete,
+    /// The entry was removed after it expired, by [Cache::cleanup] or
+    /// [Cache::drain_expired].
+    Expire,
+    /// The entry was invalidated through [Cache::invalidate] or
+    /// [Cache::invalidate_tag].
+    Invalidate,
+    /// The entry was evicted by [Cache::cleanup] since its namespace exceeded
+    /// its quota. See [namespace::Policy::max_entries].
+    Evict,
+}
+
+/// A mutation of an entry recorded in the audit log.
+///
+/// See [Builder::audit_log].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// The identifier of the record, which increases with each record.
+    #[serde(skip)]
+    pub id: u64,
+    /// When the mutation happened.
+    pub at: DateTime<Utc>,
+    /// The kind of mutation.
+    pub op: AuditOp,
+    /// The namespace of the entry as JSON, or `null` for the default
+    /// namespace.
+    pub ns: serde_json::Value,
+    /// The key of the entry as JSON, redacted according to
+    /// [Builder::key_redaction].
+    pub key: serde_json::Value,
+}
+
+/// An entry passed to the hooks registered on a [Builder], like
+/// [Builder::on_insert].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct HookEvent {
+    /// The namespace of the entry as JSON, or `null` for the default
+    /// namespace.
+    pub ns: json::Value,
+    /// The key of the entry as JSON, redacted according to
+    /// [Builder::key_redaction].
+    pub key: json::Value,
+    /// When the entry expires, if it was found and expires.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// When the entry was stored, if it was found.
+    pub stored_at: Option<DateTime<Utc>>,
+    /// The metadata stored alongside the value of the entry, which is empty
+    /// if it wasn't found.
+    pub metadata: Metadata,
+}
+
+/// A callback registered with [Builder::explain].
+type ExplainFn = Box<dyn Fn(&explain::Explanation) + Send + Sync>;
+
+/// A clock registered with [Builder::clock], shared with the entries read
+/// through it.
+#[derive(Clone)]
+pub(crate) struct Clock(Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>);
+
+impl Clock {
+    /// Wrap the given function reading the current time.
+    pub(crate) fn new<F>(clock: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn() -> DateTime<Utc>,
+    {
+        Clock(Arc::new(clock))
+    }
+
+    /// The current time according to the clock.
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_tuple("Clock").finish()
+    }
+}
+
+/// The current time according to the given clock, or the system clock if
+/// there is none.
+fn now(clock: Option<&Clock>) -> DateTime<Utc> {
+    match clock {
+        Some(clock) => clock.now(),
+        None => Utc::now(),
+    }
+}
+
+/// A callback registered with one of the hooks of a [Builder].
+type HookFn = Box<dyn Fn(&HookEvent) + Send + Sync>;
+
+/// Callbacks registered with the hooks of a [Builder].
+#[derive(Default)]
+struct Hooks {
+    insert: Vec<HookFn>,
+    hit: Vec<HookFn>,
+    miss: Vec<HookFn>,
+    expire: Vec<HookFn>,
+    evict: Vec<HookFn>,
+    corrupt: Vec<HookFn>,
+}
+
+/// The metadata of an entry passed to hooks.
+#[derive(Clone, Copy)]
+struct HookMeta<'a> {
+    expires_at: Option<DateTime<Utc>>,
+    stored_at: Option<DateTime<Utc>>,
+    metadata: &'a Metadata,
+}
+
+/// Callback reporting the progress of [Cache::migrate] and
+/// [Cache::rename_namespace].
+type ProgressFn<'a> = Box<dyn FnMut(&MigrateProgress) + 'a>;
+
+/// Options for [Cache::migrate] and [Cache::rename_namespace].
+#[derive(Default)]
+pub struct MigrateOptions<'a> {
+    limit: Option<usize>,
+    progress: Option<ProgressFn<'a>>,
+}
+
+impl<'a> MigrateOptions<'a> {
+    /// Construct the default options, which migrate every entry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop after processing `limit` entries. The migration can be resumed by
+    /// calling the same method again.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Call the given function with the progress made after each processed
+    /// entry.
+    pub fn progress<F>(mut self, progress: F) -> Self
+    where
+        F: FnMut(&MigrateProgress) + 'a,
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+}
+
+/// Progress made by [Cache::migrate] and [Cache::rename_namespace].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct MigrateProgress {
+    /// The number of entries which were rewritten.
+    pub migrated: usize,
+    /// The number of entries which were left as they are.
+    pub skipped: usize,
+    /// The number of entries which failed to be rewritten.
+    pub failed: usize,
+    /// Whether every entry has been processed.
+    pub complete: bool,
+}
+
+impl MigrateProgress {
+    /// The number of entries processed.
+    pub fn processed(&self) -> usize {
+        self.migrated + self.skipped + self.failed
+    }
+}
+
+/// A complete stored entry with a type.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredEntry<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    sliding: i64,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    epoch: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deps: Vec<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    format: u8,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    flags: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_key: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+    value: T,
+    /// The clock of the cache the entry was read from.
+    #[serde(skip)]
+    clock: Option<Clock>,
+}
+
+/// A reference to a complete stored entry with a type.
+///
+/// This is the legacy layout of entries, which were stored as a single CBOR map
+/// before entries had a header.
+#[derive(Debug, Serialize)]
+pub struct StoredEntryRef<'a, T> {
+    expires_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "is_false")]
+    pinned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "is_zero")]
+    epoch: u64,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [&'a str],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    deps: &'a [ByteBuf],
+    #[serde(skip_serializing_if = "is_zero")]
+    format: u8,
+    #[serde(skip_serializing_if = "is_zero")]
+    flags: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_key: Option<ByteBuf>,
+    value: &'a T,
+}
+
+impl<T> StoredEntry<T> {
+    /// Construct an entry which isn't stored in a database, like the ones held
+    /// by a `MemoryCache`.
+    #[cfg(feature = "testing")]
+    pub(crate) fn detached(
+        value: T,
+        stored_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        clock: Option<Clock>,
+    ) -> Self {
+        StoredEntry {
+            expires_at,
+            stored_at: Some(stored_at),
+            sliding: 0,
+            pinned: false,
+            deleted_at: None,
+            epoch: 0,
+            tags: Vec::new(),
+            deps: Vec::new(),
+            format: 0,
+            flags: 0,
+            original_key: None,
+            metadata: Metadata::new(),
+            value,
+            clock,
+        }
+    }
+
+    /// The metadata passed to hooks.
+    fn hook_meta(&self) -> HookMeta<'_> {
+        HookMeta {
+            expires_at: self.expires_at,
+            stored_at: self.stored_at,
+            metadata: &self.metadata,
+        }
+    }
+
+    /// Test if entry is expired, either because it has reached its expiration
+    /// or because it was stored in an older epoch.
+    fn is_expired(&self, now: DateTime<Utc>, epoch: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at < now) || self.epoch < epoch
+    }
+
+    /// When the entry expires, or `None` if it was inserted with
+    /// [Cache::insert_forever] and never expires.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// When the entry was stored.
+    ///
+    /// This is `None` for entries stored by versions of this crate which
+    /// didn't record it.
+    pub fn stored_at(&self) -> Option<DateTime<Utc>> {
+        self.stored_at
+    }
+
+    /// The time remaining until the entry expires, which is zero if it has
+    /// already expired, or `None` if it never expires.
+    pub fn ttl(&self) -> Option<Duration> {
+        remaining(self.expires_at, now(self.clock.as_ref()))
+    }
+
+    /// The time elapsed since the entry was stored, if known.
+    pub fn age(&self) -> Option<Duration> {
+        Some(now(self.clock.as_ref()) - self.stored_at?)
+    }
+
+    /// The idle timeout of an entry with a sliding expiration, or `None` if it
+    /// expires at a fixed time.
+    ///
+    /// See [Cache::insert_sliding].
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        idle_timeout(self.sliding)
+    }
+
+    /// Test if the entry is pinned.
+    ///
+    /// Pinned entries are never removed by cleanup, even once they have
+    /// expired. See [Cache::pin].
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Test if the entry is a tombstone left behind by [Cache::soft_delete].
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// The tags associated with the entry through [Cache::insert_tagged].
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// The metadata stored alongside the value through
+    /// [Cache::insert_with_metadata].
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The value of the entry.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Convert into the value of the entry.
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    /// Convert the value of the entry.
+    fn try_map<U, F>(self, f: F) -> Result<StoredEntry<U>, Error>
+    where
+        F: FnOnce(T) -> Result<U, Error>,
+    {
+        Ok(StoredEntry {
+            expires_at: self.expires_at,
+            stored_at: self.stored_at,
+            sliding: self.sliding,
+            pinned: self.pinned,
+            deleted_at: self.deleted_at,
+            epoch: self.epoch,
+            tags: self.tags,
+            deps: self.deps,
+            format: self.format,
+            flags: self.flags,
+            original_key: self.original_key,
+            metadata: self.metadata,
+            value: f(self.value)?,
+            clock: self.clock,
+        })
+    }
+}
+
+impl<T> StoredEntry<Option<T>> {
+    /// Convert an entry with an optional value into an optional entry.
+    fn transpose(self) -> Option<StoredEntry<T>> {
+        Some(StoredEntry {
+            expires_at: self.expires_at,
+            stored_at: self.stored_at,
+            sliding: self.sliding,
+            pinned: self.pinned,
+            deleted_at: self.deleted_at,
+            epoch: self.epoch,
+            tags: self.tags,
+            deps: self.deps,
+            format: self.format,
+            flags: self.flags,
+            original_key: self.original_key,
+            metadata: self.metadata,
+            value: self.value?,
+            clock: self.clock,
+        })
+    }
+}
+
+/// Convert the stored idle timeout of an entry in milliseconds.
+fn idle_timeout(sliding: i64) -> Option<Duration> {
+    if sliding > 0 {
+        Some(Duration::milliseconds(sliding))
+    } else {
+        None
+    }
+}
+
+/// The time remaining from `now` until `expires_at`, clamped to zero.
+fn remaining(expires_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Option<Duration> {
+    expires_at.map(|expires_at| std::cmp::max(expires_at - now, Duration::zero()))
+}
+
+/// Helper for skipping serialization of `false` fields.
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+/// Helper for skipping serialization of zero fields.
+fn is_zero<T>(value: &T) -> bool
+where
+    T: Default + PartialEq,
+{
+    *value == T::default()
+}
+
+/// The metadata of a stored entry, which is everything except for its value.
+///
+/// This is also used to only deserialize part of a legacy stored entry. The
+/// format and flags of entries with a header are stored in the header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PartialStoredEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    sliding: i64,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pinned: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    epoch: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deps: Vec<ByteBuf>,
+    #[serde(default, skip_serializing)]
+    format: u8,
+    #[serde(default, skip_serializing)]
+    flags: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original_key: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunks: Option<Chunks>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    schema: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    list: Option<List>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    indexes: Vec<(String, ByteBuf)>,
+    #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+    metadata: Metadata,
+}
+
+/// The metadata of an entry together with its serialized value.
+type LoadedEntry<'a> = (PartialStoredEntry, Cow<'a, [u8]>);
+
+/// Describes where the items of a list are stored.
+///
+/// Items are stored under chunk keys, so that they're removed like the chunks
+/// of an entry.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct List {
+    /// The unique identifier of the items.
+    id: u64,
+    /// The index of the first item.
+    start: u32,
+    /// The index after the last item.
+    end: u32,
+}
+
+impl List {
+    /// The number of items in the list.
+    fn len(&self) -> usize {
+        (self.end - self.start) as usize
+    }
+}
+
+/// Describes how the value of a chunked entry is split across chunks.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Chunks {
+    /// The unique identifier of the chunks.
+    id: u64,
+    /// The number of chunks.
+    count: u32,
+    /// The total length of the value.
+    len: u64,
+    /// The CRC32 of the value.
+    checksum: u32,
+}
+
+impl PartialStoredEntry {
+    /// The metadata passed to hooks.
+    fn hook_meta(&self) -> HookMeta<'_> {
+        HookMeta {
+            expires_at: self.expires_at,
+            stored_at: self.stored_at,
+            metadata: &self.metadata,
+        }
+    }
+
+    /// Test if entry is expired, either because it has reached its expiration
+    /// or because it was stored in an older epoch.
+    fn is_expired(&self, now: DateTime<Utc>, epoch: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at < now) || self.epoch < epoch
+    }
+
+    /// Test if the entry should be removed by a cleanup.
+    fn is_stale(&self, now: DateTime<Utc>, epoch: u64, tombstone_retention: Duration) -> bool {
+        match self.deleted_at {
+            Some(deleted_at) => deleted_at + tombstone_retention < now,
+            None => self.is_expired(now, epoch) && !self.pinned,
+        }
+    }
+
+    /// Convert into a stored entry with the given value, read through a cache
+    /// with the given configuration.
+    fn into_stored_entry<T>(self, config: &Config, value: T) -> StoredEntry<T> {
+        StoredEntry {
+            expires_at: self.expires_at,
+            stored_at: self.stored_at,
+            sliding: self.sliding,
+            pinned: self.pinned,
+            deleted_at: self.deleted_at,
+            epoch: self.epoch,
+            tags: self.tags,
+            deps: self.deps,
+            format: self.format,
+            flags: self.flags,
+            original_key: self.original_key,
+            metadata: self.metadata,
+            value,
+            clock: config.clock.clone(),
+        }
+    }
+}
+
+/// Options used when storing an entry.
+#[derive(Default, Clone, Copy)]
+struct InsertOptions<'a> {
+    /// Whether the entry is pinned.
+    pinned: bool,
+    /// Whether the expiration of the entry is extended whenever it's read.
+    sliding: bool,
+    /// Tags to associate with the entry.
+    tags: &'a [&'a str],
+    /// Keys of the entries this entry is derived from.
+    deps: &'a [ByteBuf],
+    /// Secondary indexes of the entry, by name and encoded value.
+    indexes: &'a [(String, ByteBuf)],
+    /// The encoded key of the entry before it was hashed.
+    plain_key: Option<&'a [u8]>,
+    /// The schema version of the value.
+    schema: u32,
+    /// When the entry expires, if it isn't relative to when it's stored.
+    expires_at: Option<DateTime<Utc>>,
+    /// Whether the entry never expires.
+    forever: bool,
+    /// The condition under which the entry is stored.
+    condition: Condition<'a>,
+    /// Metadata stored alongside the value.
+    metadata: Option<&'a Metadata>,
+}
+
+impl InsertOptions<'_> {
+    /// Write the tag, dependency and index records of an entry as part of a
+    /// transaction.
+    fn write_records(
+        &self,
+        tx: &TransactionalTree,
+        key: &[u8],
+    ) -> Result<(), ConflictableTransactionError<Error>> {
+        for tag in self.tags {
+            tx.insert(tag_key(tag, key), &[])?;
+        }
+
+        for dep in self.deps {
+            tx.insert(dep_key(dep, key), &[])?;
+        }
+
+        for (name, indexed) in self.indexes {
+            tx.insert(index_key(name, indexed, key), &[])?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A serialized value to write, see [Cache::write_payloads].
+struct PendingWrite<'a> {
+    /// The key of the entry.
+    key: &'a [u8],
+    /// How long the entry lives.
+    age: Duration,
+    /// The serialized value.
+    payload: Vec<u8>,
+    /// The flags of the serialized value.
+    flags: u8,
+    /// The options the entry is written with.
+    options: InsertOptions<'a>,
+}
+
+/// The condition under which an entry is written.
+#[derive(Default, Clone, Copy)]
+enum Condition<'a> {
+    /// The entry is always written.
+    #[default]
+    Always,
+    /// The entry is only written if there's no fresh entry with the same key.
+    Absent,
+    /// The entry is only written if the entry with the same key is still
+    /// stored as the given bytes, or if there's still no entry.
+    Unchanged(Option<&'a [u8]>),
+}
+
+/// The outcome of writing an entry.
+enum Written {
+    /// The entry was written, replacing the given entry if there was one.
+    Replaced(Option<sled::IVec>),
+    /// The entry wasn't written because of the condition it was written
+    /// with, and the given entry is what's currently stored.
+    Rejected(Option<sled::IVec>),
+}
+
+/// An entry which is ready to be written, along with its value if it's
+/// stored separately.
+struct Prepared {
+    /// The serialized entry.
+    value: Vec<u8>,
+    /// The chunks the value was split into.
+    chunks: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The hash and the contents of the deduplicated value.
+    blob: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Prepared {
+    /// Test if the value is stored in the entry itself.
+    fn is_inline(&self) -> bool {
+        self.chunks.is_empty() && self.blob.is_none()
+    }
+
+    /// Write the entry as part of a transaction, returning the entry it
+    /// replaced.
+    ///
+    /// Chunks and deduplicated values are written in the same transaction as
+    /// the entry, so that an entry is never visible without its value.
+    fn write(
+        &self,
+        tx: &TransactionalTree,
+        key: &[u8],
+    ) -> Result<Option<sled::IVec>, ConflictableTransactionError<Error>> {
+        if let Some((hash, blob)) = &self.blob {
+            let ref_key = blob_ref_key(hash);
+
+            let refs = tx
+                .get(&ref_key)?
+                .as_deref()
+                .map(decode_counter)
+                .unwrap_or_default();
+
+            if refs == 0 {
+                tx.insert(blob_key(hash), blob.as_slice())?;
+            }
+
+            tx.insert(ref_key, &(refs + 1).to_be_bytes()[..])?;
+        }
+
+        for (chunk_key, chunk) in &self.chunks {
+            tx.insert(chunk_key.as_slice(), chunk.as_slice())?;
+        }
+
+        Ok(tx.insert(key, self.value.as_slice())?)
+    }
+}
+
+/// Statistics of every namespace, shared by all handles of a cache.
+#[derive(Default)]
+struct Stats {
+    /// Counters by encoded namespace.
+    namespaces: RwLock<BTreeMap<Vec<u8>, Arc<Counters>>>,
+    /// The number of namespaces with their own counters.
+    max_namespaces: usize,
+    /// Counters shared by the namespaces beyond `max_namespaces`, if there
+    /// were any.
+    overflow: std::sync::OnceLock<Arc<Counters>>,
+    /// Counters of operations, see [CacheStats].
+    gets: AtomicU64,
+    wraps: AtomicU64,
+    errors: AtomicU64,
+    get_latency: Timings,
+    insert_latency: Timings,
+    loader_latency: Timings,
+    /// Time spent in the storage engine, see [StorageStats].
+    storage_read_latency: Timings,
+    decode_latency: Timings,
+    storage_write_latency: Timings,
+    /// When the last cleanup completed, see [Health].
+    last_cleanup: Mutex<Option<DateTime<Utc>>>,
+    /// Limits the warnings about slow loaders and slow storage.
+    slow_loader_warnings: WarningLimit,
+    slow_storage_warnings: WarningLimit,
+}
+
+/// How often a warning about slow operations is logged at most.
+const SLOW_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Limits how often a warning is logged, so that a stall affecting many
+/// operations doesn't flood the logs.
+#[derive(Default)]
+struct WarningLimit {
+    /// When the last warning was logged, and how many have been suppressed
+    /// since.
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl WarningLimit {
+    /// Test if a warning should be logged, returning the number of warnings
+    /// which were suppressed since the last one if it should.
+    fn check(&self) -> Option<u64> {
+        let mut state = self.state.lock();
+        let (last, suppressed) = &mut *state;
+        let now = Instant::now();
+
+        if last.is_some_and(|last| now.duration_since(last) < SLOW_WARNING_INTERVAL) {
+            *suppressed += 1;
+            return None;
+        }
+
+        *last = Some(now);
+        Some(std::mem::take(suppressed))
+    }
+}
+
+impl Stats {
+    /// Construct the statistics of a cache, where at most `max_namespaces`
+    /// namespaces have their own counters.
+    fn new(max_namespaces: usize) -> Self {
+        Self {
+            max_namespaces,
+            ..Self::default()
+        }
+    }
+
+    /// The counters of the given encoded namespace.
+    ///
+    /// Once `max_namespaces` namespaces have their own counters, other
+    /// namespaces share the same counters. The default namespace always has
+    /// its own.
+    fn counters(&self, ns: &[u8]) -> Arc<Counters> {
+        if let Some(counters) = self.namespaces.read().get(ns) {
+            return counters.clone();
+        }
+
+        let mut namespaces = self.namespaces.write();
+
+        if let Some(counters) = namespaces.get(ns) {
+            return counters.clone();
+        }
+
+        let named = namespaces.keys().filter(|ns| !ns.is_empty()).count();
+
+        if !ns.is_empty() && named >= self.max_namespaces {
+            return self.overflow.get_or_init(Default::default).clone();
+        }
+
+        namespaces.entry(ns.to_vec()).or_default().clone()
+    }
+}
+
+impl Stats {
+    /// Count a read which started at the given time.
+    fn get<T>(&self, started: Instant, result: &Result<T, Error>) {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.get_latency.record(started.elapsed());
+        self.error(result);
+    }
+
+    /// Count an operation which failed.
+    fn error<T>(&self, result: &Result<T, Error>) {
+        if result.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Durations counted in buckets, see [Histogram].
+struct Timings {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    total: AtomicU64,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            total: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Timings {
+    /// Count a duration.
+    fn record(&self, duration: std::time::Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        // the number of bits is the exponent of the bucket's upper bound.
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the timings.
+    fn snapshot(&self) -> Histogram {
+        let mut histogram = Histogram {
+            total: std::time::Duration::from_micros(self.total.load(Ordering::Relaxed)),
+            ..Histogram::default()
+        };
+
+        for (out, bucket) in histogram.buckets.iter_mut().zip(&self.buckets) {
+            *out = bucket.load(Ordering::Relaxed);
+        }
+
+        histogram
+    }
+}
+
+/// Fractions counted in buckets, see [FractionHistogram].
+#[derive(Default)]
+struct Fractions {
+    buckets: [AtomicU64; FRACTION_BUCKETS],
+}
+
+impl Fractions {
+    /// Count a fraction.
+    fn record(&self, fraction: f64) {
+        let bucket = (fraction.max(0.0) * 10.0) as usize;
+        self.buckets[bucket.min(FRACTION_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the fractions.
+    fn snapshot(&self) -> FractionHistogram {
+        let mut histogram = FractionHistogram::default();
+
+        for (out, bucket) in histogram.buckets.iter_mut().zip(&self.buckets) {
+            *out = bucket.load(Ordering::Relaxed);
+        }
+
+        histogram
+    }
+}
+
+/// Counters of a single namespace, see [NamespaceStats].
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale_hits: AtomicU64,
+    inserts: AtomicU64,
+    deletes: AtomicU64,
+    invalidations: AtomicU64,
+    expirations: AtomicU64,
+    evictions: AtomicU64,
+    loader_errors: AtomicU64,
+    loader_latency: Timings,
+    slow_loads: AtomicU64,
+    slow_storage: AtomicU64,
+    access_age: Timings,
+    ttl_utilization: Fractions,
+}
+
+impl Counters {
+    /// Count a read which resulted in the given state.
+    fn read<T>(&self, state: &State<T>) {
+        trace::outcome(state);
+
+        let counter = match state {
+            State::Fresh(..) => &self.hits,
+            State::Expired(..) => &self.stale_hits,
+            State::Missing | State::Corrupt => &self.misses,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count how old an entry which was read is.
+    fn access<T>(&self, stored: &StoredEntry<T>, now: DateTime<Utc>) {
+        if let Some(age) = stored.stored_at.and_then(|at| (now - at).to_std().ok()) {
+            self.access_age.record(age);
+        }
+    }
+
+    /// Count how much of its time to live an entry which was replaced had
+    /// used.
+    fn refresh(&self, replaced: &PartialStoredEntry, now: DateTime<Utc>) {
+        let (stored_at, expires_at) = match (replaced.stored_at, replaced.expires_at) {
+            (Some(stored_at), Some(expires_at)) if replaced.deleted_at.is_none() => {
+                (stored_at, expires_at)
+            }
+            _ => return,
+        };
+
+        let ttl = (expires_at - stored_at).num_milliseconds();
+
+        if ttl > 0 {
+            let used = (now - stored_at).num_milliseconds();
+            self.ttl_utilization.record(used as f64 / ttl as f64);
+        }
+    }
+
+    /// Count a mutation.
+    fn record(&self, op: AuditOp) {
+        let counter = match op {
+            AuditOp::Insert => &self.inserts,
+            AuditOp::Delete => &self.deletes,
+            AuditOp::Expire => &self.expirations,
+            AuditOp::Invalidate => &self.invalidations,
+            AuditOp::Evict => &self.evictions,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the counters.
+    fn snapshot(&self, namespace: Option<json::Value>) -> NamespaceStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        NamespaceStats {
+            namespace,
+            overflow: false,
+            hits: load(&self.hits),
+            misses: load(&self.misses),
+            stale_hits: load(&self.stale_hits),
+            inserts: load(&self.inserts),
+            deletes: load(&self.deletes),
+            invalidations: load(&self.invalidations),
+            expirations: load(&self.expirations),
+            evictions: load(&self.evictions),
+            loader_errors: load(&self.loader_errors),
+            loader_latency: self.loader_latency.snapshot(),
+            slow_loads: load(&self.slow_loads),
+            slow_storage: load(&self.slow_storage),
+            access_age: self.access_age.snapshot(),
+            ttl_utilization: self.ttl_utilization.snapshot(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Waker {
+    /// Number of things waiting for a response.
+    pending: AtomicUsize,
+    /// Channels to use for notifying dependents.
+    channels: SegQueue<oneshot::Sender<bool>>,
+}
+
+impl Waker {
+    /// Spin on performing cleanup, receiving channels to notify until we are in a stable state
+    /// where everything has been reset.
+    fn cleanup(&self, error: bool) {
+        let mut previous = self.pending.load(Ordering::Acquire);
+
+        loop {
+            while previous > 1 {
+                let mut received = 0usize;
+
+                while let Some(waker) = self.channels.pop() {
+                    received += 1;
+                    let _ = waker.send(error);
+                }
+
+                // Subtract the number of notifications sent here. Setting this inside the wrap
+                // function would deadlock on singlethreaded executors since they can't make
+                // progress at the same time as this procedure.
+                previous = self.pending.fetch_sub(received, Ordering::AcqRel);
+            }
+
+            previous = self
+                .pending
+                .compare_exchange(1, 0, Ordering::AcqRel, Ordering::Acquire)
+                .unwrap_or_else(|previous| previous);
+
+            if previous == 1 {
+                break;
+            }
+        }
+    }
+}
+
+/// How to treat a time to live which is outside of the bounds configured with
+/// [Builder::min_ttl] and [Builder::max_ttl].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TtlStrictness {
+    /// Silently clamp the time to live to the configured bounds.
+    #[default]
+    Clamp,
+    /// Clamp the time to live and log a warning.
+    Warn,
+    /// Fail the operation with [Error::TtlOutOfRange].
+    Error,
+}
+
+/// How keys are written to log output, configured with
+/// [Builder::key_redaction].
+///
+/// Keys are redacted the same way in [errors][EntryError], [audit
+/// records][AuditRecord], [hook events][HookEvent],
+/// [explanations][explain::Explanation] and
+/// [invalidations][invalidation::Invalidation].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRedaction {
+    /// Write keys as they are, decoded to JSON where possible.
+    #[default]
+    Full,
+    /// Write the namespace of keys, and a short hash (SHA-256) of the keys
+    /// themselves so that log lines about the same key can be correlated.
+    ///
+    /// Requires the `key-hashing` feature.
+    #[cfg(feature = "key-hashing")]
+    Hashed,
+    /// Only write the namespace of keys.
+    NamespaceOnly,
+}
+
+/// Configuration shared by a cache and all of its namespaced handles.
+#[derive(Default)]
+struct Config {
+    /// Flush the database when a cleanup or a bulk deletion removes more than
+    /// this many entries.
+    auto_flush: Option<usize>,
+    /// The smallest permitted time to live.
+    min_ttl: Option<Duration>,
+    /// The largest permitted time to live.
+    max_ttl: Option<Duration>,
+    /// How to treat a time to live outside of the configured bounds.
+    ttl_strictness: TtlStrictness,
+    /// How keys are written to log output.
+    key_redaction: KeyRedaction,
+    /// How long tombstones are retained.
+    tombstone_retention: Option<Duration>,
+    /// The format used to store values.
+    format: Format,
+    /// Custom codecs entries can be read with, by their identifier.
+    codecs: HashMap<u8, Arc<dyn Codec>>,
+    /// Policies of specific namespaces.
+    policies: HashMap<hashkey::Key, namespace::Policy>,
+    /// Compress values larger than this many bytes.
+    #[cfg(feature = "zstd")]
+    compression_threshold: Option<usize>,
+    /// Cipher used to encrypt values.
+    #[cfg(feature = "encryption")]
+    cipher: Option<XChaCha20Poly1305>,
+    /// Keyed hash used to hash keys.
+    #[cfg(feature = "key-hashing")]
+    key_hasher: Option<Hmac<Sha256>>,
+    /// Hash encoded keys longer than this many bytes.
+    max_key_len: Option<usize>,
+    /// Don't rewrite entries in an outdated layout or format when they're read.
+    disable_read_repair: bool,
+    /// Don't clean up stale entries when the cache is loaded.
+    disable_cleanup_on_load: bool,
+    /// Never modify the database implicitly, see [Builder::read_only].
+    read_only: bool,
+    /// Fail reads of entries which can't be deserialized.
+    strict_reads: bool,
+    /// Split values larger than this many bytes into chunks.
+    chunk_size: Option<usize>,
+    /// Store identical values once.
+    #[cfg(feature = "deduplicate")]
+    deduplicate: bool,
+    /// The number of replaced versions of each entry to retain.
+    history: usize,
+    /// Where mutations of entries are recorded.
+    audit_log: Option<sled::Tree>,
+    /// Where invalidations are published.
+    invalidation_bus: Option<Arc<dyn invalidation::Bus>>,
+    /// Callbacks registered with the hooks.
+    hooks: Hooks,
+    /// Callback explaining decisions made about entries.
+    explain: Option<ExplainFn>,
+    /// The clock used instead of the system clock.
+    clock: Option<Clock>,
+    /// Faults injected into reads and writes.
+    #[cfg(feature = "testing")]
+    faults: Option<faults::Faults>,
+    /// Loaders which take longer than this are reported as slow.
+    slow_loader_threshold: Option<std::time::Duration>,
+    /// Reads and writes which take longer than this are reported as slow.
+    slow_storage_threshold: Option<std::time::Duration>,
+    /// The number of namespaces with their own statistics.
+    max_stats_namespaces: Option<usize>,
+    /// Schemas of values, by their type.
+    schemas: HashMap<TypeId, schema::Migrations>,
+    /// Secondary indexes of values, by their type.
+    indexes: HashMap<TypeId, Vec<Index>>,
+}
+
+/// Extracts the encoded value to index an entry by from its value.
+type ExtractFn = Box<dyn Fn(&json::Value) -> Result<Option<Vec<u8>>, Error> + Send + Sync>;
+
+/// A secondary index registered with [Builder::index].
+struct Index {
+    /// The name of the index.
+    name: String,
+    extract: ExtractFn,
+}
+
+impl Config {
+    /// The current time according to the configured clock.
+    fn now(&self) -> DateTime<Utc> {
+        now(self.clock.as_ref())
+    }
+
+    /// Decide what to do with a read according to the configured faults.
+    #[cfg(feature = "testing")]
+    fn read_fault(&self) -> faults::Action {
+        match &self.faults {
+            Some(faults) => faults.on_read(),
+            None => faults::Action::Pass,
+        }
+    }
+
+    /// Look up the format an entry was stored with by its recorded tag.
+    fn format_of(&self, id: u8) -> Option<Format> {
+        match Format::from_id(id) {
+            Some(format) => Some(format),
+            None => self.codecs.get(&id).cloned().map(Format::Custom),
+        }
+    }
+
+    /// The schema registered for values of type `T`.
+    ///
+    /// Values are written through references to types which aren't
+    /// necessarily `'static`, so they're looked up with [typeid::of], which
+    /// ignores lifetimes.
+    fn schema<T>(&self) -> Option<&schema::Migrations>
+    where
+        T: ?Sized,
+    {
+        self.schemas.get(&typeid::of::<T>())
+    }
+
+    /// How long tombstones left by soft deletes are retained.
+    fn tombstone_retention(&self) -> Duration {
+        self.tombstone_retention
+            .unwrap_or_else(|| Duration::days(1))
+    }
+
+    /// Test if keys are hashed.
+    fn hashes_keys(&self) -> bool {
+        #[cfg(feature = "key-hashing")]
+        {
+            self.key_hasher.is_some()
+        }
+
+        #[cfg(not(feature = "key-hashing"))]
+        {
+            false
+        }
+    }
+
+    /// Test if the given encoded key is too long to be stored as it is.
+    fn is_oversized(&self, key: &[u8]) -> bool {
+        self.max_key_len.is_some_and(|max| key.len() > max)
+    }
+
+    /// Hash an encoded key if key hashing is enabled, or if it's oversized.
+    #[cfg_attr(not(feature = "key-hashing"), allow(unused_variables))]
+    fn hash_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        #[cfg(feature = "key-hashing")]
+        {
+            if let Some(hasher) = &self.key_hasher {
+                let mut hasher = hasher.clone();
+                hasher.update(key);
+
+                let mut hashed = vec![key::HASHED];
+                hashed.extend_from_slice(&hasher.finalize().into_bytes());
+                return Some(hashed);
+            }
+
+            if self.is_oversized(key) {
+                let mut hashed = vec![key::HASHED];
+                hashed.extend_from_slice(&Sha256::digest(key));
+                return Some(hashed);
+            }
+        }
+
+        None
+    }
+
+    /// The policy of the given namespace.
+    fn policy(&self, ns: Option<&hashkey::Key>) -> Option<&namespace::Policy> {
+        self.policies.get(ns?)
+    }
+
+    /// The format used to store values in the given namespace.
+    fn format(&self, ns: Option<&hashkey::Key>) -> Format {
+        self.policy(ns)
+            .and_then(|policy| policy.format.clone())
+            .unwrap_or_else(|| self.format.clone())
+    }
+
+    /// Test if entries in the given namespace have a sliding expiration.
+    fn sliding(&self, ns: Option<&hashkey::Key>) -> bool {
+        self.policy(ns).is_some_and(|policy| policy.sliding)
+    }
+
+    /// Compress values in the given namespace which are larger than this many
+    /// bytes.
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    fn compression_threshold(&self, ns: Option<&hashkey::Key>) -> Option<usize> {
+        #[cfg(feature = "zstd")]
+        {
+            match self
+                .policy(ns)
+                .and_then(|policy| policy.compression_threshold)
+            {
+                Some(threshold) => threshold,
+                None => self.compression_threshold,
+            }
+        }
+
+        #[cfg(not(feature = "zstd"))]
+        {
+            None
+        }
+    }
+
+    /// Apply the configured bounds to the given time to live.
+    fn ttl(&self, age: Duration) -> Result<Duration, Error> {
+        let clamped = match (self.min_ttl, self.max_ttl) {
+            (Some(min), _) if age < min => min,
+            (_, Some(max)) if age > max => max,
+            _ => return Ok(age),
+        };
+
+        match self.ttl_strictness {
+            TtlStrictness::Clamp => Ok(clamped),
+            TtlStrictness::Warn => {
+                log::warn!("time to live {} out of range, clamped to {}", age, clamped);
+                Ok(clamped)
+            }
+            TtlStrictness::Error => Err(Error::TtlOutOfRange(age)),
+        }
+    }
+}
+
+struct Inner {
+    /// The serialized namespace this cache belongs to.
+    ns: Option<hashkey::Key>,
+    /// The encoded namespace this cache belongs to, including the namespaces
+    /// it's nested in.
+    ns_key: Vec<u8>,
+    /// Underlying storage.
+    db: sled::Tree,
+    /// Shared configuration.
+    config: Arc<Config>,
+    /// The format used to store values.
+    format: Format,
+    /// Whether new entries have a sliding expiration.
+    sliding: bool,
+    /// Compress values larger than this many bytes.
+    compression_threshold: Option<usize>,
+    /// The policy of the namespace this cache belongs to.
+    policy: namespace::Policy,
+    /// Statistics of every namespace.
+    stats: Arc<Stats>,
+    /// Statistics of the namespace this cache belongs to.
+    counters: Arc<Counters>,
+    /// Approximate number of entries in the database.
+    len: Arc<AtomicUsize>,
+    /// The current invalidation epoch, mirrored from the database.
+    epoch: Arc<AtomicU64>,
+    /// Generations of the generational namespaces this cache belongs to, with
+    /// the offset in `ns_key` where each generation is inserted.
+    generations: Vec<(usize, Arc<AtomicU64>)>,
+    /// Current generations of every loaded generational namespace, mirrored
+    /// from the database.
+    generation_counters: Arc<Mutex<HashMap<Vec<u8>, Arc<AtomicU64>>>>,
+    /// Invalidations received from the bus which haven't been applied yet.
+    invalidations: Arc<Mutex<Option<invalidation::Subscriber>>>,
+    /// Things to wake up.
+    /// TODO: clean up wakers that have been idle for a long time in future cleanup loop.
+    wakers: RwLock<HashMap<Vec<u8>, Arc<Waker>>>,
+}
+
+/// Builder for a [Cache].
+///
+/// Constructed through [Cache::builder].
+pub struct Builder {
+    db: sled::Tree,
+    config: Config,
+}
+
+impl Builder {
+    /// Flush the database after a [Cache::cleanup], [Cache::clear],
+    /// [Cache::clear_namespace] or [Cache::delete_prefix] which removed more
+    /// than `threshold` entries.
+    ///
+    /// This doesn't compact the database, see [Cache::flush].
+    pub fn auto_flush(mut self, threshold: usize) -> Self {
+        self.config.auto_flush = Some(threshold);
+        self
+    }
+
+    /// The smallest time to live permitted for entries.
+    ///
+    /// See [Builder::ttl_strictness] for how violations are handled. Loading
+    /// fails with [Error::InvalidConfig] if it's larger than
+    /// [Builder::max_ttl].
+    pub fn min_ttl(mut self, min_ttl: Duration) -> Self {
+        self.config.min_ttl = Some(min_ttl);
+        self
+    }
+
+    /// The largest time to live permitted for entries.
+    ///
+    /// See [Builder::ttl_strictness] for how violations are handled.
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.config.max_ttl = Some(max_ttl);
+        self
+    }
+
+    /// Configure how a time to live outside of the bounds set by
+    /// [Builder::min_ttl] and [Builder::max_ttl] is handled.
+    ///
+    /// Defaults to [TtlStrictness::Clamp].
+    pub fn ttl_strictness(mut self, ttl_strictness: TtlStrictness) -> Self {
+        self.config.ttl_strictness = ttl_strictness;
+        self
+    }
+
+    /// Configure how keys are written to log output, for caches whose keys
+    /// contain sensitive information like usernames or tokens.
+    ///
+    /// This applies to every message logged by the cache, including values
+    /// which are logged when they fail to load, and to keys passed to
+    /// [hooks][Builder::on_insert], [Builder::explain], the [audit
+    /// log][Builder::audit_log] and the [invalidation
+    /// bus][Builder::invalidation_bus]. Note that keys hashed by
+    /// `KeyRedaction::Hashed` are not salted, so keys which are easy to guess
+    /// can be recovered from them.
+    ///
+    /// Defaults to [KeyRedaction::Full].
+    pub fn key_redaction(mut self, key_redaction: KeyRedaction) -> Self {
+        self.config.key_redaction = key_redaction;
+        self
+    }
+
+    /// How long tombstones left by [Cache::soft_delete] are retained before
+    /// they are garbage collected by [Cache::cleanup].
+    ///
+    /// Defaults to one day.
+    pub fn tombstone_retention(mut self, retention: Duration) -> Self {
+        self.config.tombstone_retention = Some(retention);
+        self
+    }
+
+    /// The format used to serialize values of new entries.
+    ///
+    /// Entries record the format they were stored with, so this can be changed
+    /// without losing existing entries. Defaults to [Format::Cbor].
+    pub fn format(mut self, format: Format) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Register a custom codec which entries can be read with.
+    ///
+    /// Codecs selected with [Builder::format] or
+    /// [Builder::namespace_format] are registered automatically, so this is
+    /// only needed to read entries stored with a codec which is no longer
+    /// used to store new ones.
+    pub fn codec(mut self, codec: Arc<dyn Codec>) -> Self {
+        self.config.codecs.insert(codec.id(), codec);
+        self
+    }
+
+    /// Compress values which are larger than `threshold` bytes once
+    /// serialized using zstd.
+    ///
+    /// Compressed entries are flagged as such and decompressed transparently
+    /// when read. Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.config.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// Encrypt values at rest using XChaCha20-Poly1305 with the given 256-bit
+    /// key.
+    ///
+    /// Values are encrypted after they've been serialized and compressed, using
+    /// a random nonce for each entry. Keys, tags and expiration metadata are
+    /// not encrypted. Each value is bound to the key it's stored under, so a
+    /// value which is copied to another key is reported as [State::Corrupt],
+    /// as are entries read back with a different encryption key. Requires the
+    /// `encryption` feature.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.config.cipher = Some(XChaCha20Poly1305::new(&key.into()));
+        self
+    }
+
+    /// Store a keyed hash (HMAC-SHA256) of keys instead of the keys
+    /// themselves, so that they can't be read by anyone with access to the
+    /// database.
+    ///
+    /// If an [encryption key][Builder::encryption_key] is also configured, the
+    /// original key is stored encrypted alongside the value so that it can be
+    /// listed through [Cache::list_json]. Otherwise hashed keys are listed as
+    /// their hash.
+    ///
+    /// Namespaces are not hashed, so they remain readable and shouldn't hold
+    /// identifiers. Since keys no longer share prefixes,
+    /// [Cache::delete_prefix] fails with [Error::HashedKeys]. Requires the
+    /// `key-hashing` feature.
+    #[cfg(feature = "key-hashing")]
+    pub fn hash_keys(mut self, secret: [u8; 32]) -> Self {
+        self.config.key_hasher = Some(
+            <Hmac<Sha256> as Mac>::new_from_slice(&secret).expect("HMAC accepts keys of any size"),
+        );
+        self
+    }
+
+    /// Store a hash (SHA-256) of encoded keys which are longer than `len`
+    /// bytes instead of the keys themselves, like keys built from whole
+    /// request bodies.
+    ///
+    /// The original key is stored alongside the value, so that entries can
+    /// still be listed through [Cache::list_json] and [Cache::keys]. Since
+    /// hashed keys no longer share prefixes, they're not matched by
+    /// [Cache::delete_prefix] or [Cache::scan]. If keys are already
+    /// [hashed][Builder::hash_keys], every key is hashed regardless of its
+    /// length.
+    ///
+    /// Requires the `key-hashing` feature.
+    #[cfg(feature = "key-hashing")]
+    pub fn max_key_len(mut self, len: usize) -> Self {
+        self.config.max_key_len = Some(len);
+        self
+    }
+
+    /// Rewrite entries which are read successfully, but are stored in an older
+    /// layout or in a different format than the one configured. This lets a
+    /// database gradually converge after an upgrade or a change of format.
+    ///
+    /// Entries are only converted between formats which can be decoded without
+    /// knowing the type of the value. Defaults to `true`.
+    pub fn read_repair(mut self, read_repair: bool) -> Self {
+        self.config.disable_read_repair = !read_repair;
+        self
+    }
+
+    /// Fail reads of entries which can't be deserialized, instead of treating
+    /// them as missing.
+    ///
+    /// Entries which don't match the type they're read as, or whose schema
+    /// can't be migrated, are otherwise logged and reported as
+    /// [State::Missing], which hides bugs like incompatible changes to a type.
+    /// In strict mode reads through [Cache::get], [Cache::get_many],
+    /// [Cache::get_path], [Cache::get_ref], [Cache::get_stream], [Cache::test]
+    /// and [Cache::wrap] fail with an [Error::Entry] of the kind
+    /// [ErrorKind::ValueDeserialization] instead. Corrupt entries are still
+    /// reported as [State::Corrupt]. Defaults to `false`, and is meant to be
+    /// enabled in tests and staging.
+    pub fn strict_reads(mut self, strict_reads: bool) -> Self {
+        self.config.strict_reads = strict_reads;
+        self
+    }
+
+    /// Read the current time from `clock` instead of the system clock.
+    ///
+    /// The clock decides when entries expire, when they're stored and when
+    /// they're cleaned up, which lets tests control time instead of sleeping.
+    /// Entries read from the cache use it as well, so [StoredEntry::ttl] and
+    /// [StoredEntry::age] are relative to it.
+    pub fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn() -> DateTime<Utc>,
+    {
+        self.config.clock = Some(Clock::new(clock));
+        self
+    }
+
+    /// Inject the given faults into the entries read and written by the
+    /// cache, to test how an application behaves when the cache fails.
+    ///
+    /// See the [faults] module, which requires the `testing` feature.
+    #[cfg(feature = "testing")]
+    pub fn faults(mut self, faults: faults::Faults) -> Self {
+        self.config.faults = Some(faults);
+        self
+    }
+
+    /// Warn about futures passed to [Cache::wrap] which take longer than
+    /// `threshold` to complete.
+    ///
+    /// Slow loaders are logged with the namespace and the key of the entry
+    /// they load, [redacted][Builder::key_redaction] as configured, and
+    /// counted in [NamespaceStats::slow_loads]. At most one of them is logged
+    /// per second, along with the number of warnings which were suppressed,
+    /// while every one is counted. Disabled by default.
+    pub fn slow_loader_threshold(mut self, threshold: std::time::Duration) -> Self {
+        self.config.slow_loader_threshold = Some(threshold);
+        self
+    }
+
+    /// Warn about reads and writes of entries which spend longer than
+    /// `threshold` in the storage engine.
+    ///
+    /// Slow operations are logged with the namespace and the key of the entry,
+    /// [redacted][Builder::key_redaction] as configured, and counted in
+    /// [NamespaceStats::slow_storage]. Like with
+    /// [slow loaders][Builder::slow_loader_threshold], at most one of them is
+    /// logged per second. Only the time measured by [Cache::storage_stats] is
+    /// compared. Disabled by default.
+    pub fn slow_storage_threshold(mut self, threshold: std::time::Duration) -> Self {
+        self.config.slow_storage_threshold = Some(threshold);
+        self
+    }
+
+    /// Keep separate [statistics][Cache::namespace_stats] for at most `max`
+    /// namespaces, besides the default namespace.
+    ///
+    /// Statistics are kept for every namespace a handle is created for, so
+    /// this bounds their memory when namespaces are created from untrusted
+    /// input. Namespaces beyond the first `max` share their statistics, which
+    /// are reported with [NamespaceStats::overflow] set. Defaults to 256.
+    pub fn max_stats_namespaces(mut self, max: usize) -> Self {
+        self.config.max_stats_namespaces = Some(max);
+        self
+    }
+
+    /// Clean up stale entries with [Cache::cleanup] when the cache is loaded.
+    ///
+    /// The [approximate length][Cache::approximate_len] is only counted once
+    /// the cache is cleaned up if this is disabled. Defaults to `true`.
+    pub fn cleanup_on_load(mut self, cleanup_on_load: bool) -> Self {
+        self.config.disable_cleanup_on_load = !cleanup_on_load;
+        self
+    }
+
+    /// Load the cache without modifying the database, to inspect it.
+    ///
+    /// Keys stored with an older key layout aren't converted when the cache
+    /// is loaded, so those entries aren't found. Stale entries aren't cleaned
+    /// up when the cache is loaded, entries aren't [repaired][Builder::read_repair]
+    /// when they're read, and the expiration of entries with a sliding
+    /// expiration isn't extended when they're read. Methods which write to the
+    /// cache, like [Cache::insert], still do. Defaults to `false`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Split values which are larger than `size` bytes once serialized,
+    /// compressed and encrypted into chunks of at most `size` bytes.
+    ///
+    /// Each chunk is stored under its own key, which avoids storing very large
+    /// values in a single entry. Chunked entries are written atomically and
+    /// reassembled transparently when read.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn chunk_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "chunk size must be non-zero");
+        self.config.chunk_size = Some(size);
+        self
+    }
+
+    /// Store identical values only once, no matter how many entries they're
+    /// stored in.
+    ///
+    /// Values are stored under the SHA-256 hash of their serialized form, and
+    /// entries refer to the value by its hash. Each value keeps a count of the
+    /// entries referring to it, and is removed once the last of them is
+    /// removed. Deduplicated values are never split into chunks. Defaults to
+    /// `false`.
+    ///
+    /// Since entries holding the same value refer to the same hash, anyone who
+    /// can read the database can tell which entries are equal. So this can't
+    /// be combined with [encryption][Builder::encryption_key], and loading the
+    /// cache fails with [Error::InvalidConfig] if both are configured.
+    ///
+    /// Requires the `deduplicate` feature.
+    #[cfg(feature = "deduplicate")]
+    pub fn deduplicate(mut self, deduplicate: bool) -> Self {
+        self.config.deduplicate = deduplicate;
+        self
+    }
+
+    /// Retain the last `versions` values of each entry when it's replaced, so
+    /// that they can be inspected with [Cache::history]. Defaults to `0`.
+    ///
+    /// Retained versions are stored separately from the entry, and are removed
+    /// by [Cache::cleanup] once the entry itself has been removed. Counters
+    /// and lists don't retain their versions, since they're modified in place.
+    pub fn history(mut self, versions: usize) -> Self {
+        self.config.history = versions;
+        self
+    }
+
+    /// Record every mutation of an entry in the given tree, so that it can be
+    /// inspected with [Cache::audit_log] or followed with
+    /// [Cache::watch_audit_log].
+    ///
+    /// The tree should be dedicated to the audit log, but it can be shared
+    /// by several caches. Records are only appended, so they have to be
+    /// removed with [Cache::trim_audit_log] once they're no longer needed.
+    pub fn audit_log(mut self, tree: sled::Tree) -> Self {
+        self.config.audit_log = Some(tree);
+        self
+    }
+
+    /// Publish invalidations on the given bus, and apply the epochs and
+    /// generations bumped by other caches sharing it.
+    ///
+    /// See the [invalidation] module.
+    pub fn invalidation_bus<B>(mut self, bus: B) -> Self
+    where
+        B: invalidation::Bus,
+    {
+        self.config.invalidation_bus = Some(Arc::new(bus));
+        self
+    }
+
+    /// Explain the decisions made about entries to `explain`, like why an
+    /// entry which is read is treated as expired.
+    ///
+    /// This is meant for diagnosing why entries keep missing, and makes reads
+    /// slower since keys have to be decoded as JSON. Like hooks, `explain` is
+    /// called on the thread performing the operation. See the [explain]
+    /// module.
+    pub fn explain<F>(mut self, explain: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&explain::Explanation),
+    {
+        self.config.explain = Some(Box::new(explain));
+        self
+    }
+
+    /// Call `hook` whenever an entry is inserted, replaced or restored, in
+    /// any namespace of the cache.
+    ///
+    /// Hooks are called on the thread performing the operation once it's
+    /// done, so they should be quick and must not block. Several hooks can be
+    /// registered for the same event, and they're called in the order they
+    /// were registered. Hooks can't be called for entries whose keys are
+    /// hashed, since their keys can't be decoded.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let inserted = Arc::new(Mutex::new(Vec::new()));
+    ///
+    /// let cache = Cache::builder(db.open_tree("cache")?)
+    ///     .on_insert({
+    ///         let inserted = inserted.clone();
+    ///         move |event| inserted.lock().unwrap().push(event.key.clone())
+    ///     })
+    ///     .load()?;
+    ///
+    /// cache.insert("a", Duration::hours(1), &1u32)?;
+    /// assert_eq!(vec![serde_json::json!("a")], *inserted.lock().unwrap());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn on_insert<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.insert.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever a fresh entry is read.
+    ///
+    /// See [Builder::on_insert].
+    pub fn on_hit<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.hit.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever an entry is read which is missing or expired.
+    ///
+    /// Expired entries are passed with their metadata, while missing entries
+    /// are passed without any. See [Builder::on_insert].
+    pub fn on_miss<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.miss.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever an expired entry is removed by [Cache::cleanup] or
+    /// [Cache::drain_expired].
+    ///
+    /// See [Builder::on_insert].
+    pub fn on_expire<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.expire.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever an entry is evicted since its namespace exceeded
+    /// its quota. See [namespace::Policy::max_entries].
+    ///
+    /// See [Builder::on_insert].
+    pub fn on_evict<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.evict.push(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` whenever an entry which is read turns out to be corrupt,
+    /// see [State::Corrupt].
+    ///
+    /// See [Builder::on_insert].
+    pub fn on_corrupt<F>(mut self, hook: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn(&HookEvent),
+    {
+        self.config.hooks.corrupt.push(Box::new(hook));
+        self
+    }
+
+    /// Register the schema of values of type `T`, replacing any schema
+    /// previously registered for it.
+    ///
+    /// Values of type `T` are stored with the version of the schema, and
+    /// values stored with an older version are migrated when they're read.
+    /// See [schema] for an example.
+    pub fn schema<T>(mut self, schema: Schema<T>) -> Self
+    where
+        T: 'static + Serialize + de::DeserializeOwned,
+    {
+        let migrations = schema.into_migrations();
+        self.config.schemas.insert(migrations.type_id, migrations);
+        self
+    }
+
+    /// Register a secondary index named `name` over values of type `T`.
+    ///
+    /// Whenever a value of type `T` is inserted, `extract` is called with it
+    /// and the entry is indexed by what it returns, if anything. Entries can
+    /// then be looked up with [Cache::get_by_index]. Index entries are removed
+    /// along with the entries they point to, and entries which were stored
+    /// before the index was registered aren't indexed.
+    ///
+    /// Values are passed to `extract` by serializing and deserializing them
+    /// again, so registering indexes makes inserting values of type `T`
+    /// slower.
+    pub fn index<T, V, F>(mut self, name: &str, extract: F) -> Self
+    where
+        T: 'static + de::DeserializeOwned,
+        V: Serialize,
+        F: 'static + Send + Sync + Fn(&T) -> Option<V>,
+    {
+        let extract = move |value: &json::Value| -> Result<Option<Vec<u8>>, Error> {
+            match extract(&T::deserialize(value)?) {
+                Some(value) => Ok(Some(key::to_vec(&value)?)),
+                None => Ok(None),
+            }
+        };
+
+        self.config
+            .indexes
+            .entry(TypeId::of::<T>())
+            .or_default()
+            .push(Index {
+                name: name.to_owned(),
+                extract: Box::new(extract),
+            });
+
+        self
+    }
+
+    /// The format used to serialize values of new entries in the given
+    /// namespace, overriding [Builder::format].
+    ///
+    /// See [namespace::Policy::format].
+    pub fn namespace_format<N>(mut self, ns: &N, format: Format) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        self.config.policies.entry(ns).or_default().format = Some(format);
+        Ok(self)
+    }
+
+    /// Give entries inserted in the given namespace a sliding expiration, as if
+    /// they were inserted with [Cache::insert_sliding].
+    ///
+    /// See [namespace::Policy::sliding].
+    pub fn sliding_namespace<N>(mut self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        self.config.policies.entry(ns).or_default().sliding = true;
+        Ok(self)
+    }
+
+    /// Register the policy of the given namespace, replacing any policy
+    /// previously configured for it through this or
+    /// [Builder::namespace_format] and [Builder::sliding_namespace].
+    ///
+    /// See [namespace::Policy] for an example.
+    pub fn namespace_policy<N>(mut self, ns: &N, policy: namespace::Policy) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        self.config.policies.insert(ns, policy);
+        Ok(self)
+    }
+
+    /// Load the cache from the database.
+    ///
+    /// This will also clean up any stale entries unless
+    /// [Builder::cleanup_on_load] is disabled, and convert the keys of entries
+    /// stored with an older key layout, unless the cache is
+    /// [read-only][Builder::read_only].
+    pub fn load(mut self) -> Result<Cache, Error> {
+        if let (Some(min), Some(max)) = (self.config.min_ttl, self.config.max_ttl) {
+            if min > max {
+                return Err(Error::InvalidConfig(format!(
+                    "min_ttl {} is greater than max_ttl {}",
+                    min, max
+                )));
+            }
+        }
+
+        let selected = std::iter::once(&self.config.format).chain(
+            self.config
+                .policies
+                .values()
+                .filter_map(|p| p.format.as_ref()),
+        );
+
+        let mut codecs = Vec::new();
+
+        for format in selected {
+            if let Format::Custom(codec) = format {
+                codecs.push(codec.clone());
+            }
+        }
+
+        for codec in codecs {
+            self.config.codecs.entry(codec.id()).or_insert(codec);
+        }
+
+        if let Some(id) = self
+            .config
+            .codecs
+            .keys()
+            .find(|id| **id < codec::MIN_CUSTOM_ID)
+        {
+            return Err(Error::InvalidConfig(format!(
+                "custom codec id {} is reserved for built-in formats",
+                id
+            )));
+        }
+
+        #[cfg(all(feature = "deduplicate", feature = "encryption"))]
+        if self.config.deduplicate && self.config.cipher.is_some() {
+            return Err(Error::InvalidConfig(String::from(
+                "deduplication can't be combined with encryption",
+            )));
+        }
+
+        let max_namespaces = self
+            .config
+            .max_stats_namespaces
+            .unwrap_or(DEFAULT_MAX_STATS_NAMESPACES);
+        let stats = Arc::new(Stats::new(max_namespaces));
+
+        let cache = Cache {
+            inner: Arc::new(Inner {
+                ns: None,
+                ns_key: Vec::new(),
+                db: self.db,
+                format: self.config.format(None),
+                sliding: self.config.sliding(None),
+                compression_threshold: self.config.compression_threshold(None),
+                policy: namespace::Policy::default(),
+                stats: stats.clone(),
+                counters: stats.counters(&[]),
+                config: Arc::new(self.config),
+                len: Default::default(),
+                epoch: Default::default(),
+                generations: Vec::new(),
+                generation_counters: Default::default(),
+                invalidations: Default::default(),
+                wakers: Default::default(),
+            }),
+        };
+        if let Some(bus) = &cache.inner.config.invalidation_bus {
+            *cache.inner.invalidations.lock() = Some(bus.subscribe_bumps()?);
+        }
+
+        let config = &cache.inner.config;
+
+        if !config.read_only {
+            cache.migrate_keys()?;
+        }
+
+        let epoch = cache.load_epoch()?;
+        cache.inner.epoch.store(epoch, Ordering::Release);
+
+        if !config.disable_cleanup_on_load && !config.read_only {
+            cache.cleanup()?;
+        }
+
+        Ok(cache)
+    }
+}
+
+/// Primary cache abstraction.
+///
+/// Can be cheaply cloned and namespaced.
+#[derive(Clone)]
+pub struct Cache {
+    inner: Arc<Inner>,
+}
+
+impl Cache {
+    /// Construct a builder for a cache stored in the given database.
+    pub fn builder(db: sled::Tree) -> Builder {
+        Builder {
+            db,
+            config: Config::default(),
+        }
+    }
+
+    /// Load the cache from the database.
+    pub fn load(db: sled::Tree) -> Result<Cache, Error> {
+        Self::builder(db).load()
+    }
+
+    /// Decode a key as it's stored in the database, such as the one returned
+    /// by [EntryRef::key], into its namespace and key.
+    ///
+    /// The namespace is returned as JSON like in [JsonEntry::key]: `null` for
+    /// the default namespace, or an array with one value for each level of a
+    /// nested namespace, outermost first. Keys of caches which hash their keys
+    /// can't be decoded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?.namespaced(&"users")?;
+    /// cache.insert((42u64, "name"), Duration::hours(1), &"Alice")?;
+    ///
+    /// if let Some(entry) = cache.get_ref((42u64, "name"))?.get() {
+    ///     let (ns, key) = Cache::decode_key::<(u64, String)>(entry.key())?;
+    ///     assert_eq!(serde_json::json!(["users"]), ns);
+    ///     assert_eq!((42, String::from("name")), key);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn decode_key<'de, K>(key: &'de [u8]) -> Result<(json::Value, K), Error>
+    where
+        K: Deserialize<'de>,
+    {
+        let (ns, key) = split_key(key)?;
+        Ok((namespace_json(ns)?, key::from_slice(key)?))
+    }
+
+    /// Delete the given key from the namespace of this handle.
+    pub fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.delete_key(&key)
+    }
+
+    /// Delete the given key from the specified namespace, which is nested
+    /// inside of the namespace of this handle.
+    ///
+    /// Without a namespace, this is the same as [Cache::delete].
+    pub fn delete_with_ns<N, K>(&self, ns: Option<&N>, key: &K) -> Result<(), Error>
+    where
+        N: Serialize,
+        K: CacheKey,
+    {
+        let ns = match ns {
+            Some(ns) => Some(hashkey::to_key(ns)?.normalize()),
+            None => None,
+        };
+
+        let key = self.key_with_ns(ns.as_ref(), key)?;
+        self.delete_key(&key)
+    }
+
+    /// Delete the entry stored under the given key.
+    fn delete_key(&self, key: &[u8]) -> Result<(), Error> {
+        let removed = self
+            .remove(key)
+            .map_err(|e| self.entry_error(Some(key), true, e))?;
+
+        if removed.is_some() {
+            self.record(AuditOp::Delete, key)
+                .map_err(|e| self.entry_error(Some(key), true, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete every entry in the namespace of this handle, returning the number
+    /// of entries removed.
+    ///
+    /// See [Cache::clear_namespace].
+    pub fn clear(&self) -> Result<usize, Error> {
+        let prefix = self.ns_prefix()?;
+        self.clear_prefix(&prefix)
+    }
+
+    /// Delete every entry in the specified namespace, which is nested inside of
+    /// the namespace of this handle, returning the number of entries removed.
+    /// Without a namespace, this is the same as [Cache::clear].
+    ///
+    /// All keys in a namespace share a common prefix, so this only visits the
+    /// entries in the namespace. Entries are removed in atomic batches,
+    /// together with their tags, dependencies, indexes and chunks.
+    /// Deduplicated values are released once the batch holding the last entry
+    /// referencing them has been applied.
+    pub fn clear_namespace<N>(&self, ns: Option<&N>) -> Result<usize, Error>
+    where
+        N: Serialize,
+    {
+        let ns = match ns {
+            Some(ns) => Some(hashkey::to_key(ns)?.normalize()),
+            None => None,
+        };
+
+        let prefix = self.ns_prefix_with(ns.as_ref())?;
+        self.clear_prefix(&prefix)
+    }
+
+    /// Delete every entry in the namespace of this handle whose key starts
+    /// with the given prefix, returning the number of entries removed. Entries
+    /// in nested namespaces are left alone.
+    ///
+    /// The prefix is a tuple or struct holding the leading fields of the keys
+    /// to remove. So `cache.delete_prefix((user_id,))` removes all entries with
+    /// keys like `(user_id, "profile")` or `(user_id, "posts", 2)`. Fields must
+    /// be of the same type as in the keys for them to match.
+    ///
+    /// Hashed keys don't share prefixes, so this fails with
+    /// [Error::HashedKeys] if keys are [hashed][Builder::hash_keys].
+    pub fn delete_prefix<P>(&self, prefix: P) -> Result<usize, Error>
+    where
+        P: Serialize,
+    {
+        if self.inner.config.hashes_keys() {
+            return Err(Error::HashedKeys);
+        }
+
+        let mut prefix_key = self.ns_prefix()?;
+        prefix_key.extend_from_slice(&key::to_vec(&prefix)?);
+        self.clear_prefix(&prefix_key)
+    }
+
+    /// Delete every entry whose key starts with the given prefix.
+    fn clear_prefix(&self, prefix: &[u8]) -> Result<usize, Error> {
+        self.remove_matching(prefix, |_| true)
+    }
+
+    /// Delete every entry whose key starts with the given prefix and matches
+    /// the given filter.
+    fn remove_matching<F>(&self, prefix: &[u8], mut filter: F) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8]) -> bool,
+    {
+        const BATCH_SIZE: usize = 1024;
+
+        let mut removed = 0;
+        let mut batch = sled::Batch::default();
+        let mut batched = Vec::new();
+        let mut blobs = Vec::new();
+
+        for result in self.inner.db.scan_prefix(prefix) {
+            let (key, value) = result?;
+
+            if !filter(&key) {
+                continue;
+            }
+
+            if let Ok(entry) = decode_meta(&value) {
+                for index_key in index_keys(&key, &entry) {
+                    batch.remove(index_key);
+                }
+
+                for chunk_key in chunk_keys(&key, &entry) {
+                    batch.remove(chunk_key);
+                }
+
+                blobs.extend(entry.blob);
+            }
+
+            batch.remove(&key);
+            batched.push(key);
+
+            if batched.len() == BATCH_SIZE {
+                self.inner.db.apply_batch(std::mem::take(&mut batch))?;
+                removed += batched.len();
+
+                // deletions are only recorded once they've happened.
+                for key in batched.drain(..) {
+                    self.record(AuditOp::Delete, &key)?;
+                }
+
+                for hash in blobs.drain(..) {
+                    self.release_blob(&hash)?;
+                }
+            }
+        }
+
+        self.inner.db.apply_batch(batch)?;
+        removed += batched.len();
+
+        for key in batched {
+            self.record(AuditOp::Delete, &key)?;
+        }
+
+        for hash in blobs {
+            self.release_blob(&hash)?;
+        }
+
+        self.sub_len(removed);
+        log::trace!("clear -> {}", removed);
+        self.maybe_flush(removed)?;
+        Ok(removed)
+    }
+
+    /// Count the number of entries in the namespace of this handle, not
+    /// including entries in nested namespaces.
+    ///
+    /// This is exact, but requires scanning every entry in the namespace. See
+    /// [Cache::approximate_len] for a cheap alternative which counts the
+    /// entire database.
+    pub fn len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+
+        for result in self.inner.db.scan_prefix(self.ns_prefix()?).keys() {
+            result?;
+            len += 1;
+        }
+
+        Ok(len)
+    }
+
+    /// Test if the namespace of this handle is empty, not including entries in
+    /// nested namespaces.
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        match self.inner.db.scan_prefix(self.ns_prefix()?).keys().next() {
+            Some(result) => result.map(|_| false).map_err(Error::from),
+            None => Ok(true),
+        }
+    }
+
+    /// Count the number of entries in the entire database, across all
+    /// namespaces.
+    ///
+    /// This is exact, but requires scanning the entire database.
+    pub fn total_len(&self) -> Result<usize, Error> {
+        let mut len = 0;
+
+        for result in self.entries().keys() {
+            result?;
+            len += 1;
+        }
+
+        Ok(len)
+    }
+
+    /// The approximate number of entries in the entire database, across all
+    /// namespaces.
+    ///
+    /// This is maintained in memory as entries are inserted and removed, and
+    /// is resynchronized with the database on every [Cache::cleanup] and
+    /// [Cache::verify_and_repair]. It can drift if entries are modified
+    /// concurrently with a cleanup, or if the database is modified outside of
+    /// the cache.
+    ///
+    /// A single count is kept for the database, so it's the same for every
+    /// namespaced handle. Use [Cache::len] to count a single namespace.
+    pub fn approximate_len(&self) -> usize {
+        self.inner.len.load(Ordering::Relaxed)
+    }
+
+    /// Decrement the approximate number of entries, saturating at zero since
+    /// the count might not have been synchronized with the database yet.
+    fn sub_len(&self, n: usize) {
+        let _ = self
+            .inner
+            .len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                Some(len.saturating_sub(n))
+            });
+    }
+
+    /// Report the statistics of every operation on the cache since it was
+    /// loaded.
+    ///
+    /// Counts of reads and mutations are the sums of the
+    /// [statistics of every namespace][Cache::namespace_stats].
+    pub fn stats(&self) -> CacheStats {
+        let stats = &self.inner.stats;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let mut out = CacheStats {
+            gets: load(&stats.gets),
+            wraps: load(&stats.wraps),
+            errors: load(&stats.errors),
+            get_latency: stats.get_latency.snapshot(),
+            insert_latency: stats.insert_latency.snapshot(),
+            loader_latency: stats.loader_latency.snapshot(),
+            ..CacheStats::default()
+        };
+
+        let namespaces = stats.namespaces.read();
+
+        for counters in namespaces.values().chain(stats.overflow.get()) {
+            out.hits += load(&counters.hits);
+            out.stale_hits += load(&counters.stale_hits);
+            out.misses += load(&counters.misses);
+            out.inserts += load(&counters.inserts);
+            out.deletes += load(&counters.deletes);
+            out.loader_errors += load(&counters.loader_errors);
+            out.slow_loads += load(&counters.slow_loads);
+            out.slow_storage += load(&counters.slow_storage);
+        }
+
+        out
+    }
+
+    /// Report how much of the time spent reading and writing entries since the
+    /// cache was loaded was spent in the storage engine.
+    ///
+    /// Reads through [Cache::get], [Cache::get_many], [Cache::get_path],
+    /// [Cache::get_ref] and [Cache::wrap] are counted, and writes of every
+    /// kind of insert.
+    pub fn storage_stats(&self) -> StorageStats {
+        let stats = &self.inner.stats;
+
+        StorageStats {
+            read_latency: stats.storage_read_latency.snapshot(),
+            decode_latency: stats.decode_latency.snapshot(),
+            write_latency: stats.storage_write_latency.snapshot(),
+        }
+    }
+
+    /// Probe the health of the cache by writing, reading back and deleting an
+    /// internal record, for use in readiness checks.
+    ///
+    /// The record is flushed to disk once it's written, so that a disk which
+    /// is full or read-only fails the probe. This blocks, so async callers
+    /// should run it on a blocking thread. The probe doesn't touch any
+    /// entries, and failures are reported in the [Health] rather than as an
+    /// error.
+    pub fn health(&self) -> Health {
+        let stats = self.stats();
+
+        let mut health = Health {
+            error: None,
+            write_latency: None,
+            flush_latency: None,
+            read_latency: None,
+            delete_latency: None,
+            last_cleanup: *self.inner.stats.last_cleanup.lock(),
+            errors: stats.errors,
+            loader_errors: stats.loader_errors,
+        };
+
+        if let Err(e) = self.probe(&mut health) {
+            log::warn!("health probe failed: {}", e);
+            health.error = Some(e.to_string());
+        }
+
+        health
+    }
+
+    /// Write, flush, read back and delete the health probe, recording how
+    /// long each step took.
+    fn probe(&self, health: &mut Health) -> Result<(), Error> {
+        static NEXT_PROBE: AtomicU64 = AtomicU64::new(0);
+
+        // concurrent probes use their own keys, so that they don't remove
+        // each other's records.
+        let probe = NEXT_PROBE.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let mut key = meta_key(HEALTH_KEY);
+        key.extend_from_slice(&probe);
+
+        let started = Instant::now();
+        self.inner.db.insert(&key, &probe[..])?;
+        health.write_latency = Some(started.elapsed());
+
+        // writes only reach sled's page cache, so flush to find out whether
+        // they can be persisted.
+        let started = Instant::now();
+        self.inner.db.flush()?;
+        health.flush_latency = Some(started.elapsed());
+
+        let started = Instant::now();
+        let read = self.inner.db.get(&key)?;
+        health.read_latency = Some(started.elapsed());
+
+        let started = Instant::now();
+        self.inner.db.remove(&key)?;
+        health.delete_latency = Some(started.elapsed());
+
+        if read.as_deref() != Some(&probe[..]) {
+            return Err(Error::Corrupt);
+        }
+
+        Ok(())
+    }
+
+    /// Report the statistics of every namespace which a handle has been
+    /// created for or which has been modified since the cache was loaded, in
+    /// the order of their encoded keys.
+    ///
+    /// Reads are counted for the namespace of the handle they're made
+    /// through, and mutations for the namespace of the entry they affect.
+    /// Reads through [Cache::get], [Cache::get_many], [Cache::get_path],
+    /// [Cache::get_ref] and [Cache::wrap] are counted.
+    ///
+    /// At most [Builder::max_stats_namespaces] namespaces are reported
+    /// separately, followed by the combined statistics of the rest if there
+    /// were more.
+    pub fn namespace_stats(&self) -> Result<Vec<NamespaceStats>, Error> {
+        let namespaces = self.inner.stats.namespaces.read();
+        let mut out = Vec::with_capacity(namespaces.len());
+
+        for (ns, counters) in namespaces.iter() {
+            let namespace = match ns.as_slice() {
+                [] => None,
+                ns => Some(namespace_json(ns)?),
+            };
+
+            out.push(counters.snapshot(namespace));
+        }
+
+        if let Some(overflow) = self.inner.stats.overflow.get() {
+            out.push(NamespaceStats {
+                overflow: true,
+                ..overflow.snapshot(None)
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Report the in-memory state held by this cache handle.
+    ///
+    /// This only covers the wakers of the handle, see [MemoryUsage]. The page
+    /// cache used by sled is bounded separately through
+    /// [sled::Config::cache_capacity] and is not included, since sled doesn't
+    /// report how much of it is used.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let wakers = self.inner.wakers.read();
+        let mut usage = MemoryUsage {
+            wakers: wakers.len(),
+            ..MemoryUsage::default()
+        };
+
+        for (key, waker) in wakers.iter() {
+            let channels = waker.channels.len();
+            usage.pending += waker.pending.load(Ordering::Relaxed);
+            usage.bytes += key.capacity()
+                + std::mem::size_of::<Waker>()
+                + channels * std::mem::size_of::<oneshot::Sender<bool>>();
+        }
+
+        usage
+    }
+
+    /// The current invalidation epoch.
+    ///
+    /// Entries stored in an older epoch are treated as expired. See
+    /// [Cache::bump_epoch].
+    pub fn epoch(&self) -> u64 {
+        self.apply_invalidations();
+        self.inner.epoch.load(Ordering::Acquire)
+    }
+
+    /// Subscribe to the invalidations published on the bus configured with
+    /// [Builder::invalidation_bus], including the ones published by this
+    /// cache.
+    ///
+    /// Fails with [Error::NoInvalidationBus] unless a bus is configured.
+    pub fn invalidations(&self) -> Result<invalidation::Subscriber, Error> {
+        match &self.inner.config.invalidation_bus {
+            Some(bus) => bus.subscribe(),
+            None => Err(Error::NoInvalidationBus),
+        }
+    }
+
+    /// Apply the epochs and generations bumped by other caches sharing the
+    /// invalidation bus.
+    fn apply_invalidations(&self) {
+        // another handle is already applying them.
+        let mut subscriber = match self.inner.invalidations.try_lock() {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+
+        let subscriber = match &mut *subscriber {
+            Some(subscriber) => subscriber,
+            None => return,
+        };
+
+        while let Some(message) = subscriber.try_next() {
+            match message {
+                invalidation::Invalidation::Epoch(epoch) => {
+                    self.inner.epoch.fetch_max(epoch, Ordering::AcqRel);
+                }
+                invalidation::Invalidation::Generation { ns, generation } => {
+                    if let Some(current) = self.inner.generation_counters.lock().get(&ns[..]) {
+                        current.fetch_max(generation, Ordering::AcqRel);
+                    }
+                }
+                invalidation::Invalidation::Entry { .. } => (),
+            }
+        }
+    }
+
+    /// Publish an invalidation on the configured bus, if any.
+    fn publish(&self, message: invalidation::Invalidation) {
+        if let Some(bus) = &self.inner.config.invalidation_bus {
+            // caches which only write still take what they've received.
+            self.apply_invalidations();
+
+            if let Err(e) = bus.publish(&message) {
+                log::warn!("failed to publish invalidation: {}", e);
+            }
+        }
+    }
+
+    /// Invalidate every entry in the database by bumping the invalidation
+    /// epoch, returning the new epoch.
+    ///
+    /// This takes constant time. Entries stored in an older epoch are treated
+    /// as expired and are removed by the next [Cache::cleanup] unless they are
+    /// pinned.
+    ///
+    /// The epoch is persisted in the database, but it is only mirrored to
+    /// handles which share the same loaded cache. Caches loaded separately
+    /// from the same database observe the new epoch once they are reloaded,
+    /// or once it's received through a shared
+    /// [invalidation bus][Builder::invalidation_bus].
+    pub fn bump_epoch(&self) -> Result<u64, Error> {
+        let key = meta_key(EPOCH_KEY);
+
+        let previous = self.inner.db.fetch_and_update(&key, |value| {
+            let epoch = value.map(decode_counter).unwrap_or_default();
+            Some((epoch + 1).to_be_bytes().to_vec())
+        })?;
+
+        let epoch = previous.as_deref().map(decode_counter).unwrap_or_default() + 1;
+        self.inner.epoch.fetch_max(epoch, Ordering::AcqRel);
+        log::trace!("epoch -> {}", epoch);
+        self.publish(invalidation::Invalidation::Epoch(epoch));
+        Ok(epoch)
+    }
+
+    /// Load the invalidation epoch from the database.
+    fn load_epoch(&self) -> Result<u64, Error> {
+        let value = self.inner.db.get(meta_key(EPOCH_KEY))?;
+        Ok(value.as_deref().map(decode_counter).unwrap_or_default())
+    }
+
+    /// Iterate over all entries in the database, skipping internal metadata.
+    fn entries(&self) -> sled::Iter {
+        self.inner.db.range([META_PREFIX + 1]..)
+    }
+
+    /// Get the first entry in the database, skipping internal metadata.
+    fn first_entry(&self) -> Result<Option<(sled::IVec, sled::IVec)>, Error> {
+        Ok(self.entries().next().transpose()?)
+    }
+
+    /// Remove the given key, keeping track of the number of entries.
+    fn remove(&self, key: &[u8]) -> Result<Option<sled::IVec>, Error> {
+        let removed = self.inner.db.remove(key)?;
+
+        if let Some(removed) = &removed {
+            self.remove_removed(key, removed)?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Clean up after the entry with the given key has been removed, removing
+    /// its indexes and its value if it's stored separately.
+    fn remove_removed(&self, key: &[u8], removed: &[u8]) -> Result<(), Error> {
+        self.sub_len(1);
+
+        if let Ok(entry) = decode_meta(removed) {
+            self.remove_indexes(key, &entry)?;
+            self.remove_value(key, &entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load an entry and delete it in one atomic operation.
+    ///
+    /// Only one caller can take an entry, so concurrent calls never both
+    /// receive its value. Expired entries are removed and reported as
+    /// [State::Expired], while tombstones left by [Cache::soft_delete] are
+    /// left in place and reported as [State::Missing].
+    pub fn take<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + de::DeserializeOwned,
+    {
+        let key = self.key(&key)?;
+
+        let removed = loop {
+            let value = match self.inner.db.get(&key)? {
+                Some(value) => value,
+                None => {
+                    log::trace!("take:{} -> null (missing)", self.key_format(&key));
+                    return Ok(State::Missing);
+                }
+            };
+
+            if decode_meta(&value).is_ok_and(|meta| meta.deleted_at.is_some()) {
+                log::trace!("take:{} -> null (deleted)", self.key_format(&key));
+                return Ok(State::Missing);
+            }
+
+            let result =
+                self.inner
+                    .db
+                    .compare_and_swap(&key, Some(&value), None as Option<&[u8]>)?;
+
+            if result.is_ok() {
+                break value;
+            }
+        };
+
+        // the value has to be read before the chunks or the deduplicated value
+        // of the entry are removed.
+        let loaded = self.load_payload(&key, &removed).and_then(|loaded| {
+            loaded
+                .map(|(meta, payload)| decode_payload(&self.inner.config, meta, payload))
+                .transpose()
+        });
+
+        self.remove_removed(&key, &removed)?;
+        self.record(AuditOp::Delete, &key)?;
+
+        let state = match loaded {
+            Ok(Some(stored)) if stored.is_expired(self.now(), self.epoch()) => {
+                log::trace!("take:{} -> null (expired)", self.key_format(&key));
+                State::Expired(stored)
+            }
+            Ok(Some(stored)) => {
+                log::trace!("take:{} -> *value*", self.key_format(&key));
+                State::Fresh(stored)
+            }
+            Ok(None) => State::Missing,
+            Err(Error::Corrupt) => {
+                log::warn!("{}: entry is corrupt", self.key_format(&key));
+                State::Corrupt
+            }
+            Err(e) => {
+                log::warn!("{}: failed to deserialize: {}", self.key_format(&key), e);
+                State::Missing
+            }
+        };
+
+        Ok(state)
+    }
+
+    /// List all cache entries as JSON.
+    ///
+    /// This holds every entry in memory, see [Cache::list_json_page] to list
+    /// large caches page by page.
+    pub fn list_json(&self) -> Result<Vec<JsonEntry>, Error> {
+        let mut out = Vec::new();
+
+        for result in self.entries() {
+            let (key, value) = result.map_err(|e| self.entry_error(None, false, e.into()))?;
+
+            if let Some(entry) = self.json_entry(&key, &value) {
+                out.push(entry);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// List a page of at most `limit` cache entries as JSON, starting after
+    /// the given cursor or at the first entry.
+    ///
+    /// Unlike [Cache::list_json], this only holds one page of entries in
+    /// memory at a time, so large caches can be listed page by page.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?;
+    ///
+    /// for n in 0..5u32 {
+    ///     cache.insert(n, Duration::hours(1), &n)?;
+    /// }
+    ///
+    /// let mut cursor = None;
+    /// let mut count = 0;
+    ///
+    /// loop {
+    ///     let page = cache.list_json_page(cursor.as_ref(), 2)?;
+    ///     count += page.entries.len();
+    ///
+    ///     match page.next {
+    ///         Some(next) => cursor = Some(next.to_string().parse()?),
+    ///         None => break,
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(5, count);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list_json_page(&self, cursor: Option<&Cursor>, limit: usize) -> Result<JsonPage, Error> {
+        self.list_json_page_with(cursor, limit, ListFilter::new())
+    }
+
+    /// List a page of cache entries as JSON like [Cache::list_json_page], only
+    /// including entries which match the given filter.
+    ///
+    /// Pages are filled with entries which match, so a page might scan many
+    /// entries which don't to fill it. Filtering by namespace or key prefix
+    /// only scans the entries with a matching key. At most 10000 entries are
+    /// scanned for a page, after which it's returned with the entries found so
+    /// far and a cursor to resume from, see [JsonPage::next].
+    ///
+    /// Fails with [Error::InvalidLimit] if `limit` is zero.
+    pub fn list_json_page_with(
+        &self,
+        cursor: Option<&Cursor>,
+        limit: usize,
+        filter: ListFilter,
+    ) -> Result<JsonPage, Error> {
+        if limit == 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let key_prefix = filter.key_prefix.transpose()?;
+
+        if key_prefix.is_some() && self.inner.config.hashes_keys() {
+            return Err(Error::HashedKeys);
+        }
+
+        let (prefix, end) = match filter.ns.transpose()? {
+            Some(ns) => {
+                let mut prefix = self.ns_prefix_with(ns.as_ref())?;
+                prefix.extend(key_prefix.as_deref().unwrap_or_default());
+                let end = prefix_end(&prefix).map_or(Bound::Unbounded, Bound::Excluded);
+                (prefix, end)
+            }
+            None => (vec![META_PREFIX + 1], Bound::Unbounded),
+        };
+
+        let start = match cursor {
+            Some(Cursor(cursor)) if *cursor >= prefix => Bound::Excluded(cursor.clone()),
+            _ => Bound::Included(prefix),
+        };
+
+        let now = self.now();
+        let epoch = self.epoch();
+        let mut entries = Vec::new();
+        let mut last = None;
+        let mut complete = true;
+
+        for (scanned, result) in self.inner.db.range::<Vec<u8>, _>((start, end)).enumerate() {
+            if entries.len() >= limit || scanned >= MAX_PAGE_SCAN {
+                complete = false;
+                break;
+            }
+
+            let (key, value) = result.map_err(|e| self.entry_error(None, false, e.into()))?;
+            last = Some(key.clone());
+
+            if let Some(key_prefix) = &key_prefix {
+                match split_key(&key) {
+                    Ok((_, key)) if key.starts_with(key_prefix) => (),
+                    _ => continue,
+                }
+            }
+
+            if let Some(expiry) = filter.expiry {
+                let meta = match decode_meta(&value) {
+                    Ok(meta) => meta,
+                    Err(_) => continue,
+                };
+
+                let expired = meta.is_expired(now, epoch);
+
+                match expiry {
+                    _ if meta.deleted_at.is_some() => continue,
+                    ExpiryFilter::Fresh if expired => continue,
+                    ExpiryFilter::Expired if !expired => continue,
+                    _ => (),
+                }
+            }
+
+            if let Some(entry) = self.json_entry(&key, &value) {
+                entries.push(entry);
+            }
+        }
+
+        let next = match last {
+            Some(last) if !complete => Some(Cursor(last.to_vec())),
+            _ => None,
+        };
+
+        Ok(JsonPage { entries, next })
+    }
+
+    /// Write a portable dump of the entries in the cache to `writer`,
+    /// reporting what was written.
+    ///
+    /// Fresh and pinned entries are exported along with their dependencies,
+    /// while deleted entries, lists, and expired entries aren't. Entries which
+    /// can't be exported, like entries with hashed keys whose original key
+    /// can't be recovered (see [Cache::keys]) or values which can't be
+    /// converted to the format of the dump, are logged and reported in
+    /// [dump::ExportReport::failed] instead of failing the export.
+    ///
+    /// See [dump] for an example and the format of the dump.
+    pub fn export<W>(
+        &self,
+        writer: W,
+        options: dump::ExportOptions,
+    ) -> Result<dump::ExportReport, Error>
+    where
+        W: io::Write,
+    {
+        dump::export(self, writer, options)
+    }
+
+    /// Load a dump written by [Cache::export] into the cache, reporting what
+    /// was imported.
+    ///
+    /// The format of the dump is detected automatically, and dumps written
+    /// with another version of the dump format are rejected with an error
+    /// whose [root][Error::root] is [Error::UnsupportedDumpVersion]. Entries
+    /// are imported into the
+    /// namespace they were exported from regardless of the namespace of this
+    /// cache, and their values are stored with the format of this cache.
+    /// Entries which expired since they were exported are skipped unless they
+    /// are pinned, and entries which are already in the cache are handled
+    /// according to `policy`. Records which can't be imported are logged and
+    /// counted instead of failing the import.
+    ///
+    /// See [dump] for an example.
+    pub fn import<R>(
+        &self,
+        reader: R,
+        policy: dump::ConflictPolicy,
+    ) -> Result<dump::ImportReport, Error>
+    where
+        R: io::Read,
+    {
+        dump::import(self, reader, policy).map_err(|e| self.entry_error(None, false, e))
+    }
+
+    /// Construct a query over the entries of the cache as JSON.
+    ///
+    /// See [query] for an example.
+    pub fn query(&self) -> query::Query<'_> {
+        query::Query::new(self)
+    }
+
+    /// Construct a handle to this cache with keys of type `K` and values of
+    /// type `V`.
+    ///
+    /// See [typed] for an example.
+    pub fn typed<K, V>(&self) -> typed::TypedCache<K, V>
+    where
+        K: CacheKey,
+        V: 'static + Serialize + de::DeserializeOwned,
+    {
+        typed::TypedCache::new(self.clone())
+    }
+
+    /// Stream all cache entries as JSON, like [Cache::list_json].
+    ///
+    /// The database is scanned in batches as the stream is polled, on the
+    /// pool of threads which [Cache::get_stream] also reads on, so iterating
+    /// over a large cache never blocks the executor or holds all entries in
+    /// memory. Errors from scanning the database are yielded as they're
+    /// encountered.
+    pub fn stream_entries(&self) -> EntryStream {
+        EntryStream {
+            cache: self.clone(),
+            iter: Some(self.entries()),
+            pending: None,
+            buf: VecDeque::new(),
+            done: false,
+            prefetch: false,
+        }
+    }
+
+    /// Stream all cache entries as JSON, like [Cache::list_json].
+    ///
+    /// Like [Cache::stream_entries], the database is scanned in batches on the
+    /// pool of threads which [Cache::get_stream] also reads on, but the next
+    /// batch is scanned while the previous one is being consumed. So at most
+    /// two batches are held in memory however large the cache is. If a scan
+    /// panics, the stream yields [Error::Panicked] and ends.
+    ///
+    /// ```rust
+    /// use futures::stream::TryStreamExt as _;
+    /// use futures_cache::{Cache, Duration};
+    ///
+    /// # #[tokio::main] async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?;
+    /// cache.insert("a", Duration::hours(1), &1u32)?;
+    ///
+    /// let mut stream = cache.list_json_stream();
+    ///
+    /// while let Some(entry) = stream.try_next().await? {
+    ///     println!("{}", entry.key);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list_json_stream(&self) -> JsonStream {
+        JsonStream {
+            entries: EntryStream {
+                prefetch: true,
+                ..self.stream_entries()
+            },
+        }
+    }
+
+    /// Decode an entry as JSON, returning `None` if it can't be decoded.
+    fn json_entry(&self, key: &[u8], value: &[u8]) -> Option<JsonEntry> {
+        let mut stored: StoredEntry<json::Value> = match self.decode_entry(key, value) {
+            Ok(Some(storage)) => storage,
+            // something weird stored in there, or it was just modified.
+            _ => return None,
+        };
+
+        let original_key = stored.original_key.take();
+
+        let key = match self.json_key(key, original_key.as_ref().map(|key| key.as_slice())) {
+            Ok(key) => key,
+            // key is malformed.
+            Err(_) => return None,
+        };
+
+        Some(JsonEntry { key, stored })
+    }
+
+    /// Decode the key an entry is stored under as a JSON array of its
+    /// namespace and its key, recovering the original key of hashed keys if
+    /// possible.
+    fn json_key(&self, key: &[u8], original_key: Option<&[u8]>) -> Result<json::Value, Error> {
+        let storage_key = key;
+        let (ns, key) = decode_key_json(key)?;
+
+        let key = match original_key {
+            Some(original_key) => self
+                .decode_original_key(storage_key, Cow::Borrowed(original_key))
+                .and_then(|key| Ok(key::to_json(&key)?))
+                .unwrap_or(key),
+            None => key,
+        };
+
+        Ok(json::Value::Array(vec![ns, key]))
+    }
+
+    /// List the entries in the specified namespace with their values
+    /// deserialized as `T`.
+    ///
+    /// Expired entries which haven't been cleaned up yet are included, while
+    /// deleted entries and lists aren't. Entries in namespaces nested inside
+    /// of the namespace aren't included either. Entries which can't be
+    /// deserialized as `T` are skipped and reported in [Listing::failed]
+    /// instead of failing the whole listing.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?;
+    /// let users = cache.namespaced(&"users")?;
+    /// users.insert(1u32, Duration::hours(1), &"Alice")?;
+    /// users.insert(2u32, Duration::hours(1), &42u32)?;
+    ///
+    /// let listing = cache.list::<_, String>(Some(&"users"))?;
+    /// assert_eq!(1, listing.entries.len());
+    /// assert_eq!("Alice", listing.entries[0].stored.value());
+    /// assert_eq!(1, listing.failed.len());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn list<N, T>(&self, ns: Option<&N>) -> Result<Listing<T>, Error>
+    where
+        N: Serialize,
+        T: 'static + de::DeserializeOwned,
+    {
+        let ns = match ns {
+            Some(ns) => Some(hashkey::to_key(ns)?.normalize()),
+            None => None,
+        };
+
+        let prefix = self.ns_prefix_with(ns.as_ref())?;
+
+        let mut listing = Listing {
+            entries: Vec::new(),
+            failed: Vec::new(),
+        };
+
+        for result in self.inner.db.scan_prefix(prefix) {
+            let (key, value) = result.map_err(|e| self.entry_error(None, false, e.into()))?;
+
+            let original_key = match decode_meta(&value) {
+                Ok(meta) if meta.deleted_at.is_some() || meta.list.is_some() => continue,
+                Ok(meta) => meta.original_key,
+                Err(_) => None,
+            };
+
+            let result = self.decode_entry::<T>(&key, &value).and_then(|stored| {
+                Ok((
+                    self.json_key(&key, original_key.as_ref().map(|key| key.as_slice()))?,
+                    stored,
+                ))
+            });
+
+            match result {
+                Ok((key, Some(mut stored))) => {
+                    stored.original_key = None;
+                    listing.entries.push(ListedEntry { key, stored });
+                }
+                // entry was modified while it was being read.
+                Ok((_, None)) => continue,
+                Err(error) => {
+                    log::warn!(
+                        "{}: failed to deserialize: {}",
+                        self.key_format(&key),
+                        error
+                    );
+                    let error = self.entry_error(Some(&key), false, error);
+                    let key = self
+                        .json_key(&key, original_key.as_ref().map(|key| key.as_slice()))
+                        .ok();
+                    listing.failed.push(ListFailure { key, error });
+                }
+            }
+        }
+
+        Ok(listing)
+    }
+
+    /// List the keys of the entries in the namespace of this cache, without
+    /// loading their values.
+    ///
+    /// Expired entries which haven't been cleaned up yet are included, while
+    /// deleted entries aren't. Keys which can't be deserialized as `K` are
+    /// skipped, so keys of any type can be listed as a [serde_json::Value].
+    /// Hashed keys can only be listed if an
+    /// [encryption key][Builder::encryption_key] is configured.
+    pub fn keys<K>(&self) -> Result<Vec<K>, Error>
+    where
+        K: de::DeserializeOwned,
+    {
+        let prefix = self.ns_prefix()?;
+        self.keys_with_prefix(&prefix)
+    }
+
+    /// List the keys of the entries in the specified namespace, without
+    /// loading their values.
+    ///
+    /// See [Cache::keys] for which keys are listed.
+    pub fn keys_in_ns<N, K>(&self, ns: Option<&N>) -> Result<Vec<K>, Error>
+    where
+        N: Serialize,
+        K: de::DeserializeOwned,
+    {
+        let ns = match ns {
+            Some(ns) => Some(hashkey::to_key(ns)?.normalize()),
+            None => None,
+        };
+
+        let prefix = self.ns_prefix_with(ns.as_ref())?;
+        self.keys_with_prefix(&prefix)
+    }
+
+    /// List the keys of the entries stored under the given prefix.
+    fn keys_with_prefix<K>(&self, prefix: &[u8]) -> Result<Vec<K>, Error>
+    where
+        K: de::DeserializeOwned,
+    {
+        let mut out = Vec::new();
+
+        for result in self.inner.db.scan_prefix(prefix) {
+            let (key, value) = result?;
+
+            let meta = match decode_meta(&value) {
+                Ok(meta) => meta,
+                // something weird stored in there.
+                Err(_) => continue,
+            };
+
+            if meta.deleted_at.is_some() {
+                continue;
+            }
+
+            if let Ok(Some(key)) = self.decode_plain_key(&key, &meta) {
+                out.push(key);
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Decode the key an entry is stored under, recovering the original key
+    /// of hashed keys.
+    ///
+    /// Returns `None` if the key is hashed and its original key can't be
+    /// recovered.
+    fn decode_plain_key<K>(&self, key: &[u8], meta: &PartialStoredEntry) -> Result<Option<K>, Error>
+    where
+        K: de::DeserializeOwned,
+    {
+        match (&meta.original_key, self.inner.config.hashes_keys()) {
+            (Some(original_key), _) => {
+                let original_key = Cow::Borrowed(original_key.as_slice());
+                let key = self.decode_original_key(key, original_key)?;
+                Ok(Some(key::from_slice(&key)?))
+            }
+            (None, true) => Ok(None),
+            (None, false) => {
+                let (_, key) = split_key(key)?;
+                Ok(Some(key::from_slice(key)?))
+            }
+        }
+    }
+
+    /// Iterate over the fresh entries in the namespace of this cache, with
+    /// their keys and values deserialized as `K` and `T`.
+    ///
+    /// This is meant for namespaces which only hold entries of a known type,
+    /// so keys or values which can't be deserialized are reported as errors
+    /// instead of being skipped. Expired entries and tombstones are skipped,
+    /// see [Cache::iter_expired] for the former. Entries with hashed keys are
+    /// skipped unless an [encryption key][Builder::encryption_key] is
+    /// configured, since their original key can't be recovered.
+    pub fn iter<K, T>(&self) -> Result<Iter<K, T>, Error>
+    where
+        K: de::DeserializeOwned,
+        T: de::DeserializeOwned,
+    {
+        Ok(Iter {
+            cache: self.clone(),
+            iter: self.inner.db.scan_prefix(self.ns_prefix()?),
+            now: self.now(),
+            epoch: self.epoch(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Iterate over the fresh entries in the namespace of this cache whose
+    /// keys are within the given range, in the order of their keys.
+    ///
+    /// Keys are encoded so that integers, including the fields of tuple keys,
+    /// sort by their value, so entries keyed by `(stream_id, sequence)` can be
+    /// queried with a range like `(id, 0)..(id + 1, 0)`. The iterator can be
+    /// reversed to get the latest entries first. Strings sort by their length
+    /// before their contents. See [Cache::iter] for which entries are
+    /// included.
+    ///
+    /// Hashed keys don't preserve any order, so this fails with
+    /// [Error::HashedKeys] if keys are [hashed][Builder::hash_keys].
+    pub fn scan<K, T, R>(&self, range: R) -> Result<Iter<K, T>, Error>
+    where
+        K: Serialize + de::DeserializeOwned,
+        T: de::DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        if self.inner.config.hashes_keys() {
+            return Err(Error::HashedKeys);
+        }
+
+        let prefix = self.ns_prefix()?;
+        let bound = |key: &K| -> Result<Vec<u8>, Error> {
+            let mut bound = prefix.clone();
+            bound.extend_from_slice(&key::to_vec(key)?);
+            Ok(bound)
+        };
+
+        let start = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(bound(key)?),
+            Bound::Excluded(key) => Bound::Excluded(bound(key)?),
+            Bound::Unbounded => Bound::Included(prefix.clone()),
+        };
+
+        let end = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(bound(key)?),
+            Bound::Excluded(key) => Bound::Excluded(bound(key)?),
+            Bound::Unbounded => match prefix_end(&prefix) {
+                Some(end) => Bound::Excluded(end),
+                None => Bound::Unbounded,
+            },
+        };
+
+        Ok(Iter {
+            cache: self.clone(),
+            iter: self.inner.db.range::<Vec<u8>, _>((start, end)),
+            now: self.now(),
+            epoch: self.epoch(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// List the distinct namespaces in the database together with the number
+    /// of entries stored in each, in the order of their encoded keys.
+    ///
+    /// This scans the keys of the entire database, regardless of the namespace
+    /// of this cache, but doesn't decode any values. See
+    /// [Cache::namespace_usage] to also report how much space they use.
+    pub fn namespaces(&self) -> Result<Vec<NamespaceCount>, Error> {
+        let mut out = Vec::new();
+        let mut current: Option<(Vec<u8>, usize)> = None;
+
+        for result in self.entries().keys() {
+            let key = result?;
+
+            let ns = match split_key(&key) {
+                Ok((ns, _)) => ns,
+                // key is malformed.
+                Err(_) => continue,
+            };
+
+            // all keys in a namespace share a prefix, so they're adjacent.
+            match &mut current {
+                Some((last, entries)) if last.as_slice() == ns => *entries += 1,
+                _ => {
+                    out.extend(current.replace((ns.to_vec(), 1)));
+                }
+            }
+        }
+
+        out.extend(current);
+
+        out.into_iter()
+            .map(|(ns, entries)| {
+                let namespace = match ns.as_slice() {
+                    [] => None,
+                    ns => Some(namespace_json(ns)?),
+                };
+
+                Ok(NamespaceCount { namespace, entries })
+            })
+            .collect()
+    }
+
+    /// Report the number of entries and approximate number of bytes used by
+    /// each namespace in the database.
+    ///
+    /// This scans the entire database, regardless of the namespace of this
+    /// cache.
+    pub fn namespace_usage(&self) -> Result<Vec<NamespaceUsage>, Error> {
+        let mut usage = BTreeMap::<Vec<u8>, (usize, u64)>::new();
+
+        for result in self.entries() {
+            let (key, value) = result?;
+
+            let (ns, _) = match split_key(&key) {
+                Ok(key) => key,
+                // key is malformed.
+                Err(_) => continue,
+            };
+
+            let (entries, bytes) = usage.entry(ns.to_vec()).or_default();
+            *entries += 1;
+            *bytes += (key.len() + value.len()) as u64;
+        }
+
+        let mut out = Vec::with_capacity(usage.len());
+
+        for (ns, (entries, bytes)) in usage {
+            let namespace = match ns.as_slice() {
+                [] => None,
+                ns => Some(namespace_json(ns)?),
+            };
+
+            out.push(NamespaceUsage {
+                namespace,
+                entries,
+                bytes,
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Clean up stale entries, returning the number of entries removed.
+    ///
+    /// This could be called periodically if you want to reclaim space.
+    pub fn cleanup(&self) -> Result<usize, Error> {
+        let now = self.now();
+        let epoch = self.epoch();
+        let tombstone_retention = self.inner.config.tombstone_retention();
+        let generations = self.persisted_generations()?;
+        let mut removed = 0;
+        let mut remaining = 0usize;
+
+        for result in self.entries() {
+            let (key, value) = result?;
+            remaining += 1;
+
+            let entry = match decode_meta(&value) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if log::log_enabled!(log::Level::Trace) {
+                        log::warn!(
                             "{}: failed to load: {}: {}",
-                            KeyFormat(&*key),
+                            self.key_format(&key),
+                            e,
+                            self.key_format(&value)
+                        );
+                    } else {
+                        log::warn!("{}: failed to load: {}", self.key_format(&key), e);
+                    }
+
+                    // delete key since it's invalid, unless it was replaced
+                    // since it was read.
+                    let result = self.inner.db.compare_and_swap(
+                        &key,
+                        Some(&value),
+                        None as Option<&[u8]>,
+                    )?;
+
+                    if result.is_ok() {
+                        removed += 1;
+                    }
+
+                    continue;
+                }
+            };
+
+            if entry.is_stale(now, epoch, tombstone_retention)
+                || is_old_generation(&key, &generations)
+            {
+                // Only remove the entry if it wasn't replaced since it was
+                // read, since a replaced entry has already been cleaned up
+                // after.
+                let result =
+                    self.inner
+                        .db
+                        .compare_and_swap(&key, Some(&value), None as Option<&[u8]>)?;
+
+                if result.is_err() {
+                    continue;
+                }
+
+                self.remove_indexes(&key, &entry)?;
+                self.remove_value(&key, &entry)?;
+                removed += 1;
+
+                // removing tombstones was recorded when they were deleted.
+                if entry.deleted_at.is_none() {
+                    self.record(AuditOp::Expire, &key)?;
+                    self.hook(
+                        &self.inner.config.hooks.expire,
+                        &key,
+                        Some(entry.hook_meta()),
+                    );
+                }
+            }
+        }
+
+        let evicted = self.evict_over_quota()?;
+        removed += evicted;
+
+        self.remove_orphaned_chunks()?;
+        self.remove_orphaned_history()?;
+        self.inner
+            .len
+            .store(remaining.saturating_sub(removed), Ordering::Relaxed);
+        self.maybe_flush(removed)?;
+        *self.inner.stats.last_cleanup.lock() = Some(now);
+        Ok(removed)
+    }
+
+    /// Iterate over entries in the namespace of this handle which have expired
+    /// but have not yet been removed by a cleanup. Entries in nested
+    /// namespaces aren't included.
+    ///
+    /// Each item contains the key of the entry as JSON and the expired entry.
+    /// Use [serde_json::Value] as `T` to inspect entries without knowing their
+    /// type. Tombstones and entries which can't be decoded as `T` are skipped,
+    /// while pinned entries are included.
+    pub fn iter_expired<T>(&self) -> Result<IterExpired<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        Ok(IterExpired {
+            cache: self.clone(),
+            iter: self.inner.db.scan_prefix(self.ns_prefix()?),
+            now: self.now(),
+            epoch: self.epoch(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Remove all expired entries in the namespace of this handle, yielding
+    /// them as they are removed. Entries in nested namespaces are left alone.
+    ///
+    /// This permits acting on values as they age out, like archiving them,
+    /// instead of silently losing them during [Cache::cleanup]. Each item
+    /// contains the key of the entry as JSON and the removed entry.
+    ///
+    /// Entries are only removed as the iterator is advanced. Pinned entries,
+    /// tombstones, and entries which can't be decoded as `T` are skipped and
+    /// left to cleanup.
+    pub fn drain_expired<T>(&self) -> Result<DrainExpired<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        Ok(DrainExpired {
+            cache: self.clone(),
+            iter: self.inner.db.scan_prefix(self.ns_prefix()?),
+            now: self.now(),
+            epoch: self.epoch(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Scan the database and check the integrity of every entry and internal
+    /// record, without modifying anything.
+    ///
+    /// See the [verify] module.
+    pub fn verify(&self) -> Result<verify::Report, Error> {
+        verify::verify(self, false)
+    }
+
+    /// Scan the database like [Cache::verify], and repair the problems which
+    /// are found.
+    ///
+    /// Entries which can't be read are removed, and missing or stale internal
+    /// records are restored or removed. Nothing else may use the cache while
+    /// it's repaired, which the caller asserts with [verify::Exclusive]. See
+    /// the [verify] module.
+    pub fn verify_and_repair(&self, _: verify::Exclusive) -> Result<verify::Report, Error> {
+        verify::verify(self, true)
+    }
+
+    /// Flush pending writes to disk, returning the number of bytes flushed.
+    ///
+    /// This only makes removals durable. The cache has no way to compact the
+    /// database, so disk usage doesn't necessarily shrink afterwards: sled
+    /// decides on its own when to reclaim the space used by removed entries.
+    pub fn flush(&self) -> Result<usize, Error> {
+        let flushed = self.inner.db.flush()?;
+        log::trace!("flush: {} bytes", flushed);
+        Ok(flushed)
+    }
+
+    /// Flush the database if the number of removed entries exceeds the
+    /// configured threshold.
+    fn maybe_flush(&self, removed: usize) -> Result<(), Error> {
+        if let Some(threshold) = self.inner.config.auto_flush {
+            if removed > threshold {
+                self.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create a namespaced cache.
+    ///
+    /// The namespace must be unique to avoid conflicts.
+    ///
+    /// Namespacing a namespaced cache nests the new namespace inside of it, so
+    /// that `a` and `a/b` never share entries. Nested namespaces use the format
+    /// and expiration configured for the namespace they're nested in. Methods
+    /// which take a namespace as an argument, like [Cache::clear_namespace],
+    /// refer to it relative to the namespace of the handle they're called on,
+    /// so `a.clear_namespace(Some(&"b"))` clears `b` nested inside of `a`.
+    ///
+    /// Each call to this functions will return its own queue for resolving futures.
+    pub fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        let mut ns_key = self.inner.ns_key.clone();
+        ns_key.extend(key::namespace_to_vec(Some(&ns))?);
+
+        let config = &self.inner.config;
+
+        // nested namespaces are configured like the namespace they're nested
+        // in.
+        let (format, sliding, compression_threshold, policy) = match &self.inner.ns {
+            Some(..) => (
+                self.inner.format.clone(),
+                self.inner.sliding,
+                self.inner.compression_threshold,
+                self.inner.policy.clone(),
+            ),
+            None => (
+                config.format(Some(&ns)),
+                config.sliding(Some(&ns)),
+                config.compression_threshold(Some(&ns)),
+                config.policy(Some(&ns)).cloned().unwrap_or_default(),
+            ),
+        };
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                db: self.inner.db.clone(),
+                format,
+                sliding,
+                compression_threshold,
+                policy,
+                stats: self.inner.stats.clone(),
+                counters: self.inner.stats.counters(&ns_key),
+                ns: Some(ns),
+                ns_key,
+                config: self.inner.config.clone(),
+                len: self.inner.len.clone(),
+                epoch: self.inner.epoch.clone(),
+                generations: self.inner.generations.clone(),
+                generation_counters: self.inner.generation_counters.clone(),
+                invalidations: self.inner.invalidations.clone(),
+                wakers: Default::default(),
+            }),
+        })
+    }
+
+    /// Create a namespaced cache like [Cache::namespaced], where values expire
+    /// after `ttl` unless another time to live is given.
+    ///
+    /// See [namespace] for an example.
+    pub fn namespaced_with<N>(&self, ns: &N, ttl: Duration) -> Result<namespace::Namespace, Error>
+    where
+        N: Serialize,
+    {
+        Ok(namespace::Namespace::new(self.namespaced(ns)?, ttl))
+    }
+
+    /// Create a generational namespaced cache like [Cache::namespaced].
+    ///
+    /// The namespace has a generation counter which is persisted in the
+    /// database and folded into the keys of its entries, including the
+    /// entries of namespaces nested inside of it. Calling
+    /// [Cache::bump_generation] with the same namespace invalidates all of
+    /// them in constant time instead of removing them one by one. Entries of
+    /// older generations are never read again, and are removed by the next
+    /// [Cache::cleanup].
+    ///
+    /// A namespace should either always or never be accessed through a
+    /// generational handle, since the entries of one are invisible to the
+    /// other.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?;
+    /// let search = cache.namespaced_generation(&"search")?;
+    ///
+    /// search.insert("rust", Duration::hours(1), &vec![1, 2, 3])?;
+    /// assert_eq!(Some(0), search.generation());
+    ///
+    /// assert_eq!(1, cache.bump_generation(&"search")?);
+    /// assert_eq!(Some(1), search.generation());
+    /// assert!(search.get::<_, Vec<u32>>("rust")?.get().is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn namespaced_generation<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let mut cache = self.namespaced(ns)?;
+        let ns_key = cache.ns_key()?.into_owned();
+
+        let generation = {
+            let mut counters = self.inner.generation_counters.lock();
+
+            match counters.get(&ns_key) {
+                Some(generation) => generation.clone(),
+                None => {
+                    let value = self.inner.db.get(generation_key(&ns_key))?;
+                    let generation = value.as_deref().map(decode_counter).unwrap_or_default();
+                    let generation = Arc::new(AtomicU64::new(generation));
+                    counters.insert(ns_key, generation.clone());
+                    generation
+                }
+            }
+        };
+
+        let inner = Arc::get_mut(&mut cache.inner).expect("newly created cache is unique");
+        inner.generations.push((inner.ns_key.len(), generation));
+        Ok(cache)
+    }
+
+    /// The current generation of the innermost generational namespace this
+    /// cache belongs to, or `None` if it doesn't belong to one.
+    ///
+    /// See [Cache::namespaced_generation].
+    pub fn generation(&self) -> Option<u64> {
+        let (_, generation) = self.inner.generations.last()?;
+        self.apply_invalidations();
+        Some(generation.load(Ordering::Acquire))
+    }
+
+    /// The persisted generation of every generational namespace which has
+    /// been bumped, by encoded namespace.
+    fn persisted_generations(&self) -> Result<HashMap<Vec<u8>, u64>, Error> {
+        let prefix = meta_key(GENERATION_KEY);
+        let mut out = HashMap::new();
+
+        for result in self.inner.db.scan_prefix(&prefix) {
+            let (key, value) = result?;
+            out.insert(key[prefix.len()..].to_vec(), decode_counter(&value));
+        }
+
+        Ok(out)
+    }
+
+    /// Invalidate every entry in the given generational namespace by bumping
+    /// its generation, returning the new generation.
+    ///
+    /// The namespace is nested inside of the namespace of this cache, like
+    /// with [Cache::namespaced_generation].
+    ///
+    /// The generation is persisted in the database, but it is only mirrored to
+    /// handles which share the same loaded cache, like the invalidation epoch
+    /// of [Cache::bump_epoch].
+    pub fn bump_generation<N>(&self, ns: &N) -> Result<u64, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        let mut ns_key = self.ns_key()?.into_owned();
+        ns_key.extend(key::namespace_to_vec(Some(&ns))?);
+
+        let previous = self
+            .inner
+            .db
+            .fetch_and_update(generation_key(&ns_key), |value| {
+                let generation = value.map(decode_counter).unwrap_or_default();
+                Some((generation + 1).to_be_bytes().to_vec())
+            })?;
+
+        let generation = previous.as_deref().map(decode_counter).unwrap_or_default() + 1;
+
+        if let Some(current) = self.inner.generation_counters.lock().get(&ns_key) {
+            current.fetch_max(generation, Ordering::AcqRel);
+        }
+
+        log::trace!("generation -> {}", generation);
+
+        self.publish(invalidation::Invalidation::Generation {
+            ns: ByteBuf::from(ns_key),
+            generation,
+        });
+
+        Ok(generation)
+    }
+
+    /// Create a namespaced cache like [Cache::namespaced], where values expire
+    /// after the time to live configured by the policy of the namespace unless
+    /// another time to live is given.
+    ///
+    /// Fails with [Error::NoDefaultTtl] if the policy doesn't configure one.
+    /// See [namespace::Policy] for an example.
+    pub fn namespace<N>(&self, ns: &N) -> Result<namespace::Namespace, Error>
+    where
+        N: Serialize,
+    {
+        let cache = self.namespaced(ns)?;
+        let ttl = cache.inner.policy.ttl.ok_or(Error::NoDefaultTtl)?;
+        Ok(namespace::Namespace::new(cache, ttl))
+    }
+
+    /// Insert a value into the cache.
+    pub fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, age, value, options)
+    }
+
+    /// Insert a value into the cache which expires at the given time.
+    ///
+    /// The bounds configured with [Builder::min_ttl] and [Builder::max_ttl]
+    /// apply to the time remaining until then.
+    pub fn insert_until<K, T>(
+        &self,
+        key: K,
+        expires_at: DateTime<Utc>,
+        value: &T,
+    ) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let now = self.now();
+        let age = expires_at - now;
+        let clamped = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            expires_at: Some(if clamped == age {
+                expires_at
+            } else {
+                now + clamped
+            }),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, clamped, value, options)
+    }
+
+    /// Insert a value into the cache which never expires.
+    ///
+    /// The entry is only removed when it's deleted, or once it's invalidated
+    /// through [Cache::invalidate], [Cache::invalidate_tag] or
+    /// [Cache::bump_epoch].
+    pub fn insert_forever<K, T>(&self, key: K, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            forever: true,
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, Duration::zero(), value, options)
+    }
+
+    /// Insert multiple values into the cache.
+    ///
+    /// All values are serialized up front and then written in a single
+    /// transaction, instead of with one write per entry. If any value fails to
+    /// serialize, nothing is written.
+    pub fn insert_many<K, T, I>(&self, entries: I) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+        I: IntoIterator<Item = (K, Duration, T)>,
+    {
+        let mut keys = Vec::new();
+        let mut values = Vec::new();
+
+        for (key, age, value) in entries {
+            let (key, plain_key) = self.insert_key(&key)?;
+            let age = self.inner.config.ttl(age)?;
+            let (payload, flags) =
+                encode_value(self.inner.compression_threshold, &self.inner.format, &value)?;
+            let indexes = self.extract_indexes(&value)?;
+            keys.push((key, plain_key, indexes));
+            values.push((age, payload, flags));
+        }
+
+        let schema = self.inner.config.schema::<T>().map_or(0, |s| s.version);
+
+        let writes = keys
+            .iter()
+            .zip(values)
+            .map(
+                |((key, plain_key, indexes), (age, payload, flags))| PendingWrite {
+                    key,
+                    age,
+                    payload,
+                    flags,
+                    options: InsertOptions {
+                        plain_key: Some(plain_key),
+                        schema,
+                        indexes,
+                        ..InsertOptions::default()
+                    },
+                },
+            )
+            .collect();
+
+        let replaced = self.write_payloads(writes)?;
+
+        for ((key, ..), previous) in keys.iter().zip(replaced) {
+            self.remove_replaced(key, previous, InsertOptions::default())?;
+        }
+
+        Ok(())
+    }
+
+    /// Insert a value into the cache unless there's already a fresh entry with
+    /// the same key.
+    ///
+    /// Returns `None` if the value was inserted, or the existing entry if it
+    /// wasn't. Expired entries and tombstones are replaced. The check and the
+    /// insert are atomic, so concurrent writers seeding the same key never
+    /// overwrite each other.
+    pub fn insert_if_absent<K, T>(
+        &self,
+        key: K,
+        age: Duration,
+        value: &T,
+    ) -> Result<Option<StoredEntry<T>>, Error>
+    where
+        K: CacheKey,
+        T: 'static + Serialize + de::DeserializeOwned,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            condition: Condition::Absent,
+            ..InsertOptions::default()
+        };
+
+        loop {
+            let existing = match self.write_value(&key, age, value, options)? {
+                Written::Replaced(previous) => {
+                    self.remove_replaced(&key, previous, options)?;
+                    return Ok(None);
+                }
+                Written::Rejected(Some(existing)) => existing,
+                Written::Rejected(None) => continue,
+            };
+
+            // the value of a chunked entry was replaced while it was being read.
+            if let Some((meta, payload)) = self.load_payload(&key, &existing)? {
+                return decode_payload(&self.inner.config, meta, payload).map(Some);
+            }
+        }
+    }
+
+    /// Insert a value into the cache, returning the value of the entry it
+    /// replaced regardless of whether it had expired.
+    ///
+    /// The previous value is read as part of the same atomic write, so no
+    /// other write can happen in between. Previous values which can't be
+    /// deserialized as `T` are reported as `None`, like by [Cache::get].
+    pub fn replace<K, T>(&self, key: K, age: Duration, value: &T) -> Result<Option<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + Serialize + de::DeserializeOwned,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        let previous = match self.write_value(&key, age, value, options)? {
+            Written::Replaced(previous) => previous,
+            Written::Rejected(..) => None,
+        };
+
+        // the value has to be read before the chunks or the deduplicated value
+        // of the previous entry are removed.
+        let value = match &previous {
+            Some(previous) => self.decode_replaced(&key, previous),
+            None => None,
+        };
+
+        self.remove_replaced(&key, previous, options)?;
+        Ok(value)
+    }
+
+    /// Atomically update the value of an entry, storing what `update` returns
+    /// for the current value.
+    ///
+    /// `update` is passed `None` if there's no fresh entry, or if its value
+    /// can't be deserialized as `T`. If the entry is modified concurrently,
+    /// `update` is called again with the new value, so no updates are lost.
+    /// Returns the stored value.
+    pub fn update<K, T, F>(&self, key: K, age: Duration, mut update: F) -> Result<T, Error>
+    where
+        K: CacheKey,
+        T: 'static + Serialize + de::DeserializeOwned,
+        F: FnMut(Option<T>) -> T,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+        let mut current = self.inner.db.get(&key)?;
+
+        loop {
+            let old = match &current {
+                Some(value) => match self.decode_current(&key, value)? {
+                    Some(old) => old.map(|stored| stored.value),
+                    // the value of a chunked entry was replaced while it was
+                    // being read.
+                    None => {
+                        current = self.inner.db.get(&key)?;
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let value = update(old);
+
+            let options = InsertOptions {
+                plain_key: Some(&plain_key),
+                condition: Condition::Unchanged(current.as_deref()),
+                ..InsertOptions::default()
+            };
+
+            match self.write_value(&key, age, &value, options)? {
+                Written::Replaced(previous) => {
+                    self.remove_replaced(&key, previous, options)?;
+                    log::trace!("update:{} -> *value*", self.key_format(&key));
+                    return Ok(value);
+                }
+                Written::Rejected(rejected) => current = rejected,
+            }
+        }
+    }
+
+    /// Atomically add `delta` to the counter stored under the given key,
+    /// returning its new value.
+    ///
+    /// The increment is atomic, and it's applied again if the counter is
+    /// modified concurrently, so concurrent increments are never lost. If
+    /// there's no fresh counter, a new one which expires after `age` is stored
+    /// with the value `delta`. Incrementing a counter doesn't extend its
+    /// expiration, so it counts over a fixed window, and keeps its tags and
+    /// other metadata. The counter is stored as an `i64` and can be read with
+    /// [Cache::get], and it saturates instead of overflowing.
+    ///
+    /// Fails if there's a fresh entry whose value isn't a counter.
+    pub fn incr<K>(&self, key: K, delta: i64, age: Duration) -> Result<i64, Error>
+    where
+        K: CacheKey,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        self.incr_key(&key, &plain_key, delta, age)
+            .map_err(|e| self.entry_error(Some(&key), true, e))
+    }
+
+    /// Increment the counter stored under the given key, see [Cache::incr].
+    fn incr_key(
+        &self,
+        key: &[u8],
+        plain_key: &[u8],
+        delta: i64,
+        age: Duration,
+    ) -> Result<i64, Error> {
+        let mut current = self.inner.db.get(key)?;
+
+        loop {
+            let loaded = match &current {
+                Some(value) => match self.load_payload(key, value) {
+                    Ok(Some(loaded)) => Some(loaded),
+                    // the value of a chunked entry was replaced while it was
+                    // being read.
+                    Ok(None) => {
+                        current = self.inner.db.get(key)?;
+                        continue;
+                    }
+                    Err(Error::Corrupt) => None,
+                    Err(e) => return Err(e),
+                },
+                None => None,
+            };
+
+            let (now, epoch) = (self.now(), self.epoch());
+            let loaded = loaded
+                .filter(|(meta, _)| meta.deleted_at.is_none() && !meta.is_expired(now, epoch));
+
+            let (value, meta) = match loaded {
+                Some((meta, payload)) => {
+                    let stored = decode_payload::<i64>(&self.inner.config, meta.clone(), payload)
+                        .map_err(|e| self.entry_error(Some(key), false, e))?;
+                    (stored.value.saturating_add(delta), Some(meta))
+                }
+                None => (delta, None),
+            };
+
+            let tags = meta
+                .iter()
+                .flat_map(|meta| meta.tags.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+
+            let (age, options) = match &meta {
+                Some(meta) => (
+                    Duration::milliseconds(meta.sliding),
+                    InsertOptions {
+                        pinned: meta.pinned,
+                        sliding: meta.sliding != 0,
+                        tags: &tags,
+                        deps: &meta.deps,
+                        plain_key: Some(plain_key),
+                        expires_at: meta.expires_at,
+                        forever: meta.expires_at.is_none(),
+                        metadata: Some(&meta.metadata),
+                        condition: Condition::Unchanged(current.as_deref()),
+                        ..InsertOptions::default()
+                    },
+                ),
+                None => (
+                    age,
+                    InsertOptions {
+                        plain_key: Some(plain_key),
+                        condition: Condition::Unchanged(current.as_deref()),
+                        ..InsertOptions::default()
+                    },
+                ),
+            };
+
+            match self.write_value(key, age, &value, options)? {
+                Written::Replaced(previous) => {
+                    self.remove_replaced(key, previous, options)?;
+                    log::trace!("incr:{} -> {}", self.key_format(key), value);
+                    return Ok(value);
+                }
+                Written::Rejected(rejected) => current = rejected,
+            }
+        }
+    }
+
+    /// Append an item to the list stored under the given key, returning the
+    /// new length of the list.
+    ///
+    /// Items are stored separately, so appending an item doesn't rewrite the
+    /// rest of the list. If there's no fresh list, a new one is stored in its
+    /// place. The whole list expires after `age`, which is extended with every
+    /// push.
+    pub fn push<K, T>(&self, key: K, age: Duration, item: &T) -> Result<usize, Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+        let format = &self.inner.format;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        let encoded = encode_value(self.inner.compression_threshold, format, item)?;
+        let new = self.new_meta(&key, age, &options)?;
+        let now = self.now();
+        let epoch = self.epoch();
+        let mut id = None;
+
+        loop {
+            let result = self.inner.db.transaction(|tx| {
+                let current = tx.get(&key)?;
+
+                let existing = current
+                    .as_deref()
+                    .and_then(|current| decode_meta(current).ok())
+                    .filter(|meta| meta.deleted_at.is_none() && !meta.is_expired(now, epoch))
+                    .and_then(|meta| Some((meta.list?, meta)));
+
+                let (mut meta, list, format, replaced) = match (existing, id) {
+                    // the list keeps its metadata, and only its expiration is
+                    // extended.
+                    (Some((list, meta)), _) => {
+                        let format = meta.format;
+                        let meta = PartialStoredEntry {
+                            expires_at: new.expires_at,
+                            ..meta
+                        };
+                        (meta, list, format, None)
+                    }
+                    (None, Some(id)) => {
+                        let list = List {
+                            id,
+                            start: 0,
+                            end: 0,
+                        };
+                        (new.clone(), list, format.id(), Some(current))
+                    }
+                    // a new list needs an identifier, which can't be
+                    // allocated in the transaction.
+                    (None, None) => return Ok(None),
+                };
+
+                let end = list.end.checked_add(1).ok_or_else(|| {
+                    ConflictableTransactionError::Abort(Error::Codec(
+                        "list has too many items".into(),
+                    ))
+                })?;
+
+                meta.list = Some(List { end, ..list });
+
+                // items are decoded with the format of their list, which
+                // might not be the format of this handle.
+                let (payload, flags) = if format == self.inner.format.id() {
+                    encoded.clone()
+                } else {
+                    self.inner
+                        .config
+                        .format_of(format)
+                        .ok_or(Error::UnsupportedFormat(format))
+                        .and_then(|format| {
+                            encode_value(self.inner.compression_threshold, &format, item)
+                        })
+                        .map_err(ConflictableTransactionError::Abort)?
+                };
+
+                let chunk_key = chunk_key(&key, list.id, list.end);
+                let stored = self
+                    .encode_item(&chunk_key, format, payload, flags)
+                    .map_err(ConflictableTransactionError::Abort)?;
+
+                tx.insert(chunk_key, stored)?;
+                tx.insert(
+                    key.as_slice(),
+                    join_entry(format, 0, &meta, &[])
+                        .map_err(ConflictableTransactionError::Abort)?,
+                )?;
+                Ok(Some((meta.list, replaced)))
+            });
+
+            match result? {
+                Some((list, replaced)) => {
+                    if let Some(previous) = replaced {
+                        self.remove_replaced(&key, previous, options)?;
+                    }
+
+                    let len = list.map(|list| list.len()).unwrap_or_default();
+                    log::trace!("push:{} -> {}", self.key_format(&key), len);
+                    return Ok(len);
+                }
+                None => id = Some(self.next_chunk_id()?),
+            }
+        }
+    }
+
+    /// Remove the oldest items of the list stored under the given key, so
+    /// that at most `len` of the most recently pushed items remain.
+    ///
+    /// Returns the number of items removed, or `None` if there's no fresh list.
+    /// The expiration of the list is left untouched.
+    pub fn trim_to<K>(&self, key: K, len: usize) -> Result<Option<usize>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let now = self.now();
+        let epoch = self.epoch();
+
+        let trimmed = self.inner.db.transaction(|tx| {
+            let current = match tx.get(&key)? {
+                Some(current) => current,
+                None => return Ok(None),
+            };
+
+            let (mut meta, _) = match split_entry(&current) {
+                Ok(entry) => entry,
+                Err(_) => return Ok(None),
+            };
+
+            let list = match meta.list {
+                Some(list) if meta.deleted_at.is_none() && !meta.is_expired(now, epoch) => list,
+                _ => return Ok(None),
+            };
+
+            let excess = list.len().saturating_sub(len);
+
+            if excess == 0 {
+                return Ok(Some(0));
+            }
+
+            let start = list.start + excess as u32;
+
+            for index in list.start..start {
+                tx.remove(chunk_key(&key, list.id, index))?;
+            }
+
+            meta.list = Some(List { start, ..list });
+            let value = join_entry(meta.format, meta.flags, &meta, &[])
+                .map_err(ConflictableTransactionError::Abort)?;
+            tx.insert(key.as_slice(), value)?;
+            Ok(Some(excess))
+        })?;
+
+        log::trace!("trim:{} -> {:?}", self.key_format(&key), trimmed);
+        Ok(trimmed)
+    }
+
+    /// Load the items of the list stored under the given key in the range
+    /// `start..end`, where `0` is the oldest item.
+    ///
+    /// The range is clamped to the items in the list, so `0..usize::MAX`
+    /// loads all of them.
+    pub fn range<K, T>(&self, key: K, start: usize, end: usize) -> Result<State<Vec<T>>, Error>
+    where
+        K: CacheKey,
+        T: de::DeserializeOwned,
+    {
+        let key = self.key(&key)?;
+
+        loop {
+            let value = match self.inner.db.get(&key)? {
+                Some(value) => value,
+                None => return Ok(State::Missing),
+            };
+
+            let meta = match decode_meta(&value) {
+                Ok(meta) => meta,
+                Err(Error::Corrupt) => return Ok(State::Corrupt),
+                Err(e) => {
+                    log::warn!("{}: failed to deserialize: {}", self.key_format(&key), e);
+                    return Ok(State::Missing);
+                }
+            };
+
+            let list = match meta.list {
+                Some(list) if meta.deleted_at.is_none() => list,
+                _ => return Ok(State::Missing),
+            };
+
+            let end = end.min(list.len());
+            let start = start.min(end);
+
+            let items = match self.load_items(&key, &value, meta.format, list, start..end) {
+                Ok(Some(items)) => items,
+                // the list was trimmed or replaced while it was being read.
+                Ok(None) => continue,
+                Err(Error::Corrupt) => {
+                    log::warn!("{}: entry is corrupt", self.key_format(&key));
+                    return Ok(State::Corrupt);
+                }
+                Err(e) => {
+                    log::warn!("{}: failed to deserialize: {}", self.key_format(&key), e);
+                    return Ok(State::Missing);
+                }
+            };
+
+            let stored = meta.into_stored_entry(&self.inner.config, items);
+
+            if stored.is_expired(self.now(), self.epoch()) {
+                return Ok(State::Expired(stored));
+            }
+
+            return Ok(State::Fresh(stored));
+        }
+    }
+
+    /// Encrypt a single serialized item of a list for the key of its chunk,
+    /// prefixing it with its flags.
+    fn encode_item(
+        &self,
+        chunk_key: &[u8],
+        format: u8,
+        payload: Vec<u8>,
+        flags: u8,
+    ) -> Result<Vec<u8>, Error> {
+        let (payload, flags) = encrypt(&self.inner.config, chunk_key, format, payload, flags)?;
+        let mut encoded = Vec::with_capacity(1 + payload.len());
+        encoded.push(flags);
+        encoded.extend_from_slice(&payload);
+        Ok(encoded)
+    }
+
+    /// Load and deserialize the items of a list in the given range.
+    ///
+    /// Returns `None` if the list was modified while it was being read.
+    fn load_items<T>(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        format: u8,
+        list: List,
+        range: Range<usize>,
+    ) -> Result<Option<Vec<T>>, Error>
+    where
+        T: de::DeserializeOwned,
+    {
+        let id = format;
+        let format = self
+            .inner
+            .config
+            .format_of(format)
+            .ok_or(Error::UnsupportedFormat(format))?;
+        let mut items = Vec::with_capacity(range.len());
+
+        for index in range {
+            let index = list.start + index as u32;
+            let chunk_key = chunk_key(key, list.id, index);
+
+            let item = match self.inner.db.get(&chunk_key)? {
+                Some(item) => item,
+                None => {
+                    if self.inner.db.get(key)?.as_deref() != Some(value) {
+                        return Ok(None);
+                    }
+
+                    return Err(Error::Corrupt);
+                }
+            };
+
+            let (flags, payload) = item.split_first().ok_or(Error::Corrupt)?;
+            let payload = decrypt(
+                &self.inner.config,
+                &chunk_key,
+                id,
+                *flags,
+                Cow::Borrowed(payload),
+            )?;
+            let payload = decompress(*flags, payload)?;
+            items.push(format.decode(&payload)?);
+        }
+
+        Ok(Some(items))
+    }
+
+    /// Atomically add a member to the set stored under the given key,
+    /// returning `true` if it wasn't already a member.
+    ///
+    /// If there's no fresh set, a new one which expires after `age` is stored.
+    /// Adding members doesn't extend the expiration of an existing set, so it
+    /// can be used to deduplicate over a fixed window. The set is stored as a
+    /// `BTreeSet<T>` and can be read as a whole with [Cache::get].
+    pub fn sadd<K, T>(&self, key: K, age: Duration, member: &T) -> Result<bool, Error>
+    where
+        K: CacheKey,
+        T: 'static + Clone + Ord + Serialize + de::DeserializeOwned,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+        self.update_set(&key, &plain_key, Some(age), |set| {
+            set.insert(member.clone())
+        })
+    }
+
+    /// Atomically remove a member from the set stored under the given key,
+    /// returning `true` if it was a member.
+    ///
+    /// The expiration of the set is left unchanged.
+    pub fn srem<K, T>(&self, key: K, member: &T) -> Result<bool, Error>
+    where
+        K: CacheKey,
+        T: 'static + Ord + Serialize + de::DeserializeOwned,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        self.update_set(&key, &plain_key, None, |set: &mut BTreeSet<T>| {
+            set.remove(member)
+        })
+    }
+
+    /// Test if the fresh set stored under the given key contains a member.
+    pub fn scontains<K, T>(&self, key: K, member: &T) -> Result<bool, Error>
+    where
+        K: CacheKey,
+        T: 'static + Ord + de::DeserializeOwned,
+    {
+        match self.get::<_, BTreeSet<T>>(key)? {
+            State::Fresh(e) => Ok(e.value.contains(member)),
+            _ => Ok(false),
+        }
+    }
+
+    /// Atomically modify the set stored under the given key with `modify`,
+    /// which returns whether it changed the set.
+    ///
+    /// If there's no fresh set, an empty one which expires after `age` is
+    /// modified, or nothing happens if `age` is `None`. Existing sets keep
+    /// their expiration.
+    fn update_set<T, F>(
+        &self,
+        key: &[u8],
+        plain_key: &[u8],
+        age: Option<Duration>,
+        mut modify: F,
+    ) -> Result<bool, Error>
+    where
+        T: 'static + Ord + Serialize + de::DeserializeOwned,
+        F: FnMut(&mut BTreeSet<T>) -> bool,
+    {
+        let mut current = self.inner.db.get(key)?;
+
+        loop {
+            let stored = match &current {
+                Some(value) => match self.decode_current::<BTreeSet<T>>(key, value)? {
+                    Some(stored) => stored,
+                    // the value of a chunked entry was replaced while it was
+                    // being read.
+                    None => {
+                        current = self.inner.db.get(key)?;
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let (mut set, stored) = match (stored, age) {
+                (Some(mut stored), _) => (std::mem::take(&mut stored.value), Some(stored)),
+                (None, Some(..)) => (BTreeSet::new(), None),
+                (None, None) => return Ok(false),
+            };
+
+            if !modify(&mut set) {
+                return Ok(false);
+            }
+
+            let tags = stored
+                .iter()
+                .flat_map(|stored| stored.tags.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+
+            let (age, options) = match &stored {
+                Some(stored) => (
+                    Duration::milliseconds(stored.sliding),
+                    InsertOptions {
+                        pinned: stored.pinned,
+                        sliding: stored.sliding != 0,
+                        tags: &tags,
+                        deps: &stored.deps,
+                        plain_key: Some(plain_key),
+                        expires_at: stored.expires_at,
+                        forever: stored.expires_at.is_none(),
+                        metadata: Some(&stored.metadata),
+                        condition: Condition::Unchanged(current.as_deref()),
+                        ..InsertOptions::default()
+                    },
+                ),
+                None => (
+                    age.unwrap_or_else(Duration::zero),
+                    InsertOptions {
+                        plain_key: Some(plain_key),
+                        condition: Condition::Unchanged(current.as_deref()),
+                        ..InsertOptions::default()
+                    },
+                ),
+            };
+
+            match self.write_value(key, age, &set, options)? {
+                Written::Replaced(previous) => {
+                    self.remove_replaced(key, previous, options)?;
+                    log::trace!(
+                        "update-set:{} -> {} members",
+                        self.key_format(key),
+                        set.len()
+                    );
+                    return Ok(true);
+                }
+                Written::Rejected(rejected) => current = rejected,
+            }
+        }
+    }
+
+    /// Decode the fresh value of the entry currently stored under the given
+    /// key for an update.
+    ///
+    /// Returns `None` if the entry was modified while it was being read.
+    fn decode_current<T>(
+        &self,
+        key: &[u8],
+        current: &[u8],
+    ) -> Result<Option<Option<StoredEntry<T>>>, Error>
+    where
+        T: 'static + de::DeserializeOwned,
+    {
+        let (meta, payload) = match self.load_payload(key, current) {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => return Ok(None),
+            Err(Error::Corrupt) => return Ok(Some(None)),
+            Err(e) => return Err(e),
+        };
+
+        if meta.deleted_at.is_some() || meta.is_expired(self.now(), self.epoch()) {
+            return Ok(Some(None));
+        }
+
+        match decode_payload(&self.inner.config, meta, payload) {
+            Ok(stored) => Ok(Some(Some(stored))),
+            Err(e) => {
+                log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                Ok(Some(None))
+            }
+        }
+    }
+
+    /// Decode the value of an entry which has just been replaced.
+    fn decode_replaced<T>(&self, key: &[u8], previous: &[u8]) -> Option<T>
+    where
+        T: 'static + de::DeserializeOwned,
+    {
+        let result = self.load_payload(key, previous).and_then(|loaded| {
+            loaded
+                .filter(|(meta, _)| meta.deleted_at.is_none())
+                .map(|(meta, payload)| decode_payload(&self.inner.config, meta, payload))
+                .transpose()
+        });
+
+        match result {
+            Ok(stored) => stored.map(|stored| stored.value),
+            Err(e) => {
+                log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                None
+            }
+        }
+    }
+
+    /// Insert a dynamic value into the cache.
+    ///
+    /// This permits tooling to write entries without knowing the concrete type
+    /// of the value. The key is serialized like the key of any other entry, so
+    /// it has to have the same shape as the keys used by the application.
+    pub fn insert_value<K>(&self, key: K, age: Duration, value: &json::Value) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        self.insert(key, age, value)
+    }
+
+    /// Load an entry from the cache as a dynamic value, regardless of its type.
+    ///
+    /// This complements [Cache::list_json] with access to a single entry. The
+    /// value is decoded with the format it was stored in. Maps with keys which
+    /// aren't strings are converted into objects with their keys formatted as
+    /// strings.
+    pub fn get_value<K>(&self, key: K) -> Result<State<json::Value>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+
+        let convert = |stored: StoredEntry<dynamic::Dynamic>| stored.try_map(|value| Ok(value.0));
+
+        Ok(match self.inner_get::<dynamic::Dynamic>(&key)? {
+            State::Fresh(stored) => State::Fresh(convert(stored)?),
+            State::Expired(stored) => State::Expired(convert(stored)?),
+            State::Missing => State::Missing,
+            State::Corrupt => State::Corrupt,
+        })
+    }
+
+    /// Insert raw bytes into the cache, without serializing them.
+    ///
+    /// This is useful for values which are already encoded, since they are
+    /// stored as they are rather than as a byte string in the configured
+    /// format. They are still compressed and encrypted as configured. Read them
+    /// back with [Cache::get_bytes], or as a [serde_bytes::ByteBuf].
+    pub fn insert_bytes<K>(&self, key: K, age: Duration, value: &[u8]) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        let (payload, flags) = encode_raw(self.inner.compression_threshold, value)?;
+        self.insert_payload(&key, age, payload, flags, options)
+    }
+
+    /// Load raw bytes from the cache.
+    ///
+    /// This reads values stored with [Cache::insert_bytes] or
+    /// [Cache::insert_stream], as well as values stored as byte strings like a
+    /// [serde_bytes::ByteBuf].
+    pub fn get_bytes<K>(&self, key: K) -> Result<State<Vec<u8>>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+
+        let convert = |stored: StoredEntry<ByteBuf>| stored.try_map(|value| Ok(value.into_vec()));
+
+        Ok(match self.inner_get::<ByteBuf>(&key)? {
+            State::Fresh(stored) => State::Fresh(convert(stored)?),
+            State::Expired(stored) => State::Expired(convert(stored)?),
+            State::Missing => State::Missing,
+            State::Corrupt => State::Corrupt,
+        })
+    }
+
+    /// Insert a pinned value into the cache.
+    ///
+    /// See [Cache::pin].
+    pub fn insert_pinned<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            pinned: true,
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, age, value, options)
+    }
+
+    /// Insert a value into the cache with a sliding expiration.
+    ///
+    /// The entry expires once it hasn't been read for `idle`, since every
+    /// fresh hit through [Cache::get] or [Cache::wrap] extends its expiration
+    /// to `idle` from then. So that not every read rewrites the entry, its
+    /// expiration is only extended once a tenth of `idle` has passed since it
+    /// was last extended.
+    pub fn insert_sliding<K, T>(&self, key: K, idle: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let idle = self.inner.config.ttl(idle)?;
+
+        let options = InsertOptions {
+            sliding: true,
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, idle, value, options)
+    }
+
+    /// Insert a value into the cache which is associated with the given tags.
+    ///
+    /// All entries associated with a tag can be removed at once with
+    /// [Cache::invalidate_tag], regardless of which namespace they belong to.
+    pub fn insert_tagged<K, T>(
+        &self,
+        key: K,
+        age: Duration,
+        value: &T,
+        tags: &[&str],
+    ) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            tags,
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, age, value, options)
+    }
+
+    /// Insert a value into the cache together with the given metadata.
+    ///
+    /// Metadata like where the value was fetched from can be read back through
+    /// [StoredEntry::metadata] or [EntryRef::metadata] without being part of
+    /// the value. It's stored in plain text even if an
+    /// [encryption key][Builder::encryption_key] is configured.
+    pub fn insert_with_metadata<K, T>(
+        &self,
+        key: K,
+        age: Duration,
+        value: &T,
+        metadata: &Metadata,
+    ) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            metadata: Some(metadata),
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, age, value, options)
+    }
+
+    /// Remove every entry associated with the given tag, returning the number
+    /// of entries removed.
+    ///
+    /// Tags are shared by the entire database, so this removes tagged entries
+    /// in every namespace, not just the namespace of this handle. See
+    /// [Cache::insert_tagged].
+    pub fn invalidate_tag(&self, tag: &str) -> Result<usize, Error> {
+        let prefix = tag_prefix(tag);
+        let mut removed = 0;
+
+        for result in self.inner.db.scan_prefix(&prefix).keys() {
+            let index_key = result?;
+            let key = &index_key[prefix.len()..];
+
+            // The entry might have been replaced by one which no longer has
+            // the tag since the index was written.
+            let value = match self.inner.db.get(key)? {
+                Some(value)
+                    if decode_meta(&value).is_ok_and(|e| e.tags.iter().any(|t| t == tag)) =>
+                {
+                    value
+                }
+                _ => {
+                    self.inner.db.remove(index_key)?;
+                    continue;
+                }
+            };
+
+            // Only remove the entry we checked, leaving the index of an entry
+            // which replaced it in the meantime alone.
+            let result =
+                self.inner
+                    .db
+                    .compare_and_swap(key, Some(&value), None as Option<&[u8]>)?;
+
+            if result.is_ok() {
+                self.remove_removed(key, &value)?;
+                self.record(AuditOp::Invalidate, key)?;
+                self.inner.db.remove(&index_key)?;
+                removed += 1;
+            }
+        }
+
+        log::trace!("invalidate-tag:{} -> {}", tag, removed);
+        Ok(removed)
+    }
+
+    /// Load the fresh entries in the namespace of this cache which are indexed
+    /// by the given value in the index named `name`.
+    ///
+    /// See [Builder::index]. Entries whose value can't be deserialized as `T`
+    /// are skipped.
+    pub fn get_by_index<V, T>(&self, name: &str, value: &V) -> Result<Vec<StoredEntry<T>>, Error>
+    where
+        V: Serialize,
+        T: 'static + de::DeserializeOwned,
+    {
+        let indexed = key::to_vec(value)?;
+        let prefix = index_prefix(name, &indexed);
+        let ns_prefix = self.ns_prefix()?;
+        let now = self.now();
+        let epoch = self.epoch();
+        let mut out = Vec::new();
+
+        for result in self.inner.db.scan_prefix(&prefix).keys() {
+            let index_key = result?;
+            let key = &index_key[prefix.len()..];
+
+            if !key.starts_with(&ns_prefix) {
+                continue;
+            }
+
+            let value = match self.inner.db.get(key)? {
+                Some(value) => value,
+                None => {
+                    self.inner.db.remove(&index_key)?;
+                    continue;
+                }
+            };
+
+            let meta = match decode_meta(&value) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+
+            // The entry might have been replaced by one which is no longer
+            // indexed by the value since the index was written.
+            if !meta
+                .indexes
+                .iter()
+                .any(|(n, v)| n == name && v.as_slice() == indexed.as_slice())
+            {
+                self.inner.db.remove(&index_key)?;
+                continue;
+            }
+
+            if meta.deleted_at.is_some() || meta.is_expired(now, epoch) {
+                continue;
+            }
+
+            match self.decode_entry(key, &value) {
+                Ok(Some(stored)) => out.push(stored),
+                Ok(None) => continue,
+                Err(e) => log::warn!("{}: failed to deserialize: {}", self.key_format(key), e),
+            }
+        }
+
+        log::trace!("get-by-index:{} -> {}", name, out.len());
+        Ok(out)
+    }
+
+    /// Insert a value into the cache which is derived from the entries with
+    /// the given keys in the same namespace.
+    ///
+    /// Invalidating any of the dependencies with [Cache::invalidate] also
+    /// invalidates this entry.
+    pub fn insert_derived<K, D, T>(
+        &self,
+        key: K,
+        age: Duration,
+        value: &T,
+        dependencies: &[D],
+    ) -> Result<(), Error>
+    where
+        K: CacheKey,
+        D: Serialize,
+        T: Serialize,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let deps = dependencies
+            .iter()
+            .map(|dep| Ok(ByteBuf::from(self.key(dep)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let options = InsertOptions {
+            deps: &deps,
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        self.inner_insert(&key, age, value, options)
+    }
+
+    /// Mark the entry with the given key as expired, together with every entry
+    /// which was derived from it, recursively.
+    ///
+    /// Returns the number of entries which were marked as expired. Expired
+    /// entries are still available through [State::Expired] until they are
+    /// removed by a cleanup.
+    ///
+    /// See [Cache::insert_derived].
+    pub fn invalidate<K>(&self, key: K) -> Result<usize, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let now = self.now();
+        let mut invalidated = 0;
+        let mut seen = HashSet::new();
+        let mut queue = vec![key];
+
+        while let Some(key) = queue.pop() {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+
+            let expired = self.modify(&key, |stored| {
+                if stored.expires_at.is_some_and(|expires_at| expires_at < now) {
+                    return false;
+                }
+
+                // entries expire once their expiration has passed.
+                stored.expires_at = Some(now - Duration::nanoseconds(1));
+                true
+            })?;
+
+            if expired {
+                log::trace!("invalidate:{}", self.key_format(&key));
+                self.record(AuditOp::Invalidate, &key)?;
+                invalidated += 1;
+            }
+
+            let prefix = dep_prefix(&key);
+
+            for result in self.inner.db.scan_prefix(&prefix).keys() {
+                let index_key = result?;
+                let dependent = &index_key[prefix.len()..];
+
+                // The dependent might have been replaced by an entry which is
+                // no longer derived from this one.
+                let derived = match self.inner.db.get(dependent)? {
+                    Some(value) => match decode_meta(&value) {
+                        Ok(entry) => entry.deps.iter().any(|dep| **dep == *key),
+                        Err(_) => false,
+                    },
+                    None => false,
+                };
+
+                if derived {
+                    queue.push(dependent.to_vec());
+                } else {
+                    self.inner.db.remove(index_key)?;
+                }
+            }
+        }
+
+        Ok(invalidated)
+    }
+
+    /// Remove the tag and dependency index entries of the given key.
+    fn remove_indexes(&self, key: &[u8], entry: &PartialStoredEntry) -> Result<(), Error> {
+        for index_key in index_keys(key, entry) {
+            self.inner.db.remove(index_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the value of the given entry if it's stored outside of the
+    /// entry, which must already have been removed.
+    fn remove_value(&self, key: &[u8], entry: &PartialStoredEntry) -> Result<(), Error> {
+        for chunk_key in chunk_keys(key, entry) {
+            self.inner.db.remove(chunk_key)?;
+        }
+
+        if let Some(hash) = &entry.blob {
+            self.release_blob(hash)?;
+        }
+
+        Ok(())
+    }
+
+    /// Release a reference to a deduplicated value, removing it if it was the
+    /// last one.
+    ///
+    /// References are only released once the entry holding them has been
+    /// removed, so a value is never removed while it's referenced.
+    fn release_blob(&self, hash: &[u8]) -> Result<(), Error> {
+        let blob_key = blob_key(hash);
+        let ref_key = blob_ref_key(hash);
+
+        self.inner.db.transaction(|tx| {
+            let refs = tx
+                .get(&ref_key)?
+                .as_deref()
+                .map(decode_counter)
+                .unwrap_or_default();
+
+            if refs <= 1 {
+                tx.remove(ref_key.as_slice())?;
+                tx.remove(blob_key.as_slice())?;
+            } else {
+                tx.insert(ref_key.as_slice(), &(refs - 1).to_be_bytes()[..])?;
+            }
+
+            Ok::<_, ConflictableTransactionError<Error>>(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Load the deduplicated value with the given hash, returning its flags,
+    /// and the buffer and range holding it.
+    ///
+    /// Returns `None` if the entry referring to it was modified or removed
+    /// while it was being read.
+    fn load_blob(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        hash: &[u8],
+    ) -> Result<Option<(u8, sled::IVec, Range<usize>)>, Error> {
+        let blob = match self.inner.db.get(blob_key(hash))? {
+            Some(blob) => blob,
+            None => {
+                if self.inner.db.get(key)?.as_deref() != Some(value) {
+                    return Ok(None);
+                }
+
+                return Err(Error::Corrupt);
+            }
+        };
+
+        if blob.len() < 1 + CHECKSUM_LEN {
+            return Err(Error::Corrupt);
+        }
+
+        let end = blob.len() - CHECKSUM_LEN;
+
+        if crc32fast::hash(&blob[..end]).to_be_bytes() != blob[end..] {
+            return Err(Error::Corrupt);
+        }
+
+        Ok(Some((blob[0], blob, 1..end)))
+    }
+
+    /// Remove chunks which don't belong to the entry currently stored under
+    /// their key, like the chunks of entries which were removed but whose
+    /// chunks weren't because of a crash.
+    ///
+    /// Chunks are written atomically with their entry and chunk identifiers
+    /// are never reused, so chunks not referenced by their entry will never be
+    /// referenced again.
+    fn remove_orphaned_chunks(&self) -> Result<usize, Error> {
+        let mut removed = 0;
+        let mut last: Option<(Vec<u8>, u64, bool)> = None;
+
+        for result in self.inner.db.scan_prefix(meta_key(CHUNK_KEY)).keys() {
+            let chunk_key = result?;
+
+            let (key, id) = match split_chunk_key(&chunk_key) {
+                Some(parts) => parts,
+                None => continue,
+            };
+
+            let orphaned = match &last {
+                Some((last_key, last_id, orphaned)) if last_key == key && *last_id == id => {
+                    *orphaned
+                }
+                // chunks which are being written are checked before the entry is
+                // read, since they're no longer pending once the entry is written.
+                _ if self.is_pending(id)? => {
+                    last = Some((key.to_vec(), id, false));
+                    false
+                }
+                _ => {
+                    let current = match self.inner.db.get(key)? {
+                        Some(value) => decode_meta(&value).ok().and_then(|entry| {
+                            entry
+                                .chunks
+                                .map(|chunks| chunks.id)
+                                .or(entry.list.map(|list| list.id))
+                        }),
+                        None => None,
+                    };
+
+                    let orphaned = current != Some(id);
+                    last = Some((key.to_vec(), id, orphaned));
+                    orphaned
+                }
+            };
+
+            if orphaned {
+                self.inner.db.remove(&chunk_key)?;
+                removed += 1;
+            }
+        }
+
+        // markers of abandoned writes are removed after their chunks, so that
+        // the chunks are never seen without them while they're pending.
+        for result in self.inner.db.scan_prefix(meta_key(PENDING_KEY)) {
+            let (marker, started) = result?;
+
+            if self.is_abandoned(&started) {
+                self.inner.db.remove(&marker)?;
+            }
+        }
+
+        log::trace!("remove-orphaned-chunks -> {}", removed);
+        Ok(removed)
+    }
+
+    /// Test if the chunks with the given identifier are being written by
+    /// [Cache::insert_stream], by this or any other handle to the database.
+    pub(crate) fn is_pending(&self, id: u64) -> Result<bool, Error> {
+        Ok(match self.inner.db.get(pending_key(id))? {
+            Some(started) => !self.is_abandoned(&started),
+            None => false,
+        })
+    }
+
+    /// Test if a write of chunks which started at the time recorded in its
+    /// pending marker has been abandoned, like when the process writing them
+    /// crashed.
+    fn is_abandoned(&self, started: &[u8]) -> bool {
+        let started = match <[u8; 8]>::try_from(started) {
+            Ok(started) => i64::from_be_bytes(started),
+            Err(_) => return true,
+        };
+
+        self.now().timestamp_millis().saturating_sub(started) > PENDING_TIMEOUT.num_milliseconds()
+    }
+
+    /// Evict the entries which expire soonest from namespaces which exceed
+    /// their quota, returning the number of entries evicted.
+    ///
+    /// See [namespace::Policy::max_entries].
+    fn evict_over_quota(&self) -> Result<usize, Error> {
+        let mut evicted = 0;
+
+        for (ns, policy) in &self.inner.config.policies {
+            let max_entries = match policy.max_entries {
+                Some(max_entries) => max_entries,
+                None => continue,
+            };
+
+            let mut entries = 0usize;
+            let mut candidates = Vec::new();
+
+            let prefix = join_key(&key::namespace_to_vec(Some(ns))?, &[]);
+
+            for result in self.inner.db.scan_prefix(prefix) {
+                let (key, value) = result?;
+
+                let entry = match decode_meta(&value) {
+                    Ok(entry) if entry.deleted_at.is_none() => entry,
+                    _ => continue,
+                };
+
+                entries += 1;
+
+                if !entry.pinned {
+                    candidates.push((entry.expires_at.is_none(), entry.expires_at, key, value));
+                }
+            }
+
+            candidates.sort();
+
+            for (.., key, value) in candidates
+                .into_iter()
+                .take(entries.saturating_sub(max_entries))
+            {
+                // Only evict the entry which was ranked, and not one which
+                // replaced it since.
+                let result =
+                    self.inner
+                        .db
+                        .compare_and_swap(&key, Some(&value), None as Option<&[u8]>)?;
+
+                if result.is_err() {
+                    continue;
+                }
+
+                self.remove_removed(&key, &value)?;
+                self.record(AuditOp::Evict, &key)?;
+                let meta = decode_meta(&value).ok();
+                let meta = meta.as_ref().map(PartialStoredEntry::hook_meta);
+                self.hook(&self.inner.config.hooks.evict, &key, meta);
+                evicted += 1;
+            }
+        }
+
+        log::trace!("evict-over-quota -> {}", evicted);
+        Ok(evicted)
+    }
+
+    /// Remove the retained versions of entries which no longer exist.
+    fn remove_orphaned_history(&self) -> Result<usize, Error> {
+        let mut removed = 0;
+        let mut last: Option<(Vec<u8>, bool)> = None;
+
+        for result in self.inner.db.scan_prefix(meta_key(HISTORY_KEY)).keys() {
+            let history_key = result?;
+
+            let key = match split_history_key(&history_key) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            let orphaned = match &last {
+                Some((last_key, orphaned)) if last_key == key => *orphaned,
+                _ => {
+                    let orphaned = !self.inner.db.contains_key(key)?;
+                    last = Some((key.to_vec(), orphaned));
+                    orphaned
+                }
+            };
+
+            if orphaned {
+                self.inner.db.remove(&history_key)?;
+                removed += 1;
+            }
+        }
+
+        log::trace!("remove-orphaned-history -> {}", removed);
+        Ok(removed)
+    }
+
+    /// Record the value of a replaced entry in the history of its key,
+    /// removing the oldest versions beyond the number to retain.
+    ///
+    /// This has to happen before the chunks or the deduplicated value of the
+    /// replaced entry are removed.
+    fn record_history(&self, key: &[u8], previous: &[u8]) -> Result<(), Error> {
+        let (mut meta, payload) = match self.load_payload(key, previous) {
+            Ok(Some(loaded)) => loaded,
+            // the value of the replaced entry can't be recovered.
+            Ok(None) | Err(_) => return Ok(()),
+        };
+
+        if meta.deleted_at.is_some() || meta.list.is_some() {
+            return Ok(());
+        }
+
+        // values are decrypted as they're loaded, and are encrypted again for
+        // the key of their entry.
+        let flags = meta.flags & !FLAG_CHUNKS_ENCRYPTED;
+        let (payload, flags) = encrypt(
+            &self.inner.config,
+            key,
+            meta.format,
+            payload.into_owned(),
+            flags,
+        )?;
+
+        meta.chunks = None;
+        meta.blob = None;
+
+        let version = join_entry(meta.format, flags, &meta, &payload)?;
+        let id = self.next_chunk_id()?;
+        self.inner.db.insert(history_key(key, id), version)?;
+
+        let versions = self
+            .inner
+            .db
+            .scan_prefix(history_prefix(key))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let excess = versions.len().saturating_sub(self.inner.config.history);
+
+        for history_key in &versions[..excess] {
+            self.inner.db.remove(history_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Load the retained versions of the entry with the given key, newest
+    /// first.
+    ///
+    /// Versions are only retained if [Builder::history] is configured. Each
+    /// version is reported with the metadata it had when it was replaced, so
+    /// [StoredEntry::stored_at] tells when it was stored. Versions which can't
+    /// be deserialized as `T` are skipped.
+    pub fn history<K, T>(&self, key: K) -> Result<Vec<StoredEntry<T>>, Error>
+    where
+        K: CacheKey,
+        T: 'static + de::DeserializeOwned,
+    {
+        let key = self.key(&key)?;
+        let mut out = Vec::new();
+
+        for result in self
+            .inner
+            .db
+            .scan_prefix(history_prefix(&key))
+            .values()
+            .rev()
+        {
+            let value = result?;
+
+            match self.decode_entry(&key, &value) {
+                Ok(Some(stored)) => out.push(stored),
+                Ok(None) => continue,
+                Err(e) => log::warn!("{}: failed to deserialize: {}", self.key_format(&key), e),
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Record a mutation of the entry with the given key in the statistics of
+    /// its namespace, and in the audit log if one is configured.
+    fn record(&self, op: AuditOp, key: &[u8]) -> Result<(), Error> {
+        if let Some(counters) = self.entry_counters(key)? {
+            counters.record(op);
+        }
+
+        if op == AuditOp::Insert && !self.inner.config.hooks.insert.is_empty() {
+            let meta = match self.inner.db.get(key)? {
+                Some(value) => decode_meta(&value).ok(),
+                None => None,
+            };
+
+            let meta = meta.as_ref().map(PartialStoredEntry::hook_meta);
+            self.hook(&self.inner.config.hooks.insert, key, meta);
+        }
+
+        if self.inner.config.invalidation_bus.is_some() {
+            if let Some((ns, key)) = self.redacted_key_json(key, "publish") {
+                self.publish(invalidation::Invalidation::Entry { op, ns, key });
+            }
+        }
+
+        let audit_log = match &self.inner.config.audit_log {
+            Some(audit_log) => audit_log,
+            None => return Ok(()),
+        };
+
+        let (ns, json_key) = match self.redacted_key_json(key, "audit") {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        let id = audit_log.update_and_fetch(AUDIT_ID_KEY, |value| {
+            let id = value.map(decode_counter).unwrap_or_default();
+            Some((id + 1).to_be_bytes().to_vec())
+        })?;
+
+        let id = id.as_deref().map(decode_counter).unwrap_or_default();
+
+        let record = AuditRecord {
+            id,
+            at: self.now(),
+            op,
+            ns,
+            key: json_key,
+        };
+
+        audit_log.insert(id.to_be_bytes(), cbor::to_vec(&record)?)?;
+        Ok(())
+    }
+
+    /// The counters of the namespace of the entry with the given key, unless
+    /// the key can't be decoded.
+    fn entry_counters(&self, key: &[u8]) -> Result<Option<Arc<Counters>>, Error> {
+        Ok(match split_key(key) {
+            Ok((ns, _)) if ns == &*self.ns_key()? => Some(self.inner.counters.clone()),
+            Ok((ns, _)) => Some(self.inner.stats.counters(ns)),
+            Err(_) => None,
+        })
+    }
+
+    /// Warn about and count a read or write of the entry with the given key
+    /// which was slower than the configured threshold.
+    fn check_slow_storage(&self, key: &[u8], op: &str, took: std::time::Duration) {
+        let threshold = match self.inner.config.slow_storage_threshold {
+            Some(threshold) if took > threshold => threshold,
+            _ => return,
+        };
+
+        if let Some(suppressed) = self.inner.stats.slow_storage_warnings.check() {
+            log::warn!(
+                "{}: slow storage {} took {:?}, over the threshold of {:?}{}",
+                self.key_format(key),
+                op,
+                took,
+                threshold,
+                suppressed_warnings(suppressed)
+            );
+        }
+
+        if let Ok(Some(counters)) = self.entry_counters(key) {
+            counters.slow_storage.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Warn about and count a future passed to [Cache::wrap] for the entry
+    /// with the given key which was slower than the configured threshold.
+    fn check_slow_loader(&self, key: &[u8], took: std::time::Duration) {
+        let threshold = match self.inner.config.slow_loader_threshold {
+            Some(threshold) if took > threshold => threshold,
+            _ => return,
+        };
+
+        if let Some(suppressed) = self.inner.stats.slow_loader_warnings.check() {
+            log::warn!(
+                "{}: slow loader took {:?}, over the threshold of {:?}{}",
+                self.key_format(key),
+                took,
+                threshold,
+                suppressed_warnings(suppressed)
+            );
+        }
+
+        if let Ok(Some(counters)) = self.entry_counters(key) {
+            counters.slow_loads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a read of the entry with the given key, and call the hooks of its
+    /// outcome.
+    fn read<T>(&self, key: &[u8], state: &State<T>) {
+        self.inner.counters.read(state);
+
+        if let State::Fresh(stored) | State::Expired(stored) = state {
+            if let Ok(Some(counters)) = self.entry_counters(key) {
+                counters.access(stored, self.now());
+            }
+        }
+
+        let hooks = &self.inner.config.hooks;
+
+        match state {
+            State::Fresh(stored) => self.hook(&hooks.hit, key, Some(stored.hook_meta())),
+            State::Expired(stored) => self.hook(&hooks.miss, key, Some(stored.hook_meta())),
+            State::Missing => self.hook(&hooks.miss, key, None),
+            State::Corrupt => self.hook(&hooks.corrupt, key, None),
+        }
+    }
+
+    /// Explain a decision made about the entry with the given key, if
+    /// [Builder::explain] is configured.
+    fn explain<F>(&self, key: &[u8], reason: F)
+    where
+        F: FnOnce() -> explain::Reason,
+    {
+        let explain = match &self.inner.config.explain {
+            Some(explain) => explain,
+            None => return,
+        };
+
+        let (ns, json_key) = match self.redacted_key_json(key, "explain") {
+            Some(key) => key,
+            None => return,
+        };
+
+        explain(&explain::Explanation {
+            ns,
+            key: json_key,
+            reason: reason(),
+        });
+    }
+
+    /// Call the given hooks with the entry with the given key.
+    fn hook(&self, hooks: &[HookFn], key: &[u8], meta: Option<HookMeta<'_>>) {
+        if hooks.is_empty() {
+            return;
+        }
+
+        let (ns, json_key) = match self.redacted_key_json(key, "call hooks") {
+            Some(key) => key,
+            None => return,
+        };
+
+        let event = HookEvent {
+            ns,
+            key: json_key,
+            expires_at: meta.and_then(|meta| meta.expires_at),
+            stored_at: meta.and_then(|meta| meta.stored_at),
+            metadata: meta.map(|meta| meta.metadata.clone()).unwrap_or_default(),
+        };
+
+        for hook in hooks {
+            hook(&event);
+        }
+    }
+
+    /// Iterate over the records in the audit log, oldest first, starting with
+    /// the record identified by `from`.
+    ///
+    /// Pass `0` to read the log from the beginning, or one more than the
+    /// identifier of the last record consumed to resume where a previous
+    /// reader left off. The log is shared by every handle of the cache, so
+    /// records aren't limited to the namespace of this handle. Fails with
+    /// [Error::NoAuditLog] unless [Builder::audit_log] is configured.
+    pub fn audit_log(&self, from: u64) -> Result<AuditLog, Error> {
+        let audit_log = self.audit_tree()?;
+
+        Ok(AuditLog {
+            iter: audit_log.range(from.to_be_bytes()..),
+        })
+    }
+
+    /// Follow the records appended to the audit log from now on.
+    ///
+    /// Records are delivered in the order they were appended. Use
+    /// [Cache::audit_log] to read the records which were already appended.
+    /// Fails with [Error::NoAuditLog] unless [Builder::audit_log] is
+    /// configured.
+    pub fn watch_audit_log(&self) -> Result<AuditStream, Error> {
+        let audit_log = self.audit_tree()?;
+
+        Ok(AuditStream {
+            subscriber: audit_log.watch_prefix(Vec::new()),
+        })
+    }
+
+    /// Remove the records in the audit log which are identified by less than
+    /// `before`, returning the number of records removed.
+    ///
+    /// Fails with [Error::NoAuditLog] unless [Builder::audit_log] is
+    /// configured.
+    pub fn trim_audit_log(&self, before: u64) -> Result<usize, Error> {
+        let audit_log = self.audit_tree()?;
+        let mut removed = 0;
+
+        for result in audit_log
+            .range(0u64.to_be_bytes()..before.to_be_bytes())
+            .keys()
+        {
+            audit_log.remove(result?)?;
+            removed += 1;
+        }
+
+        log::trace!("trim-audit-log -> {}", removed);
+        Ok(removed)
+    }
+
+    /// The tree configured with [Builder::audit_log].
+    fn audit_tree(&self) -> Result<&sled::Tree, Error> {
+        self.inner
+            .config
+            .audit_log
+            .as_ref()
+            .ok_or(Error::NoAuditLog)
+    }
+
+    /// Allocate a unique identifier for the chunks of an entry.
+    fn next_chunk_id(&self) -> Result<u64, Error> {
+        let value = self
+            .inner
+            .db
+            .update_and_fetch(meta_key(CHUNK_ID_KEY), |value| {
+                let id = value.map(decode_counter).unwrap_or_default();
+                Some((id + 1).to_be_bytes().to_vec())
+            })?;
+
+        Ok(value.as_deref().map(decode_counter).unwrap_or_default())
+    }
+
+    /// Read and reassemble the value of a chunked entry.
+    ///
+    /// Returns `None` if the entry was modified or removed while it was being
+    /// read, since its chunks might have been removed as well.
+    fn load_chunks(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u8,
+        chunks: &Chunks,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut loaded = Vec::with_capacity(chunks.count as usize);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut len = 0;
+
+        for index in 0..chunks.count {
+            match self.inner.db.get(chunk_key(key, chunks.id, index))? {
+                Some(chunk) => {
+                    hasher.update(&chunk);
+                    len += chunk.len() as u64;
+                    loaded.push(chunk);
+                }
+                None => {
+                    if self.inner.db.get(key)?.as_deref() != Some(value) {
+                        return Ok(None);
+                    }
+
+                    return Err(Error::Corrupt);
+                }
+            }
+        }
+
+        if len != chunks.len || hasher.finalize() != chunks.checksum {
+            return Err(Error::Corrupt);
+        }
+
+        let mut out = Vec::with_capacity(usize::try_from(len).unwrap_or_default());
+
+        for (chunk, index) in loaded.into_iter().zip(0..) {
+            if flags & FLAG_CHUNKS_ENCRYPTED != 0 {
+                let chunk = decrypt(
+                    &self.inner.config,
+                    &chunk_key(key, chunks.id, index),
+                    0,
+                    FLAG_ENCRYPTED,
+                    Cow::Borrowed(&chunk),
+                )?;
+                out.extend_from_slice(&chunk);
+            } else {
+                out.extend_from_slice(&chunk);
+            }
+        }
+
+        Ok(Some(out))
+    }
+
+    /// Decode an entry, deserializing its value with the format it was stored
+    /// with and reassembling the value of chunked entries.
+    ///
+    /// Returns `None` if a chunked entry was modified while it was being read.
+    fn decode_entry<T>(&self, key: &[u8], value: &[u8]) -> Result<Option<StoredEntry<T>>, Error>
+    where
+        T: 'static + serde::de::DeserializeOwned,
+    {
+        match self.load_payload(key, value)? {
+            Some((meta, payload)) => Ok(Some(decode_payload(&self.inner.config, meta, payload)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Split an entry into its metadata and serialized value, reassembling the
+    /// value of chunked entries, loading deduplicated values and decrypting
+    /// them.
+    ///
+    /// Returns `None` if the entry was modified while it was being read.
+    fn load_payload<'a>(
+        &self,
+        key: &[u8],
+        value: &'a [u8],
+    ) -> Result<Option<LoadedEntry<'a>>, Error> {
+        let (mut meta, payload) = split_entry(value)?;
+
+        let payload = match (meta.chunks, meta.blob.clone()) {
+            (Some(chunks), _) => match self.load_chunks(key, value, meta.flags, &chunks)? {
+                Some(payload) => {
+                    let payload = Cow::Owned(payload);
+                    decrypt(&self.inner.config, key, meta.format, meta.flags, payload)?
+                }
+                None => return Ok(None),
+            },
+            (None, Some(hash)) => match self.load_blob(key, value, &hash)? {
+                Some((flags, blob, range)) => {
+                    meta.flags = flags;
+                    let payload = Cow::Owned(blob[range].to_vec());
+                    let blob_key = blob_key(&hash);
+                    decrypt(&self.inner.config, &blob_key, meta.format, flags, payload)?
+                }
+                None => return Ok(None),
+            },
+            (None, None) => decrypt(&self.inner.config, key, meta.format, meta.flags, payload)?,
+        };
+
+        meta.flags &= !FLAG_ENCRYPTED;
+        Ok(Some((meta, payload)))
+    }
+
+    /// Pin an existing entry, returning `false` if the entry does not exist.
+    ///
+    /// Pinned entries are never removed by cleanup, even once they have
+    /// expired. They are still reported as [State::Expired] so that they can be
+    /// refreshed, but the last known value is retained until the entry is
+    /// explicitly deleted or unpinned.
+    pub fn pin<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.set_pinned(&key, true)
+    }
+
+    /// Unpin an existing entry, returning `false` if the entry does not exist.
+    pub fn unpin<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.set_pinned(&key, false)
+    }
+
+    /// Update the pinned flag of the entry with the given key.
+    fn set_pinned(&self, key: &[u8], pinned: bool) -> Result<bool, Error> {
+        let updated = self.modify(key, |stored| {
+            stored.pinned = pinned;
+            true
+        })?;
+
+        log::trace!("pin:{} -> {}", self.key_format(key), pinned);
+        Ok(updated)
+    }
+
+    /// Soft delete the given key, replacing it with a tombstone.
+    ///
+    /// The entry is treated as missing, but can be restored with
+    /// [Cache::undelete] until the tombstone is garbage collected by a cleanup
+    /// once the retention configured with [Builder::tombstone_retention] has
+    /// passed.
+    ///
+    /// Returns `false` if there was no live entry to delete.
+    pub fn soft_delete<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let now = self.now();
+
+        let deleted = self.modify(&key, |stored| {
+            if stored.deleted_at.is_some() {
+                return false;
+            }
+
+            stored.deleted_at = Some(now);
+            true
+        })?;
+
+        if deleted {
+            self.record(AuditOp::Delete, &key)?;
+        }
+
+        log::trace!("soft-delete:{} -> {}", self.key_format(&key), deleted);
+        Ok(deleted)
+    }
+
+    /// Restore an entry which was deleted with [Cache::soft_delete].
+    ///
+    /// Returns `false` if there was no tombstone to restore, or if the
+    /// retention window of the tombstone has passed.
+    pub fn undelete<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let retention = self.inner.config.tombstone_retention();
+        let now = self.now();
+
+        let restored = self.modify(&key, |stored| match stored.deleted_at {
+            Some(deleted_at) if deleted_at + retention >= now => {
+                stored.deleted_at = None;
+                true
+            }
+            _ => false,
+        })?;
+
+        if restored {
+            self.record(AuditOp::Insert, &key)?;
+        }
+
+        log::trace!("undelete:{} -> {}", self.key_format(&key), restored);
+        Ok(restored)
+    }
+
+    /// Extend the expiration of an entry so that it expires `age` from now,
+    /// without deserializing or rewriting its value.
+    ///
+    /// Returns `false` if there was no fresh entry to touch. Entries which have
+    /// already expired have to be inserted again.
+    pub fn touch<K>(&self, key: K, age: Duration) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+        let now = self.now();
+        let epoch = self.epoch();
+
+        let expires_at = now
+            .checked_add_signed(age)
+            .ok_or(Error::TtlOutOfRange(age))?;
+
+        let touched = self.modify(&key, |stored| {
+            if stored.deleted_at.is_some() || stored.is_expired(now, epoch) {
+                return false;
+            }
+
+            stored.expires_at = Some(expires_at);
+            true
+        })?;
+
+        log::trace!("touch:{} -> {}", self.key_format(&key), touched);
+        Ok(touched)
+    }
+
+    /// Extend the expiration of a fresh entry with a sliding expiration after
+    /// it has been read, updating `expires_at` to the new expiration.
+    fn slide(&self, key: &[u8], sliding: i64, expires_at: &mut Option<DateTime<Utc>>) {
+        if self.inner.config.read_only {
+            return;
+        }
+
+        let idle = match idle_timeout(sliding) {
+            Some(idle) => idle,
+            None => return,
+        };
+
+        let now = self.now();
+        let epoch = self.epoch();
+        let extended = now + idle;
+        // extending the expiration rewrites the entry, so it's only extended
+        // once a tenth of the idle timeout has passed.
+        let threshold = extended - idle / 10;
+
+        let result = self.modify(key, |stored| {
+            if stored.deleted_at.is_some()
+                || stored.is_expired(now, epoch)
+                || !matches!(stored.expires_at, Some(expires_at) if expires_at < threshold)
+            {
+                return false;
+            }
+
+            stored.expires_at = Some(extended);
+            true
+        });
+
+        match result {
+            Ok(true) => {
+                log::trace!("slide:{} -> {}", self.key_format(key), extended);
+                *expires_at = Some(extended);
+            }
+            Ok(false) => (),
+            Err(e) => log::warn!(
+                "{}: failed to extend expiration: {}",
+                self.key_format(key),
+                e
+            ),
+        }
+    }
+
+    /// Atomically modify the stored entry with the given key without knowing
+    /// the type of its value.
+    ///
+    /// Returns what the callback returns, or `false` if the entry does not
+    /// exist. The entry is only written back if the callback returns `true`
+    /// and actually changed it.
+    fn modify<F>(&self, key: &[u8], mut f: F) -> Result<bool, Error>
+    where
+        F: FnMut(&mut PartialStoredEntry) -> bool,
+    {
+        loop {
+            let value = match self.inner.db.get(key)? {
+                Some(value) => value,
+                None => return Ok(false),
+            };
+
+            let (mut stored, payload) = split_entry(&value)?;
+
+            if !f(&mut stored) {
+                return Ok(false);
+            }
+
+            let modified = join_entry(stored.format, stored.flags, &stored, &payload)?;
+
+            if value == modified {
+                return Ok(true);
+            }
+
+            let result = self
+                .inner
+                .db
+                .compare_and_swap(key, Some(&value), Some(modified))?;
+
+            if result.is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Rewrite an entry which is stored in an older layout or in a different
+    /// format than the one configured, unless it has been modified since it was
+    /// read.
+    ///
+    /// Returns `true` if the entry was rewritten.
+    fn repair(&self, key: &[u8], value: &[u8]) -> Result<bool, Error> {
+        let format = &self.inner.format;
+        let legacy = value.first() != Some(&ENTRY_MAGIC);
+
+        // entries with a header record their format in it, so entries which
+        // are up to date are recognized without decoding them again.
+        if !legacy && value.get(3) == Some(&format.id()) {
+            return Ok(false);
+        }
+
+        let (mut meta, payload) = split_entry(value)?;
+
+        // the value of chunked, deduplicated, raw and list entries is never
+        // rewritten.
+        if meta.chunks.is_some()
+            || meta.blob.is_some()
+            || meta.list.is_some()
+            || meta.flags & FLAG_RAW != 0
+        {
+            return Ok(false);
+        }
+
+        let payload = if meta.format == format.id() {
+            if !legacy {
+                return Ok(false);
+            }
+
+            payload
+        } else {
+            match self.reencode(key, &mut meta, payload, format)? {
+                Some(payload) => Cow::Owned(payload),
+                None => return Ok(false),
+            }
+        };
+
+        let repaired = join_entry(meta.format, meta.flags, &meta, &payload)?;
+
+        let result = self
+            .inner
+            .db
+            .compare_and_swap(key, Some(value), Some(repaired))?;
+
+        log::trace!("repair:{} -> {}", self.key_format(key), result.is_ok());
+        Ok(result.is_ok())
+    }
+
+    /// Re-encode the value of an entry in the given format, compressing and
+    /// encrypting it as configured and updating the metadata to match.
+    ///
+    /// Returns `None` if the value can't be converted to the given format.
+    fn reencode(
+        &self,
+        key: &[u8],
+        meta: &mut PartialStoredEntry,
+        payload: Cow<'_, [u8]>,
+        format: &Format,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let config = &self.inner.config;
+        let raw = meta.flags & FLAG_RAW;
+        let payload = decrypt(config, key, meta.format, meta.flags, payload)?;
+
+        let payload = match convert_payload(config, meta, payload, format)? {
+            Some(payload) => payload,
+            None => return Ok(None),
+        };
+
+        let (payload, flags) = compress(self.inner.compression_threshold, payload)?;
+        let (payload, flags) = encrypt(config, key, format.id(), payload, flags | raw)?;
+        meta.format = format.id();
+        meta.flags = flags;
+        Ok(Some(payload))
+    }
+
+    /// Rewrite the entries in the namespace of this handle which are stored in
+    /// the `from` format to the `to` format, returning the progress made.
+    /// Entries in nested namespaces are left alone.
+    ///
+    /// Values are compressed and encrypted as currently configured when
+    /// they're rewritten, so migrating from a format to itself can be used to
+    /// turn on compression or encryption for existing entries. Values are
+    /// converted between formats which can be decoded without knowing the type
+    /// of the value, and entries which can't be converted are skipped, as are
+    /// chunked and deduplicated entries. Entries which are modified while
+    /// being migrated are skipped as well.
+    ///
+    /// The position of the migration is recorded in the database, so if it's
+    /// interrupted or stopped through [MigrateOptions::limit], calling this
+    /// again resumes where it left off. Unless [read repair][Builder::read_repair]
+    /// is disabled, the cache should be configured to use the `to` format, or
+    /// migrated entries are converted back as they're read.
+    pub fn migrate(
+        &self,
+        from: Format,
+        to: Format,
+        mut options: MigrateOptions<'_>,
+    ) -> Result<MigrateProgress, Error> {
+        /// How often the position of the migration is recorded.
+        const CURSOR_INTERVAL: usize = 1024;
+
+        let prefix = self.ns_prefix()?;
+        let cursor_key = migrate_cursor_key(&from, &to, &prefix);
+        let cursor = self.inner.db.get(&cursor_key)?;
+
+        let start = match &cursor {
+            Some(cursor) => cursor.to_vec(),
+            None => prefix.clone(),
+        };
+
+        let mut progress = MigrateProgress::default();
+        let mut last = None;
+
+        for result in self.inner.db.range(start..) {
+            let (key, value) = result?;
+
+            if !key.starts_with(&prefix) {
+                break;
+            }
+
+            if cursor.as_ref() == Some(&key) {
+                continue;
+            }
+
+            if let Some(limit) = options.limit {
+                if progress.processed() >= limit {
+                    if let Some(last) = last {
+                        self.inner.db.insert(&cursor_key, last)?;
+                    }
+
+                    log::trace!("migrate -> {:?}", progress);
+                    return Ok(progress);
+                }
+            }
+
+            match self.migrate_entry(&key, &value, &from, &to) {
+                Ok(true) => progress.migrated += 1,
+                Ok(false) => progress.skipped += 1,
+                Err(e) => {
+                    log::warn!("{}: failed to migrate: {}", self.key_format(&key), e);
+                    progress.failed += 1;
+                }
+            }
+
+            if progress.processed() % CURSOR_INTERVAL == 0 {
+                self.inner.db.insert(&cursor_key, &key)?;
+            }
+
+            if let Some(report) = &mut options.progress {
+                report(&progress);
+            }
+
+            last = Some(key);
+        }
+
+        self.inner.db.remove(&cursor_key)?;
+        progress.complete = true;
+        log::trace!("migrate -> {:?}", progress);
+        Ok(progress)
+    }
+
+    /// Move the entries in the namespace `from` to the namespace `to`,
+    /// returning the progress made.
+    ///
+    /// Namespaces nested inside of `from` are moved along with it, and the
+    /// tags, dependencies, indexes, chunks and retained versions of entries
+    /// are moved with them. Entries in other namespaces which are derived from
+    /// a moved entry are updated to be derived from it in its new namespace.
+    /// Entries whose key is already used in `to` are skipped and left where
+    /// they are.
+    ///
+    /// Both namespaces are nested inside of the namespace of this handle, like
+    /// with [Cache::delete_with_ns]. Finding the entries requires scanning
+    /// every entry in the database.
+    ///
+    /// Like with [Cache::migrate], the position of the rename is recorded in
+    /// the database, so if it's interrupted or stopped through
+    /// [MigrateOptions::limit], calling this again resumes where it left off.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration, MigrateOptions};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let cache = Cache::load(db.open_tree("cache")?)?;
+    /// cache.namespaced(&"accounts")?.insert("alice", Duration::hours(1), &42u32)?;
+    ///
+    /// let progress = cache.rename_namespace(&"accounts", &"users", MigrateOptions::new())?;
+    /// assert_eq!(1, progress.migrated);
+    ///
+    /// let users = cache.namespaced(&"users")?;
+    /// assert_eq!(Some(42), users.get::<_, u32>("alice")?.get());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rename_namespace<A, B>(
+        &self,
+        from: &A,
+        to: &B,
+        mut options: MigrateOptions<'_>,
+    ) -> Result<MigrateProgress, Error>
+    where
+        A: Serialize,
+        B: Serialize,
+    {
+        /// How often the position of the rename is recorded.
+        const CURSOR_INTERVAL: usize = 1024;
+
+        let from = self.nested_ns_key(Some(&hashkey::to_key(from)?.normalize()))?;
+        let to = self.nested_ns_key(Some(&hashkey::to_key(to)?.normalize()))?;
+
+        let mut progress = MigrateProgress::default();
+
+        if from == to {
+            progress.complete = true;
+            return Ok(progress);
+        }
+
+        let cursor_key = rename_cursor_key(&from, &to);
+        let cursor = self.inner.db.get(&cursor_key)?;
+
+        let iter = match &cursor {
+            Some(cursor) => self.inner.db.range(cursor.to_vec()..),
+            None => self.entries(),
+        };
+
+        let mut last = None;
+
+        for result in iter {
+            let (key, value) = result?;
+
+            if cursor.as_ref() == Some(&key) {
+                continue;
+            }
+
+            let new_key = rename_key(&key, &from, &to);
+
+            if let Some(limit) = options.limit {
+                if new_key.is_some() && progress.processed() >= limit {
+                    if let Some(last) = last {
+                        self.inner.db.insert(&cursor_key, last)?;
+                    }
+
+                    log::trace!("rename-namespace -> {:?}", progress);
+                    return Ok(progress);
+                }
+            }
+
+            let moved = self.rename_entry(&key, new_key.as_deref(), &value, &from, &to);
+
+            match (moved, new_key.is_some()) {
+                (Ok(true), true) => progress.migrated += 1,
+                (Ok(false), true) => progress.skipped += 1,
+                (Err(e), true) => {
+                    log::warn!("{}: failed to rename: {}", self.key_format(&key), e);
+                    progress.failed += 1;
+                }
+                (Err(e), false) => {
+                    log::warn!("{}: failed to update: {}", self.key_format(&key), e);
+                }
+                // entries outside of the renamed namespace aren't counted.
+                (Ok(_), false) => continue,
+            }
+
+            if progress.processed() % CURSOR_INTERVAL == 0 {
+                self.inner.db.insert(&cursor_key, &key)?;
+            }
+
+            if let Some(report) = &mut options.progress {
+                report(&progress);
+            }
+
+            last = Some(key);
+        }
+
+        self.inner.db.remove(&cursor_key)?;
+        progress.complete = true;
+        log::trace!("rename-namespace -> {:?}", progress);
+        Ok(progress)
+    }
+
+    /// Copy the entries in the namespace `from` of this cache which match
+    /// `filter` to the namespace `to` of the cache `dest`, returning the number
+    /// of entries copied.
+    ///
+    /// `dest` can be this cache or a cache stored elsewhere, like a staging
+    /// cache seeded from production. Entries keep their expiration, tags and
+    /// metadata, and replace entries with the same key in `to`. Dependencies
+    /// on entries in `from` are moved to `to` along with them, while
+    /// dependencies on entries in other namespaces are only kept if `dest` is
+    /// this cache, since they don't exist in other caches. Values are stored in the format of `dest`,
+    /// and compressed, encrypted, chunked or deduplicated as `dest` is
+    /// configured.
+    ///
+    /// Only entries which are fresh, or expired but pinned, are copied.
+    /// Entries whose key is hashed and whose original key can't be recovered
+    /// are skipped, as are lists and entries which can't be converted to the
+    /// format of `dest`. Namespaces nested inside of `from` aren't copied.
+    /// `from` is nested inside of the namespace of this handle and `to` inside
+    /// of the namespace of `dest`, like with [Cache::delete_with_ns].
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration};
+    /// use serde_json::json;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let production = Cache::load(db.open_tree("production")?)?;
+    /// let staging = Cache::load(db.open_tree("staging")?)?;
+    ///
+    /// let users = production.namespaced(&"users")?;
+    /// users.insert("alice", Duration::hours(1), &1u32)?;
+    /// users.insert("bob", Duration::hours(1), &2u32)?;
+    ///
+    /// // entries are filtered by their key, which is the namespace followed by
+    /// // the key itself.
+    /// let copied = production.copy_namespace(&"users", &staging, &"users", |entry| {
+    ///     entry.key[1] == json!("alice")
+    /// })?;
+    ///
+    /// assert_eq!(1, copied);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn copy_namespace<A, B, F>(
+        &self,
+        from: &A,
+        dest: &Cache,
+        to: &B,
+        mut filter: F,
+    ) -> Result<usize, Error>
+    where
+        A: Serialize,
+        B: Serialize,
+        F: FnMut(&JsonEntry) -> bool,
+    {
+        let from = self.nested_ns_key(Some(&hashkey::to_key(from)?.normalize()))?;
+        let to = dest.nested_ns_key(Some(&hashkey::to_key(to)?.normalize()))?;
+
+        let now = self.now();
+        let epoch = self.epoch();
+        let mut copied = 0;
+
+        for result in self.inner.db.scan_prefix(join_key(&from, &[])) {
+            let (key, value) = result?;
+
+            let meta = match decode_meta(&value) {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+
+            if meta.deleted_at.is_some()
+                || meta.list.is_some()
+                || (meta.is_expired(now, epoch) && !meta.pinned)
+            {
+                continue;
+            }
+
+            match self.json_entry(&key, &value) {
+                Some(entry) if filter(&entry) => (),
+                _ => continue,
+            }
+
+            match self.copy_entry(&key, &value, dest, &from, Some(&to), Condition::Always) {
+                Ok(CopyOutcome::Copied) => copied += 1,
+                Ok(outcome) => log::warn!(
+                    "{}: failed to copy: {}",
+                    self.key_format(&key),
+                    outcome.reason()
+                ),
+                Err(e) => log::warn!("{}: failed to copy: {}", self.key_format(&key), e),
+            }
+        }
+
+        log::trace!("copy-namespace -> {}", copied);
+        Ok(copied)
+    }
+
+    /// Merge the fresh entries of another cache into this one, resolving
+    /// conflicts with entries which are already in this cache according to
+    /// `policy`.
+    ///
+    /// Every namespace is merged regardless of the namespace of either cache,
+    /// and merged values are stored with the format of this cache. Pinned
+    /// entries are merged even if they've expired, while deleted entries and
+    /// lists aren't merged. Entries with hashed keys are only merged if their
+    /// original key can be recovered, see [Cache::keys].
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Duration, MergePolicy};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let blue = Cache::load(db.open_tree("blue")?)?;
+    /// let green = Cache::load(db.open_tree("green")?)?;
+    ///
+    /// blue.insert("a", Duration::hours(2), &1u32)?;
+    /// blue.insert("b", Duration::hours(1), &2u32)?;
+    /// green.insert("b", Duration::hours(2), &3u32)?;
+    ///
+    /// let report = green.merge_from(&blue, MergePolicy::FreshestExpiry)?;
+    /// assert_eq!((1, 1), (report.merged, report.kept));
+    /// assert_eq!(Some(1), green.get::<_, u32>("a")?.get());
+    /// assert_eq!(Some(3), green.get::<_, u32>("b")?.get());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn merge_from(&self, other: &Cache, mut policy: MergePolicy) -> Result<MergeReport, Error> {
+        let now = self.now();
+        let epoch = other.epoch();
+        let mut report = MergeReport::default();
+
+        for result in other.entries() {
+            let (key, value) = result?;
+
+            let meta = match decode_meta(&value) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    log::warn!("{}: failed to merge: {}", other.key_format(&key), e);
+                    report.failed += 1;
+                    continue;
+                }
+            };
+
+            if meta.deleted_at.is_some()
+                || meta.list.is_some()
+                || (meta.is_expired(now, epoch) && !meta.pinned)
+            {
+                continue;
+            }
+
+            match self.merge_entry(other, &key, &value, &meta, &mut policy) {
+                Ok(CopyOutcome::Copied) => report.merged += 1,
+                Ok(CopyOutcome::Kept) => report.kept += 1,
+                Ok(outcome) => {
+                    log::warn!(
+                        "{}: failed to merge: {}",
+                        other.key_format(&key),
+                        outcome.reason()
+                    );
+                    report.failed += 1;
+                }
+                Err(e) => {
+                    log::warn!("{}: failed to merge: {}", other.key_format(&key), e);
+                    report.failed += 1;
+                }
+            }
+        }
+
+        log::trace!("merge -> {:?}", report);
+        Ok(report)
+    }
+
+    /// Merge a single entry for [Cache::merge_from].
+    fn merge_entry(
+        &self,
+        other: &Cache,
+        key: &[u8],
+        value: &[u8],
+        meta: &PartialStoredEntry,
+        policy: &mut MergePolicy,
+    ) -> Result<CopyOutcome, Error> {
+        let plain_key = match other.encoded_plain_key(key, meta)? {
+            Some(plain_key) => plain_key,
+            None => return Ok(CopyOutcome::UnrecoverableKey),
+        };
+
+        let (ns, _) = split_key(key)?;
+        let dest_key = self.storage_key_with(ns, &plain_key);
+        let existing = self.inner.db.get(&dest_key)?;
+
+        let existing_meta = existing
+            .as_deref()
+            .and_then(|existing| decode_meta(existing).ok())
+            .filter(|existing| {
+                existing.deleted_at.is_none() && !existing.is_expired(self.now(), self.epoch())
+            });
+
+        if let (Some(existing), Some(existing_meta)) = (&existing, existing_meta) {
+            let replace = match policy {
+                MergePolicy::FreshestExpiry => match (existing_meta.expires_at, meta.expires_at) {
+                    (Some(existing), Some(merged)) => merged > existing,
+                    (Some(..), None) => true,
+                    (None, _) => false,
+                },
+                MergePolicy::KeepExisting => false,
+                MergePolicy::Overwrite => true,
+                MergePolicy::Custom(replace) => {
+                    match (
+                        self.json_entry(&dest_key, existing),
+                        other.json_entry(key, value),
+                    ) {
+                        (Some(existing), Some(merged)) => replace(&existing, &merged),
+                        _ => false,
+                    }
+                }
+            };
+
+            if !replace {
+                return Ok(CopyOutcome::Kept);
+            }
+        }
+
+        let condition = Condition::Unchanged(existing.as_deref());
+        other.copy_entry(key, value, self, ns, None, condition)
+    }
+
+    /// Compare the entries of this cache with the entries of another cache,
+    /// iterating over the differences in the order of their keys.
+    ///
+    /// Both databases are scanned side by side, so only one entry of each is
+    /// held in memory at a time. Values are compared as JSON, so entries
+    /// stored with different formats, compression, or encryption are equal if
+    /// their values are. Deleted entries and lists are treated as missing,
+    /// while expired entries which haven't been cleaned up yet are compared
+    /// like any other entry.
+    ///
+    /// Entries are matched by the key they're stored under, so both caches
+    /// have to be configured to [hash keys][Builder::hash_keys] the same way.
+    ///
+    /// ```rust
+    /// use futures_cache::{Cache, Difference, Duration};
+    /// use serde_json::json;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let a = Cache::load(db.open_tree("a")?)?;
+    /// let b = Cache::load(db.open_tree("b")?)?;
+    ///
+    /// a.insert_forever("x", &1u32)?;
+    /// b.insert_forever("x", &1u32)?;
+    /// a.insert_forever("y", &2u32)?;
+    ///
+    /// let diff = a.diff(&b).collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(vec![Difference::OnlyInThis { key: json!([null, "y"]) }], diff);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff(&self, other: &Cache) -> Diff {
+        Diff {
+            this: self.clone(),
+            other: other.clone(),
+            left: self.entries().peekable(),
+            right: other.entries().peekable(),
+        }
+    }
+
+    /// Decode the key of an entry reported by [Cache::diff], falling back to
+    /// the hex-encoded key if it's malformed.
+    fn diff_key(&self, key: &[u8], meta: Option<&PartialStoredEntry>) -> json::Value {
+        let original_key = meta.and_then(|meta| meta.original_key.as_ref());
+
+        match self.json_key(key, original_key.map(|key| key.as_slice())) {
+            Ok(key) => key,
+            Err(_) => json::Value::String(key.encode_hex()),
+        }
+    }
+
+    /// Recover the encoded key an entry was stored with before it was hashed.
+    ///
+    /// Returns `None` if the key is hashed and its original key can't be
+    /// recovered.
+    fn encoded_plain_key(
+        &self,
+        key: &[u8],
+        meta: &PartialStoredEntry,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (_, stored_key) = split_key(key)?;
+
+        match &meta.original_key {
+            Some(original_key) => Ok(Some(
+                self.decode_original_key(key, Cow::Borrowed(original_key.as_slice()))?
+                    .into_owned(),
+            )),
+            None if stored_key.first() == Some(&key::HASHED) => Ok(None),
+            None => Ok(Some(stored_key.to_vec())),
+        }
+    }
+
+    /// Copy a single entry in the namespace `from` for [Cache::copy_namespace]
+    /// and [Cache::merge_from], writing it under the given condition.
+    ///
+    /// The entry is copied to the namespace `to`, or to the same namespace if
+    /// it's `None`, in which case every namespace is being copied and its
+    /// dependencies are kept as they are. Otherwise only dependencies on
+    /// entries in `from` are kept and moved to `to`, along with any others if
+    /// `dest` is this cache, since they don't exist in other caches.
+    fn copy_entry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        dest: &Cache,
+        from: &[u8],
+        to: Option<&[u8]>,
+        condition: Condition<'_>,
+    ) -> Result<CopyOutcome, Error> {
+        let (meta, payload) = match self.load_payload(key, value)? {
+            Some(loaded) => loaded,
+            None => return Ok(CopyOutcome::Modified),
+        };
+
+        let plain_key = match self.encoded_plain_key(key, &meta)? {
+            Some(plain_key) => plain_key,
+            None => return Ok(CopyOutcome::UnrecoverableKey),
+        };
+
+        let payload = match convert_payload(&self.inner.config, &meta, payload, &dest.inner.format)?
+        {
+            Some(payload) => payload,
+            None => return Ok(CopyOutcome::Unconvertible),
+        };
+
+        let raw = meta.flags & FLAG_RAW;
+        let (payload, flags) = compress(dest.inner.compression_threshold, payload)?;
+
+        let dest_key = dest.storage_key_with(to.unwrap_or(from), &plain_key);
+        let tags = meta.tags.iter().map(String::as_str).collect::<Vec<_>>();
+        let same_cache = Arc::ptr_eq(&self.inner.stats, &dest.inner.stats);
+
+        let deps = match to {
+            Some(to) => meta
+                .deps
+                .iter()
+                .filter_map(|dep| match split_key(dep) {
+                    Ok((ns, key)) if ns == from => Some(ByteBuf::from(join_key(to, key))),
+                    _ if same_cache => Some(dep.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            None => meta.deps.clone(),
+        };
+
+        let options = InsertOptions {
+            pinned: meta.pinned,
+            sliding: meta.sliding != 0,
+            tags: &tags,
+            deps: &deps,
+            indexes: &meta.indexes,
+            plain_key: Some(&plain_key),
+            schema: meta.schema,
+            expires_at: meta.expires_at,
+            forever: meta.expires_at.is_none(),
+            metadata: Some(&meta.metadata),
+            condition,
+        };
+
+        let age = Duration::milliseconds(meta.sliding);
+
+        match dest.write_payload(&dest_key, age, payload, flags | raw, options)? {
+            Written::Replaced(previous) => dest.remove_replaced(&dest_key, previous, options)?,
+            Written::Rejected(..) => return Ok(CopyOutcome::Modified),
+        }
+
+        Ok(CopyOutcome::Copied)
+    }
+
+    /// Move a single entry for [Cache::rename_namespace] to `new_key`, or
+    /// update its dependencies in place if it isn't moved.
+    ///
+    /// This happens in a transaction, so an entry which is inserted under
+    /// `new_key` concurrently is never overwritten, and an entry which is
+    /// modified since it was read is left alone.
+    ///
+    /// Returns `true` if the entry was modified.
+    fn rename_entry(
+        &self,
+        key: &[u8],
+        new_key: Option<&[u8]>,
+        value: &[u8],
+        from: &[u8],
+        to: &[u8],
+    ) -> Result<bool, Error> {
+        let (mut entry, payload) = split_entry(value)?;
+
+        let renamed_deps = entry
+            .deps
+            .iter()
+            .map(|dep| rename_key(dep, from, to))
+            .collect::<Vec<_>>();
+
+        let new_key = match new_key {
+            Some(new_key) => new_key,
+            None if renamed_deps.iter().all(Option::is_none) => return Ok(false),
+            None => key,
+        };
+
+        let mut batch = sled::Batch::default();
+        let mut chunks = Vec::new();
+        let mut payload = payload;
+
+        for tag in &entry.tags {
+            batch.remove(tag_key(tag, key));
+            batch.insert(tag_key(tag, new_key), &[]);
+        }
+
+        for (name, indexed) in &entry.indexes {
+            batch.remove(index_key(name, indexed, key));
+            batch.insert(index_key(name, indexed, new_key), &[]);
+        }
+
+        for (dep, renamed) in entry.deps.iter_mut().zip(renamed_deps) {
+            batch.remove(dep_key(dep, key));
+
+            if let Some(renamed) = renamed {
+                *dep = ByteBuf::from(renamed);
+            }
+
+            batch.insert(dep_key(dep, new_key), &[]);
+        }
+
+        if new_key != key {
+            for (chunk_key, new_chunk_key) in
+                chunk_keys(key, &entry).zip(chunk_keys(new_key, &entry))
+            {
+                if let Some(chunk) = self.inner.db.get(&chunk_key)? {
+                    chunks.push((chunk_key, new_chunk_key, chunk.to_vec()));
+                }
+            }
+
+            // encrypted values are bound to the key they're stored under, so
+            // they have to be encrypted again for their new key.
+            self.reencrypt_chunks(key, new_key, &mut entry, &mut chunks)?;
+            payload = self.reencrypt_entry(key, new_key, &mut entry, payload)?;
+
+            let prefix = history_prefix(key);
+
+            for result in self.inner.db.scan_prefix(&prefix) {
+                let (history_key, version) = result?;
+                let mut new_history_key = history_prefix(new_key);
+                new_history_key.extend_from_slice(&history_key[prefix.len()..]);
+
+                let (mut meta, payload) = split_entry(&version)?;
+                let payload = self.reencrypt_entry(key, new_key, &mut meta, payload)?;
+                let version = join_entry(meta.format, meta.flags, &meta, &payload)?;
+
+                batch.insert(new_history_key, version);
+                batch.remove(history_key);
+            }
+
+            batch.remove(key);
+        }
+
+        batch.insert(
+            new_key,
+            join_entry(entry.format, entry.flags, &entry, &payload)?,
+        );
+
+        let renamed = self.inner.db.transaction(|tx| {
+            if tx.get(key)?.as_deref() != Some(value) {
+                return Ok(false);
+            }
+
+            if new_key != key && tx.get(new_key)?.is_some() {
+                return Ok(false);
+            }
+
+            for (chunk_key, new_chunk_key, chunk) in &chunks {
+                tx.remove(chunk_key.as_slice())?;
+                tx.insert(new_chunk_key.as_slice(), chunk.as_slice())?;
+            }
+
+            tx.apply_batch(&batch)?;
+            Ok::<_, ConflictableTransactionError<Error>>(true)
+        })?;
+
+        Ok(renamed)
+    }
+
+    /// Re-encrypt the original key and the value stored in an entry which is
+    /// moved from `from` to `to`.
+    ///
+    /// Values which are stored in chunks or deduplicated are left as they are.
+    fn reencrypt_entry<'a>(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        meta: &mut PartialStoredEntry,
+        payload: Cow<'a, [u8]>,
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        let config = &self.inner.config;
+
+        if config.hashes_keys() {
+            if let Some(original_key) = &mut meta.original_key {
+                let encrypted = reencrypt(config, from, to, 0, FLAG_ENCRYPTED, original_key)?;
+                *original_key = ByteBuf::from(encrypted);
+            }
+        }
+
+        if meta.chunks.is_some() || meta.flags & FLAG_ENCRYPTED == 0 {
+            return Ok(payload);
+        }
+
+        let encrypted = reencrypt(config, from, to, meta.format, meta.flags, &payload)?;
+        Ok(Cow::Owned(encrypted))
+    }
+
+    /// Re-encrypt the chunks of an entry which is moved from `from` to `to`,
+    /// given as their current key, their new key, and their contents.
+    ///
+    /// Re-encrypting a value doesn't change its length, so values which were
+    /// encrypted as a whole are split along the same boundaries again.
+    fn reencrypt_chunks(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        meta: &mut PartialStoredEntry,
+        chunks: &mut [(Vec<u8>, Vec<u8>, Vec<u8>)],
+    ) -> Result<(), Error> {
+        let config = &self.inner.config;
+
+        if meta.list.is_some() {
+            for (chunk_key, new_chunk_key, item) in chunks.iter_mut() {
+                let flags = match item.first() {
+                    Some(&flags) if flags & FLAG_ENCRYPTED != 0 => flags,
+                    _ => continue,
+                };
+
+                let payload = reencrypt(
+                    config,
+                    chunk_key,
+                    new_chunk_key,
+                    meta.format,
+                    flags,
+                    &item[1..],
+                )?;
+                item.truncate(1);
+                item.extend_from_slice(&payload);
+            }
+
+            return Ok(());
+        }
+
+        let stored = match &mut meta.chunks {
+            Some(stored) => stored,
+            None => return Ok(()),
+        };
+
+        if meta.flags & FLAG_CHUNKS_ENCRYPTED != 0 {
+            for (chunk_key, new_chunk_key, chunk) in chunks.iter_mut() {
+                *chunk = reencrypt(config, chunk_key, new_chunk_key, 0, FLAG_ENCRYPTED, chunk)?;
+            }
+        } else if meta.flags & FLAG_ENCRYPTED != 0 {
+            let value = chunks
+                .iter()
+                .flat_map(|(_, _, chunk)| chunk.iter().copied())
+                .collect::<Vec<_>>();
+            let value = reencrypt(config, from, to, meta.format, meta.flags, &value)?;
+            let mut rest = &value[..];
+
+            for (_, _, chunk) in chunks.iter_mut() {
+                let (head, tail) = rest.split_at(chunk.len().min(rest.len()));
+                head.clone_into(chunk);
+                rest = tail;
+            }
+        } else {
+            return Ok(());
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+
+        for (_, _, chunk) in chunks.iter() {
+            hasher.update(chunk);
+        }
+
+        stored.checksum = hasher.finalize();
+        Ok(())
+    }
+
+    /// Rewrite a single entry for [Cache::migrate], unless it has been
+    /// modified since it was read.
+    ///
+    /// Returns `true` if the entry was rewritten.
+    fn migrate_entry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        from: &Format,
+        to: &Format,
+    ) -> Result<bool, Error> {
+        let (mut meta, payload) = split_entry(value)?;
+
+        if meta.format != from.id()
+            || meta.chunks.is_some()
+            || meta.blob.is_some()
+            || meta.list.is_some()
+        {
+            return Ok(false);
+        }
+
+        let payload = match self.reencode(key, &mut meta, payload, to)? {
+            Some(payload) => payload,
+            None => return Ok(false),
+        };
+
+        let migrated = join_entry(meta.format, meta.flags, &meta, &payload)?;
+
+        let result = self
+            .inner
+            .db
+            .compare_and_swap(key, Some(value), Some(migrated))?;
+
+        Ok(result.is_ok())
+    }
+
+    /// Insert a value into the cache.
+    #[inline(always)]
+    fn inner_insert<T>(
+        &self,
+        key: &[u8],
+        mut age: Duration,
+        value: &T,
+        mut options: InsertOptions<'_>,
+    ) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        if let Some(ttl) = self.inner.policy.negative_ttl {
+            if json::to_value(value).is_ok_and(|value| value.is_null()) {
+                if ttl <= Duration::zero() {
+                    log::trace!("insert:{} -> skipped (negative)", self.key_format(key));
+                    return Ok(());
+                }
+
+                age = self.inner.config.ttl(ttl)?;
+                options.expires_at = None;
+                options.forever = false;
+            }
+        }
+
+        let written = self
+            .write_value(key, age, value, options)
+            .map_err(|e| self.entry_error(Some(key), true, e))?;
+
+        match written {
+            Written::Replaced(previous) => self.remove_replaced(key, previous, options),
+            Written::Rejected(..) => Ok(()),
+        }
+    }
+
+    /// Serialize a value and write it into the cache.
+    ///
+    /// What belonged to the entry it replaced has to be removed with
+    /// [Cache::remove_replaced] afterwards.
+    fn write_value<T>(
+        &self,
+        key: &[u8],
+        age: Duration,
+        value: &T,
+        options: InsertOptions<'_>,
+    ) -> Result<Written, Error>
+    where
+        T: Serialize,
+    {
+        let (payload, flags) =
+            match encode_value(self.inner.compression_threshold, &self.inner.format, value) {
+                Ok(value) => value,
+                Err(e) => {
+                    log::trace!("store:{} *errored*", self.key_format(key));
+                    return Err(e);
+                }
+            };
+
+        let indexes = self.extract_indexes(value)?;
+
+        let options = InsertOptions {
+            schema: self.inner.config.schema::<T>().map_or(0, |s| s.version),
+            indexes: &indexes,
+            ..options
+        };
+
+        self.write_payload(key, age, payload, flags, options)
+    }
+
+    /// Extract the secondary indexes registered for values of type `T` from
+    /// a value.
+    ///
+    /// Like schemas, indexes are looked up with [typeid::of], since values
+    /// are written through references to types which aren't necessarily
+    /// `'static`.
+    fn extract_indexes<T>(&self, value: &T) -> Result<Vec<(String, ByteBuf)>, Error>
+    where
+        T: Serialize,
+    {
+        let indexes = match self.inner.config.indexes.get(&typeid::of::<T>()) {
+            Some(indexes) => indexes,
+            None => return Ok(Vec::new()),
+        };
+
+        let value = json::to_value(value)?;
+        let mut out = Vec::new();
+
+        for index in indexes {
+            if let Some(indexed) = (index.extract)(&value)? {
+                out.push((index.name.clone(), ByteBuf::from(indexed)));
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Insert a serialized value into the cache, encrypting it as configured.
+    fn insert_payload(
+        &self,
+        key: &[u8],
+        age: Duration,
+        payload: Vec<u8>,
+        flags: u8,
+        options: InsertOptions<'_>,
+    ) -> Result<(), Error> {
+        match self.write_payload(key, age, payload, flags, options)? {
+            Written::Replaced(previous) => self.remove_replaced(key, previous, options),
+            Written::Rejected(..) => Ok(()),
+        }
+    }
+
+    /// Write a serialized value into the cache, encrypting it as configured.
+    ///
+    /// The value is either deduplicated, split into chunks if it's large, or
+    /// stored in the entry. Nothing is written unless the condition in
+    /// [InsertOptions::condition] holds.
+    fn write_payload(
+        &self,
+        key: &[u8],
+        age: Duration,
+        payload: Vec<u8>,
+        flags: u8,
+        options: InsertOptions<'_>,
+    ) -> Result<Written, Error> {
+        self.record_insert(|| self.store_payload(key, age, payload, flags, options))
+    }
+
+    /// Write multiple serialized values into the cache in a single
+    /// transaction, returning the entries they replaced.
+    ///
+    /// Unlike with [Cache::write_payload], the values are written regardless
+    /// of their [InsertOptions::condition], and their tags, dependencies and
+    /// indexes are written in the same transaction.
+    fn write_payloads(
+        &self,
+        writes: Vec<PendingWrite<'_>>,
+    ) -> Result<Vec<Option<sled::IVec>>, Error> {
+        self.record_insert(|| {
+            let mut prepared = Vec::with_capacity(writes.len());
+
+            for PendingWrite {
+                key,
+                age,
+                payload,
+                flags,
+                options,
+            } in writes
+            {
+                let entry = self.prepare_write(key, age, payload, flags, &options)?;
+                prepared.push((key, entry, options));
+            }
+
+            log::trace!("store-many: {} entries", prepared.len());
+
+            let started = Instant::now();
+
+            let result = self.inner.db.transaction(|tx| {
+                prepared
+                    .iter()
+                    .map(|(key, entry, options)| {
+                        options.write_records(tx, key)?;
+                        entry.write(tx, key)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            });
+
+            self.inner
+                .stats
+                .storage_write_latency
+                .record(started.elapsed());
+            Ok(result?)
+        })
+    }
+
+    /// Write entries into the cache in a trace span, counting how long it took
+    /// and whether it failed.
+    fn record_insert<T, F>(&self, write: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let span = trace::span(trace::Op::Insert, || self.ns_json());
+
+        trace::in_span(&span, || {
+            let started = Instant::now();
+            let result = write();
+            self.inner.stats.insert_latency.record(started.elapsed());
+            self.inner.stats.error(&result);
+            result
+        })
+    }
+
+    /// Store a serialized value, see [Cache::write_payload].
+    fn store_payload(
+        &self,
+        key: &[u8],
+        age: Duration,
+        payload: Vec<u8>,
+        flags: u8,
+        options: InsertOptions<'_>,
+    ) -> Result<Written, Error> {
+        let prepared = self.prepare_write(key, age, payload, flags, &options)?;
+        log::trace!("store:{}", self.key_format(key));
+
+        let started = Instant::now();
+        let result = self.write_prepared(key, prepared, &options);
+        let took = started.elapsed();
+        self.inner.stats.storage_write_latency.record(took);
+        self.check_slow_storage(key, "write", took);
+        result
+    }
+
+    /// Prepare an entry to write, applying the configured faults to it.
+    fn prepare_write(
+        &self,
+        key: &[u8],
+        age: Duration,
+        payload: Vec<u8>,
+        flags: u8,
+        options: &InsertOptions<'_>,
+    ) -> Result<Prepared, Error> {
+        #[cfg_attr(not(feature = "testing"), allow(unused_mut))]
+        let mut prepared = self.prepare_payload(key, age, payload, flags, options)?;
+
+        #[cfg(feature = "testing")]
+        if let Some(faults) = &self.inner.config.faults {
+            match faults.on_write() {
+                faults::Action::Fail => return Err(faults::error().into()),
+                faults::Action::Corrupt => faults::corrupt(&mut prepared.value),
+                faults::Action::Pass => (),
+            }
+        }
+
+        Ok(prepared)
+    }
+
+    /// Write a prepared entry along with its tags, dependencies and indexes,
+    /// see [Cache::store_payload].
+    fn write_prepared(
+        &self,
+        key: &[u8],
+        prepared: Prepared,
+        options: &InsertOptions<'_>,
+    ) -> Result<Written, Error> {
+        // Write indexes before the entry, so that an index never misses an
+        // entry.
+        for tag in options.tags {
+            self.inner.db.insert(tag_key(tag, key), &[])?;
+        }
+
+        for dep in options.deps {
+            self.inner.db.insert(dep_key(dep, key), &[])?;
+        }
+
+        for (name, indexed) in options.indexes {
+            self.inner.db.insert(index_key(name, indexed, key), &[])?;
+        }
+
+        let now = self.now();
+        let epoch = self.epoch();
+
+        // Test the condition, returning the current entry if it doesn't hold.
+        let rejected = |tx: &TransactionalTree| -> Result<_, ConflictableTransactionError<Error>> {
+            match options.condition {
+                Condition::Always => Ok(None),
+                Condition::Absent => match tx.get(key)? {
+                    Some(current)
+                        if decode_meta(&current).is_ok_and(|meta| {
+                            meta.deleted_at.is_none() && !meta.is_expired(now, epoch)
+                        }) =>
+                    {
+                        Ok(Some(Some(current)))
+                    }
+                    _ => Ok(None),
+                },
+                Condition::Unchanged(expected) => {
+                    let current = tx.get(key)?;
+
+                    if current.as_deref() == expected {
+                        Ok(None)
+                    } else {
+                        Ok(Some(current))
+                    }
+                }
+            }
+        };
+
+        let write = if prepared.is_inline() && matches!(options.condition, Condition::Always) {
+            Ok(self.inner.db.insert(key, prepared.value)?)
+        } else {
+            self.inner.db.transaction(|tx| {
+                if let Some(current) = rejected(tx)? {
+                    return Ok(Err(current));
+                }
+
+                Ok(Ok(prepared.write(tx, key)?))
+            })?
+        };
+
+        match write {
+            Ok(previous) => Ok(Written::Replaced(previous)),
+            Err(current) => {
+                log::trace!("store:{} -> rejected", self.key_format(key));
+                self.explain(key, || explain::Reason::Rejected);
+                Ok(Written::Rejected(current))
+            }
+        }
+    }
+
+    /// Encrypt a serialized value and construct the entry to write for it,
+    /// splitting it into chunks or deduplicating it as configured.
+    fn prepare_payload(
+        &self,
+        key: &[u8],
+        age: Duration,
+        payload: Vec<u8>,
+        flags: u8,
+        options: &InsertOptions<'_>,
+    ) -> Result<Prepared, Error> {
+        let mut meta = self.new_meta(key, age, options)?;
+        let format = &self.inner.format;
+
+        // values are deduplicated before they're encrypted, since encrypting
+        // them uses a random nonce.
+        let (payload, flags, blob) = if let Some(hash) =
+            blob_hash(&self.inner.config, format.id(), flags, &payload)
+        {
+            let blob_key = blob_key(&hash);
+            let (payload, flags) =
+                encrypt(&self.inner.config, &blob_key, format.id(), payload, flags)?;
+
+            let mut blob = Vec::with_capacity(1 + payload.len() + CHECKSUM_LEN);
+            blob.push(flags);
+            blob.extend_from_slice(&payload);
+            let checksum = crc32fast::hash(&blob);
+            blob.extend_from_slice(&checksum.to_be_bytes());
+
+            meta.blob = Some(ByteBuf::from(hash.clone()));
+            (Vec::new(), 0, Some((hash, blob)))
+        } else {
+            let (payload, flags) = encrypt(&self.inner.config, key, format.id(), payload, flags)?;
+            (payload, flags, None)
+        };
+
+        let chunks = match self.inner.config.chunk_size {
+            Some(size) if payload.len() > size => {
+                let id = self.next_chunk_id()?;
+
+                let chunks = payload
+                    .chunks(size)
+                    .zip(0u32..)
+                    .map(|(chunk, index)| (chunk_key(key, id, index), chunk.to_vec()))
+                    .collect::<Vec<_>>();
+
+                meta.chunks = Some(Chunks {
+                    id,
+                    count: u32::try_from(chunks.len())
+                        .map_err(|_| Error::Codec("value has too many chunks".into()))?,
+                    len: payload.len() as u64,
+                    checksum: crc32fast::hash(&payload),
+                });
+
+                chunks
+            }
+            _ => Vec::new(),
+        };
+
+        let value = if chunks.is_empty() {
+            join_entry(format.id(), flags, &meta, &payload)?
+        } else {
+            join_entry(format.id(), flags, &meta, &[])?
+        };
+
+        Ok(Prepared {
+            value,
+            chunks,
+            blob,
+        })
+    }
+
+    /// Insert a value read from the given reader into the cache, without
+    /// holding all of it in memory.
+    ///
+    /// The value is stored as raw bytes, split into chunks of the size
+    /// configured with [Builder::chunk_size] or 64 KiB by default. Chunks are
+    /// written as they are read and the entry only becomes visible once all of
+    /// them have been written. The value is not compressed, and each chunk is
+    /// encrypted separately if an [encryption key][Builder::encryption_key] is
+    /// configured. It can be read back as a [serde_bytes::ByteBuf].
+    pub async fn insert_stream<K, R>(
+        &self,
+        key: K,
+        age: Duration,
+        mut reader: R,
+    ) -> Result<(), Error>
+    where
+        K: CacheKey,
+        R: AsyncRead + Unpin,
+    {
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+
+        let options = InsertOptions {
+            plain_key: Some(&plain_key),
+            ..InsertOptions::default()
+        };
+
+        let id = self.next_chunk_id()?;
+        self.write_stream(&key, age, id, &mut reader, options).await
+    }
+
+    /// Write the chunks read from the given reader, followed by the entry
+    /// referencing them.
+    ///
+    /// The chunks are marked as pending until the entry is written, so that
+    /// they aren't removed as orphaned in the meantime. Chunks which have been
+    /// written are removed if this fails.
+    async fn write_stream<R>(
+        &self,
+        key: &[u8],
+        age: Duration,
+        id: u64,
+        reader: &mut R,
+        options: InsertOptions<'_>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let size = self.inner.config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        let mut buf = vec![0; size];
+        let mut flags = FLAG_RAW;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut len = 0;
+        let mut count = 0u32;
+
+        let result = async {
+            loop {
+                let n = read_chunk(reader, &mut buf).await?;
+
+                if n == 0 {
+                    return Ok(());
+                }
+
+                let chunk_key = chunk_key(key, id, count);
+                let (chunk, chunk_flags) =
+                    encrypt(&self.inner.config, &chunk_key, 0, buf[..n].to_vec(), 0)?;
+
+                if chunk_flags & FLAG_ENCRYPTED != 0 {
+                    flags |= FLAG_CHUNKS_ENCRYPTED;
+                }
+
+                hasher.update(&chunk);
+                len += chunk.len() as u64;
+
+                let mut batch = sled::Batch::default();
+
+                if count == 0 {
+                    let started = self.now().timestamp_millis().to_be_bytes();
+                    batch.insert(pending_key(id), &started[..]);
+                }
+
+                batch.insert(chunk_key, chunk);
+                self.inner.db.apply_batch(batch)?;
+
+                count = count
+                    .checked_add(1)
+                    .ok_or_else(|| Error::Codec("value has too many chunks".into()))?;
+
+                if n < size {
+                    return Ok(());
+                }
+            }
+        };
+
+        let result = match result.await {
+            Ok(()) => self.new_meta(key, age, &options).and_then(|mut meta| {
+                meta.chunks = Some(Chunks {
+                    id,
+                    count,
+                    len,
+                    checksum: hasher.finalize(),
+                });
+
+                join_entry(self.inner.format.id(), flags, &meta, &[])
+            }),
+            Err(e) => Err(e),
+        };
+
+        let value = match result {
+            Ok(value) => value,
+            Err(e) => {
+                log::trace!("store-stream:{} *errored*", self.key_format(key));
+
+                let mut batch = sled::Batch::default();
+                batch.remove(pending_key(id));
+
+                for index in 0..count {
+                    batch.remove(chunk_key(key, id, index));
+                }
+
+                self.inner.db.apply_batch(batch)?;
+                return Err(e);
+            }
+        };
+
+        log::trace!("store-stream:{} -> {} chunks", self.key_format(key), count);
+
+        let previous = self.inner.db.transaction(|tx| {
+            tx.remove(pending_key(id))?;
+            Ok(tx.insert(key, value.as_slice())?)
+        })?;
+
+        self.remove_replaced(key, previous, options)
+    }
+
+    /// Construct the metadata of a new entry.
+    fn new_meta(
+        &self,
+        key: &[u8],
+        age: Duration,
+        options: &InsertOptions<'_>,
+    ) -> Result<PartialStoredEntry, Error> {
+        let original_key = match options.plain_key {
+            Some(plain_key) => self.original_key(key, plain_key)?,
+            None => None,
+        };
+
+        let now = self.now();
+
+        let sliding = if options.sliding || self.inner.sliding {
+            age.num_milliseconds().max(0)
+        } else {
+            0
+        };
+
+        let expires_at = match (options.forever, options.expires_at) {
+            (true, _) => None,
+            (false, Some(expires_at)) => Some(expires_at),
+            (false, None) => Some(
+                now.checked_add_signed(age)
+                    .ok_or(Error::TtlOutOfRange(age))?,
+            ),
+        };
+
+        Ok(PartialStoredEntry {
+            expires_at,
+            stored_at: Some(now),
+            sliding,
+            pinned: options.pinned,
+            deleted_at: None,
+            epoch: self.epoch(),
+            tags: options.tags.iter().map(|tag| tag.to_string()).collect(),
+            deps: options.deps.to_vec(),
+            format: 0,
+            flags: 0,
+            original_key,
+            chunks: None,
+            blob: None,
+            schema: options.schema,
+            list: None,
+            indexes: options.indexes.to_vec(),
+            metadata: options.metadata.cloned().unwrap_or_default(),
+        })
+    }
+
+    /// Clean up after the entry with the given key has been written, removing
+    /// what belonged to the entry it replaced.
+    fn remove_replaced(
+        &self,
+        key: &[u8],
+        previous: Option<sled::IVec>,
+        options: InsertOptions<'_>,
+    ) -> Result<(), Error> {
+        self.record(AuditOp::Insert, key)?;
+
+        let previous = match previous {
+            Some(previous) => previous,
+            None => {
+                self.inner.len.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
+
+        if self.inner.config.history > 0 {
+            self.record_history(key, &previous)?;
+        }
+
+        if let Ok(mut stale) = decode_meta(&previous) {
+            if let Some(counters) = self.entry_counters(key)? {
+                counters.refresh(&stale, self.now());
+            }
+
+            stale
+                .tags
+                .retain(|tag| !options.tags.contains(&tag.as_str()));
+            stale.deps.retain(|dep| !options.deps.contains(dep));
+
+            // the indexes of the new entry aren't known by every caller, so
+            // they're read back from the entry.
+            if !stale.indexes.is_empty() {
+                let current = self.inner.db.get(key)?;
+                let current = current.as_deref().and_then(|v| decode_meta(v).ok());
+
+                stale.indexes.retain(|index| {
+                    !current
+                        .as_ref()
+                        .is_some_and(|current| current.indexes.contains(index))
+                });
+            }
+
+            self.remove_indexes(key, &stale)?;
+            self.remove_value(key, &stale)?;
+        }
+
+        Ok(())
+    }
+
+    /// Test an entry from the cache.
+    ///
+    /// Only the metadata at the start of the entry is decoded and verified and
+    /// never its value, so this is cheap regardless of how large the value is.
+    /// Chunked and deduplicated values aren't read at all, and a corrupt value
+    /// is only reported once it's read.
+    pub fn test<K>(&self, key: K) -> Result<State<()>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.inner_test(&key)
+    }
+
+    /// Test if there's a fresh entry with the given key.
+    ///
+    /// Like [Cache::test], only the metadata at the start of the entry is
+    /// decoded and never its value, so this is cheap regardless of how large
+    /// the value is. Chunked and deduplicated values aren't read at all.
+    pub fn contains<K>(&self, key: K) -> Result<bool, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        Ok(matches!(self.inner_test(&key)?, State::Fresh(..)))
+    }
+
+    /// Load an entry from the cache.
+    #[inline(always)]
+    fn inner_test(&self, key: &[u8]) -> Result<State<()>, Error> {
+        let value = match self.inner.db.get(key)? {
+            Some(value) => value,
+            None => {
+                log::trace!("test:{} -> null (missing)", self.key_format(key));
+                return Ok(State::Missing);
+            }
+        };
+
+        let stored = match decode_meta(&value) {
+            Ok(value) => value,
+            Err(Error::Corrupt) => {
+                log::warn!("{}: entry is corrupt", self.key_format(key));
+                log::trace!("test:{} -> null (corrupt)", self.key_format(key));
+                return Ok(State::Corrupt);
+            }
+            Err(e) => {
+                if self.inner.config.strict_reads {
+                    return Err(self.entry_error(Some(key), false, e));
+                }
+
+                if log::log_enabled!(log::Level::Trace) {
+                    log::warn!(
+                        "{}: failed to deserialize: {}: {}",
+                        self.key_format(key),
+                        e,
+                        self.key_format(&value)
+                    );
+                } else {
+                    log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                }
+
+                log::trace!("test:{} -> null (deserialize error)", self.key_format(key));
+                return Ok(State::Missing);
+            }
+        };
+
+        if stored.deleted_at.is_some() {
+            log::trace!("test:{} -> null (deleted)", self.key_format(key));
+            return Ok(State::Missing);
+        }
+
+        if stored.is_expired(self.now(), self.epoch()) {
+            log::trace!("test:{} -> null (expired)", self.key_format(key));
+            return Ok(State::Expired(
+                stored.into_stored_entry(&self.inner.config, ()),
+            ));
+        }
+
+        log::trace!("test:{} -> *value*", self.key_format(key));
+        Ok(State::Fresh(
+            stored.into_stored_entry(&self.inner.config, ()),
+        ))
+    }
+
+    /// Load an entry from the cache.
+    pub fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: 'static + serde::de::DeserializeOwned,
+    {
+        let key = self.key(&key)?;
+        let span = trace::span(trace::Op::Get, || self.ns_json());
+
+        trace::in_span(&span, || {
+            let started = Instant::now();
+            let result = self.inner_get(&key);
+            self.inner.stats.get(started, &result);
+            let state = result?;
+            self.read(&key, &state);
+            Ok(state)
+        })
+    }
+
+    /// Load multiple entries from the cache, returning their states in the
+    /// order of the given keys.
+    ///
+    /// All keys are serialized before anything is read, so an error from a key
+    /// which can't be serialized is reported without loading any entries. The
+    /// entries are then read in a single transaction, so they're a consistent
+    /// view of the cache where no entry was written in between the reads.
+    pub fn get_many<K, T, I>(&self, keys: I) -> Result<Vec<State<T>>, Error>
+    where
+        K: CacheKey,
+        T: 'static + serde::de::DeserializeOwned,
+        I: IntoIterator<Item = K>,
+    {
+        let keys = keys
+            .into_iter()
+            .map(|key| self.key(&key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let config = &self.inner.config;
+        let span = trace::span(trace::Op::Get, || self.ns_json());
+
+        trace::in_span(&span, || {
+            let values = self.read_many_raw(&keys)?;
+
+            keys.iter()
+                .zip(values)
+                .map(|(key, value)| {
+                    let started = Instant::now();
+                    let result = self
+                        .inner_load_from(key, Some(value), true, &|meta, payload| {
+                            decode_payload(config, meta, payload)
+                        })
+                        .map(|(state, _)| state);
+                    self.inner.stats.get(started, &result);
+                    let state = result?;
+                    self.read(key, &state);
+                    Ok(state)
+                })
+                .collect()
+        })
+    }
+
+    /// Load the part of the value of an entry at the given
+    /// [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901), like
+    /// `/user/name`.
+    ///
+    /// Only the requested part of the value is deserialized, while the rest of
+    /// it is skipped over. The entry is reported as [State::Missing] if there's
+    /// nothing at the pointer. Values have to be stored in a self-describing
+    /// format, so values stored as postcard or raw bytes can't be navigated.
+    pub fn get_path<K, T>(&self, key: K, pointer: &str) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: serde::de::DeserializeOwned,
+    {
+        let path = path::parse(pointer).ok_or_else(|| Error::InvalidPointer(pointer.to_owned()))?;
+        let key = self.key(&key)?;
+        let config = &self.inner.config;
+
+        let started = Instant::now();
+        let result = self.inner_get_with(&key, &|meta, payload| {
+            decode_path(config, meta, payload, &path)
+        });
+        self.inner.stats.get(started, &result);
+        let state = result?;
+
+        let state = match state {
+            State::Fresh(stored) => stored.transpose().map_or(State::Missing, State::Fresh),
+            State::Expired(stored) => stored.transpose().map_or(State::Missing, State::Expired),
+            State::Missing => State::Missing,
+            State::Corrupt => State::Corrupt,
+        };
+
+        self.read(&key, &state);
+        Ok(state)
+    }
+
+    /// Update the part of the value of an entry at the given
+    /// [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901), without
+    /// changing when the entry expires or any of its other metadata.
+    ///
+    /// The part of the value is passed to `update` as a dynamic value, and the
+    /// rest of the value is left untouched. Only what `update` changes is
+    /// converted back, and nothing is written if it changes nothing. The update is atomic, so `update`
+    /// may be called again if the entry is modified concurrently. Secondary
+    /// indexes the entry is indexed by are extracted again from the updated
+    /// value.
+    ///
+    /// Returns `false` if there's no fresh entry or there's nothing at the
+    /// pointer. Values have to be stored in a self-describing format, and raw
+    /// and list values can't be updated in place.
+    pub fn update_path<K, F>(&self, key: K, pointer: &str, mut update: F) -> Result<bool, Error>
+    where
+        K: CacheKey,
+        F: FnMut(&mut json::Value),
+    {
+        let path = path::parse(pointer).ok_or_else(|| Error::InvalidPointer(pointer.to_owned()))?;
+        let (key, plain_key) = self.insert_key(&key)?;
+
+        if !self.inner.format.is_self_describing() {
+            return Err(Error::Codec(
+                "values in this format can't be updated in place".into(),
+            ));
+        }
+
+        self.update_path_key(&key, &plain_key, &path, &mut update)
+            .map_err(|e| self.entry_error(Some(&key), true, e))
+            .map(|updated| {
+                if updated {
+                    log::trace!("update:{} -> {}", self.key_format(&key), pointer);
+                }
+
+                updated
+            })
+    }
+
+    /// Update the part of the value of the entry stored under the given key at
+    /// the given path, see [Cache::update_path].
+    fn update_path_key(
+        &self,
+        key: &[u8],
+        plain_key: &[u8],
+        path: &[String],
+        update: &mut dyn FnMut(&mut json::Value),
+    ) -> Result<bool, Error> {
+        let config = &self.inner.config;
+
+        loop {
+            let (state, current) = self.inner_load_with(key, false, &|meta, payload| {
+                let root = decode_dynamic(config, &meta, payload);
+                Ok(meta.clone().into_stored_entry(config, (meta, root)))
+            })?;
+
+            let (meta, root) = match state {
+                State::Fresh(stored) => stored.value,
+                _ => return Ok(false),
+            };
+
+            let mut root = root.map_err(|e| self.entry_error(Some(key), false, e))?;
+
+            let part = match path::pointer_mut(&mut root, path) {
+                Some(part) => part,
+                None => return Ok(false),
+            };
+
+            let value_part = json::to_value(&*part)?;
+            let mut updated_part = value_part.clone();
+            update(&mut updated_part);
+
+            if updated_part == value_part {
+                return Ok(true);
+            }
+
+            path::assign(part, updated_part)?;
+
+            let indexes = self.reextract_indexes(&meta.indexes, &root)?;
+            let (payload, flags) =
+                encode_value(self.inner.compression_threshold, &self.inner.format, &root)?;
+            let tags = meta.tags.iter().map(String::as_str).collect::<Vec<_>>();
+
+            let options = InsertOptions {
+                pinned: meta.pinned,
+                sliding: meta.sliding != 0,
+                tags: &tags,
+                deps: &meta.deps,
+                indexes: &indexes,
+                plain_key: Some(plain_key),
+                schema: meta.schema,
+                expires_at: meta.expires_at,
+                forever: meta.expires_at.is_none(),
+                metadata: Some(&meta.metadata),
+                condition: Condition::Unchanged(current.as_deref()),
+            };
+
+            let age = Duration::milliseconds(meta.sliding);
+
+            match self.write_payload(key, age, payload, flags, options)? {
+                Written::Replaced(previous) => {
+                    self.remove_replaced(key, previous, options)?;
+                    return Ok(true);
+                }
+                Written::Rejected(..) => continue,
+            }
+        }
+    }
+
+    /// Extract the secondary indexes of a value whose type isn't known, with
+    /// the registered indexes named like the ones the entry is indexed by.
+    fn reextract_indexes<T>(
+        &self,
+        indexed: &[(String, ByteBuf)],
+        value: &T,
+    ) -> Result<Vec<(String, ByteBuf)>, Error>
+    where
+        T: Serialize,
+    {
+        if indexed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let value = json::to_value(value)?;
+        let mut out = Vec::new();
+
+        for index in self.inner.config.indexes.values().flatten() {
+            if !indexed.iter().any(|(name, _)| *name == index.name) {
+                continue;
+            }
+
+            if let Some(extracted) = (index.extract)(&value)? {
+                out.push((index.name.clone(), ByteBuf::from(extracted)));
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Load an entry from the cache.
+    #[inline(always)]
+    fn inner_get<T>(&self, key: &[u8]) -> Result<State<T>, Error>
+    where
+        T: 'static + serde::de::DeserializeOwned,
+    {
+        let config = &self.inner.config;
+        self.inner_get_with(key, &|meta, payload| decode_payload(config, meta, payload))
+    }
+
+    /// Read the serialized entry stored under the given key, injecting the
+    /// configured faults.
+    fn read_raw(&self, key: &[u8]) -> Result<Option<sled::IVec>, Error> {
+        #[cfg(feature = "testing")]
+        let action = self.inner.config.read_fault();
+
+        #[cfg(feature = "testing")]
+        if action == faults::Action::Fail {
+            return Err(faults::error().into());
+        }
+
+        let started = Instant::now();
+        let value = self.inner.db.get(key);
+        let took = started.elapsed();
+        self.inner.stats.storage_read_latency.record(took);
+        self.check_slow_storage(key, "read", took);
+
+        #[cfg(feature = "testing")]
+        let value = value.map(|value| faults::apply(action, value));
+
+        Ok(value?)
+    }
+
+    /// Read the serialized entries stored under the given keys in a single
+    /// transaction, injecting the configured faults.
+    fn read_many_raw(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<sled::IVec>>, Error> {
+        #[cfg(feature = "testing")]
+        let actions = keys
+            .iter()
+            .map(|_| self.inner.config.read_fault())
+            .collect::<Vec<_>>();
+
+        #[cfg(feature = "testing")]
+        if actions.contains(&faults::Action::Fail) {
+            return Err(faults::error().into());
+        }
+
+        let started = Instant::now();
+
+        let values: Result<_, TransactionError<Error>> = self.inner.db.transaction(|tx| {
+            let values = keys
+                .iter()
+                .map(|key| tx.get(key))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(values)
+        });
+
+        let took = started.elapsed();
+        self.inner.stats.storage_read_latency.record(took);
+
+        let values: Vec<Option<sled::IVec>> = values?;
+
+        #[cfg(feature = "testing")]
+        let values = values
+            .into_iter()
+            .zip(actions)
+            .map(|(value, action)| faults::apply(action, value))
+            .collect();
+
+        Ok(values)
+    }
+
+    /// Load an entry from the cache, decoding its value with the given
+    /// function.
+    fn inner_get_with<T, F>(&self, key: &[u8], decode: &F) -> Result<State<T>, Error>
+    where
+        F: Fn(PartialStoredEntry, Cow<'_, [u8]>) -> Result<StoredEntry<T>, Error>,
+    {
+        let (state, _) = self.inner_load_with(key, true, decode)?;
+        Ok(state)
+    }
+
+    /// Load an entry from the cache like [Cache::inner_get_with], also
+    /// returning the serialized entry it was loaded from.
+    ///
+    /// The expiration of sliding entries is only extended if `slide` is set,
+    /// since extending it rewrites the entry.
+    fn inner_load_with<T, F>(
+        &self,
+        key: &[u8],
+        slide: bool,
+        decode: &F,
+    ) -> Result<(State<T>, Option<sled::IVec>), Error>
+    where
+        F: Fn(PartialStoredEntry, Cow<'_, [u8]>) -> Result<StoredEntry<T>, Error>,
+    {
+        self.inner_load_from(key, None, slide, decode)
+    }
+
+    /// Load an entry from the cache like [Cache::inner_load_with], starting
+    /// from the given serialized entry if it has already been read.
+    fn inner_load_from<T, F>(
+        &self,
+        key: &[u8],
+        mut read: Option<Option<sled::IVec>>,
+        slide: bool,
+        decode: &F,
+    ) -> Result<(State<T>, Option<sled::IVec>), Error>
+    where
+        F: Fn(PartialStoredEntry, Cow<'_, [u8]>) -> Result<StoredEntry<T>, Error>,
+    {
+        let mut attempts = 0;
+
+        let (value, mut stored) = loop {
+            let value = match read.take() {
+                Some(value) => value,
+                None => self
+                    .read_raw(key)
+                    .map_err(|e| self.entry_error(Some(key), false, e))?,
+            };
+
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    log::trace!("load:{} -> null (missing)", self.key_format(key));
+                    self.explain(key, || explain::Reason::Missing);
+                    return Ok((State::Missing, None));
+                }
+            };
+
+            trace::size(value.len());
+
+            let started = Instant::now();
+            let loaded = self.load_payload(key, &value);
+            let decoded = loaded.and_then(|loaded| loaded.map(|(m, p)| decode(m, p)).transpose());
+            self.inner.stats.decode_latency.record(started.elapsed());
+
+            match decoded {
+                Ok(Some(stored)) => break (value, stored),
+                Ok(None) => {
+                    log::trace!("load:{} -> retry (modified)", self.key_format(key));
+                    attempts += 1;
+
+                    if attempts == MAX_READ_ATTEMPTS {
+                        return Err(self.entry_error(Some(key), false, Error::Modified));
+                    }
+                }
+                Err(Error::Corrupt) => {
+                    log::warn!("{}: entry is corrupt", self.key_format(key));
+                    log::trace!("load:{} -> null (corrupt)", self.key_format(key));
+                    self.explain(key, || explain::Reason::Corrupt);
+                    return Ok((State::Corrupt, Some(value)));
+                }
+                Err(e) => {
+                    self.explain(key, || explain::Reason::Undecodable {
+                        error: e.to_string(),
+                    });
+
+                    if self.inner.config.strict_reads {
+                        return Err(self.entry_error(Some(key), false, e));
+                    }
+
+                    if log::log_enabled!(log::Level::Trace) {
+                        log::warn!(
+                            "{}: failed to deserialize: {}: {}",
+                            self.key_format(key),
                             e,
-                            KeyFormat(&*value)
+                            self.key_format(&value)
                         );
                     } else {
-                        log::warn!("{}: failed to load: {}", KeyFormat(&*key), e);
+                        log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                    }
+
+                    log::trace!("load:{} -> null (deserialize error)", self.key_format(key));
+                    return Ok((State::Missing, Some(value)));
+                }
+            }
+        };
+
+        if !self.inner.config.disable_read_repair && !self.inner.config.read_only {
+            if let Err(e) = self.repair(key, &value) {
+                log::warn!("{}: failed to repair: {}", self.key_format(key), e);
+            }
+        }
+
+        if let Some(at) = stored.deleted_at {
+            log::trace!("load:{} -> null (deleted)", self.key_format(key));
+            self.explain(key, || explain::Reason::Deleted { at });
+            return Ok((State::Missing, Some(value)));
+        }
+
+        let (now, epoch) = (self.now(), self.epoch());
+        self.explain(key, || {
+            explain::Reason::expiry(stored.expires_at, stored.epoch, now, epoch)
+        });
+
+        if stored.is_expired(now, epoch) {
+            log::trace!("load:{} -> null (expired)", self.key_format(key));
+            return Ok((State::Expired(stored), Some(value)));
+        }
+
+        if slide {
+            self.slide(key, stored.sliding, &mut stored.expires_at);
+        }
+
+        log::trace!("load:{} -> *value*", self.key_format(key));
+        Ok((State::Fresh(stored), Some(value)))
+    }
+
+    /// Load an entry from the cache without deserializing its value.
+    ///
+    /// The value can then be deserialized with [EntryRef::value], borrowing
+    /// from the loaded entry where possible instead of copying. Values which
+    /// are compressed, encrypted or chunked are decoded into a buffer first.
+    pub fn get_ref<K>(&self, key: K) -> Result<State<EntryRef>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        let started = Instant::now();
+        let result = self.inner_get_ref(&key);
+        self.inner.stats.get(started, &result);
+        let state = result?;
+        self.read(&key, &state);
+        Ok(state)
+    }
+
+    /// Load an entry from the cache without deserializing its value.
+    fn inner_get_ref(&self, key: &[u8]) -> Result<State<EntryRef>, Error> {
+        let mut attempts = 0;
+
+        let mut stored = loop {
+            let value = self
+                .read_raw(key)
+                .map_err(|e| self.entry_error(Some(key), false, e))?;
+
+            let value = match value {
+                Some(value) => value,
+                None => {
+                    log::trace!("load-ref:{} -> null (missing)", self.key_format(key));
+                    self.explain(key, || explain::Reason::Missing);
+                    return Ok(State::Missing);
+                }
+            };
+
+            let started = Instant::now();
+            let decoded = EntryRef::new(self, key, value);
+            self.inner.stats.decode_latency.record(started.elapsed());
+
+            match decoded {
+                Ok(Some(stored)) => break stored,
+                Ok(None) => {
+                    log::trace!("load-ref:{} -> retry (modified)", self.key_format(key));
+                    attempts += 1;
+
+                    if attempts == MAX_READ_ATTEMPTS {
+                        return Err(self.entry_error(Some(key), false, Error::Modified));
+                    }
+                }
+                Err(Error::Corrupt) => {
+                    log::warn!("{}: entry is corrupt", self.key_format(key));
+                    log::trace!("load-ref:{} -> null (corrupt)", self.key_format(key));
+                    self.explain(key, || explain::Reason::Corrupt);
+                    return Ok(State::Corrupt);
+                }
+                Err(e) => {
+                    self.explain(key, || explain::Reason::Undecodable {
+                        error: e.to_string(),
+                    });
+
+                    if self.inner.config.strict_reads {
+                        return Err(self.entry_error(Some(key), false, e));
+                    }
+
+                    log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                    log::trace!(
+                        "load-ref:{} -> null (deserialize error)",
+                        self.key_format(key)
+                    );
+                    return Ok(State::Missing);
+                }
+            }
+        };
+
+        if let Some(at) = stored.deleted_at {
+            log::trace!("load-ref:{} -> null (deleted)", self.key_format(key));
+            self.explain(key, || explain::Reason::Deleted { at });
+            return Ok(State::Missing);
+        }
+
+        let (now, epoch) = (self.now(), self.epoch());
+        self.explain(key, || {
+            explain::Reason::expiry(stored.expires_at, stored.epoch, now, epoch)
+        });
+
+        if stored.is_expired(now, epoch) {
+            log::trace!("load-ref:{} -> null (expired)", self.key_format(key));
+            return Ok(State::Expired(stored));
+        }
+
+        self.slide(key, stored.sliding, &mut stored.expires_at);
+        stored.value.expires_at = stored.expires_at;
+        log::trace!("load-ref:{} -> *value*", self.key_format(key));
+        Ok(State::Fresh(stored))
+    }
+
+    /// Load an entry from the cache as a stream of bytes.
+    ///
+    /// Values stored with [Cache::insert_stream] are read one chunk at a time
+    /// as the stream is polled, so they are never held in memory in full.
+    /// Chunks are read on a pool of threads shared by all caches, so polling
+    /// the stream never blocks the executor. Other values must be byte strings, like a [serde_bytes::ByteBuf], and
+    /// are loaded up front.
+    ///
+    /// The checksum of a streamed value can only be verified once all of it has
+    /// been read, so a corrupt value is reported through [Error::Corrupt] as
+    /// the last item of the stream. If the entry is modified while the stream
+    /// is being read, it fails with [Error::Modified].
+    pub fn get_stream<K>(&self, key: K) -> Result<State<ValueStream>, Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.inner_get_stream(&key)
+    }
+
+    /// Load an entry from the cache as a stream of bytes.
+    fn inner_get_stream(&self, key: &[u8]) -> Result<State<ValueStream>, Error> {
+        let mut attempts = 0;
+
+        let stored = loop {
+            let value = match self.inner.db.get(key)? {
+                Some(value) => value,
+                None => {
+                    log::trace!("load-stream:{} -> null (missing)", self.key_format(key));
+                    return Ok(State::Missing);
+                }
+            };
+
+            let result = match split_entry(&value) {
+                Ok((meta, _)) if meta.flags & FLAG_RAW != 0 && meta.chunks.is_some() => {
+                    let stream = ValueStream::chunks(self.clone(), key, value.clone(), &meta);
+                    Ok(Some(meta.into_stored_entry(&self.inner.config, stream)))
+                }
+                Ok(..) => match self.decode_entry::<ByteBuf>(key, &value) {
+                    Ok(Some(stored)) => stored
+                        .try_map(|value| Ok(ValueStream::value(value.into_vec())))
+                        .map(Some),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(Some(stored)) => break stored,
+                Ok(None) => {
+                    log::trace!("load-stream:{} -> retry (modified)", self.key_format(key));
+                    attempts += 1;
+
+                    if attempts == MAX_READ_ATTEMPTS {
+                        return Err(self.entry_error(Some(key), false, Error::Modified));
+                    }
+                }
+                Err(Error::Corrupt) => {
+                    log::warn!("{}: entry is corrupt", self.key_format(key));
+                    log::trace!("load-stream:{} -> null (corrupt)", self.key_format(key));
+                    return Ok(State::Corrupt);
+                }
+                Err(e) => {
+                    if self.inner.config.strict_reads {
+                        return Err(self.entry_error(Some(key), false, e));
+                    }
+
+                    log::warn!("{}: failed to deserialize: {}", self.key_format(key), e);
+                    log::trace!(
+                        "load-stream:{} -> null (deserialize error)",
+                        self.key_format(key)
+                    );
+                    return Ok(State::Missing);
+                }
+            }
+        };
+
+        if stored.deleted_at.is_some() {
+            log::trace!("load-stream:{} -> null (deleted)", self.key_format(key));
+            return Ok(State::Missing);
+        }
+
+        if stored.is_expired(self.now(), self.epoch()) {
+            log::trace!("load-stream:{} -> null (expired)", self.key_format(key));
+            return Ok(State::Expired(stored));
+        }
+
+        log::trace!("load-stream:{} -> *stream*", self.key_format(key));
+        Ok(State::Fresh(stored))
+    }
+
+    /// Get the waker associated with the given key.
+    fn waker(&self, key: &[u8]) -> Arc<Waker> {
+        let wakers = self.inner.wakers.read();
+
+        match wakers.get(key) {
+            Some(waker) => return waker.clone(),
+            None => drop(wakers),
+        }
+
+        self.inner
+            .wakers
+            .write()
+            .entry(key.to_vec())
+            .or_default()
+            .clone()
+    }
+
+    /// Expires the given key
+    pub async fn expire<E>(&self, key: ExpiredKey) -> Result<(), E>
+    where
+        E: From<Error>,
+    {
+        // expired keys are found by scanning the whole database, so their
+        // namespace isn't relative to this handle.
+        let key = self.storage_key(
+            key.ns.as_ref(),
+            &key::encode(&key.key).map_err(Error::from)?,
+        )?;
+        self.delete_key(&key)?;
+        Ok(())
+    }
+
+    /// Wrap the result of the given future to load and store from cache.
+    pub async fn wrap<K, F, T, E>(&self, key: K, age: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey,
+        F: Future<Output = Result<T, E>>,
+        T: 'static + Serialize + serde::de::DeserializeOwned,
+        E: From<Error>,
+    {
+        let span = trace::span(trace::Op::Wrap, || self.ns_json());
+        trace::instrument(span, self.inner_wrap(key, age, future)).await
+    }
+
+    async fn inner_wrap<K, F, T, E>(&self, key: K, age: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey,
+        F: Future<Output = Result<T, E>>,
+        T: 'static + Serialize + serde::de::DeserializeOwned,
+        E: From<Error>,
+    {
+        self.inner.stats.wraps.fetch_add(1, Ordering::Relaxed);
+
+        let (key, plain_key) = self.insert_key(&key)?;
+        let age = self.inner.config.ttl(age)?;
+        let mut first = true;
+
+        loop {
+            // There a slight race here. The answer might _just_ have been provided when we perform
+            // this check.
+            //
+            // If that happens, worst case we will end up re-computing the answer again.
+            let result = self.inner_get(&key);
+            self.inner.stats.error(&result);
+            let state = result?;
+
+            // only the first read counts, since waiting for another call to
+            // provide the answer reads the entry again.
+            if std::mem::take(&mut first) {
+                self.read(&key, &state);
+            }
+
+            if let State::Fresh(e) = state {
+                return Ok(e.value);
+            }
+
+            let waker = self.waker(&key);
+
+            // only pending == 0 will be driving the future for a response.
+            if waker.pending.fetch_add(1, Ordering::AcqRel) > 0 {
+                let (tx, rx) = oneshot::channel();
+                waker.channels.push(tx);
+
+                let result = rx.await;
+
+                // Ignore if sender is cancelled, just loop again.
+                match result {
+                    Ok(true) => {
+                        self.explain(&key, || explain::Reason::CoalescedFailed);
+                        return Err(E::from(self.entry_error(Some(&key), false, Error::Failed)));
+                    }
+                    Ok(false) => {
+                        self.explain(&key, || explain::Reason::Coalesced);
+                        continue;
+                    }
+                    Err(oneshot::Canceled) => continue,
+                }
+            }
+
+            // Check key again in case we got really unlucky and had two call do an interleaving
+            // pass for the previous check:
+            //
+            // T1 just went passed the first inner_get test above.
+            // T2 just finished the Waker::cleanup procedure and reduces pending to 0.
+            // T1 notices that it is the first pending thread (pending == 0) and ends up here.
+            //
+            // Pinned entries which are being refreshed stay pinned.
+            let pinned = match self.inner_get::<T>(&key)? {
+                State::Fresh(e) => {
+                    waker.cleanup(false);
+                    return Ok(e.value);
+                }
+                State::Expired(e) => e.pinned,
+                State::Missing | State::Corrupt => false,
+            };
+
+            // Guard in case it is cancelled.
+            let started = Instant::now();
+            let result = Guard::new(|| waker.cleanup(false)).wrap(future).await;
+            let took = started.elapsed();
+            self.inner.stats.loader_latency.record(took);
+            self.inner.counters.loader_latency.record(took);
+            trace::loader(took);
+
+            self.check_slow_loader(&key, took);
+
+            // Compute the answer by polling the underlying future and store it in the cache,
+            // then acquire the wakers lock and dispatch to all pending futures.
+            match result {
+                Ok(output) => {
+                    let options = InsertOptions {
+                        pinned,
+                        plain_key: Some(&plain_key),
+                        ..InsertOptions::default()
+                    };
+
+                    self.inner_insert(&key, age, &output, options)?;
+                    waker.cleanup(false);
+                    self.explain(&key, || explain::Reason::Loaded { took });
+                    return Ok(output);
+                }
+                Err(e) => {
+                    self.inner
+                        .counters
+                        .loader_errors
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.explain(&key, || explain::Reason::LoaderFailed { took });
+                    waker.cleanup(true);
+                    return Err(e);
+                }
+            }
+        }
+
+        /// Create a stack guard that will run unless it is forgotten.
+        struct Guard<F>
+        where
+            F: FnMut(),
+        {
+            f: F,
+        }
+
+        impl<F> Guard<F>
+        where
+            F: FnMut(),
+        {
+            /// Construct a new finalizer.
+            pub fn new(f: F) -> Self {
+                Self { f }
+            }
+
+            /// Wrap the given future with this cancellation guard.
+            pub async fn wrap<O>(self, future: O) -> O::Output
+            where
+                O: Future,
+            {
+                let result = future.await;
+                std::mem::forget(self);
+                result
+            }
+        }
+
+        impl<F> Drop for Guard<F>
+        where
+            F: FnMut(),
+        {
+            fn drop(&mut self) {
+                (self.f)();
+            }
+        }
+    }
+
+    /// Helper to format a key or a value for log output.
+    fn key_format<'a>(&self, bytes: &'a [u8]) -> KeyFormat<'a> {
+        KeyFormat {
+            bytes,
+            redaction: self.inner.config.key_redaction,
+        }
+    }
+
+    /// Wrap an error raised while reading or writing the entry with the given
+    /// key in [Error::Entry], or while serializing its key if it's `None`.
+    fn entry_error(&self, key: Option<&[u8]>, writing: bool, error: Error) -> Error {
+        let kind = match &error {
+            Error::Entry(..) => return error,
+            Error::Cbor(..) | Error::Json(..) | Error::Codec(..) if writing => {
+                ErrorKind::ValueSerialization
+            }
+            Error::Cbor(..) | Error::Json(..) | Error::Codec(..) => ErrorKind::ValueDeserialization,
+            error => error.kind(),
+        };
+
+        let (ns, key) = match key.map(|key| (key, decode_key_json(key))) {
+            Some((bytes, Ok((ns, key)))) => (ns, Some(self.redact_key(bytes, key))),
+            _ => {
+                let ns = self.ns_key().and_then(|ns_key| namespace_json(&ns_key));
+                (ns.unwrap_or(json::Value::Null), None)
+            }
+        };
+
+        Error::Entry(Box::new(EntryError {
+            kind,
+            ns,
+            key,
+            error,
+        }))
+    }
+
+    /// Decode the namespace and key of the entry with the given key as JSON,
+    /// with the key redacted according to [Builder::key_redaction].
+    ///
+    /// If the key can't be decoded, this logs that it failed to `action`.
+    fn redacted_key_json(&self, key: &[u8], action: &str) -> Option<(json::Value, json::Value)> {
+        match decode_key_json(key) {
+            Ok((ns, json_key)) => Some((ns, self.redact_key(key, json_key))),
+            Err(e) => {
+                log::warn!("{}: failed to {}: {}", self.key_format(key), action, e);
+                None
+            }
+        }
+    }
+
+    /// Redact the decoded key of the entry with the given key according to
+    /// [Builder::key_redaction].
+    fn redact_key(&self, key: &[u8], json_key: json::Value) -> json::Value {
+        if self.inner.config.key_redaction == KeyRedaction::Full {
+            return json_key;
+        }
+
+        let plain = split_key(key).map_or(key, |(_, key)| key);
+        self.key_format(key).redact(plain)
+    }
+
+    /// Helper to serialize the key with the default namespace.
+    fn key<T>(&self, key: &T) -> Result<Vec<u8>, Error>
+    where
+        T: ?Sized + CacheKey,
+    {
+        let encoded = key::encode(key).map_err(|e| self.entry_error(None, false, e.into()))?;
+        Ok(self.storage_key_with(&self.ns_key()?, &encoded))
+    }
+
+    /// Helper to serialize the key with the default namespace, also returning
+    /// the encoded key before it's hashed.
+    fn insert_key<T>(&self, key: &T) -> Result<(Vec<u8>, Vec<u8>), Error>
+    where
+        T: ?Sized + CacheKey,
+    {
+        let plain_key = key::encode(key).map_err(|e| self.entry_error(None, true, e.into()))?;
+        let key = self.storage_key_with(&self.ns_key()?, &plain_key);
+        Ok((key, plain_key))
+    }
+
+    /// Helper to construct the original key stored in an entry whose key is
+    /// hashed, so that it can be listed.
+    ///
+    /// If keys are hashed with a secret, it's only stored encrypted if an
+    /// encryption key is configured, bound to the key of its entry. Oversized
+    /// keys are stored as they are.
+    fn original_key(&self, key: &[u8], plain_key: &[u8]) -> Result<Option<ByteBuf>, Error> {
+        let config = &self.inner.config;
+
+        if !config.hashes_keys() {
+            return Ok(if config.is_oversized(plain_key) {
+                Some(ByteBuf::from(plain_key))
+            } else {
+                None
+            });
+        }
+
+        match encrypt(config, key, 0, plain_key.to_vec(), 0)? {
+            (encrypted, FLAG_ENCRYPTED) => Ok(Some(ByteBuf::from(encrypted))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Helper to decode the original key stored in an entry, see
+    /// [Cache::original_key].
+    fn decode_original_key<'a>(
+        &self,
+        key: &[u8],
+        original_key: Cow<'a, [u8]>,
+    ) -> Result<Cow<'a, [u8]>, Error> {
+        if !self.inner.config.hashes_keys() {
+            return Ok(original_key);
+        }
+
+        decrypt(&self.inner.config, key, 0, FLAG_ENCRYPTED, original_key)
+    }
+
+    /// Helper to construct the prefix shared by all keys in the default
+    /// namespace.
+    fn ns_prefix(&self) -> Result<Vec<u8>, Error> {
+        Ok(join_key(&self.ns_key()?, &[]))
+    }
+
+    /// Helper to construct the encoded namespace this cache belongs to, with
+    /// the current generation of each generational namespace inserted after
+    /// it.
+    fn ns_key(&self) -> Result<Cow<'_, [u8]>, Error> {
+        let ns_key = &self.inner.ns_key;
+
+        if self.inner.generations.is_empty() {
+            return Ok(Cow::Borrowed(ns_key));
+        }
+
+        self.apply_invalidations();
+
+        let mut out = Vec::with_capacity(ns_key.len() + 16);
+        let mut start = 0;
+
+        for (offset, generation) in &self.inner.generations {
+            out.extend_from_slice(&ns_key[start..*offset]);
+            out.extend(key::generation_level(generation.load(Ordering::Acquire)));
+            start = *offset;
+        }
+
+        out.extend_from_slice(&ns_key[start..]);
+        Ok(Cow::Owned(out))
+    }
+
+    /// The current time according to the configured clock.
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.inner.config.now()
+    }
+
+    /// The namespace of this handle as JSON, for diagnostics.
+    fn ns_json(&self) -> String {
+        match self.ns_key().and_then(|ns_key| namespace_json(&ns_key)) {
+            Ok(ns) => ns.to_string(),
+            Err(e) => format!("<{}>", e),
+        }
+    }
+
+    /// Helper to construct the encoded namespace nested inside of the
+    /// namespace of this handle.
+    ///
+    /// Namespaces passed as arguments are always relative to the handle they're
+    /// passed to, like with [Cache::namespaced].
+    fn nested_ns_key(&self, ns: Option<&hashkey::Key>) -> Result<Vec<u8>, Error> {
+        let mut ns_key = self.ns_key()?.into_owned();
+        ns_key.extend(key::namespace_to_vec(ns)?);
+        Ok(ns_key)
+    }
+
+    /// Helper to construct the prefix shared by all keys in a specific
+    /// namespace, nested inside of the namespace of this handle.
+    pub(crate) fn ns_prefix_with(&self, ns: Option<&hashkey::Key>) -> Result<Vec<u8>, Error> {
+        Ok(join_key(&self.nested_ns_key(ns)?, &[]))
+    }
+
+    /// Helper to serialize the key with a specific namespace, nested inside of
+    /// the namespace of this handle.
+    ///
+    /// The key itself is encoded using the length-prefixed encoding in the
+    /// [key] module and appended to the encoded namespace.
+    fn key_with_ns<T>(&self, ns: Option<&hashkey::Key>, key: &T) -> Result<Vec<u8>, Error>
+    where
+        T: ?Sized + CacheKey,
+    {
+        let ns = self.nested_ns_key(ns)?;
+        Ok(self.storage_key_with(&ns, &key::encode(key)?))
+    }
+
+    /// Helper to construct the key an entry is stored under from its encoded
+    /// key, hashing it if configured.
+    fn storage_key(&self, ns: Option<&hashkey::Key>, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let ns = key::namespace_to_vec(ns)?;
+        Ok(self.storage_key_with(&ns, key))
+    }
+
+    /// Helper to construct the key an entry is stored under from its encoded
+    /// namespace and key.
+    fn storage_key_with(&self, ns: &[u8], key: &[u8]) -> Vec<u8> {
+        match self.inner.config.hash_key(key) {
+            Some(hashed) => join_key(ns, &hashed),
+            None => join_key(ns, key),
+        }
+    }
+
+    /// Convert the keys of entries stored with an older key layout to the
+    /// current one, returning the number of entries converted.
+    ///
+    /// Each entry is moved together with its chunks and index entries in an
+    /// atomic batch, so an interrupted conversion resumes where it left off.
+    fn migrate_keys(&self) -> Result<usize, Error> {
+        let version_key = meta_key(KEY_VERSION_KEY);
+        let version = self.inner.db.get(&version_key)?;
+
+        match version.as_deref().and_then(|v| v.first().copied()) {
+            Some(KEY_VERSION) => return Ok(0),
+            Some(version) if version > KEY_VERSION => {
+                return Err(Error::UnsupportedKeyVersion(version));
+            }
+            _ => (),
+        }
+
+        let mut migrated = 0;
+
+        for result in self.entries() {
+            let (key, value) = result?;
+
+            let new_key = match upgrade_key(&key) {
+                Ok(Cow::Borrowed(_)) => continue,
+                Ok(Cow::Owned(new_key)) => new_key,
+                Err(e) => {
+                    log::warn!("{}: failed to convert key: {}", self.key_format(&key), e);
+                    continue;
+                }
+            };
+
+            let (mut entry, payload) = match split_entry(&value) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    log::warn!("{}: failed to load: {}", self.key_format(&key), e);
+                    continue;
+                }
+            };
+
+            let mut batch = sled::Batch::default();
+
+            for tag in &entry.tags {
+                batch.remove(tag_key(tag, &key));
+                batch.insert(tag_key(tag, &new_key), &[]);
+            }
+
+            let mut deps = Vec::with_capacity(entry.deps.len());
+
+            for dep in &entry.deps {
+                batch.remove(dep_key(dep, &key));
+
+                if let Ok(dep) = upgrade_key(dep) {
+                    batch.insert(dep_key(&dep, &new_key), &[]);
+                    deps.push(ByteBuf::from(dep.into_owned()));
+                }
+            }
+
+            entry.deps = deps;
+
+            for (chunk_key, new_chunk_key) in
+                chunk_keys(&key, &entry).zip(chunk_keys(&new_key, &entry))
+            {
+                if let Some(chunk) = self.inner.db.get(&chunk_key)? {
+                    batch.insert(new_chunk_key, chunk);
+                }
+
+                batch.remove(chunk_key);
+            }
+
+            batch.insert(
+                new_key.as_slice(),
+                join_entry(entry.format, entry.flags, &entry, &payload)?,
+            );
+            batch.remove(key);
+            self.inner.db.apply_batch(batch)?;
+            migrated += 1;
+        }
+
+        // Positions of migrations between formats refer to keys in the old
+        // layout, so those migrations start over.
+        for result in self.inner.db.scan_prefix(meta_key(MIGRATE_KEY)).keys() {
+            self.inner.db.remove(result?)?;
+        }
+
+        self.inner.db.insert(version_key, &[KEY_VERSION])?;
+        log::trace!("migrated keys of {} entries", migrated);
+        Ok(migrated)
+    }
+}
+
+/// Version of the layout of the keys of entries.
+///
+/// See the [crate documentation](crate#key-layout).
+const KEY_VERSION: u8 = 0x01;
+
+/// Metadata key storing the version of the key layout used by the database.
+const KEY_VERSION_KEY: &str = "key-version";
+
+/// Construct the key of an entry from its encoded namespace and key.
+fn join_key(ns: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5 + ns.len() + key.len());
+    out.push(KEY_VERSION);
+    out.extend_from_slice(&(ns.len() as u32).to_be_bytes());
+    out.extend_from_slice(ns);
+    out.extend_from_slice(key);
+    out
+}
+
+/// The smallest key which is greater than all keys starting with the given
+/// prefix, or `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
+
+/// Split the key of an entry into its encoded namespace and key.
+fn split_key(key: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    match key.split_first() {
+        Some((&KEY_VERSION, rest)) => {
+            let (len, rest) = split_array::<4>(rest).ok_or(Error::Corrupt)?;
+            let len = u32::from_be_bytes(len) as usize;
+
+            if rest.len() < len {
+                return Err(Error::Corrupt);
+            }
+
+            Ok(rest.split_at(len))
+        }
+        Some((&version, _)) => Err(Error::UnsupportedKeyVersion(version)),
+        None => Err(Error::UnsupportedKeyVersion(0)),
+    }
+}
+
+/// Convert the key of an entry to the current key layout, borrowing it if it
+/// already uses the current layout.
+fn upgrade_key(key: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    match key.first() {
+        Some(&KEY_VERSION) => Ok(Cow::Borrowed(key)),
+        // Keys without a version are CBOR-encoded arrays of the namespace and
+        // the encoded key.
+        Some(0x82) => {
+            let (ns, key): (Option<hashkey::Key>, ByteBuf) = cbor::from_slice(key)?;
+            let ns = ns.map(hashkey::Key::normalize);
+            Ok(Cow::Owned(join_key(
+                &key::namespace_to_vec(ns.as_ref())?,
+                &key,
+            )))
+        }
+        Some(&version) => Err(Error::UnsupportedKeyVersion(version)),
+        None => Err(Error::UnsupportedKeyVersion(0)),
+    }
+}
+
+/// Prefix of keys used to store internal metadata.
+///
+/// Keys of entries start with the version of the key layout, so they never
+/// start with a zero byte.
+const META_PREFIX: u8 = 0x00;
+
+/// Metadata key prefix of the records written and removed by health probes.
+const HEALTH_KEY: &str = "health/";
+
+/// Metadata key storing the current invalidation epoch.
+const EPOCH_KEY: &str = "epoch";
+
+/// Metadata key prefix of the generations of generational namespaces.
+const GENERATION_KEY: &str = "generation/";
+
+/// Construct the key storing the generation of the given encoded namespace.
+fn generation_key(ns: &[u8]) -> Vec<u8> {
+    let mut key = meta_key(GENERATION_KEY);
+    key.extend_from_slice(ns);
+    key
+}
+
+/// Test if the entry with the given key belongs to an older generation of a
+/// generational namespace than the given persisted generations.
+fn is_old_generation(key: &[u8], generations: &HashMap<Vec<u8>, u64>) -> bool {
+    if generations.is_empty() {
+        return false;
+    }
+
+    let levels = match split_key(key) {
+        Ok((ns, _)) => key::generations(ns).ok().map(|levels| (ns, levels)),
+        Err(_) => None,
+    };
+
+    let (ns, levels) = match levels {
+        Some(levels) => levels,
+        None => return false,
+    };
+
+    levels.into_iter().any(|(offset, generation)| {
+        generations
+            .get(&ns[..offset])
+            .is_some_and(|current| generation < *current)
+    })
+}
+
+/// Construct the key used to store the given kind of internal metadata.
+fn meta_key(name: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(name.len() + 1);
+    key.push(META_PREFIX);
+    key.extend_from_slice(name.as_bytes());
+    key
+}
+
+/// Metadata key prefix of the tag index.
+const TAG_KEY: &str = "tag/";
+
+/// Construct the prefix of all index keys for the given tag.
+///
+/// The tag is length-prefixed so that no tag is a prefix of another.
+fn tag_prefix(tag: &str) -> Vec<u8> {
+    let mut prefix = meta_key(TAG_KEY);
+    prefix.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(tag.as_bytes());
+    prefix
+}
+
+/// Construct the index key associating the given tag with an entry key.
+fn tag_key(tag: &str, key: &[u8]) -> Vec<u8> {
+    let mut index_key = tag_prefix(tag);
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Metadata key prefix of the dependency index.
+const DEP_KEY: &str = "dep/";
+
+/// Construct the prefix of all index keys for entries derived from the entry
+/// with the given key.
+fn dep_prefix(dep: &[u8]) -> Vec<u8> {
+    let mut prefix = meta_key(DEP_KEY);
+    prefix.extend_from_slice(&(dep.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(dep);
+    prefix
+}
+
+/// Construct the index key recording that an entry is derived from another.
+fn dep_key(dep: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut index_key = dep_prefix(dep);
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Metadata key prefix of secondary indexes.
+const INDEX_KEY: &str = "index/";
+
+/// Construct the prefix of all index keys for entries with the given value in
+/// the given index.
+///
+/// The name and the value are length-prefixed so that neither is a prefix of
+/// another.
+fn index_prefix(name: &str, indexed: &[u8]) -> Vec<u8> {
+    let mut prefix = meta_key(INDEX_KEY);
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name.as_bytes());
+    prefix.extend_from_slice(&(indexed.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(indexed);
+    prefix
+}
+
+/// Construct the index key associating a value in an index with an entry key.
+fn index_key(name: &str, indexed: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut index_key = index_prefix(name, indexed);
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Metadata key storing the last allocated chunk identifier.
+const CHUNK_ID_KEY: &str = "chunk-id";
+
+/// Metadata key prefix of chunks.
+const CHUNK_KEY: &str = "chunk/";
+
+/// Metadata key prefix of the markers of chunks which are being written.
+const PENDING_KEY: &str = "pending/";
+
+/// Describe how many warnings were suppressed since the last one was logged.
+fn suppressed_warnings(suppressed: u64) -> String {
+    match suppressed {
+        0 => String::new(),
+        n => format!(" ({} similar warnings suppressed)", n),
+    }
+}
+
+/// How long chunks can be pending before their write is considered to be
+/// abandoned.
+const PENDING_TIMEOUT: Duration = Duration::hours(24);
+
+/// Construct the key of the marker of the pending chunks with the given
+/// identifier.
+///
+/// The marker stores when the chunks started being written, in milliseconds
+/// since the Unix epoch.
+fn pending_key(id: u64) -> Vec<u8> {
+    let mut key = meta_key(PENDING_KEY);
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+/// Construct the key of a chunk of the given entry.
+///
+/// The entry key is length-prefixed, followed by the identifier of the chunks
+/// and the index of the chunk, so that the chunks of an entry are stored in
+/// order.
+fn chunk_key(key: &[u8], id: u64, index: u32) -> Vec<u8> {
+    let mut chunk_key = meta_key(CHUNK_KEY);
+    chunk_key.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    chunk_key.extend_from_slice(key);
+    chunk_key.extend_from_slice(&id.to_be_bytes());
+    chunk_key.extend_from_slice(&index.to_be_bytes());
+    chunk_key
+}
+
+/// Split a chunk key into the key of its entry and the identifier of its
+/// chunks.
+fn split_chunk_key(chunk_key: &[u8]) -> Option<(&[u8], u64)> {
+    let rest = chunk_key.get(CHUNK_KEY.len() + 1..)?;
+    let (len, rest) = split_array::<4>(rest)?;
+    let len = u32::from_be_bytes(len) as usize;
+    let key = rest.get(..len)?;
+    let (id, _) = split_array::<8>(rest.get(len..)?)?;
+    Some((key, u64::from_be_bytes(id)))
+}
+
+/// Split a fixed-size array off the front of a slice.
+fn split_array<const N: usize>(bytes: &[u8]) -> Option<([u8; N], &[u8])> {
+    let head = <[u8; N]>::try_from(bytes.get(..N)?).ok()?;
+    Some((head, &bytes[N..]))
+}
+
+/// The keys of the tag, dependency and secondary index records of the given
+/// entry.
+fn index_keys(key: &[u8], entry: &PartialStoredEntry) -> Vec<Vec<u8>> {
+    let tags = entry.tags.iter().map(|tag| tag_key(tag, key));
+    let deps = entry.deps.iter().map(|dep| dep_key(dep, key));
+    let indexes = entry
+        .indexes
+        .iter()
+        .map(|(name, indexed)| index_key(name, indexed, key));
+
+    tags.chain(deps).chain(indexes).collect()
+}
+
+/// The keys of the chunks of the given entry, or of its items if it's a list.
+fn chunk_keys<'a>(key: &'a [u8], entry: &PartialStoredEntry) -> impl Iterator<Item = Vec<u8>> + 'a {
+    let chunks = entry
+        .chunks
+        .map(|chunks| (chunks.id, 0..chunks.count))
+        .or(entry.list.map(|list| (list.id, list.start..list.end)));
+
+    chunks
+        .into_iter()
+        .flat_map(move |(id, range)| range.map(move |index| chunk_key(key, id, index)))
+}
+
+/// Metadata key prefix of the retained versions of entries.
+const HISTORY_KEY: &str = "history/";
+
+/// Construct the prefix of the keys of the retained versions of an entry.
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = meta_key(HISTORY_KEY);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+/// Construct the key of a retained version of an entry.
+///
+/// Versions are identified by increasing identifiers, so that the versions of
+/// an entry are stored from oldest to newest.
+fn history_key(key: &[u8], id: u64) -> Vec<u8> {
+    let mut history_key = history_prefix(key);
+    history_key.extend_from_slice(&id.to_be_bytes());
+    history_key
+}
+
+/// Split the key of a retained version into the key of its entry.
+fn split_history_key(history_key: &[u8]) -> Option<&[u8]> {
+    let rest = history_key.get(HISTORY_KEY.len() + 1..)?;
+    let (len, rest) = split_array::<4>(rest)?;
+    rest.get(..u32::from_be_bytes(len) as usize)
+}
+
+/// Key of the counter of records in an audit log.
+///
+/// Records are stored under their identifier, which is always eight bytes, so
+/// the counter sorts before every record.
+const AUDIT_ID_KEY: &[u8] = &[];
+
+/// Decode a record of an audit log.
+fn decode_audit_record(key: &[u8], value: &[u8]) -> Result<AuditRecord, Error> {
+    let mut record: AuditRecord = cbor::from_slice(value)?;
+    record.id = decode_counter(key);
+    Ok(record)
+}
+
+/// Metadata key prefix of the positions of migrations.
+const MIGRATE_KEY: &str = "migrate/";
+
+/// Construct the key recording the position of the migration of the entries
+/// with the given prefix from the format `from` to `to`.
+fn migrate_cursor_key(from: &Format, to: &Format, prefix: &[u8]) -> Vec<u8> {
+    let mut key = meta_key(MIGRATE_KEY);
+    key.extend_from_slice(&[from.id(), to.id()]);
+    key.extend_from_slice(prefix);
+    key
+}
+
+/// Metadata key prefix of the positions of namespace renames.
+const RENAME_KEY: &str = "rename/";
+
+/// Construct the key recording the position of the rename of the namespace
+/// `from` to `to`.
+fn rename_cursor_key(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut key = meta_key(RENAME_KEY);
+    key.extend_from_slice(&(from.len() as u32).to_be_bytes());
+    key.extend_from_slice(from);
+    key.extend_from_slice(to);
+    key
+}
+
+/// Construct the key of an entry after the namespace `from` is renamed to
+/// `to`, or `None` if the entry isn't in `from` or a namespace nested inside
+/// of it.
+///
+/// Since each level of a namespace is self-delimiting, an encoded namespace
+/// only starts with `from` if it's `from` or nested inside of it.
+fn rename_key(key: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+    let (ns, key) = split_key(key).ok()?;
+    let rest = ns.strip_prefix(from)?;
+
+    let mut new_ns = to.to_vec();
+    new_ns.extend_from_slice(rest);
+    Some(join_key(&new_ns, key))
+}
+
+/// Metadata key prefix of deduplicated values.
+const BLOB_KEY: &str = "blob/";
+
+/// Metadata key prefix of the reference counts of deduplicated values.
+const BLOB_REF_KEY: &str = "blob-ref/";
+
+/// Construct the key of the deduplicated value with the given hash.
+fn blob_key(hash: &[u8]) -> Vec<u8> {
+    let mut key = meta_key(BLOB_KEY);
+    key.extend_from_slice(hash);
+    key
+}
+
+/// Construct the key of the reference count of the deduplicated value with
+/// the given hash.
+fn blob_ref_key(hash: &[u8]) -> Vec<u8> {
+    let mut key = meta_key(BLOB_REF_KEY);
+    key.extend_from_slice(hash);
+    key
+}
+
+/// Hash a serialized value for deduplication, or return `None` if values
+/// aren't deduplicated.
+///
+/// The format and flags are included, since the same bytes decode differently
+/// depending on them.
+#[cfg_attr(not(feature = "deduplicate"), allow(unused_variables))]
+fn blob_hash(config: &Config, format: u8, flags: u8, payload: &[u8]) -> Option<Vec<u8>> {
+    #[cfg(feature = "deduplicate")]
+    {
+        if config.deduplicate {
+            let mut hasher = Sha256::new();
+            hasher.update([format, flags]);
+            hasher.update(payload);
+            return Some(hasher.finalize().to_vec());
+        }
+    }
+
+    None
+}
+
+/// Decode a stored counter, like the invalidation epoch.
+fn decode_counter(value: &[u8]) -> u64 {
+    match <[u8; 8]>::try_from(value) {
+        Ok(bytes) => u64::from_be_bytes(bytes),
+        Err(_) => {
+            log::warn!(
+                "ignoring malformed counter: {}",
+                value.encode_hex::<String>()
+            );
+            0
+        }
+    }
+}
+
+/// Magic byte which starts entries stored with a header.
+///
+/// Legacy entries are CBOR maps, which never start with this byte.
+const ENTRY_MAGIC: u8 = 0xfc;
+/// How many times reading an entry is attempted when it's modified while
+/// it's being read.
+const MAX_READ_ATTEMPTS: usize = 16;
+/// The current version of the entry format.
+const ENTRY_VERSION: u8 = 1;
+/// The length of the entry header.
+const HEADER_LEN: usize = 8;
+
+/// Flag set on entries whose value is compressed with zstd.
+const FLAG_COMPRESSED: u8 = 0b0000_0001;
+/// Flag set on entries whose value is encrypted.
+const FLAG_ENCRYPTED: u8 = 0b0000_0010;
+/// Flag set on entries with checksums of their metadata and value.
+const FLAG_CHECKSUM: u8 = 0b0000_0100;
+/// Flag set on entries whose value is raw bytes rather than serialized with
+/// their format.
+const FLAG_RAW: u8 = 0b0000_1000;
+/// Flag set on chunked entries whose chunks are encrypted separately.
+const FLAG_CHUNKS_ENCRYPTED: u8 = 0b0001_0000;
+/// The length of the checksums of entries and blobs.
+const CHECKSUM_LEN: usize = 4;
+/// The size of chunks used when streaming values, unless configured.
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// The number of entries scanned to fill a page of a listing.
+const MAX_PAGE_SCAN: usize = 10_000;
+/// The number of namespaces with their own statistics, unless configured.
+const DEFAULT_MAX_STATS_NAMESPACES: usize = 256;
+/// The number of entries scanned at a time by [EntryStream] and [JsonStream].
+const STREAM_BATCH_SIZE: usize = 256;
+
+/// Serialize a value with the given format, compressing it as configured and
+/// returning the flags to record in the entry.
+fn encode_value<T>(
+    threshold: Option<usize>,
+    format: &Format,
+    value: &T,
+) -> Result<(Vec<u8>, u8), Error>
+where
+    T: Serialize,
+{
+    compress(threshold, format.encode(value)?)
+}
+
+/// Compress raw bytes as configured, returning the flags to record in the
+/// entry.
+fn encode_raw(threshold: Option<usize>, value: &[u8]) -> Result<(Vec<u8>, u8), Error> {
+    let (value, flags) = compress(threshold, value.to_vec())?;
+    Ok((value, flags | FLAG_RAW))
+}
+
+/// Join the metadata and serialized value of an entry.
+///
+/// Entries are stored with a header, followed by their metadata and value:
+///
+/// ```text
+/// [magic][version][flags][format][metadata length][metadata][checksum][value][checksum]
+/// ```
+///
+/// The length of the metadata is a big-endian `u32`, and the metadata is a
+/// CBOR map. The flags record how the value was transformed after it was
+/// serialized with the given format, and whether the entry has checksums. The
+/// first checksum is the big-endian CRC32 of the header and the metadata, and
+/// the second one of the value, so that the metadata can be verified without
+/// reading the value.
+fn join_entry(
+    format: u8,
+    flags: u8,
+    meta: &PartialStoredEntry,
+    value: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let flags = flags | FLAG_CHECKSUM;
+    let mut out = vec![ENTRY_MAGIC, ENTRY_VERSION, flags, format, 0, 0, 0, 0];
+    cbor::to_writer(&mut out, meta)?;
+
+    let meta_len = u32::try_from(out.len() - HEADER_LEN)
+        .map_err(|_| Error::Codec("entry metadata is too large".into()))?;
+
+    out[4..HEADER_LEN].copy_from_slice(&meta_len.to_be_bytes());
+    out.reserve(value.len() + 2 * CHECKSUM_LEN);
+
+    let checksum = crc32fast::hash(&out);
+    out.extend_from_slice(&checksum.to_be_bytes());
+    out.extend_from_slice(value);
+    out.extend_from_slice(&crc32fast::hash(value).to_be_bytes());
+    Ok(out)
+}
+
+/// Split an entry into its metadata and serialized value.
+///
+/// The value of legacy entries without a header is converted into how it
+/// would be stored with a header.
+fn split_entry(value: &[u8]) -> Result<(PartialStoredEntry, Cow<'_, [u8]>), Error> {
+    if let Some((meta, value)) = split_header(value, true)? {
+        return Ok((meta, Cow::Borrowed(value)));
+    }
+
+    let stored: StoredEntry<cbor::Value> = cbor::from_slice(value)?;
+
+    // untransformed CBOR values are embedded directly in legacy entries, while
+    // other values are embedded as a byte string.
+    let value = match (stored.format, stored.flags, &stored.value) {
+        (0, 0, value) => cbor::to_vec(value)?,
+        (_, _, cbor::Value::Bytes(bytes)) => bytes.clone(),
+        _ => return Err(Error::Codec("malformed legacy entry".into())),
+    };
+
+    let meta = PartialStoredEntry {
+        expires_at: stored.expires_at,
+        stored_at: stored.stored_at,
+        sliding: stored.sliding,
+        pinned: stored.pinned,
+        deleted_at: stored.deleted_at,
+        epoch: stored.epoch,
+        tags: stored.tags,
+        deps: stored.deps,
+        format: stored.format,
+        flags: stored.flags,
+        original_key: stored.original_key,
+        chunks: None,
+        blob: None,
+        schema: 0,
+        list: None,
+        indexes: Vec::new(),
+        metadata: stored.metadata,
+    };
+
+    Ok((meta, Cow::Owned(value)))
+}
+
+/// Split an entry with a header into its metadata and serialized value.
+///
+/// The checksum of the metadata is always verified, while the checksum of the
+/// value is only verified if `verify_value` is set, so that reading only the
+/// metadata doesn't depend on the size of the value.
+///
+/// Returns `None` if this is a legacy entry without a header.
+fn split_header(
+    value: &[u8],
+    verify_value: bool,
+) -> Result<Option<(PartialStoredEntry, &[u8])>, Error> {
+    if value.first() != Some(&ENTRY_MAGIC) {
+        return Ok(None);
+    }
+
+    if value.len() < HEADER_LEN {
+        return Err(Error::Corrupt);
+    }
+
+    if value[1] != ENTRY_VERSION {
+        return Err(Error::UnsupportedVersion(value[1]));
+    }
+
+    let meta_len = u32::from_be_bytes([value[4], value[5], value[6], value[7]]) as usize;
+    let meta_end = HEADER_LEN.saturating_add(meta_len);
+
+    if value.len() < meta_end {
+        return Err(Error::Corrupt);
+    }
+
+    let (header, rest) = value.split_at(meta_end);
+
+    let rest = if value[2] & FLAG_CHECKSUM != 0 {
+        if rest.len() < 2 * CHECKSUM_LEN {
+            return Err(Error::Corrupt);
+        }
+
+        let (checksum, rest) = rest.split_at(CHECKSUM_LEN);
+        let (rest, value_checksum) = rest.split_at(rest.len() - CHECKSUM_LEN);
+
+        if crc32fast::hash(header).to_be_bytes() != checksum {
+            return Err(Error::Corrupt);
+        }
+
+        if verify_value && crc32fast::hash(rest).to_be_bytes() != value_checksum {
+            return Err(Error::Corrupt);
+        }
+
+        rest
+    } else {
+        rest
+    };
+
+    let (header, meta) = header.split_at(HEADER_LEN);
+    let mut meta: PartialStoredEntry = cbor::from_slice(meta)?;
+    meta.flags = header[2] & !FLAG_CHECKSUM;
+    meta.format = header[3];
+    Ok(Some((meta, rest)))
+}
+
+/// Decode the metadata of an entry without decoding or verifying its value.
+fn decode_meta(value: &[u8]) -> Result<PartialStoredEntry, Error> {
+    match split_header(value, false)? {
+        Some((meta, _)) => Ok(meta),
+        None => Ok(cbor::from_slice(value)?),
+    }
+}
+
+/// Decode the serialized value of an entry with the format it was stored
+/// with.
+fn decode_payload<T>(
+    config: &Config,
+    meta: PartialStoredEntry,
+    value: Cow<'_, [u8]>,
+) -> Result<StoredEntry<T>, Error>
+where
+    T: 'static + serde::de::DeserializeOwned,
+{
+    let value = decompress(meta.flags, value)?;
+
+    if meta.flags & FLAG_RAW != 0 {
+        let value = T::deserialize(de::value::BytesDeserializer::new(&value))
+            .map_err(|e: de::value::Error| Error::Codec(e.into()))?;
+        return Ok(meta.into_stored_entry(config, value));
+    }
+
+    let format = match config.format_of(meta.format) {
+        Some(format) => format,
+        None => return Err(Error::UnsupportedFormat(meta.format)),
+    };
+
+    let value = match config.schema::<T>() {
+        Some(schema) if schema.version != meta.schema => {
+            schema.migrate(&format, meta.schema, &value)?
+        }
+        _ => format.decode(&value)?,
+    };
+
+    Ok(meta.into_stored_entry(config, value))
+}
+
+/// Decompress the decrypted serialized value of an entry, converting it to
+/// the given format.
+///
+/// Returns `None` if the value can't be converted to the given format.
+fn convert_payload(
+    config: &Config,
+    meta: &PartialStoredEntry,
+    payload: Cow<'_, [u8]>,
+    format: &Format,
+) -> Result<Option<Vec<u8>>, Error> {
+    let payload = decompress(meta.flags, payload)?;
+
+    if meta.flags & FLAG_RAW != 0 || meta.format == format.id() {
+        return Ok(Some(payload.into_owned()));
+    }
+
+    let from = match config.format_of(meta.format) {
+        Some(from) => from,
+        None => return Err(Error::UnsupportedFormat(meta.format)),
+    };
+
+    if !from.is_self_describing() || !format.is_self_describing() {
+        return Ok(None);
+    }
+
+    let value: cbor::Value = from.decode(&payload)?;
+    Ok(Some(format.encode(&value)?))
+}
+
+/// Decode the serialized value of an entry without knowing its type, for
+/// [Cache::update_path].
+fn decode_dynamic(
+    config: &Config,
+    meta: &PartialStoredEntry,
+    value: Cow<'_, [u8]>,
+) -> Result<cbor::Value, Error> {
+    if meta.list.is_some() || meta.flags & FLAG_RAW != 0 {
+        return Err(Error::Codec(
+            "raw and list values can't be updated in place".into(),
+        ));
+    }
+
+    let format = match config.format_of(meta.format) {
+        Some(format) if format.is_self_describing() => format,
+        Some(_) => {
+            return Err(Error::Codec(
+                "values in this format can't be updated in place".into(),
+            ))
+        }
+        None => return Err(Error::UnsupportedFormat(meta.format)),
+    };
+
+    let value = decompress(meta.flags, value)?;
+    format.decode(&value)
+}
+
+/// Decode the part of the serialized value of an entry at the given path.
+fn decode_path<T>(
+    config: &Config,
+    meta: PartialStoredEntry,
+    value: Cow<'_, [u8]>,
+    path: &[String],
+) -> Result<StoredEntry<Option<T>>, Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let value = decompress(meta.flags, value)?;
+
+    if meta.flags & FLAG_RAW != 0 {
+        return Err(Error::Codec("raw values can't be navigated".into()));
+    }
+
+    let format = match config.format_of(meta.format) {
+        Some(format) => format,
+        None => return Err(Error::UnsupportedFormat(meta.format)),
+    };
+
+    let (format, value) = format.transcode(value)?;
+    let value = format.decode_seed(&value, path::PathSeed::new(path))?;
+    Ok(meta.into_stored_entry(config, value))
+}
+
+/// Compress a serialized value if it exceeds the given threshold, returning
+/// the flags to record in the entry.
+#[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+fn compress(threshold: Option<usize>, value: Vec<u8>) -> Result<(Vec<u8>, u8), Error> {
+    #[cfg(feature = "zstd")]
+    {
+        if let Some(threshold) = threshold {
+            if value.len() > threshold {
+                let value = zstd::encode_all(&value[..], 0).map_err(|e| Error::Codec(e.into()))?;
+                return Ok((value, FLAG_COMPRESSED));
+            }
+        }
+    }
+
+    Ok((value, 0))
+}
+
+/// Encrypt a serialized value if a cipher is configured, returning the
+/// updated flags to record in the entry.
+///
+/// The random nonce used is prepended to the encrypted value. The key the
+/// value is stored under and how it's encoded are authenticated along with it,
+/// so that encrypted values can't be moved between keys undetected. See
+/// [associated_data].
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn encrypt(
+    config: &Config,
+    key: &[u8],
+    format: u8,
+    value: Vec<u8>,
+    flags: u8,
+) -> Result<(Vec<u8>, u8), Error> {
+    #[cfg(feature = "encryption")]
+    {
+        if let Some(cipher) = &config.cipher {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let aad = associated_data(key, format, flags);
+
+            let payload = aead::Payload {
+                msg: &value[..],
+                aad: &aad,
+            };
+
+            let encrypted = cipher
+                .encrypt(&nonce, payload)
+                .map_err(|_| Error::Codec("failed to encrypt entry".into()))?;
+
+            let mut out = Vec::with_capacity(nonce.len() + encrypted.len());
+            out.extend_from_slice(&nonce);
+            out.extend_from_slice(&encrypted);
+            return Ok((out, flags | FLAG_ENCRYPTED));
+        }
+    }
+
+    Ok((value, flags))
+}
+
+/// Decrypt a serialized value according to the flags of its entry.
+///
+/// The value has to be read from the same key and with the same format and
+/// flags that it was encrypted with, or else it's reported as
+/// [Error::Corrupt]. So is a value encrypted with a different encryption key,
+/// since the two can't be told apart.
+#[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+fn decrypt<'a>(
+    config: &Config,
+    key: &[u8],
+    format: u8,
+    flags: u8,
+    value: Cow<'a, [u8]>,
+) -> Result<Cow<'a, [u8]>, Error> {
+    if flags & FLAG_ENCRYPTED == 0 {
+        return Ok(value);
+    }
+
+    #[cfg(feature = "encryption")]
+    {
+        let cipher = match &config.cipher {
+            Some(cipher) => cipher,
+            None => {
+                return Err(Error::Codec(
+                    "entry is encrypted, but no encryption key is configured".into(),
+                ))
+            }
+        };
+
+        const NONCE_LEN: usize = 24;
+
+        if value.len() < NONCE_LEN {
+            return Err(Error::Codec("encrypted entry is truncated".into()));
+        }
+
+        let (nonce, encrypted) = value.split_at(NONCE_LEN);
+        let aad = associated_data(key, format, flags);
+
+        let payload = aead::Payload {
+            msg: encrypted,
+            aad: &aad,
+        };
+
+        let decrypted = cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Corrupt)?;
+
+        Ok(Cow::Owned(decrypted))
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    {
+        Err(Error::Codec(
+            "entry is encrypted, but the encryption feature is not enabled".into(),
+        ))
+    }
+}
+
+/// Re-encrypt a value which was encrypted for the key `from` for the key `to`,
+/// for when entries are moved.
+fn reencrypt(
+    config: &Config,
+    from: &[u8],
+    to: &[u8],
+    format: u8,
+    flags: u8,
+    value: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let value = decrypt(config, from, format, flags, Cow::Borrowed(value))?;
+    let (value, _) = encrypt(
+        config,
+        to,
+        format,
+        value.into_owned(),
+        flags & !FLAG_ENCRYPTED,
+    )?;
+    Ok(value)
+}
+
+/// The data authenticated along with an encrypted value: the format and the
+/// flags describing how it's encoded, followed by the key it's stored under.
+///
+/// Values of entries are bound to the key of their entry, or the key of their
+/// blob if they're deduplicated. Separately encrypted chunks and items of lists
+/// are bound to the key of their chunk.
+#[cfg(feature = "encryption")]
+fn associated_data(key: &[u8], format: u8, flags: u8) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(2 + key.len());
+    aad.push(format);
+    aad.push(flags & (FLAG_COMPRESSED | FLAG_RAW));
+    aad.extend_from_slice(key);
+    aad
+}
+
+/// Decompress a serialized value according to the flags of its entry.
+fn decompress(flags: u8, value: Cow<'_, [u8]>) -> Result<Cow<'_, [u8]>, Error> {
+    if flags & FLAG_COMPRESSED == 0 {
+        return Ok(value);
+    }
+
+    #[cfg(feature = "zstd")]
+    {
+        let decompressed = zstd::decode_all(&value[..]).map_err(|e| Error::Codec(e.into()))?;
+        Ok(Cow::Owned(decompressed))
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    {
+        Err(Error::Codec(
+            "entry is compressed, but the zstd feature is not enabled".into(),
+        ))
+    }
+}
+
+/// Read from the given reader until the buffer is full or the reader is
+/// exhausted, returning the number of bytes read.
+async fn read_chunk<R>(reader: &mut R, buf: &mut [u8]) -> Result<usize, io::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+
+    Ok(filled)
+}
+
+/// Decode the namespace and key of an entry as JSON.
+fn decode_key_json(key: &[u8]) -> Result<(json::Value, json::Value), Error> {
+    let (ns, key) = split_key(key)?;
+    Ok((namespace_json(ns)?, key::to_json(key)?))
+}
+
+/// Decode an encoded namespace as JSON.
+///
+/// The absence of a namespace is decoded as `null`, and namespaces as an array
+/// of their levels, outermost first. Namespaces which aren't nested are arrays
+/// too, so that a nested namespace is never mistaken for a namespace which is
+/// itself an array or a tuple.
+fn namespace_json(ns: &[u8]) -> Result<json::Value, Error> {
+    let levels = key::namespace_to_json(ns)?;
+
+    if levels.is_empty() {
+        return Ok(json::Value::Null);
+    }
+
+    Ok(json::Value::Array(levels))
+}
+
+/// Helper formatter to convert keys or cbor bytes to JSON or hex, redacted
+/// according to the configured [KeyRedaction].
+struct KeyFormat<'a> {
+    bytes: &'a [u8],
+    redaction: KeyRedaction,
+}
+
+impl KeyFormat<'_> {
+    /// Redact the given bytes, which are either a key or a value.
+    #[cfg_attr(not(feature = "key-hashing"), allow(unused_variables))]
+    fn redact(&self, bytes: &[u8]) -> json::Value {
+        let mut out = json::Map::new();
+
+        match self.redaction {
+            #[cfg(feature = "key-hashing")]
+            KeyRedaction::Hashed => {
+                let hash = Sha256::digest(bytes);
+                out.insert(String::from("hashed"), hex::encode(&hash[..8]).into());
+            }
+            _ => {
+                out.insert(String::from("redacted"), true.into());
+            }
+        }
+
+        json::Value::Object(out)
+    }
+}
+
+impl fmt::Display for KeyFormat<'_> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Ok((ns, key)) = decode_key_json(self.bytes) {
+            let key = match self.redaction {
+                KeyRedaction::Full => key,
+                _ => self.redact(split_key(self.bytes).map_or(self.bytes, |(_, key)| key)),
+            };
+
+            return json::Value::Array(vec![ns, key]).fmt(fmt);
+        }
+
+        if self.redaction != KeyRedaction::Full {
+            return self.redact(self.bytes).fmt(fmt);
+        }
+
+        let value = match cbor::from_slice::<cbor::Value>(self.bytes) {
+            Ok(value) => value,
+            Err(_) => return self.bytes.encode_hex::<String>().fmt(fmt),
+        };
+
+        let value = match json::to_string(&value) {
+            Ok(value) => value,
+            Err(_) => return self.bytes.encode_hex::<String>().fmt(fmt),
+        };
+
+        value.fmt(fmt)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+/// Represents an expired key. The key is not introspectable except observing it's namespace
+/// and when it expired.
+pub struct ExpiredKey {
+    ns: Option<hashkey::Key>,
+    key: hashkey::Key,
+    expired_at: DateTime<Utc>,
+    /// When the key was found to be expired, according to the clock of the
+    /// cache it was found in.
+    checked_at: DateTime<Utc>,
+}
+
+impl ExpiredKey {
+    /// Returns the namespace of the expired key
+    pub fn namespace(&self) -> Option<&hashkey::Key> {
+        self.ns.as_ref()
+    }
+    /// Returns the datetime (UTC) the key expired at
+    pub fn expired_at(&self) -> &DateTime<Utc> {
+        assert!(
+            self.expired_at <= self.checked_at,
+            "Expired key expired before current time (did your clock skew?)"
+        );
+        &self.expired_at
+    }
+    #[cfg(test)]
+    /// Convert a namespace and key into an ExpiredKey with DateTime set to the unix epoch
+    pub fn from(ns: Option<&[u8]>, key: &[u8]) -> Result<Self, Error> {
+        Ok(Self {
+            ns: ns.map(|ns| hashkey::to_key(&ns)).transpose()?,
+            key: hashkey::to_key(&key)?,
+            expired_at: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
+            checked_at: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
+        })
+    }
+    #[cfg(test)]
+    /// The DateTime of the expired_at timestamp is set to the unix epoch
+    pub fn zero_expiry(mut self) -> Self {
+        self.expired_at = DateTime::<Utc>::from(std::time::UNIX_EPOCH);
+        self.checked_at = DateTime::<Utc>::from(std::time::UNIX_EPOCH);
+        self
+    }
+}
+
+/// Decode an entry which has expired but is still present in the database,
+/// returning `None` if the entry is not expired, is a tombstone, or can't be
+/// decoded.
+fn decode_expired<T>(
+    cache: &Cache,
+    key: &[u8],
+    value: &[u8],
+    now: DateTime<Utc>,
+    epoch: u64,
+) -> Option<(json::Value, StoredEntry<T>)>
+where
+    T: 'static + serde::de::DeserializeOwned,
+{
+    let entry = decode_meta(value).ok()?;
+
+    if !entry.is_expired(now, epoch) || entry.deleted_at.is_some() {
+        return None;
+    }
+
+    let stored: StoredEntry<T> = match cache.decode_entry(key, value) {
+        Ok(stored) => stored?,
+        Err(e) => {
+            log::warn!("{}: failed to deserialize: {}", cache.key_format(key), e);
+            return None;
+        }
+    };
+
+    let (_, key) = decode_key_json(key).ok()?;
+    Some((key, stored))
+}
+
+/// Stream returned by [Cache::stream_entries].
+pub struct EntryStream {
+    cache: Cache,
+    /// The iterator over the database, unless it's scanning the next batch.
+    iter: Option<sled::Iter>,
+    /// The batch being scanned on the pool, along with the iterator.
+    pending: Option<pool::Task<(sled::Iter, JsonBatch)>>,
+    buf: VecDeque<Result<JsonEntry, Error>>,
+    done: bool,
+    /// Scan the next batch while the current one is consumed.
+    prefetch: bool,
+}
+
+impl EntryStream {
+    /// Scan the next batch of entries on the pool.
+    fn fill(&mut self) {
+        let (cache, mut iter) = match self.iter.take() {
+            Some(iter) => (self.cache.clone(), iter),
+            None => return,
+        };
+
+        self.pending = Some(pool::spawn(move || {
+            let mut buf = VecDeque::new();
+            let done = fill_json_batch(&cache, &mut iter, &mut buf);
+            (iter, (buf, done))
+        }));
+    }
+}
+
+/// Scan the next batch of entries as JSON into the buffer, returning `true`
+/// once the iterator is exhausted.
+fn fill_json_batch(
+    cache: &Cache,
+    iter: &mut sled::Iter,
+    buf: &mut VecDeque<Result<JsonEntry, Error>>,
+) -> bool {
+    while buf.len() < STREAM_BATCH_SIZE {
+        let (key, value) = match iter.next() {
+            Some(Ok(entry)) => entry,
+            Some(Err(e)) => {
+                buf.push_back(Err(e.into()));
+                continue;
+            }
+            None => return true,
+        };
+
+        if let Some(entry) = cache.json_entry(&key, &value) {
+            buf.push_back(Ok(entry));
+        }
+    }
+
+    false
+}
+
+/// A batch of entries scanned on the pool, and whether the scan is complete.
+type JsonBatch = (VecDeque<Result<JsonEntry, Error>>, bool);
+
+/// Stream returned by [Cache::list_json_stream].
+pub struct JsonStream {
+    entries: EntryStream,
+}
+
+impl Stream for JsonStream {
+    type Item = Result<JsonEntry, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().entries).poll_next(cx)
+    }
+}
+
+impl Stream for EntryStream {
+    type Item = Result<JsonEntry, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(entry) = this.buf.pop_front() {
+                return Poll::Ready(Some(entry));
+            }
+
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let pending = match &mut this.pending {
+                Some(pending) => pending,
+                None => {
+                    this.fill();
+                    continue;
+                }
+            };
+
+            match Pin::new(pending).poll(cx) {
+                Poll::Ready(Ok((iter, (buf, done)))) => {
+                    this.iter = Some(iter);
+                    this.buf = buf;
+                    this.done = done;
+                    this.pending = None;
+
+                    if this.prefetch && !done {
+                        this.fill();
+                    }
+                }
+                // the scan panicked, so the rest of the entries can't be
+                // scanned.
+                Poll::Ready(Err(e)) => {
+                    this.done = true;
+                    this.pending = None;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Iterator returned by [Cache::diff].
+pub struct Diff {
+    this: Cache,
+    other: Cache,
+    left: std::iter::Peekable<sled::Iter>,
+    right: std::iter::Peekable<sled::Iter>,
+}
+
+impl Diff {
+    /// Compare two entries stored under the same key.
+    fn compare(&self, key: &[u8], left: &[u8], right: &[u8]) -> Option<Difference> {
+        let (left_meta, right_meta) = match (live_meta(left), live_meta(right)) {
+            (None, None) => return None,
+            (Some(meta), None) => {
+                let key = self.this.diff_key(key, meta.ok().as_ref());
+                return Some(Difference::OnlyInThis { key });
+            }
+            (None, Some(meta)) => {
+                let key = self.other.diff_key(key, meta.ok().as_ref());
+                return Some(Difference::OnlyInOther { key });
+            }
+            (Some(Ok(left)), Some(Ok(right))) => (left, right),
+            // entries which can't be decoded are only equal if they're
+            // identical.
+            _ if left == right => return None,
+            (Some(left), Some(..)) => {
+                return Some(Difference::Changed {
+                    key: self.this.diff_key(key, left.ok().as_ref()),
+                    value: true,
+                    expires_at: true,
+                });
+            }
+        };
+
+        let expires_at = left_meta.expires_at != right_meta.expires_at;
+
+        let value = match (
+            self.this.json_entry(key, left),
+            self.other.json_entry(key, right),
+        ) {
+            (Some(left), Some(right)) => left.stored.value != right.stored.value,
+            _ => left != right,
+        };
+
+        if !value && !expires_at {
+            return None;
+        }
+
+        Some(Difference::Changed {
+            key: self.this.diff_key(key, Some(&left_meta)),
+            value,
+            expires_at,
+        })
+    }
+}
+
+impl Iterator for Diff {
+    type Item = Result<Difference, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ordering = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                (Some(Err(..)), _) => return Some(Err(self.left.next()?.err()?.into())),
+                (_, Some(Err(..))) => return Some(Err(self.right.next()?.err()?.into())),
+                (Some(Ok((left, _))), Some(Ok((right, _)))) => left.cmp(right),
+                (Some(..), None) => std::cmp::Ordering::Less,
+                (None, Some(..)) => std::cmp::Ordering::Greater,
+            };
+
+            match ordering {
+                std::cmp::Ordering::Less => {
+                    let (key, value) = self.left.next()?.ok()?;
+
+                    if let Some(meta) = live_meta(&value) {
+                        let key = self.this.diff_key(&key, meta.ok().as_ref());
+                        return Some(Ok(Difference::OnlyInThis { key }));
+                    }
+                }
+                std::cmp::Ordering::Greater => {
+                    let (key, value) = self.right.next()?.ok()?;
+
+                    if let Some(meta) = live_meta(&value) {
+                        let key = self.other.diff_key(&key, meta.ok().as_ref());
+                        return Some(Ok(Difference::OnlyInOther { key }));
+                    }
+                }
+                std::cmp::Ordering::Equal => {
+                    let (key, left) = self.left.next()?.ok()?;
+                    let (_, right) = self.right.next()?.ok()?;
+
+                    if let Some(difference) = self.compare(&key, &left, &right) {
+                        return Some(Ok(difference));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Decode the metadata of an entry which is compared by [Cache::diff], or
+/// `None` if the entry is deleted or a list and is treated as missing.
+fn live_meta(value: &[u8]) -> Option<Result<PartialStoredEntry, Error>> {
+    match decode_meta(value) {
+        Ok(meta) if meta.deleted_at.is_some() || meta.list.is_some() => None,
+        meta => Some(meta),
+    }
+}
+
+/// Iterator returned by [Cache::iter] and [Cache::scan].
+pub struct Iter<K, T> {
+    cache: Cache,
+    iter: sled::Iter,
+    now: DateTime<Utc>,
+    epoch: u64,
+    _marker: PhantomData<(K, T)>,
+}
+
+impl<K, T> Iter<K, T>
+where
+    K: de::DeserializeOwned,
+    T: 'static + de::DeserializeOwned,
+{
+    /// Decode an entry, returning `None` if it should be skipped.
+    fn decode(
+        &self,
+        entry: sled::Result<(sled::IVec, sled::IVec)>,
+    ) -> Option<Result<(K, StoredEntry<T>), Error>> {
+        let (key, value) = match entry {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        let meta = match decode_meta(&value) {
+            Ok(meta) => meta,
+            Err(e) => return Some(Err(e)),
+        };
+
+        if meta.deleted_at.is_some() || meta.is_expired(self.now, self.epoch) {
+            return None;
+        }
+
+        let plain_key = match self.cache.decode_plain_key(&key, &meta) {
+            Ok(Some(plain_key)) => plain_key,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        match self.cache.decode_entry(&key, &value) {
+            Ok(Some(stored)) => Some(Ok((plain_key, stored))),
+            // the entry was modified while it was being read.
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<K, T> Iterator for Iter<K, T>
+where
+    K: de::DeserializeOwned,
+    T: 'static + de::DeserializeOwned,
+{
+    type Item = Result<(K, StoredEntry<T>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.iter.next()?;
+
+            if let Some(item) = self.decode(entry) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+impl<K, T> DoubleEndedIterator for Iter<K, T>
+where
+    K: de::DeserializeOwned,
+    T: 'static + de::DeserializeOwned,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = self.iter.next_back()?;
+
+            if let Some(item) = self.decode(entry) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// Iterator returned by [Cache::iter_expired].
+pub struct IterExpired<T> {
+    cache: Cache,
+    iter: sled::Iter,
+    now: DateTime<Utc>,
+    epoch: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Iterator for IterExpired<T>
+where
+    T: 'static + serde::de::DeserializeOwned,
+{
+    type Item = Result<(json::Value, StoredEntry<T>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.iter.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            if let Some(entry) = decode_expired(&self.cache, &key, &value, self.now, self.epoch) {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+/// Iterator returned by [Cache::drain_expired].
+pub struct DrainExpired<T> {
+    cache: Cache,
+    iter: sled::Iter,
+    now: DateTime<Utc>,
+    epoch: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> Iterator for DrainExpired<T>
+where
+    T: 'static + serde::de::DeserializeOwned,
+{
+    type Item = Result<(json::Value, StoredEntry<T>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, value) = match self.iter.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            let (json_key, stored) =
+                match decode_expired::<T>(&self.cache, &key, &value, self.now, self.epoch) {
+                    Some(entry) if !entry.1.pinned => entry,
+                    _ => continue,
+                };
+
+            // Only remove the entry if it wasn't refreshed since we read it.
+            let result =
+                self.cache
+                    .inner
+                    .db
+                    .compare_and_swap(&key, Some(&value), None as Option<&[u8]>);
+
+            match result {
+                Ok(Ok(())) => self.cache.sub_len(1),
+                Ok(Err(_)) => continue,
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            if let Ok(entry) = decode_meta(&value) {
+                if let Err(e) = self.cache.remove_indexes(&key, &entry) {
+                    return Some(Err(e));
+                }
+
+                if let Err(e) = self.cache.remove_value(&key, &entry) {
+                    return Some(Err(e));
+                }
+            }
+
+            if let Err(e) = self.cache.record(AuditOp::Expire, &key) {
+                return Some(Err(e));
+            }
+
+            let hooks = &self.cache.inner.config.hooks;
+            self.cache
+                .hook(&hooks.expire, &key, Some(stored.hook_meta()));
+
+            log::trace!("drain:{}", self.cache.key_format(&key));
+            return Some(Ok((json_key, stored)));
+        }
+    }
+}
+
+/// Iterator returned by [Cache::audit_log].
+pub struct AuditLog {
+    iter: sled::Iter,
+}
+
+impl Iterator for AuditLog {
+    type Item = Result<AuditRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match self.iter.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        Some(decode_audit_record(&key, &value))
+    }
+}
+
+/// Stream returned by [Cache::watch_audit_log].
+pub struct AuditStream {
+    subscriber: sled::Subscriber,
+}
+
+impl Stream for AuditStream {
+    type Item = Result<AuditRecord, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            let event = match Pin::new(&mut this.subscriber).poll(cx) {
+                Poll::Ready(Some(event)) => event,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+
+            // skip updates of the counter and records being trimmed.
+            if let sled::Event::Insert { key, value } = event {
+                if key.len() == 8 {
+                    return Poll::Ready(Some(decode_audit_record(&key, &value)));
+                }
+            }
+        }
+    }
+}
+
+/// An entry whose value hasn't been deserialized, returned by
+/// [Cache::get_ref].
+pub struct EntryRef {
+    key: Vec<u8>,
+    expires_at: Option<DateTime<Utc>>,
+    stored_at: Option<DateTime<Utc>>,
+    /// The buffer holding the value.
+    buf: sled::IVec,
+    payload: Payload,
+    format: u8,
+    raw: bool,
+    metadata: Metadata,
+    clock: Option<Clock>,
+}
+
+/// The serialized value of an [EntryRef].
+enum Payload {
+    /// The value is stored as is in the buffer, at the given range.
+    Borrowed(Range<usize>),
+    /// The value had to be decoded from how it was stored.
+    Owned(Vec<u8>),
+}
+
+impl EntryRef {
+    /// Load the entry stored under the given key.
+    ///
+    /// Returns `None` if a chunked entry was modified while it was being read.
+    fn new(
+        cache: &Cache,
+        key: &[u8],
+        entry: sled::IVec,
+    ) -> Result<Option<StoredEntry<EntryRef>>, Error> {
+        let (mut meta, payload) = split_entry(&entry)?;
+
+        let (buf, payload) = match (meta.chunks, meta.blob.clone(), payload) {
+            (Some(chunks), ..) => match cache.load_chunks(key, &entry, meta.flags, &chunks)? {
+                Some(payload) => (entry.clone(), Payload::Owned(payload)),
+                None => return Ok(None),
+            },
+            (None, Some(hash), _) => match cache.load_blob(key, &entry, &hash)? {
+                Some((flags, blob, range)) => {
+                    meta.flags = flags;
+                    (blob, Payload::Borrowed(range))
+                }
+                None => return Ok(None),
+            },
+            (None, None, Cow::Borrowed(payload)) => {
+                let start = payload.as_ptr() as usize - entry.as_ptr() as usize;
+                (
+                    entry.clone(),
+                    Payload::Borrowed(start..start + payload.len()),
+                )
+            }
+            (None, None, Cow::Owned(payload)) => (entry.clone(), Payload::Owned(payload)),
+        };
+
+        // deduplicated values are encrypted for the key of their blob.
+        let value_key = match &meta.blob {
+            Some(hash) => Cow::Owned(blob_key(hash)),
+            None => Cow::Borrowed(key),
+        };
+
+        let config = &cache.inner.config;
+
+        let payload = match payload {
+            Payload::Borrowed(range) if meta.flags & (FLAG_COMPRESSED | FLAG_ENCRYPTED) != 0 => {
+                let payload = Cow::Borrowed(&buf[range]);
+                let payload = decrypt(config, &value_key, meta.format, meta.flags, payload)?;
+                Payload::Owned(decompress(meta.flags, payload)?.into_owned())
+            }
+            Payload::Owned(payload) => {
+                let payload = Cow::Owned(payload);
+                let payload = decrypt(config, &value_key, meta.format, meta.flags, payload)?;
+                Payload::Owned(decompress(meta.flags, payload)?.into_owned())
+            }
+            payload => payload,
+        };
+
+        // Values stored with a custom codec are converted to CBOR, so that
+        // they can be deserialized while borrowing from them.
+        let mut format = meta.format;
+
+        let payload = match cache.inner.config.format_of(format) {
+            Some(custom @ Format::Custom(..)) if meta.flags & FLAG_RAW == 0 => {
+                let bytes = match &payload {
+                    Payload::Borrowed(range) => &buf[range.clone()],
+                    Payload::Owned(payload) => &payload[..],
+                };
+
+                let (cbor, bytes) = custom.transcode(Cow::Borrowed(bytes))?;
+                format = cbor.id();
+                Payload::Owned(bytes.into_owned())
+            }
+            _ => payload,
+        };
+
+        let value = EntryRef {
+            key: key.to_vec(),
+            expires_at: meta.expires_at,
+            stored_at: meta.stored_at,
+            format,
+            raw: meta.flags & FLAG_RAW != 0,
+            metadata: meta.metadata.clone(),
+            buf,
+            payload,
+            clock: cache.inner.config.clock.clone(),
+        };
+
+        Ok(Some(meta.into_stored_entry(&cache.inner.config, value)))
+    }
+
+    /// The key the entry is stored under in the database.
+    ///
+    /// It can be decoded with [Cache::decode_key].
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// When the entry expires.
+    ///
+    /// See [StoredEntry::expires_at].
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// When the entry was stored, if known.
+    ///
+    /// See [StoredEntry::stored_at].
+    pub fn stored_at(&self) -> Option<DateTime<Utc>> {
+        self.stored_at
+    }
+
+    /// The time remaining until the entry expires.
+    ///
+    /// See [StoredEntry::ttl].
+    pub fn ttl(&self) -> Option<Duration> {
+        remaining(self.expires_at, now(self.clock.as_ref()))
+    }
+
+    /// The time elapsed since the entry was stored, if known.
+    ///
+    /// See [StoredEntry::age].
+    pub fn age(&self) -> Option<Duration> {
+        Some(now(self.clock.as_ref()) - self.stored_at?)
+    }
+
+    /// The metadata stored alongside the value.
+    ///
+    /// See [StoredEntry::metadata].
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// The serialized value of the entry.
+    ///
+    /// This is the value as stored with [Cache::insert_bytes] or
+    /// [Cache::insert_stream], or serialized in the format it was stored with
+    /// otherwise.
+    pub fn bytes(&self) -> &[u8] {
+        match &self.payload {
+            Payload::Borrowed(range) => &self.buf[range.clone()],
+            Payload::Owned(payload) => payload,
+        }
+    }
+
+    /// Deserialize the value of the entry, borrowing from it where possible.
+    ///
+    /// Raw values can be deserialized as byte slices.
+    pub fn value<'de, T>(&'de self) -> Result<T, Error>
+    where
+        T: Deserialize<'de>,
+    {
+        let bytes = self.bytes();
+
+        if self.raw {
+            return T::deserialize(de::value::BorrowedBytesDeserializer::new(bytes))
+                .map_err(|e: de::value::Error| Error::Codec(e.into()));
+        }
+
+        match Format::from_id(self.format) {
+            Some(format) => format.decode_borrowed(bytes),
+            None => Err(Error::UnsupportedFormat(self.format)),
+        }
+    }
+}
+
+/// A stream over the bytes of a value returned by [Cache::get_stream].
+///
+/// This is both a [Stream] of byte buffers and an [AsyncRead].
+pub struct ValueStream {
+    source: Source,
+    /// Bytes which have been loaded but not yet read through [AsyncRead].
+    buf: Vec<u8>,
+    /// The position of the next byte to read from `buf`.
+    pos: usize,
+}
+
+/// Where the bytes of a [ValueStream] come from.
+enum Source {
+    /// A value which has been loaded in full.
+    Value(Option<Vec<u8>>),
+    /// The chunks of a streamed value, which are loaded as they're read.
+    Chunks(Box<ChunkReader>),
+    /// The next chunk is being loaded.
+    Loading(pool::Task<LoadedChunk>),
+    /// The stream is exhausted or has failed.
+    Done,
+}
+
+/// The reader of a [ValueStream] once it has loaded a chunk, and the chunk.
+type LoadedChunk = (Box<ChunkReader>, Option<Result<Vec<u8>, Error>>);
+
+/// Reads the chunks of a streamed value in order.
+struct ChunkReader {
+    cache: Cache,
+    key: Vec<u8>,
+    entry: sled::IVec,
+    flags: u8,
+    chunks: Chunks,
+    index: u32,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl ChunkReader {
+    /// Load the next chunk, returning `None` once all chunks have been read
+    /// and the value has been verified.
+    fn next_chunk(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        if self.index == self.chunks.count {
+            if self.len != self.chunks.len || self.hasher.clone().finalize() != self.chunks.checksum
+            {
+                return Some(Err(Error::Corrupt));
+            }
+
+            return None;
+        }
+
+        let result = load_chunk(
+            &self.cache,
+            &self.key,
+            &self.entry,
+            self.flags,
+            self.chunks.id,
+            self.index,
+        );
+
+        Some(result.map(|(chunk, decoded)| {
+            self.hasher.update(&chunk);
+            self.len += chunk.len() as u64;
+            self.index += 1;
+            decoded
+        }))
+    }
+}
+
+impl ValueStream {
+    /// Construct a stream over a value which has been loaded in full.
+    fn value(value: Vec<u8>) -> Self {
+        Self {
+            source: Source::Value(Some(value)),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Construct a stream over the chunks of the given entry.
+    fn chunks(cache: Cache, key: &[u8], entry: sled::IVec, meta: &PartialStoredEntry) -> Self {
+        let source = match meta.chunks {
+            Some(chunks) => Source::Chunks(Box::new(ChunkReader {
+                cache,
+                key: key.to_vec(),
+                entry,
+                flags: meta.flags,
+                chunks,
+                index: 0,
+                hasher: crc32fast::Hasher::new(),
+                len: 0,
+            })),
+            None => Source::Done,
+        };
+
+        Self {
+            source,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Load the next buffer of bytes.
+    ///
+    /// Chunks are loaded on the blocking pool, so that reading them doesn't
+    /// block the executor.
+    fn poll_buf(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Vec<u8>, Error>>> {
+        loop {
+            match std::mem::replace(&mut self.source, Source::Done) {
+                Source::Value(mut value) => return Poll::Ready(value.take().map(Ok)),
+                Source::Chunks(mut reader) => {
+                    self.source = Source::Loading(pool::spawn(move || {
+                        let chunk = reader.next_chunk();
+                        (reader, chunk)
+                    }));
+                }
+                Source::Loading(mut task) => match Pin::new(&mut task).poll(cx) {
+                    Poll::Ready(Ok((reader, Some(Ok(chunk))))) => {
+                        self.source = Source::Chunks(reader);
+                        return Poll::Ready(Some(Ok(chunk)));
+                    }
+                    Poll::Ready(Ok((_, result))) => return Poll::Ready(result),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                    Poll::Pending => {
+                        self.source = Source::Loading(task);
+                        return Poll::Pending;
+                    }
+                },
+                Source::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Load a single chunk of a streamed value, returning it as stored and
+/// decrypted.
+fn load_chunk(
+    cache: &Cache,
+    key: &[u8],
+    entry: &[u8],
+    flags: u8,
+    id: u64,
+    index: u32,
+) -> Result<(sled::IVec, Vec<u8>), Error> {
+    let chunk_key = chunk_key(key, id, index);
+
+    let chunk = match cache.inner.db.get(&chunk_key)? {
+        Some(chunk) => chunk,
+        None => {
+            if cache.inner.db.get(key)?.as_deref() != Some(entry) {
+                return Err(Error::Modified);
+            }
+
+            return Err(Error::Corrupt);
+        }
+    };
+
+    let decoded = if flags & FLAG_CHUNKS_ENCRYPTED != 0 {
+        let chunk = Cow::Borrowed(&chunk[..]);
+        decrypt(&cache.inner.config, &chunk_key, 0, FLAG_ENCRYPTED, chunk)?.into_owned()
+    } else {
+        chunk.to_vec()
+    };
+
+    Ok((chunk, decoded))
+}
+
+impl Stream for ValueStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_buf(cx)
+    }
+}
+
+impl AsyncRead for ValueStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        while this.pos == this.buf.len() {
+            match this.poll_buf(cx) {
+                Poll::Ready(Some(Ok(buf))) => {
+                    this.buf = buf;
+                    this.pos = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = usize::min(out.len(), this.buf.len() - this.pos);
+        out[..n].copy_from_slice(&this.buf[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// This iterator will return all expired entries in cache. Can scan multiple caches. The Iterator will continue
+/// to scan and may return more entries after returning None.
+pub struct CacheExpiredKeyIterator {
+    caches: Vec<Cache>,
+    cache_idx: usize,
+    last_key: Option<hashkey::Key>,
+    max_scans: Option<usize>,
+}
+
+impl CacheExpiredKeyIterator {
+    /// Construct an empty CacheExpiredKeyIterator. This instance will not return anything unless caches are added to it
+    pub fn empty() -> Self {
+        Self {
+            caches: Vec::new(),
+            cache_idx: 0,
+            last_key: None,
+            max_scans: None,
+        }
+    }
+    /// Construct an empty CacheExpiredKeyIterator with a limit on the number of keys to check for expiration each invocation
+    pub fn empty_with_max_scans(max_scans: Option<usize>) -> Self {
+        Self {
+            max_scans,
+            ..Self::empty()
+        }
+    }
+    /// Construct a CacheExpiredKeyIterator from an existing Cache and a limit on the number of scans to perform
+    pub fn from_cache(f: Cache, max_scans: Option<usize>) -> Self {
+        Self {
+            caches: vec![f],
+            ..Self::empty_with_max_scans(max_scans)
+        }
+    }
+    /// Add a cache to the list of caches to scan.
+    pub fn add_cache(&mut self, c: Cache) {
+        self.caches.push(c);
+    }
+}
+
+impl From<Cache> for CacheExpiredKeyIterator {
+    fn from(f: Cache) -> Self {
+        Self::from_cache(f, None)
+    }
+}
+
+impl Iterator for CacheExpiredKeyIterator {
+    type Item = Result<ExpiredKey, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.caches.is_empty() {
+            return None;
+        }
+        let cache = self.caches[self.cache_idx].clone();
+        let mut scans = 0;
+
+        let last_key = match self.last_key.clone() {
+            Some(t) => t,
+            None => {
+                let first = cache.first_entry();
+                let first = match first {
+                    Err(e) => return Some(Err(e)),
+                    Ok(v) => v,
+                };
+                match first {
+                    None => {
+                        log::trace!("cache empty, advancing to next cache");
+                        self.cache_idx += 1;
+                        self.cache_idx %= self.caches.len();
+                        return None;
+                    }
+                    Some(t) => match hashkey::to_key(&t.0.to_vec()) {
+                        Err(e) => return Some(Err(Error::HashKey(e))),
+                        Ok(v) => v.normalize(),
+                    },
+                }
+            }
+        };
+        let mut last_key = match cache.key(&last_key) {
+            Err(e) => return Some(Err(e)),
+            Ok(v) => v,
+        };
+        log::trace!("last_key = {}", cache.key_format(&last_key));
+        while scans < self.max_scans.unwrap_or(1000) {
+            // Retrieve key. If there is no more keys, go to the next cache and return None
+            let (new_last_key, value): (Vec<u8>, Vec<u8>) = match cache.inner.db.get_gt(&last_key) {
+                Ok(v) => match v {
+                    None => {
+                        if scans == 0 {
+                            log::trace!("key was empty on first scan, making exception, trying for the first key");
+                            match cache.first_entry() {
+                                Ok(Some((k, v))) => (k.to_vec(), v.to_vec()),
+                                Ok(None) => {
+                                    log::trace!("current key empty, probably a race, aborting");
+                                    self.cache_idx += 1;
+                                    self.cache_idx %= self.caches.len();
+                                    self.last_key = None;
+                                    return None;
+                                }
+                                Err(e) => return Some(Err(e)),
+                            }
+                        } else {
+                            log::trace!("scanned all keys in cache, resetting index and advancing to next cache");
+                            self.cache_idx += 1;
+                            // Clamp cache index to index count
+                            self.cache_idx %= self.caches.len();
+                            log::trace!("next cache id: {}", self.cache_idx);
+                            self.last_key = None;
+                            return None;
+                        }
+                    }
+                    Some(v) => (v.0.to_vec(), v.1.to_vec()),
+                },
+                Err(e) => return Some(Err(Error::Sled(e))),
+            };
+            log::trace!("next_key = {}", cache.key_format(&new_last_key));
+            if new_last_key == last_key {
+                log::trace!("lastkey = nextkey => returning");
+                return None;
+            }
+            last_key = new_last_key;
+            let value = match decode_meta(&value) {
+                Ok(value) => value,
+                Err(e) => {
+                    log::trace!("skipping entry which can't be decoded: {}", e);
+                    scans += 1;
+                    continue;
+                }
+            };
+            let now = cache.now();
+            if value.is_expired(now, cache.epoch()) && !value.pinned && value.deleted_at.is_none() {
+                log::trace!("key expired, returning");
+                return Some(Ok(ExpiredKey {
+                    ns: cache.inner.ns.clone(),
+                    key: hashkey::to_key(&last_key).expect("just deserialized, must be valid key"),
+                    // Entries expired by an epoch bump may not have reached
+                    // their expiry time yet.
+                    expired_at: value.expires_at.filter(|at| *at < now).unwrap_or(now),
+                    checked_at: now,
+                }));
+            }
+            log::trace!("key didn't expire, check next key");
+            // Increment scan counter
+            scans += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cbor, codec, Cache, CacheExpiredKeyIterator, Codec, Difference, Duration, Error,
+        ExpiredKey, ExpiryFilter, Format, ListFilter, MergePolicy, RawKey, State, Utc,
+    };
+    use std::{collections::BTreeSet, error, fs, sync::Arc, thread};
+    use tempdir::TempDir;
+
+    fn db(name: &str) -> Result<sled::Tree, Box<dyn error::Error>> {
+        let path = TempDir::new(name)?;
+        let path = path.path();
+
+        if !path.is_dir() {
+            fs::create_dir_all(path)?;
+        }
+
+        let db = sled::open(path)?;
+        Ok(db.open_tree("test")?)
+    }
+
+    #[test]
+    fn test_expiry_iterator() -> Result<(), Box<dyn error::Error>> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let db = db("test_cached_expiry")?;
+        let cache = Cache::load(db.clone())?;
+
+        let count = Arc::new(AtomicUsize::default());
+        let c = count.clone();
+
+        let op1 = cache.wrap("a", Duration::seconds(1), async move {
+            let _ = c.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, Error>(String::from("foo"))
+        });
+
+        ::futures::executor::block_on(op1)?;
+
+        let key = cache.key(&"a")?;
+        // Entries which can't be decoded are skipped.
+        db.insert(cache.key(&"b")?, &b"garbage"[..])?;
+        let mut expiry_iter: CacheExpiredKeyIterator = cache.into();
+
+        assert_eq!(
+            expiry_iter.next().transpose()?,
+            None,
+            "No expiry after creation of entry"
+        );
+
+        std::thread::sleep(Duration::seconds(2).to_std()?);
+
+        assert_eq!(
+            expiry_iter.next().transpose()?.map(|x| x.zero_expiry()),
+            Some(ExpiredKey::from(None, &key)?),
+            "Expired entry must be returned"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_prefix() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_delete_prefix")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        cache.insert((1u64, "profile"), Duration::hours(12), &1u32)?;
+        cache.insert((1u64, "posts", 2u32), Duration::hours(12), &2u32)?;
+        cache.insert((12u64, "profile"), Duration::hours(12), &3u32)?;
+        cache.insert((2u64, "profile"), Duration::hours(12), &4u32)?;
+        cache.insert(1u64, Duration::hours(12), &5u32)?;
+        other.insert((1u64, "profile"), Duration::hours(12), &6u32)?;
+
+        assert_eq!(2, cache.delete_prefix((1u64,))?);
+        assert!(matches!(
+            cache.get::<_, u32>((1u64, "profile"))?,
+            State::Missing
+        ));
+        assert!(matches!(
+            cache.get::<_, u32>((1u64, "posts", 2u32))?,
+            State::Missing
+        ));
+        assert!(matches!(
+            cache.get::<_, u32>((12u64, "profile"))?,
+            State::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.get::<_, u32>((2u64, "profile"))?,
+            State::Fresh(_)
+        ));
+        assert!(matches!(cache.get::<_, u32>(1u64)?, State::Fresh(_)));
+        assert!(matches!(
+            other.get::<_, u32>((1u64, "profile"))?,
+            State::Fresh(_)
+        ));
+
+        assert_eq!(1, cache.delete_prefix((2u64, "profile"))?);
+        assert_eq!(2, cache.len()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mixed_formats() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_mixed_formats")?;
+        let cbor = Cache::load(db.clone())?;
+        let json = Cache::builder(db).format(Format::Json).load()?;
+
+        cbor.insert("a", Duration::hours(12), &1u32)?;
+        json.insert("b", Duration::hours(12), &2u32)?;
+
+        for cache in &[&cbor, &json] {
+            assert!(matches!(cache.get::<_, u32>("a")?, State::Fresh(e) if e.value == 1));
+            assert!(matches!(cache.get::<_, u32>("b")?, State::Fresh(e) if e.value == 2));
+        }
+
+        assert_eq!(2, json.list_json()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_codec() -> Result<(), Box<dyn error::Error>> {
+        struct Reversed;
+
+        impl Codec for Reversed {
+            fn id(&self) -> u8 {
+                codec::MIN_CUSTOM_ID
+            }
+
+            fn encode(&self, value: &cbor::Value) -> Result<Vec<u8>, Error> {
+                let mut bytes = cbor::to_vec(value)?;
+                bytes.reverse();
+                Ok(bytes)
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<cbor::Value, Error> {
+                let mut bytes = bytes.to_vec();
+                bytes.reverse();
+                Ok(cbor::from_slice(&bytes)?)
+            }
+        }
+
+        let db = db("test_custom_codec")?;
+        let custom = Cache::builder(db.clone())
+            .format(Format::Custom(Arc::new(Reversed)))
+            .load()?;
+
+        custom.insert("a", Duration::hours(12), &String::from("foo"))?;
+        assert!(matches!(custom.get::<_, String>("a")?, State::Fresh(e) if e.value == "foo"));
+
+        let entry = match custom.get_ref("a")? {
+            State::Fresh(entry) => entry,
+            _ => return Err("missing".into()),
+        };
+        assert_eq!("foo", entry.value.value::<&str>()?);
+        assert_eq!(1, custom.list_json()?.len());
+
+        // Entries can't be read without the codec, until it's registered.
+        let cbor = Cache::load(db.clone())?;
+        assert!(matches!(cbor.get::<_, String>("a")?, State::Missing));
+
+        let cbor = Cache::builder(db.clone())
+            .codec(Arc::new(Reversed))
+            .load()?;
+        assert!(matches!(cbor.get::<_, String>("a")?, State::Fresh(e) if e.value == "foo"));
+
+        struct Reserved;
+
+        impl Codec for Reserved {
+            fn id(&self) -> u8 {
+                1
+            }
+
+            fn encode(&self, _: &cbor::Value) -> Result<Vec<u8>, Error> {
+                unreachable!()
+            }
+
+            fn decode(&self, _: &[u8]) -> Result<cbor::Value, Error> {
+                unreachable!()
+            }
+        }
+
+        let result = Cache::builder(db).codec(Arc::new(Reserved)).load();
+        assert!(matches!(result, Err(Error::InvalidConfig(..))));
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_compression")?;
+        let cache = Cache::builder(db).compression_threshold(64).load()?;
+
+        let large = "foo".repeat(1000);
+        cache.insert("a", Duration::hours(12), &large)?;
+        cache.insert("b", Duration::hours(12), &"foo")?;
+
+        let stored = cache.inner.db.get(cache.key(&"a")?)?.ok_or("missing")?;
+        assert!(stored.len() < large.len() / 10);
+
+        assert!(matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == large));
+        assert!(matches!(cache.get::<_, String>("b")?, State::Fresh(e) if e.value == "foo"));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_encryption")?;
+        let cache = Cache::builder(db.clone()).encryption_key([1; 32]).load()?;
+
+        cache.insert("a", Duration::hours(12), &"secret value")?;
+
+        let stored = db.get(cache.key(&"a")?)?.ok_or("missing")?;
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        assert!(
+            matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == "secret value")
+        );
+
+        // values encrypted with another key fail to authenticate, just like
+        // values which have been tampered with.
+        let other = Cache::builder(db).encryption_key([2; 32]).load()?;
+        assert!(matches!(other.get::<_, String>("a")?, State::Corrupt));
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_bound_to_key() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_encryption_bound_to_key")?;
+        let cache = Cache::builder(db.clone()).encryption_key([1; 32]).load()?;
+
+        cache.insert("a", Duration::hours(12), &"secret value")?;
+        cache.insert("b", Duration::hours(12), &"other value")?;
+
+        let stored = db.get(cache.key(&"a")?)?.ok_or("missing")?;
+        db.insert(cache.key(&"b")?, stored)?;
+        assert!(matches!(cache.get::<_, String>("b")?, State::Corrupt));
+        assert!(
+            matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == "secret value")
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_rename_namespace() -> Result<(), Box<dyn error::Error>> {
+        use super::MigrateOptions;
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_encryption_rename_namespace")?;
+        let cache = Cache::builder(db)
+            .chunk_size(16)
+            .history(2)
+            .encryption_key([1; 32])
+            .load()?;
+        let from = cache.namespaced(&"from")?;
+
+        let large = b"secret".repeat(10);
+        let reader = ::futures::io::Cursor::new(large.clone());
+        ::futures::executor::block_on(from.insert_stream("a", Duration::hours(1), reader))?;
+        from.insert("b", Duration::hours(1), &"a value which is chunked")?;
+        from.insert("c", Duration::hours(1), &1u32)?;
+        from.insert("c", Duration::hours(1), &2u32)?;
+        from.push("d", Duration::hours(1), &1u32)?;
+        from.push("d", Duration::hours(1), &2u32)?;
+
+        let progress = cache.rename_namespace(&"from", &"to", MigrateOptions::new())?;
+        assert_eq!(4, progress.migrated);
+
+        // values are encrypted again for the keys they're moved to.
+        let to = cache.namespaced(&"to")?;
+        assert!(matches!(to.get::<_, ByteBuf>("a")?, State::Fresh(e) if *e.value == large));
+        assert!(matches!(
+            to.get::<_, String>("b")?,
+            State::Fresh(e) if e.value == "a value which is chunked"
+        ));
+        assert!(matches!(to.get::<_, u32>("c")?, State::Fresh(e) if e.value == 2));
+        assert_eq!(
+            vec![1],
+            to.history::<_, u32>("c")?
+                .into_iter()
+                .map(|e| e.value)
+                .collect::<Vec<_>>()
+        );
+        assert!(matches!(
+            to.range::<_, u32>("d", 0, usize::MAX)?,
+            State::Fresh(e) if e.value == [1, 2]
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "key-hashing")]
+    #[test]
+    fn test_hash_keys() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_hash_keys")?;
+        let cache = Cache::builder(db.clone()).hash_keys([1; 32]).load()?;
+
+        cache.insert("someone@example.com", Duration::hours(12), &1u32)?;
+
+        for result in db.iter().keys() {
+            assert!(!result?.windows(7).any(|w| w == b"someone"));
+        }
+
+        assert!(matches!(
+            cache.get::<_, u32>("someone@example.com")?,
+            State::Fresh(e) if e.value == 1
+        ));
+
+        let listed = cache.list_json()?;
+        assert_eq!(1, listed.len());
+        assert!(listed[0].key[1].get("hashed").is_some());
+        assert!(matches!(
+            cache.delete_prefix(("someone",)),
+            Err(Error::HashedKeys)
+        ));
+        Ok(())
+    }
+
+    #[cfg(all(feature = "key-hashing", feature = "encryption"))]
+    #[test]
+    fn test_hash_keys_encrypted() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_hash_keys_encrypted")?;
+        let cache = Cache::builder(db)
+            .hash_keys([1; 32])
+            .encryption_key([2; 32])
+            .load()?;
+
+        cache.insert("someone@example.com", Duration::hours(12), &1u32)?;
+
+        let listed = cache.list_json()?;
+        assert_eq!(1, listed.len());
+        assert_eq!(
+            serde_json::json!([null, "someone@example.com"]),
+            listed[0].key
+        );
+        assert_eq!(vec!["someone@example.com"], cache.keys::<String>()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn test_postcard() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_postcard")?;
+        let cache = Cache::builder(db).format(Format::Postcard).load()?;
+
+        cache.insert("a", Duration::hours(12), &(1u32, String::from("foo")))?;
+
+        assert!(matches!(
+            cache.get::<_, (u32, String)>("a")?,
+            State::Fresh(e) if e.value == (1, String::from("foo"))
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_namespace_format() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_namespace_format")?;
+        let cache = Cache::builder(db)
+            .namespace_format(&"msgpack", Format::MessagePack)?
+            .load()?;
+        let msgpack = cache.namespaced(&"msgpack")?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        msgpack.insert("a", Duration::hours(12), &2u32)?;
+
+        let formats = cache
+            .entries()
+            .values()
+            .map(|value| Ok(super::decode_meta(&value?)?.format))
+            .collect::<Result<Vec<_>, Box<dyn error::Error>>>()?;
+
+        assert!(formats.contains(&Format::Cbor.id()));
+        assert!(formats.contains(&Format::MessagePack.id()));
+        assert!(matches!(msgpack.get::<_, u32>("a")?, State::Fresh(e) if e.value == 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunked() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_chunked")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+        let chunks = || db.scan_prefix(super::meta_key(super::CHUNK_KEY)).count();
+
+        let large = "foo".repeat(100);
+        cache.insert("a", Duration::hours(12), &large)?;
+        assert!(chunks() > 1);
+        assert!(matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == large));
+        assert_eq!(1, cache.list_json()?.len());
+
+        // replacing the entry removes its chunks.
+        cache.insert("a", Duration::hours(12), &"foo")?;
+        assert_eq!(0, chunks());
+        assert!(matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == "foo"));
+
+        cache.insert("a", Duration::hours(12), &large)?;
+        let (chunk_key, _) = db
+            .scan_prefix(super::meta_key(super::CHUNK_KEY))
+            .next()
+            .ok_or("missing chunk")??;
+        db.insert(&chunk_key, &b"corrupted"[..])?;
+        assert!(matches!(cache.get::<_, String>("a")?, State::Corrupt));
+
+        cache.delete_with_ns::<(), _>(None, &"a")?;
+        assert_eq!(0, chunks());
+
+        // chunks which don't belong to any entry are removed by cleanup.
+        let key = cache.key(&"a")?;
+        cache.insert("a", Duration::hours(12), &large)?;
+        let live = chunks();
+        db.insert(super::chunk_key(&key, u64::MAX, 0), &b"orphan"[..])?;
+        cache.cleanup()?;
+        assert_eq!(live, chunks());
+
+        cache.insert("b", Duration::seconds(-1), &large)?;
+        assert!(chunks() > live);
+        cache.cleanup()?;
+        assert_eq!(live, chunks());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_stream() -> Result<(), Box<dyn error::Error>> {
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_insert_stream")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+        let chunks = || db.scan_prefix(super::meta_key(super::CHUNK_KEY)).count();
+
+        let large = b"foo".repeat(100);
+        let reader = ::futures::io::Cursor::new(large.clone());
+        ::futures::executor::block_on(cache.insert_stream("a", Duration::hours(12), reader))?;
+        assert_eq!(large.len().div_ceil(16), chunks());
+        assert!(matches!(cache.get::<_, ByteBuf>("a")?, State::Fresh(e) if *e.value == large));
+
+        let reader = ::futures::io::Cursor::new(Vec::new());
+        ::futures::executor::block_on(cache.insert_stream("a", Duration::hours(12), reader))?;
+        assert_eq!(0, chunks());
+        assert!(matches!(cache.get::<_, ByteBuf>("a")?, State::Fresh(e) if e.value.is_empty()));
+
+        // chunks of failed streams are removed.
+        let reader = ::futures::io::AsyncReadExt::chain(
+            ::futures::io::Cursor::new(large.clone()),
+            FailingReader,
+        );
+        let result =
+            ::futures::executor::block_on(cache.insert_stream("b", Duration::hours(12), reader));
+        assert!(matches!(result, Err(Error::Io(..))));
+        assert_eq!(0, chunks());
+        assert!(matches!(cache.get::<_, ByteBuf>("b")?, State::Missing));
+
+        // chunks which are being written aren't removed by a cleanup through
+        // another handle.
+        let other = Cache::load(db.clone())?;
+        let reader = ::futures::io::AsyncReadExt::chain(
+            ::futures::io::Cursor::new(large.clone()),
+            CleanupReader(other),
+        );
+        ::futures::executor::block_on(cache.insert_stream("c", Duration::hours(12), reader))?;
+        assert!(matches!(cache.get::<_, ByteBuf>("c")?, State::Fresh(e) if *e.value == large));
+        assert_eq!(
+            0,
+            db.scan_prefix(super::meta_key(super::PENDING_KEY)).count()
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_insert_stream_encrypted() -> Result<(), Box<dyn error::Error>> {
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_insert_stream_encrypted")?;
+        let cache = Cache::builder(db.clone())
+            .chunk_size(16)
+            .encryption_key([1; 32])
+            .load()?;
+
+        let large = b"secret".repeat(100);
+        let reader = ::futures::io::Cursor::new(large.clone());
+        ::futures::executor::block_on(cache.insert_stream("a", Duration::hours(12), reader))?;
+
+        for result in db.iter() {
+            let (_, value) = result?;
+            assert!(!value.windows(6).any(|w| w == b"secret"));
+        }
+
+        assert!(matches!(cache.get::<_, ByteBuf>("a")?, State::Fresh(e) if *e.value == large));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_stream() -> Result<(), Box<dyn error::Error>> {
+        use ::futures::io::AsyncReadExt as _;
+        use ::futures::stream::TryStreamExt as _;
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_get_stream")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+
+        let large = b"foo".repeat(100);
+        let reader = ::futures::io::Cursor::new(large.clone());
+        ::futures::executor::block_on(cache.insert_stream("a", Duration::hours(12), reader))?;
+        cache.insert("b", Duration::hours(12), &ByteBuf::from(&b"bar"[..]))?;
+
+        let stream = cache.get_stream("a")?.get().ok_or("missing")?;
+        let buffers = ::futures::executor::block_on(stream.try_collect::<Vec<_>>())?;
+        assert!(buffers.iter().all(|buf| buf.len() <= 16));
+        assert_eq!(large, buffers.concat());
+
+        let mut stream = cache.get_stream("b")?.get().ok_or("missing")?;
+        let mut out = Vec::new();
+        ::futures::executor::block_on(stream.read_to_end(&mut out))?;
+        assert_eq!(b"bar", &out[..]);
+
+        // replacing a value while it's being streamed fails the stream.
+        let mut stream = cache.get_stream("a")?.get().ok_or("missing")?;
+        ::futures::executor::block_on(stream.try_next())?;
+        cache.insert("a", Duration::hours(12), &ByteBuf::from(&b"baz"[..]))?;
+        let result = ::futures::executor::block_on(stream.try_next());
+        assert!(matches!(result, Err(Error::Modified)));
+
+        assert!(matches!(cache.get_stream("c")?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytes() -> Result<(), Box<dyn error::Error>> {
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_bytes")?;
+        let cache = Cache::load(db.clone())?;
+
+        cache.insert_bytes("a", Duration::hours(12), b"\x0a\x03foo")?;
+        let stored = db.get(cache.key(&"a")?)?.ok_or("missing")?;
+        assert!(stored.windows(5).any(|w| w == b"\x0a\x03foo"));
+
+        assert!(matches!(cache.get_bytes("a")?, State::Fresh(e) if e.value == b"\x0a\x03foo"));
+        assert!(
+            matches!(cache.get::<_, ByteBuf>("a")?, State::Fresh(e) if *e.value == b"\x0a\x03foo")
+        );
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Missing));
+
+        cache.insert("b", Duration::hours(12), &ByteBuf::from(&b"bar"[..]))?;
+        assert!(matches!(cache.get_bytes("b")?, State::Fresh(e) if e.value == b"bar"));
+        assert!(matches!(cache.get_bytes("c")?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_ref() -> Result<(), Box<dyn error::Error>> {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Borrowed<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            data: &'a serde_bytes::Bytes,
+        }
+
+        let db = db("test_get_ref")?;
+        let cache = Cache::builder(db)
+            .namespace_format(&"json", Format::Json)?
+            .load()?;
+        let json = cache.namespaced(&"json")?;
+
+        let value = Borrowed {
+            name: "foo",
+            data: serde_bytes::Bytes::new(b"bar"),
+        };
+
+        cache.insert("a", Duration::hours(12), &value)?;
+        cache.insert_bytes("b", Duration::hours(12), b"baz")?;
+        json.insert("a", Duration::hours(12), &"foo")?;
+
+        let entry = cache.get_ref("a")?.get().ok_or("missing")?;
+        assert_eq!(cache.key(&"a")?, entry.key());
+        let borrowed = entry.value::<Borrowed<'_>>()?;
+        assert_eq!("foo", borrowed.name);
+        assert_eq!(b"bar", &borrowed.data[..]);
+
+        let entry = cache.get_ref("b")?.get().ok_or("missing")?;
+        assert_eq!(b"baz", entry.bytes());
+        assert_eq!(b"baz", entry.value::<&[u8]>()?);
+
+        let entry = json.get_ref("a")?.get().ok_or("missing")?;
+        assert_eq!(b"\"foo\"", entry.bytes());
+        assert_eq!("foo", entry.value::<&str>()?);
+
+        assert!(matches!(cache.get_ref("c")?, State::Missing));
+        Ok(())
+    }
+
+    /// A reader which always fails.
+    struct FailingReader;
+
+    impl ::futures::io::AsyncRead for FailingReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::Other.into()))
+        }
+    }
+
+    /// A reader which cleans up the given cache, and then ends.
+    struct CleanupReader(Cache);
+
+    impl ::futures::io::AsyncRead for CleanupReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let result = self.0.cleanup().map_err(std::io::Error::other);
+            std::task::Poll::Ready(result.map(|_| 0))
+        }
+    }
+
+    #[cfg(feature = "deduplicate")]
+    #[test]
+    fn test_deduplicate() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_deduplicate")?;
+        let cache = Cache::builder(db.clone()).deduplicate(true).load()?;
+        let blobs = || db.scan_prefix(super::meta_key(super::BLOB_KEY)).count();
+
+        let large = "foo".repeat(1000);
+        cache.insert("a", Duration::hours(12), &large)?;
+        cache.insert("b", Duration::hours(12), &large)?;
+        cache.insert("c", Duration::seconds(-1), &large)?;
+        assert_eq!(1, blobs());
+
+        // the entry only holds its metadata and the hash of the value. The
+        // bound used to be 100 bytes, before entries also recorded when they
+        // were stored, which adds another timestamp of about 40 bytes.
+        let stored = db.get(cache.key(&"a")?)?.ok_or("missing")?;
+        assert!(stored.len() < 150);
+
+        for key in &["a", "b", "c"] {
+            assert!(
+                matches!(cache.get::<_, String>(key)?, State::Fresh(e) | State::Expired(e) if e.value == large)
+            );
+        }
+
+        let entry = cache.get_ref("a")?.get().ok_or("missing")?;
+        assert_eq!(large, entry.value::<&str>()?);
+
+        // the value is retained as long as any entry refers to it.
+        cache.delete_with_ns::<(), _>(None, &"a")?;
+        cache.insert("b", Duration::hours(12), &"bar")?;
+        assert_eq!(2, blobs());
+        assert!(matches!(cache.get::<_, String>("c")?, State::Expired(e) if e.value == large));
+
+        cache.cleanup()?;
+        assert_eq!(1, blobs());
+        assert!(matches!(cache.get::<_, String>("b")?, State::Fresh(e) if e.value == "bar"));
+
+        cache.clear()?;
+        assert_eq!(0, blobs());
+        assert_eq!(
+            0,
+            db.scan_prefix(super::meta_key(super::BLOB_REF_KEY)).count()
+        );
+        Ok(())
+    }
+
+    #[cfg(all(feature = "deduplicate", feature = "encryption"))]
+    #[test]
+    fn test_deduplicate_encrypted() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_deduplicate_encrypted")?;
+        let result = Cache::builder(db)
+            .deduplicate(true)
+            .encryption_key([1; 32])
+            .load();
+        assert!(matches!(result, Err(Error::InvalidConfig(..))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate() -> Result<(), Box<dyn error::Error>> {
+        use super::MigrateOptions;
+
+        let db = db("test_migrate")?;
+        let json = Cache::builder(db.clone()).format(Format::Json).load()?;
+
+        for n in 0..5u32 {
+            json.insert(n, Duration::hours(12), &n)?;
+        }
+
+        json.namespaced(&"other")?
+            .insert(0, Duration::hours(12), &0u32)?;
+
+        let cache = Cache::load(db.clone())?;
+        let formats = || -> Result<Vec<u8>, Box<dyn error::Error>> {
+            (0..5u32)
+                .map(|n| Ok(super::decode_meta(&db.get(cache.key(&n)?)?.ok_or("missing")?)?.format))
+                .collect()
+        };
+
+        let mut reported = 0;
+        let progress = cache.migrate(
+            Format::Json,
+            Format::Cbor,
+            MigrateOptions::new().limit(2).progress(|_| reported += 1),
+        )?;
+        assert_eq!(2, reported);
+        assert_eq!(2, progress.migrated);
+        assert!(!progress.complete);
+        assert_eq!(vec![0, 0, 1, 1, 1], formats()?);
+
+        // a migration between other formats doesn't resume from its position.
+        let progress = cache.migrate(Format::Json, Format::Json, MigrateOptions::new())?;
+        assert_eq!((3, 2), (progress.migrated, progress.skipped));
+
+        // resumes where it left off.
+        let progress = cache.migrate(Format::Json, Format::Cbor, MigrateOptions::new())?;
+        assert_eq!(3, progress.migrated);
+        assert!(progress.complete);
+        assert_eq!(vec![0; 5], formats()?);
+
+        let progress = cache.migrate(Format::Json, Format::Cbor, MigrateOptions::new())?;
+        assert_eq!((0, 5), (progress.migrated, progress.skipped));
+
+        for n in 0..5u32 {
+            assert!(matches!(cache.get::<_, u32>(n)?, State::Fresh(e) if e.value == n));
+        }
+
+        let other = cache.namespaced(&"other")?;
+        let key = other.key(&0)?;
+        assert_eq!(
+            Format::Json.id(),
+            super::decode_meta(&db.get(key)?.ok_or("missing")?)?.format
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_layout() -> Result<(), Box<dyn error::Error>> {
+        use serde_bytes::ByteBuf;
+        use serde_hashkey as hashkey;
+
+        fn legacy_key(ns: Option<i32>, key: &str) -> Result<ByteBuf, Box<dyn error::Error>> {
+            let ns = ns.map(|ns| hashkey::to_key(&ns)).transpose()?;
+            let key = ByteBuf::from(super::key::to_vec(key)?);
+            Ok(ByteBuf::from(serde_cbor::to_vec(&(ns, key))?))
+        }
+
+        fn legacy_entry(tags: &[&str], deps: &[ByteBuf]) -> Result<Vec<u8>, serde_cbor::Error> {
+            serde_cbor::to_vec(&super::StoredEntryRef {
+                expires_at: chrono::Utc::now() + Duration::hours(12),
+                pinned: false,
+                deleted_at: None,
+                epoch: 0,
+                tags,
+                deps,
+                format: 0,
+                flags: 0,
+                original_key: None,
+                value: &1u32,
+            })
+        }
+
+        // entries stored before keys were versioned.
+        let db = db("test_key_layout")?;
+        let a = legacy_key(None, "a")?;
+        db.insert(&a, legacy_entry(&[], &[])?)?;
+        db.insert(legacy_key(None, "b")?, legacy_entry(&["t"], &[a])?)?;
+        db.insert(legacy_key(Some(7), "c")?, legacy_entry(&[], &[])?)?;
+
+        let cache = Cache::load(db.clone())?;
+        let key = cache.key(&"a")?;
+        assert_eq!(Some(&super::KEY_VERSION), key.first());
+        assert!(db
+            .iter()
+            .keys()
+            .all(|key| key.is_ok_and(|key| key[0] <= super::KEY_VERSION)));
+
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Fresh(e) if e.value == 1));
+        // integers in namespaces are compared by value.
+        let other = cache.namespaced(&7u64)?;
+        assert!(matches!(other.get::<_, u32>("c")?, State::Fresh(e) if e.value == 1));
+
+        // indexes are moved along with the entries.
+        assert!(db.contains_key(super::tag_key("t", &cache.key(&"b")?))?);
+        assert_eq!(2, cache.invalidate("a")?);
+
+        db.insert(
+            super::meta_key(super::KEY_VERSION_KEY),
+            &[super::KEY_VERSION + 1],
+        )?;
+        assert!(matches!(
+            Cache::load(db),
+            Err(Error::UnsupportedKeyVersion(version)) if version == super::KEY_VERSION + 1
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_key() -> Result<(), Box<dyn error::Error>> {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Key {
+            Unit,
+            Newtype(i32),
+            Tuple(u8, Option<String>),
+            Struct { id: u64, name: String },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Id(u32);
+
+        let db = db("test_decode_key")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&7u32)?;
+
+        let keys = [
+            Key::Unit,
+            Key::Newtype(-1),
+            Key::Tuple(1, Some(String::from("a"))),
+            Key::Struct {
+                id: 2,
+                name: String::from("b"),
+            },
+        ];
+
+        for key in keys {
+            let encoded = cache.key(&(Id(3), &key))?;
+            assert_eq!(
+                (serde_json::Value::Null, (Id(3), key)),
+                Cache::decode_key(&encoded)?
+            );
+        }
+
+        let encoded = other.key(&"a")?;
+        let (ns, key) = Cache::decode_key::<&str>(&encoded)?;
+        assert_eq!((serde_json::json!([7]), "a"), (ns, key));
+        assert!(Cache::decode_key::<u32>(&cache.key(&"a")?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema() -> Result<(), Box<dyn error::Error>> {
+        use super::schema::Schema;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize)]
+        struct V1(u32);
+
+        #[derive(Serialize, Deserialize)]
+        struct V2 {
+            count: u32,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct V3 {
+            count: u64,
+            label: String,
+        }
+
+        let db = db("test_schema")?;
+        let cache = Cache::load(db.clone())?;
+        cache.insert("unversioned", Duration::hours(12), &V1(0))?;
+
+        let v1 = Cache::builder(db.clone())
+            .schema(Schema::<V1>::new(1))
+            .load()?;
+        v1.insert("a", Duration::hours(12), &V1(1))?;
+
+        let v2 = Cache::builder(db.clone())
+            .schema(Schema::<V2>::new(2).migration(1, |V1(count)| V2 { count }))
+            .load()?;
+        v2.insert("b", Duration::hours(12), &V2 { count: 2 })?;
+
+        let v3 = Cache::builder(db.clone())
+            .schema(
+                Schema::<V3>::new(3)
+                    .migration(1, |V1(count)| V2 { count })
+                    .migration(2, |v: V2| V3 {
+                        count: v.count.into(),
+                        label: v.count.to_string(),
+                    }),
+            )
+            .load()?;
+        v3.insert(
+            "c",
+            Duration::hours(12),
+            &V3 {
+                count: 3,
+                label: "c".into(),
+            },
+        )?;
+
+        let get = |key| v3.get::<_, V3>(key).map(|state| state.get());
+        assert_eq!(
+            Some(V3 {
+                count: 1,
+                label: "1".into()
+            }),
+            get("a")?
+        );
+        assert_eq!(
+            Some(V3 {
+                count: 2,
+                label: "2".into()
+            }),
+            get("b")?
+        );
+        assert_eq!(
+            Some(V3 {
+                count: 3,
+                label: "c".into()
+            }),
+            get("c")?
+        );
+        // there's no migration from before the schema was registered.
+        assert_eq!(None, get("unversioned")?);
+
+        // values stored with a newer version are treated as missing.
+        assert!(v2.get::<_, V2>("c")?.get().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_path() -> Result<(), Box<dyn error::Error>> {
+        let value = serde_json::json!({
+            "user": {"name": "Alice", "a/b": 1},
+            "posts": [{"id": 1}, {"id": 2}],
+        });
+
+        for format in [Format::Cbor, Format::Json] {
+            let db = db("test_get_path")?;
+            let cache = Cache::builder(db).format(format).load()?;
+            cache.insert("a", Duration::hours(12), &value)?;
+
+            let get = |pointer| cache.get_path::<_, serde_json::Value>("a", pointer);
+            assert!(matches!(get("/user/name")?, State::Fresh(e) if e.value == "Alice"));
+            assert!(matches!(get("/user/a~1b")?, State::Fresh(e) if e.value == 1));
+            assert!(matches!(get("/posts/1/id")?, State::Fresh(e) if e.value == 2));
+            assert!(matches!(get("")?, State::Fresh(e) if e.value == value));
+            assert!(matches!(get("/posts/2")?, State::Missing));
+            assert!(matches!(get("/user/name/first")?, State::Missing));
+            assert!(matches!(get("/missing")?, State::Missing));
+            assert!(matches!(get("user"), Err(Error::InvalidPointer(..))));
+
+            let name = cache.get_path::<_, String>("a", "/user/name")?;
+            assert_eq!(Some(String::from("Alice")), name.get());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_path() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_update_path")?;
+        let cache = Cache::load(db)?;
+
+        let value = serde_json::json!({"counters": {"views": 1}, "name": "a"});
+        cache.insert("a", Duration::hours(12), &value)?;
+        assert!(cache.pin("a")?);
+        let expires_at = match cache.get::<_, serde_json::Value>("a")? {
+            State::Fresh(e) => e.expires_at,
+            _ => panic!("expected a fresh entry"),
+        };
+
+        let increment = |v: &mut serde_json::Value| *v = (v.as_u64().unwrap_or(0) + 1).into();
+        assert!(cache.update_path("a", "/counters/views", increment)?);
+        assert!(cache.update_path("a", "/counters/views", increment)?);
+        assert!(!cache.update_path("a", "/counters/likes", increment)?);
+        assert!(!cache.update_path("b", "/counters/views", increment)?);
+
+        let stored = cache.get::<_, serde_json::Value>("a")?;
+        assert!(matches!(
+            stored,
+            State::Fresh(e) if e.value == serde_json::json!({"counters": {"views": 3}, "name": "a"})
+                && e.is_pinned()
+                && e.expires_at == expires_at
+        ));
+
+        // parts which aren't updated are left as they are.
+        let bytes = serde_bytes::ByteBuf::from(vec![1, 2, 3]);
+        cache.insert("b", Duration::hours(12), &(0u32, (bytes, 1u32)))?;
+        assert!(cache.update_path("b", "/1", |v| v[1] = 2.into())?);
+
+        let expected = cbor::Value::Array(vec![
+            cbor::Value::Integer(0),
+            cbor::Value::Array(vec![
+                cbor::Value::Bytes(vec![1, 2, 3]),
+                cbor::Value::Integer(2),
+            ]),
+        ]);
+        assert!(
+            matches!(cache.get::<_, cbor::Value>("b")?, State::Fresh(e) if e.value == expected)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_header() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_entry_header")?;
+        let cache = Cache::load(db)?;
+        let key = cache.key(&"a")?;
+
+        // entries stored before the header was introduced.
+        let legacy = serde_cbor::to_vec(&super::StoredEntryRef {
+            expires_at: chrono::Utc::now() + Duration::hours(12),
+            pinned: false,
+            deleted_at: None,
+            epoch: 0,
+            tags: &[],
+            deps: &[],
+            format: 0,
+            flags: 0,
+            original_key: None,
+            value: &1u32,
+        })?;
+
+        cache.inner.db.insert(&key, legacy)?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Fresh(e) if e.value == 1));
+
+        // reading a legacy entry stores it with a header.
+        let stored = cache.inner.db.get(&key)?.ok_or("missing")?;
+        assert_eq!(Some(&super::ENTRY_MAGIC), stored.first());
+
+        // reading an entry in another format converts it, keeping its
+        // metadata.
+        assert!(cache.pin("a")?);
+        let json = Cache::builder(cache.inner.db.clone())
+            .format(Format::Json)
+            .load()?;
+        assert!(matches!(json.get::<_, u32>("a")?, State::Fresh(e) if e.value == 1));
+        let stored = cache.inner.db.get(&key)?.ok_or("missing")?;
+        assert_eq!(Format::Json.id(), super::decode_meta(&stored)?.format);
+        assert!(
+            matches!(cache.get::<_, u32>("a")?, State::Fresh(e) if e.value == 1 && e.is_pinned())
+        );
+
+        // a corrupt value is only noticed once it's read, while corrupt
+        // metadata is noticed when it's tested.
+        let mut corrupt = stored.to_vec();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        cache.inner.db.insert(&key, corrupt)?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Corrupt));
+        assert!(matches!(cache.test("a")?, State::Fresh(..)));
+
+        let mut corrupt = stored.to_vec();
+        corrupt[super::HEADER_LEN] ^= 0xff;
+        cache.inner.db.insert(&key, corrupt)?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Corrupt));
+        assert!(matches!(cache.test("a")?, State::Corrupt));
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Missing));
+
+        let mut future = stored.to_vec();
+        future[1] = super::ENTRY_VERSION + 1;
+        assert!(matches!(
+            super::decode_meta(&future),
+            Err(Error::UnsupportedVersion(version)) if version == super::ENTRY_VERSION + 1
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dynamic_values() -> Result<(), Box<dyn error::Error>> {
+        use std::collections::BTreeMap;
+
+        let db = db("test_dynamic_values")?;
+        let cache = Cache::load(db.clone())?;
+
+        let mut value = BTreeMap::new();
+        value.insert(1u32, String::from("foo"));
+        cache.insert("a", Duration::hours(12), &value)?;
+
+        let expected = serde_json::json!({"1": "foo"});
+        assert!(matches!(cache.get_value("a")?, State::Fresh(e) if e.value == expected));
+
+        cache.insert_value("b", Duration::hours(12), &serde_json::json!([1, 2]))?;
+        assert!(matches!(cache.get::<_, Vec<u32>>("b")?, State::Fresh(e) if e.value == [1, 2]));
+        assert!(matches!(cache.get_value("c")?, State::Missing));
+
+        let json = Cache::builder(db).format(Format::Json).load()?;
+        let expected = serde_json::json!({"a": u64::MAX, "b": [1.5, null, "c"]});
+        json.insert_value("d", Duration::hours(12), &expected)?;
+        assert!(matches!(json.get_value("d")?, State::Fresh(e) if e.value == expected));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cleanup_auto_flush() -> Result<(), Box<dyn error::Error>> {
+        // only flush when told to, so that what's pending is deterministic.
+        let db = sled::Config::new()
+            .temporary(true)
+            .flush_every_ms(None)
+            .open()?;
+
+        let cache = Cache::builder(db.open_tree("test")?).auto_flush(1).load()?;
+
+        cache.insert("a", Duration::seconds(-1), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+        cache.insert("c", Duration::hours(12), &3u32)?;
+
+        assert_eq!(2, cache.cleanup()?);
+        assert_eq!(0, cache.flush()?);
+
+        // removing fewer entries than the threshold doesn't flush.
+        cache.insert("d", Duration::seconds(-1), &4u32)?;
+        assert_eq!(1, cache.cleanup()?);
+        assert!(cache.flush()? > 0);
+        assert_eq!(Some(3u32), cache.get::<_, u32>("c")?.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl_clamps() -> Result<(), Box<dyn error::Error>> {
+        use super::TtlStrictness;
+
+        let db = db("test_ttl_clamps")?;
+        let cache = Cache::builder(db.clone())
+            .min_ttl(Duration::seconds(10))
+            .max_ttl(Duration::hours(1))
+            .load()?;
+
+        cache.insert("a", Duration::seconds(0), &1u32)?;
+        assert!(matches!(cache.test("a")?, State::Fresh(..)));
+
+        let cache = Cache::builder(db.clone())
+            .max_ttl(Duration::hours(1))
+            .ttl_strictness(TtlStrictness::Error)
+            .load()?;
+
+        assert!(matches!(
+            cache.insert("b", Duration::days(3650), &2u32),
+            Err(Error::TtlOutOfRange(..))
+        ));
+
+        let result = Cache::load(db.clone())?.insert("b", Duration::MAX, &2u32);
+        assert!(matches!(
+            result.as_ref().map_err(Error::root),
+            Err(Error::TtlOutOfRange(..))
+        ));
+        assert!(matches!(
+            Cache::load(db.clone())?.touch("b", Duration::MAX),
+            Err(Error::TtlOutOfRange(..))
+        ));
+
+        let result = Cache::builder(db)
+            .min_ttl(Duration::hours(2))
+            .max_ttl(Duration::hours(1))
+            .load();
+
+        assert!(matches!(result, Err(Error::InvalidConfig(..))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_expiration_metadata() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_expiration_metadata")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+
+        let e = match cache.get::<_, u32>("a")? {
+            State::Fresh(e) => e,
+            _ => panic!("expected a fresh entry"),
+        };
+
+        let stored_at = e.stored_at().ok_or("missing stored_at")?;
+        assert_eq!(Some(stored_at + Duration::hours(1)), e.expires_at());
+        let ttl = e.ttl().ok_or("missing ttl")?;
+        assert!(ttl > Duration::minutes(59) && ttl <= Duration::hours(1));
+        assert!(e.age().ok_or("missing age")? >= Duration::zero());
+
+        assert!(
+            matches!(cache.get::<_, u32>("b")?, State::Expired(e) if e.ttl() == Some(Duration::zero()))
+        );
+
+        let entry = cache.get_ref("a")?.get().ok_or("missing")?;
+        assert_eq!(e.expires_at(), entry.expires_at());
+        assert_eq!(Some(stored_at), entry.stored_at());
+        assert!(entry.ttl() > Some(Duration::minutes(59)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_pinned")?;
+        let cache = Cache::load(db.clone())?;
+
+        cache.insert_pinned("a", Duration::seconds(-1), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+        let mut writes = db.watch_prefix(cache.key(&"b")?);
+        assert!(cache.pin("b")?);
+        assert!(cache.pin("b")?);
+        assert!(!cache.pin("c")?);
+
+        // pinning an entry which is already pinned doesn't write it again.
+        let timeout = std::time::Duration::from_millis(10);
+        assert!(writes.next_timeout(timeout).is_ok());
+        assert!(writes.next_timeout(timeout).is_err());
+
+        assert_eq!(0, cache.cleanup()?);
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Expired(e) if e.is_pinned()));
+
+        assert!(cache.unpin("b")?);
+        assert_eq!(1, cache.cleanup()?);
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_touch() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_touch")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert("a", Duration::seconds(10), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+
+        assert!(cache.touch("a", Duration::hours(1))?);
+        assert!(!cache.touch("b", Duration::hours(1))?);
+        assert!(!cache.touch("c", Duration::hours(1))?);
+
+        assert!(matches!(
+            cache.get::<_, u32>("a")?,
+            State::Fresh(e) if e.value == 1 && e.ttl() > Some(Duration::minutes(59))
+        ));
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Expired(..)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sliding() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_sliding")?;
+        let cache = Cache::builder(db.clone())
+            .sliding_namespace(&"sessions")?
+            .load()?;
+        let sessions = cache.namespaced(&"sessions")?;
+
+        cache.insert_sliding("a", Duration::hours(1), &1u32)?;
+        cache.insert("b", Duration::hours(1), &2u32)?;
+        sessions.insert("c", Duration::hours(1), &3u32)?;
+
+        // shorten the expiration, so that it's visibly extended by a read.
+        for (cache, key) in &[(&cache, "a"), (&cache, "b"), (&sessions, "c")] {
+            assert!(cache.touch(key, Duration::seconds(10))?);
+        }
+
+        assert!(matches!(
+            cache.get::<_, u32>("a")?,
+            State::Fresh(e) if e.idle_timeout() == Some(Duration::hours(1)) && e.ttl() > Some(Duration::minutes(59))
+        ));
+        assert!(matches!(
+            cache.get::<_, u32>("b")?,
+            State::Fresh(e) if e.idle_timeout().is_none() && e.ttl().is_some_and(|ttl| ttl <= Duration::seconds(10))
+        ));
+        assert!(matches!(
+            sessions.get_ref("c")?,
+            State::Fresh(e) if e.value.ttl() > Some(Duration::minutes(59))
+        ));
+
+        // the extension is persisted, and isn't written again right away.
+        let mut writes = db.watch_prefix(cache.key(&"a")?);
+        assert!(matches!(
+            cache.get::<_, u32>("a")?,
+            State::Fresh(e) if e.ttl() > Some(Duration::minutes(59))
+        ));
+        assert!(writes
+            .next_timeout(std::time::Duration::from_millis(10))
+            .is_err());
+
+        cache.insert_sliding("d", Duration::seconds(-1), &4u32)?;
+        assert!(matches!(cache.get::<_, u32>("d")?, State::Expired(..)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_until() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_insert_until")?;
+        let cache = Cache::builder(db).max_ttl(Duration::days(1)).load()?;
+
+        let expires_at = Utc::now() + Duration::hours(1);
+        cache.insert_until("a", expires_at, &1u32)?;
+        cache.insert_until("b", Utc::now() - Duration::seconds(1), &2u32)?;
+        cache.insert_until("c", Utc::now() + Duration::days(7), &3u32)?;
+
+        assert!(
+            matches!(cache.get::<_, u32>("a")?, State::Fresh(e) if e.expires_at() == Some(expires_at))
+        );
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Expired(..)));
+        assert!(
+            matches!(cache.get::<_, u32>("c")?, State::Fresh(e) if e.ttl().is_some_and(|ttl| ttl <= Duration::days(1)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_forever() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_insert_forever")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert_forever("a", &1u32)?;
+        cache.insert_forever("b", &2u32)?;
+
+        assert!(matches!(
+            cache.get::<_, u32>("a")?,
+            State::Fresh(e) if e.value == 1 && e.expires_at().is_none() && e.ttl().is_none()
+        ));
+
+        assert_eq!(0, cache.cleanup()?);
+        assert_eq!(1, cache.invalidate("a")?);
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Expired(..)));
+
+        cache.bump_epoch()?;
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Expired(..)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_if_absent() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_insert_if_absent")?;
+        let cache = Cache::load(db)?;
+
+        assert!(cache
+            .insert_if_absent("a", Duration::hours(1), &1u32)?
+            .is_none());
+        let existing = cache.insert_if_absent("a", Duration::hours(2), &2u32)?;
+        assert!(matches!(existing, Some(e) if e.value == 1));
+        assert!(matches!(
+            cache.get::<_, u32>("a")?,
+            State::Fresh(e) if e.value == 1 && e.ttl() <= Some(Duration::hours(1))
+        ));
+
+        // expired entries are replaced.
+        cache.insert("b", Duration::seconds(-1), &1u32)?;
+        assert!(cache
+            .insert_if_absent("b", Duration::hours(1), &2u32)?
+            .is_none());
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Fresh(e) if e.value == 2));
+
+        let threads = (0..16u32)
+            .map(|n| {
+                let cache = cache.clone();
+                thread::spawn(move || cache.insert_if_absent("c", Duration::hours(1), &n))
+            })
+            .collect::<Vec<_>>();
+
+        let mut inserted = 0;
+
+        for t in threads {
+            if t.join().expect("thread to join")?.is_none() {
+                inserted += 1;
+            }
+        }
+
+        assert_eq!(1, inserted);
+        assert_eq!(3, cache.len()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_replace")?;
+        let cache = Cache::builder(db).chunk_size(16).load()?;
+
+        assert_eq!(None, cache.replace("a", Duration::hours(1), &1u32)?);
+        assert_eq!(Some(1), cache.replace("a", Duration::hours(1), &2u32)?);
+
+        // expired and chunked values are returned too.
+        let large = "foo".repeat(100);
+        cache.insert("b", Duration::seconds(-1), &large)?;
+        assert_eq!(
+            Some(large),
+            cache.replace("b", Duration::hours(1), &"bar".to_owned())?
+        );
+
+        assert!(cache.soft_delete("a")?);
+        assert_eq!(None, cache.replace("a", Duration::hours(1), &3u32)?);
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Fresh(e) if e.value == 3));
+        assert_eq!(2, cache.len()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_take() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_take")?;
+        let cache = Cache::builder(db).chunk_size(16).load()?;
+
+        let large = "foo".repeat(100);
+        cache.insert("a", Duration::hours(1), &large)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+        cache.insert("c", Duration::hours(1), &3u32)?;
+        assert!(cache.soft_delete("c")?);
+
+        assert!(matches!(cache.take::<_, String>("a")?, State::Fresh(e) if e.value == large));
+        assert!(matches!(cache.take::<_, String>("a")?, State::Missing));
+        assert!(matches!(cache.take::<_, u32>("b")?, State::Expired(e) if e.value == 2));
+        assert!(matches!(cache.take::<_, u32>("c")?, State::Missing));
+        assert!(cache.undelete("c")?);
+        assert_eq!(1, cache.len()?);
+
+        cache.insert("d", Duration::hours(1), &4u32)?;
+
+        let threads = (0..16)
+            .map(|_| {
+                let cache = cache.clone();
+                thread::spawn(move || cache.take::<_, u32>("d"))
+            })
+            .collect::<Vec<_>>();
+
+        let mut taken = 0;
+
+        for t in threads {
+            if let State::Fresh(..) = t.join().expect("thread to join")? {
+                taken += 1;
+            }
+        }
+
+        assert_eq!(1, taken);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update() -> Result<(), Box<dyn error::Error>> {
+        const THREAD_COUNT: u32 = 16;
+        const INCREMENTS: u32 = 50;
+
+        let db = db("test_update")?;
+        let cache = Cache::load(db)?;
+
+        assert_eq!(
+            1,
+            cache.update("a", Duration::hours(1), |old: Option<u32>| old.unwrap_or(0)
+                + 1)?
+        );
+        assert_eq!(
+            2,
+            cache.update("a", Duration::hours(1), |old: Option<u32>| old.unwrap_or(0)
+                + 1)?
+        );
+
+        // expired values aren't passed on.
+        cache.insert("b", Duration::seconds(-1), &10u32)?;
+        assert_eq!(
+            1,
+            cache.update("b", Duration::hours(1), |old: Option<u32>| old.unwrap_or(0)
+                + 1)?
+        );
+
+        let threads = (0..THREAD_COUNT)
+            .map(|_| {
+                let cache = cache.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..INCREMENTS {
+                        cache.update("c", Duration::hours(1), |old: Option<u32>| {
+                            old.unwrap_or(0) + 1
+                        })?;
+                    }
+
+                    Ok::<_, Error>(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().expect("thread to join")?;
+        }
+
+        assert!(matches!(
+            cache.get::<_, u32>("c")?,
+            State::Fresh(e) if e.value == THREAD_COUNT * INCREMENTS
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_incr() -> Result<(), Box<dyn error::Error>> {
+        const THREAD_COUNT: i64 = 16;
+        const INCREMENTS: i64 = 50;
+
+        let db = db("test_incr")?;
+        let cache = Cache::load(db)?;
+
+        assert_eq!(1, cache.incr("a", 1, Duration::hours(1))?);
+        assert_eq!(-1, cache.incr("a", -2, Duration::hours(1))?);
+        assert!(matches!(cache.get::<_, i64>("a")?, State::Fresh(e) if e.value == -1));
+
+        // expired counters are replaced, while other values are left alone.
+        cache.insert("b", Duration::seconds(-1), &10i64)?;
+        cache.insert("c", Duration::hours(1), &"foo")?;
+        assert_eq!(5, cache.incr("b", 5, Duration::hours(1))?);
+        assert!(cache.incr("c", 5, Duration::hours(1)).is_err());
+        assert!(matches!(cache.get::<_, String>("c")?, State::Fresh(e) if e.value == "foo"));
+        assert_eq!(3, cache.len()?);
+
+        // counters keep their tags.
+        cache.insert_tagged("e", Duration::hours(1), &1i64, &["counters"])?;
+        assert_eq!(2, cache.incr("e", 1, Duration::hours(1))?);
+        assert_eq!(1, cache.invalidate_tag("counters")?);
+
+        let threads = (0..THREAD_COUNT)
+            .map(|_| {
+                let cache = cache.clone();
+
+                thread::spawn(move || {
+                    for _ in 0..INCREMENTS {
+                        cache.incr("d", 1, Duration::hours(1))?;
                     }
 
-                    // delete key since it's invalid.
-                    self.inner.db.remove(key)?;
-                    continue;
-                }
-            };
+                    Ok::<_, Error>(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for t in threads {
+            t.join().expect("thread to join")?;
+        }
+
+        assert!(matches!(
+            cache.get::<_, i64>("d")?,
+            State::Fresh(e) if e.value == THREAD_COUNT * INCREMENTS
+        ));
+        assert_eq!(cache.len()?, cache.approximate_len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_list")?;
+        let cache = Cache::load(db.clone())?;
+        let chunks = || db.scan_prefix(super::meta_key(super::CHUNK_KEY)).count();
+
+        for n in 0..5u32 {
+            assert_eq!(n as usize + 1, cache.push("a", Duration::hours(1), &n)?);
+        }
+
+        assert!(matches!(
+            cache.range::<_, u32>("a", 0, usize::MAX)?,
+            State::Fresh(e) if e.value == [0, 1, 2, 3, 4]
+        ));
+        assert!(matches!(cache.range::<_, u32>("a", 1, 3)?, State::Fresh(e) if e.value == [1, 2]));
+        assert!(matches!(cache.range::<_, u32>("a", 4, 10)?, State::Fresh(e) if e.value == [4]));
+
+        assert_eq!(Some(2), cache.trim_to("a", 3)?);
+        assert_eq!(Some(0), cache.trim_to("a", 3)?);
+        assert_eq!(None, cache.trim_to("b", 3)?);
+        assert_eq!(3, chunks());
+        assert!(matches!(
+            cache.range::<_, u32>("a", 0, usize::MAX)?,
+            State::Fresh(e) if e.value == [2, 3, 4]
+        ));
+
+        // pushing keeps the metadata of the list.
+        assert!(cache.pin("a")?);
+        assert_eq!(4, cache.push("a", Duration::hours(1), &5u32)?);
+        assert!(matches!(
+            cache.range::<_, u32>("a", 0, usize::MAX)?,
+            State::Fresh(e) if e.is_pinned() && e.value == [2, 3, 4, 5]
+        ));
+        assert_eq!(4, chunks());
+
+        // other values and expired lists are replaced.
+        cache.insert("b", Duration::hours(1), &"foo")?;
+        assert_eq!(1, cache.push("b", Duration::seconds(-1), &1u32)?);
+        assert!(matches!(cache.range::<_, u32>("b", 0, 1)?, State::Expired(e) if e.value == [1]));
+        assert_eq!(1, cache.push("b", Duration::hours(1), &2u32)?);
+        assert!(matches!(cache.range::<_, u32>("b", 0, 1)?, State::Fresh(e) if e.value == [2]));
+        assert_eq!(5, chunks());
+
+        cache.delete_with_ns::<(), _>(None, &"a")?;
+        assert_eq!(1, chunks());
+        assert!(matches!(cache.range::<_, u32>("a", 0, 1)?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_format_change() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_list_format_change")?;
+
+        let cache = Cache::builder(db.clone()).format(Format::Cbor).load()?;
+        cache.push("a", Duration::hours(1), &1u32)?;
+
+        // items pushed after reopening with another format are still encoded
+        // with the format of the list.
+        let cache = Cache::builder(db).format(Format::Json).load()?;
+        assert_eq!(2, cache.push("a", Duration::hours(1), &2u32)?);
+        assert!(matches!(
+            cache.range::<_, u32>("a", 0, usize::MAX)?,
+            State::Fresh(e) if e.value == [1, 2]
+        ));
+
+        // new lists use the format of the handle.
+        cache.delete("a")?;
+        assert_eq!(1, cache.push("a", Duration::hours(1), &3u32)?);
+        assert!(matches!(
+            cache.range::<_, u32>("a", 0, usize::MAX)?,
+            State::Fresh(e) if e.value == [3]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_set() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_set")?;
+        let cache = Cache::load(db)?;
+
+        assert!(cache.sadd("a", Duration::hours(1), &1u32)?);
+        assert!(cache.sadd("a", Duration::hours(1), &2u32)?);
+        assert!(!cache.sadd("a", Duration::hours(1), &1u32)?);
+        assert!(cache.scontains("a", &1u32)?);
+        assert!(!cache.scontains("a", &3u32)?);
+        assert!(!cache.scontains("b", &1u32)?);
+
+        assert!(cache.srem("a", &1u32)?);
+        assert!(!cache.srem("a", &1u32)?);
+        assert!(!cache.srem("b", &1u32)?);
+        assert!(!cache.scontains("a", &1u32)?);
+        assert!(matches!(cache.get::<_, Vec<u32>>("a")?, State::Fresh(e) if e.value == [2]));
+
+        // adding members doesn't extend the expiration.
+        let expires_at = match cache.get::<_, BTreeSet<u32>>("a")? {
+            State::Fresh(e) => e.expires_at(),
+            _ => panic!("expected fresh set"),
+        };
+        assert!(cache.sadd("a", Duration::hours(2), &3u32)?);
+        assert!(matches!(
+            cache.get::<_, BTreeSet<u32>>("a")?,
+            State::Fresh(e) if e.expires_at() == expires_at && e.value.len() == 2
+        ));
+
+        // expired sets and other values are replaced.
+        cache.insert("c", Duration::seconds(-1), &vec![1u32])?;
+        cache.insert("d", Duration::hours(1), &"foo")?;
+        assert!(cache.sadd("c", Duration::hours(1), &1u32)?);
+        assert!(cache.sadd("d", Duration::hours(1), &1u32)?);
+        assert!(matches!(cache.get::<_, Vec<u32>>("d")?, State::Fresh(e) if e.value == [1]));
+
+        // modifying a set keeps its options.
+        cache.insert_tagged("e", Duration::hours(1), &vec![1u32], &["tag"])?;
+        assert!(cache.pin("e")?);
+        assert!(cache.sadd("e", Duration::hours(1), &2u32)?);
+        assert!(cache.srem("e", &1u32)?);
+        assert!(matches!(
+            cache.get::<_, Vec<u32>>("e")?,
+            State::Fresh(e) if e.is_pinned() && e.value == [2]
+        ));
+        assert_eq!(1, cache.invalidate_tag("tag")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_many() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_get_many")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+
+        let states = cache.get_many::<_, u32, _>(vec!["c", "b", "a"])?;
+        assert!(matches!(
+            &states[..],
+            [State::Missing, State::Expired(b), State::Fresh(a)] if b.value == 2 && a.value == 1
+        ));
+        assert!(cache.get_many::<&str, u32, _>(vec![])?.is_empty());
+        assert_eq!(3, cache.stats().gets);
+
+        // chunked values are read along with the entries.
+        cache.insert("d", Duration::hours(1), &"a value which is chunked")?;
+        let states = cache.get_many::<_, String, _>(vec!["d", "d"])?;
+        assert!(states
+            .iter()
+            .all(|s| matches!(s, State::Fresh(e) if e.value.len() == 24)));
+
+        #[cfg(feature = "testing")]
+        {
+            let failing = Cache::builder(db)
+                .faults(super::faults::Faults::new().fail_reads(1.0))
+                .load()?;
+            assert!(failing.get_many::<_, u32, _>(vec!["a"]).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_insert_many")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+        let chunks = || db.scan_prefix(super::meta_key(super::CHUNK_KEY)).count();
+
+        cache.insert("a", Duration::hours(1), &"a".repeat(64))?;
+
+        cache.insert_many(vec![
+            ("a", Duration::hours(1), "b".repeat(64)),
+            ("b", Duration::seconds(-1), "c".to_string()),
+            ("c", Duration::hours(1), "d".to_string()),
+        ])?;
+
+        assert_eq!(Some("b".repeat(64)), cache.get::<_, String>("a")?.get());
+        assert!(matches!(cache.get::<_, String>("b")?, State::Expired(e) if e.value == "c"));
+        assert!(matches!(cache.get::<_, String>("c")?, State::Fresh(e) if e.value == "d"));
+
+        // the chunks of the replaced value are removed.
+        assert_eq!(5, chunks());
+        cache.delete_with_ns::<(), _>(None, &"a")?;
+        assert_eq!(0, chunks());
+        Ok(())
+    }
+
+    #[test]
+    fn test_soft_delete() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_soft_delete")?;
+        let cache = Cache::builder(db)
+            .tombstone_retention(Duration::hours(1))
+            .load()?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        assert!(cache.soft_delete("a")?);
+        assert!(!cache.soft_delete("a")?);
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Missing));
+        assert_eq!(0, cache.cleanup()?);
+
+        assert!(cache.undelete("a")?);
+        assert_eq!(Some(1u32), cache.get::<_, u32>("a")?.get());
+        assert!(!cache.undelete("a")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drain_expired() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_drain_expired")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        cache.insert("a", Duration::seconds(-1), &1u32)?;
+        cache.insert("b", Duration::hours(12), &2u32)?;
+        other.insert("c", Duration::seconds(-1), &3u32)?;
+
+        let drained = cache
+            .drain_expired::<u32>()?
+            .map(|e| e.map(|(key, stored)| (key, stored.value)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(vec![(serde_json::json!("a"), 1)], drained);
+        assert!(matches!(cache.test("a")?, State::Missing));
+        assert!(matches!(other.test("c")?, State::Expired(..)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespace_usage() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_namespace_usage")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        other.insert("b", Duration::hours(12), &2u32)?;
+        other.insert("c", Duration::hours(12), &3u32)?;
+
+        let usage = cache.namespace_usage()?;
+        assert_eq!(2, usage.len());
+        let default = usage.iter().find(|u| u.namespace.is_none());
+        let other = usage.iter().find(|u| u.namespace.is_some());
+        let (default, other) = (default.ok_or("default")?, other.ok_or("other")?);
+        assert_eq!(1, default.entries);
+        assert_eq!(Some(serde_json::json!(["other"])), other.namespace);
+        assert_eq!(2, other.entries);
+        assert!(other.bytes > default.bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_keys")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+        cache.insert(42u32, Duration::hours(12), &3u32)?;
+        other.insert(("c", 1u32), Duration::hours(12), &4u32)?;
+        other.insert(("d", 2u32), Duration::hours(12), &5u32)?;
+        other.soft_delete(("d", 2u32))?;
+
+        assert_eq!(vec!["a", "b"], cache.keys::<String>()?);
+        assert_eq!(vec![42], cache.keys_in_ns::<(), u32>(None)?);
+        assert_eq!(
+            vec![("c".to_string(), 1u32)],
+            other.keys::<(String, u32)>()?
+        );
+        assert_eq!(
+            vec![serde_json::json!(["c", 1])],
+            cache.keys_in_ns::<_, serde_json::Value>(Some(&"other"))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_iter")?;
+        let cache = Cache::load(db)?.namespaced(&"users")?;
+
+        cache.insert((1u64, "name"), Duration::hours(12), &"Alice")?;
+        cache.insert((2u64, "name"), Duration::hours(12), &"Bob")?;
+        cache.insert((3u64, "name"), Duration::seconds(-1), &"Carol")?;
+
+        let entries = cache
+            .iter::<(u64, String), String>()?
+            .map(|e| e.map(|(key, stored)| (key.0, stored.value)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            vec![(1, "Alice".to_string()), (2, "Bob".to_string())],
+            entries
+        );
+
+        // values of other types are reported.
+        cache.insert((4u64, "name"), Duration::hours(12), &4u32)?;
+        assert!(cache.iter::<(u64, String), String>()?.any(|e| e.is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_entries() -> Result<(), Box<dyn error::Error>> {
+        use ::futures::stream::TryStreamExt as _;
+
+        let db = db("test_stream_entries")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        for n in 0..super::STREAM_BATCH_SIZE as u32 + 10 {
+            cache.insert(n, Duration::hours(12), &n)?;
+        }
+
+        other.insert("a", Duration::seconds(-1), &"b")?;
+
+        let entries =
+            ::futures::executor::block_on(cache.stream_entries().try_collect::<Vec<_>>())?;
+        assert_eq!(super::STREAM_BATCH_SIZE + 11, entries.len());
+        assert_eq!(cache.list_json()?.len(), entries.len());
+        assert!(entries
+            .iter()
+            .any(|e| e.key == serde_json::json!([["other"], "a"]) && e.stored.value == "b"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_json_stream() -> Result<(), Box<dyn error::Error>> {
+        use ::futures::stream::{StreamExt as _, TryStreamExt as _};
+
+        let db = db("test_list_json_stream")?;
+        let cache = Cache::load(db)?;
+
+        for n in 0..super::STREAM_BATCH_SIZE as u32 * 2 + 10 {
+            cache.insert(n, Duration::hours(12), &n)?;
+        }
+
+        let entries =
+            ::futures::executor::block_on(cache.list_json_stream().try_collect::<Vec<_>>())?;
+        assert_eq!(cache.list_json()?.len(), entries.len());
+        assert_eq!(serde_json::json!([null, 0]), entries[0].key);
+
+        let stream = cache.list_json_stream().take(3);
+        assert_eq!(
+            3,
+            ::futures::executor::block_on(stream.collect::<Vec<_>>()).len()
+        );
+
+        // a scan which panics ends the stream with an error.
+        struct Panicking;
+
+        impl Codec for Panicking {
+            fn id(&self) -> u8 {
+                codec::MIN_CUSTOM_ID
+            }
+
+            fn encode(&self, value: &cbor::Value) -> Result<Vec<u8>, Error> {
+                Ok(cbor::to_vec(value)?)
+            }
+
+            fn decode(&self, _: &[u8]) -> Result<cbor::Value, Error> {
+                panic!("failed to decode")
+            }
+        }
+
+        let cache = Cache::builder(self::db("test_list_json_stream_panic")?)
+            .format(Format::Custom(Arc::new(Panicking)))
+            .load()?;
+        cache.insert("a", Duration::hours(12), &1u32)?;
+
+        let results = ::futures::executor::block_on(cache.list_json_stream().collect::<Vec<_>>());
+        assert_eq!(1, results.len());
+        assert!(matches!(results[0], Err(Error::Panicked)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_contains() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_contains")?;
+        let cache = Cache::builder(db).chunk_size(16).load()?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        cache.insert("b", Duration::seconds(-1), &2u32)?;
+        cache.insert("c", Duration::hours(12), &"c".repeat(64))?;
+        cache.insert("d", Duration::hours(12), &4u32)?;
+        cache.soft_delete("d")?;
+
+        assert!(matches!(cache.test("a")?, State::Fresh(..)));
+        assert!(matches!(cache.test("b")?, State::Expired(..)));
+        assert!(matches!(cache.test("c")?, State::Fresh(..)));
+        assert!(matches!(cache.test("d")?, State::Missing));
+        assert!(matches!(cache.test("e")?, State::Missing));
+
+        assert!(cache.contains("a")?);
+        assert!(!cache.contains("b")?);
+        assert!(cache.contains("c")?);
+        assert!(!cache.contains("d")?);
+        assert!(!cache.contains("e")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_scan")?;
+        let cache = Cache::load(db)?.namespaced(&"events")?;
+
+        for stream in 0..3u64 {
+            for sequence in 0..20u64 {
+                cache.insert(
+                    (stream, sequence),
+                    Duration::hours(12),
+                    &(stream * 100 + sequence),
+                )?;
+            }
+        }
+
+        fn values<I>(iter: I) -> Result<Vec<u64>, Error>
+        where
+            I: Iterator<Item = Result<((u64, u64), super::StoredEntry<u64>), Error>>,
+        {
+            iter.map(|e| e.map(|(_, stored)| stored.value)).collect()
+        }
+
+        assert_eq!(
+            vec![119, 118, 117],
+            values(cache.scan((1, 0)..(2, 0))?.rev().take(3))?
+        );
+        assert_eq!(vec![108, 109, 110], values(cache.scan((1, 8)..=(1, 10))?)?);
+        assert_eq!(vec![218, 219], values(cache.scan((2, 18)..)?)?);
+        assert_eq!(60, values(cache.scan(..)?)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_index() -> Result<(), Box<dyn error::Error>> {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct User {
+            name: String,
+            email: Option<String>,
+        }
+
+        let user = |name: &str, email: Option<&str>| User {
+            name: name.to_owned(),
+            email: email.map(str::to_owned),
+        };
+
+        let db = db("test_index")?;
+        let cache = Cache::builder(db.clone())
+            .index("email", |user: &User| user.email.clone())
+            .index("parity", |n: &i64| Some(n % 2 == 0))
+            .load()?;
+        let indexes = || db.scan_prefix(super::meta_key(super::INDEX_KEY)).count();
+
+        cache.insert(
+            1u32,
+            Duration::hours(1),
+            &user("Alice", Some("alice@example.com")),
+        )?;
+        cache.insert(
+            2u32,
+            Duration::hours(1),
+            &user("Bob", Some("bob@example.com")),
+        )?;
+        cache.insert(3u32, Duration::hours(1), &user("Carol", None))?;
+        assert_eq!(2, indexes());
+
+        let found = cache.get_by_index::<_, User>("email", &"alice@example.com")?;
+        assert_eq!(
+            vec![user("Alice", Some("alice@example.com"))],
+            found.into_iter().map(|e| e.value).collect::<Vec<_>>()
+        );
+
+        // reindexing an entry keeps its index, while changing it moves it.
+        cache.insert(
+            1u32,
+            Duration::hours(1),
+            &user("Alice", Some("alice@example.com")),
+        )?;
+        assert_eq!(
+            1,
+            cache
+                .get_by_index::<_, User>("email", &"alice@example.com")?
+                .len()
+        );
+        cache.insert(
+            2u32,
+            Duration::hours(1),
+            &user("Bob", Some("robert@example.com")),
+        )?;
+        assert!(cache
+            .get_by_index::<_, User>("email", &"bob@example.com")?
+            .is_empty());
+        assert_eq!(
+            1,
+            cache
+                .get_by_index::<_, User>("email", &"robert@example.com")?
+                .len()
+        );
+        assert_eq!(2, indexes());
+
+        // updating part of a value moves its index as well.
+        assert!(cache.update_path(2u32, "/email", |email| {
+            *email = "bobby@example.com".into()
+        })?);
+        assert!(cache
+            .get_by_index::<_, User>("email", &"robert@example.com")?
+            .is_empty());
+        assert_eq!(
+            1,
+            cache
+                .get_by_index::<_, User>("email", &"bobby@example.com")?
+                .len()
+        );
+        assert_eq!(2, indexes());
+
+        // so does incrementing a counter.
+        cache.incr("n", 1, Duration::hours(1))?;
+        assert_eq!(1, cache.get_by_index::<_, i64>("parity", &false)?.len());
+        cache.incr("n", 1, Duration::hours(1))?;
+        assert!(cache.get_by_index::<_, i64>("parity", &false)?.is_empty());
+        assert_eq!(1, cache.get_by_index::<_, i64>("parity", &true)?.len());
+        cache.delete_with_ns::<(), _>(None, &"n")?;
+        assert_eq!(2, indexes());
+
+        cache.delete_with_ns::<(), _>(None, &1u32)?;
+        assert!(cache
+            .get_by_index::<_, User>("email", &"alice@example.com")?
+            .is_empty());
+        cache.insert(
+            2u32,
+            Duration::seconds(-1),
+            &user("Bob", Some("robert@example.com")),
+        )?;
+        assert!(cache
+            .get_by_index::<_, User>("email", &"robert@example.com")?
+            .is_empty());
+        cache.cleanup()?;
+        assert_eq!(0, indexes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_query() -> Result<(), Box<dyn error::Error>> {
+        use crate::query::{eq, exists, ge, json_path, lt, ne};
+        use serde_json::json;
+
+        let db = db("test_query")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        users.insert(
+            1u32,
+            Duration::hours(1),
+            &json!({"name": "Alice", "age": 30, "tags": ["admin"]}),
+        )?;
+        users.insert(
+            2u32,
+            Duration::hours(1),
+            &json!({"name": "Bob", "age": 25.5}),
+        )?;
+        users.insert(
+            3u32,
+            Duration::hours(1),
+            &json!({"name": "Carol", "age": 41}),
+        )?;
+        cache.insert(
+            4u32,
+            Duration::hours(1),
+            &json!({"name": "Alice", "age": 30}),
+        )?;
+
+        let names = |query: crate::query::Query<'_>| -> Result<Vec<serde_json::Value>, Error> {
+            Ok(query.run()?.into_iter().map(|e| e.key).collect())
+        };
+
+        assert_eq!(
+            vec![json!([null, 4]), json!([["users"], 1])],
+            names(cache.query().filter(json_path("$.name"), eq("Alice")))?
+        );
+        assert_eq!(
+            vec![json!([["users"], 1])],
+            names(
+                cache
+                    .query()
+                    .ns(&"users")
+                    .filter(json_path("$.age"), eq(30.0))
+            )?
+        );
+        assert_eq!(
+            vec![json!([null, 4])],
+            names(
+                cache
+                    .query()
+                    .default_ns()
+                    .filter(json_path("$.age"), ge(30))
+            )?
+        );
+        assert_eq!(
+            vec![json!([["users"], 1]), json!([["users"], 2])],
+            names(
+                cache
+                    .query()
+                    .ns(&"users")
+                    .filter(json_path("$.age"), lt(40))
+                    .filter(json_path("$.name"), ne("Carol"))
+            )?
+        );
+        assert_eq!(
+            vec![json!([["users"], 1])],
+            names(cache.query().filter(json_path("$.tags[0]"), exists()))?
+        );
+        assert_eq!(1, cache.query().ns(&"users").limit(1).run()?.len());
+        assert!(matches!(
+            cache.query().filter(json_path("name"), exists()).run(),
+            Err(Error::InvalidPath(..))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_history")?;
+        let cache = Cache::builder(db.clone())
+            .history(2)
+            .chunk_size(16)
+            .load()?;
+        let history = || db.scan_prefix(super::meta_key(super::HISTORY_KEY)).count();
+
+        assert!(cache.history::<_, String>("a")?.is_empty());
+
+        for value in ["one", "two", "three"] {
+            cache.insert("a", Duration::hours(1), &value.repeat(8))?;
+        }
+
+        cache.insert("a", Duration::hours(1), &"four")?;
+
+        let versions = cache
+            .history::<_, String>("a")?
+            .into_iter()
+            .map(|e| e.value)
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["three".repeat(8), "two".repeat(8)], versions);
+        assert!(matches!(cache.get::<_, String>("a")?, State::Fresh(e) if e.value == "four"));
+        assert_eq!(2, history());
+
+        cache.delete_with_ns::<(), _>(None, &"a")?;
+        assert_eq!(2, history());
+        cache.cleanup()?;
+        assert_eq!(0, history());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_log() -> Result<(), Box<dyn error::Error>> {
+        use super::AuditOp;
+        use ::futures::stream::TryStreamExt as _;
+        use serde_json::json;
+
+        let audit_log = db("test_audit_log_records")?;
+        let cache = Cache::builder(db("test_audit_log")?)
+            .audit_log(audit_log)
+            .load()?;
+        let other = cache.namespaced(&"other")?;
+        let mut watch = cache.watch_audit_log()?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        other.insert("b", Duration::hours(-1), &2u32)?;
+        cache.invalidate("a")?;
+        cache.delete_with_ns::<(), _>(None, &"missing")?;
+        other.delete_with_ns(None::<&()>, &"b")?;
+        cache.insert("c", Duration::hours(-1), &3u32)?;
+        cache.cleanup()?;
+
+        let records = cache.audit_log(0)?.collect::<Result<Vec<_>, _>>()?;
+
+        let ops = records
+            .iter()
+            .map(|r| (r.op, r.ns.clone(), r.key.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (AuditOp::Insert, json!(null), json!("a")),
+                (AuditOp::Insert, json!(["other"]), json!("b")),
+                (AuditOp::Invalidate, json!(null), json!("a")),
+                (AuditOp::Delete, json!(["other"]), json!("b")),
+                (AuditOp::Insert, json!(null), json!("c")),
+                (AuditOp::Expire, json!(null), json!("a")),
+                (AuditOp::Expire, json!(null), json!("c")),
+            ],
+            ops
+        );
+
+        assert!(records.windows(2).all(|w| w[0].id < w[1].id));
+
+        let watched = ::futures::executor::block_on(watch.try_next())?.ok_or("missing")?;
+        assert_eq!(records[0], watched);
+
+        let resumed = cache.audit_log(records[4].id)?.count();
+        assert_eq!(3, resumed);
+
+        assert_eq!(4, cache.trim_audit_log(records[4].id)?);
+        assert_eq!(3, cache.audit_log(0)?.count());
+
+        let cache = Cache::load(db("test_audit_log_disabled")?)?;
+        assert!(matches!(cache.audit_log(0), Err(Error::NoAuditLog)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_metadata() -> Result<(), Box<dyn error::Error>> {
+        use super::Metadata;
+        use serde_cbor::Value;
+
+        let db = db("test_metadata")?;
+        let cache = Cache::builder(db).chunk_size(16).load()?;
+
+        let mut metadata = Metadata::new();
+        metadata.insert("etag".into(), Value::Text("\"abc\"".into()));
+        metadata.insert("status".into(), Value::Integer(200));
+
+        cache.insert_with_metadata("a", Duration::hours(1), &"a".repeat(64), &metadata)?;
+        cache.insert("b", Duration::hours(1), &1u32)?;
+
+        let entry = cache.get_ref("a")?.get().ok_or("missing")?;
+        assert_eq!(&metadata, entry.metadata());
+        assert_eq!("a".repeat(64), entry.value::<String>()?);
+
+        let stored = cache.get::<_, String>("a")?;
+        assert!(matches!(stored, State::Fresh(e) if e.metadata() == &metadata));
+
+        let entry = cache.get_ref("b")?.get().ok_or("missing")?;
+        assert!(entry.metadata().is_empty());
+
+        cache.insert("a", Duration::hours(1), &"b")?;
+        let stored = cache.get::<_, String>("a")?;
+        assert!(matches!(stored, State::Fresh(e) if e.metadata().is_empty()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_typed")?;
+        let cache = Cache::load(db)?;
+        let typed = cache.namespaced(&"typed")?.typed::<String, Vec<u32>>();
+
+        typed.insert("a", Duration::hours(1), &vec![1, 2])?;
+        assert_eq!(Some(vec![1, 2]), typed.get("a")?.get());
+        assert_eq!(Some(vec![1, 2]), typed.get(&String::from("a"))?.get());
+        assert!(matches!(typed.get("b")?, State::Missing));
+
+        let value = ::futures::executor::block_on(
+            typed.wrap("b", Duration::hours(1), async { Ok::<_, Error>(vec![3]) }),
+        )?;
+
+        assert_eq!(vec![3], value);
+        assert_eq!(Some(vec![3]), typed.cache().get::<_, Vec<u32>>("b")?.get());
+        assert!(matches!(cache.get::<_, Vec<u32>>("b")?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaced_with() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_namespaced_with")?;
+        let cache = Cache::load(db)?;
+        let ns = cache.namespaced_with(&"ns", Duration::hours(1))?;
+
+        ns.insert("a", &1u32)?;
+        ns.insert_with_ttl("b", Duration::hours(-1), &2u32)?;
+
+        let value = ::futures::executor::block_on(ns.wrap("c", async { Ok::<_, Error>(3u32) }))?;
+        assert_eq!(3, value);
+
+        for key in ["a", "c"] {
+            let fresh = match ns.get::<_, u32>(key)? {
+                State::Fresh(e) => e.ttl().is_some_and(|ttl| ttl > Duration::minutes(59)),
+                _ => false,
+            };
+
+            assert!(fresh);
+        }
+
+        assert!(matches!(ns.get::<_, u32>("b")?, State::Expired(..)));
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Missing));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_namespaces() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_nested_namespaces")?;
+        let cache = Cache::load(db)?;
+        let a = cache.namespaced(&"a")?;
+        let b = a.namespaced(&"b")?;
+        let sibling = a.namespaced(&"c")?;
+        let top = cache.namespaced(&"b")?;
+        let tuple = cache.namespaced(&("a", "b"))?;
+
+        a.insert("k", Duration::hours(1), &1u32)?;
+        b.insert("k", Duration::hours(1), &2u32)?;
+        sibling.insert("k", Duration::hours(1), &3u32)?;
+        top.insert("k", Duration::hours(1), &4u32)?;
+        tuple.insert("k", Duration::hours(1), &5u32)?;
+
+        assert_eq!(Some(1), a.get::<_, u32>("k")?.get());
+        assert_eq!(Some(2), b.get::<_, u32>("k")?.get());
+        assert_eq!(Some(3), sibling.get::<_, u32>("k")?.get());
+        assert_eq!(Some(4), top.get::<_, u32>("k")?.get());
+
+        let entry = b.get_ref("k")?.get().ok_or("missing")?;
+        let (ns, key) = Cache::decode_key::<String>(entry.key())?;
+        assert_eq!((json!(["a", "b"]), String::from("k")), (ns, key));
+
+        let keys = cache
+            .list_json()?
+            .into_iter()
+            .map(|e| e.key)
+            .collect::<Vec<_>>();
+
+        // a namespace which is a tuple is a single level.
+        assert!(keys.contains(&json!([["a"], "k"])));
+        assert!(keys.contains(&json!([["a", "b"], "k"])));
+        assert!(keys.contains(&json!([[["a", "b"]], "k"])));
+        assert_eq!(5, cache.namespace_usage()?.len());
+
+        assert_eq!(1, b.clear()?);
+        assert!(matches!(b.get::<_, u32>("k")?, State::Missing));
+        assert_eq!(Some(1), a.get::<_, u32>("k")?.get());
+        assert_eq!(Some(3), sibling.get::<_, u32>("k")?.get());
+
+        // namespaces passed as arguments are nested inside of the handle.
+        a.delete_with_ns(Some(&"c"), &"k")?;
+        assert!(matches!(sibling.get::<_, u32>("k")?, State::Missing));
+        b.insert("k", Duration::hours(1), &2u32)?;
+        assert_eq!(1, a.clear_namespace(Some(&"b"))?);
+        assert_eq!(Some(1), a.get::<_, u32>("k")?.get());
+        assert_eq!(Some(4), top.get::<_, u32>("k")?.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaces() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_namespaces")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+        let admins = users.namespaced(&"admins")?;
+
+        assert!(cache.namespaces()?.is_empty());
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        users.insert("b", Duration::hours(1), &2u32)?;
+        users.insert("c", Duration::hours(-1), &3u32)?;
+        admins.insert("d", Duration::hours(1), &4u32)?;
+
+        let namespaces = cache
+            .namespaces()?
+            .into_iter()
+            .map(|n| (n.namespace, n.entries))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (None, 1),
+                (Some(json!(["users"])), 2),
+                (Some(json!(["users", "admins"])), 1),
+            ],
+            namespaces
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespace_policy() -> Result<(), Box<dyn error::Error>> {
+        use super::namespace::Policy;
+
+        let db = db("test_namespace_policy")?;
+        let cache = Cache::builder(db)
+            .namespace_policy(
+                &"users",
+                Policy::new()
+                    .ttl(Duration::hours(1))
+                    .negative_ttl(Duration::minutes(1))
+                    .max_entries(2),
+            )?
+            .namespace_policy(&"skip", Policy::new().negative_ttl(Duration::zero()))?
+            .load()?;
+
+        assert!(matches!(
+            cache.namespace(&"other"),
+            Err(Error::NoDefaultTtl)
+        ));
+
+        let users = cache.namespace(&"users")?;
+        assert_eq!(Duration::hours(1), users.ttl());
+
+        users.insert_with_ttl("a", Duration::minutes(30), &Some(1u32))?;
+        users.insert("b", &Some(2u32))?;
+        users.insert("c", &None::<u32>)?;
+        users.pin("c")?;
+        users
+            .namespaced(&"nested")?
+            .insert("d", Duration::hours(1), &4u32)?;
+
+        let ttl = |key| match users.get::<_, Option<u32>>(key) {
+            Ok(State::Fresh(e)) => e.ttl(),
+            _ => None,
+        };
+
+        assert!(ttl("b").is_some_and(|ttl| ttl > Duration::minutes(59)));
+        assert!(ttl("c").is_some_and(|ttl| ttl <= Duration::minutes(1)));
+
+        let skip = cache.namespaced(&"skip")?;
+        skip.insert("a", Duration::hours(1), &())?;
+        skip.insert("b", Duration::hours(1), &1u32)?;
+        assert!(matches!(skip.get::<_, ()>("a")?, State::Missing));
+        assert_eq!(Some(1), skip.get::<_, u32>("b")?.get());
+
+        // the entry expiring soonest is evicted, while the pinned entry and
+        // entries in nested namespaces are kept.
+        assert_eq!(1, cache.cleanup()?);
+        assert!(matches!(users.get::<_, Option<u32>>("a")?, State::Missing));
+        assert_eq!(Some(Some(2)), users.get::<_, Option<u32>>("b")?.get());
+        assert_eq!(Some(None), users.get::<_, Option<u32>>("c")?.get());
+        assert_eq!(0, cache.cleanup()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_stats")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        users.insert("b", Duration::hours(-1), &2u32)?;
+        users.insert("c", Duration::hours(1), &3u32)?;
+
+        cache.get::<_, u32>("a")?;
+        cache.get::<_, u32>("missing")?;
+        users.get::<_, u32>("b")?;
+        users.get_ref("c")?;
+        users.delete_with_ns(None::<&()>, &"c")?;
+        cache.cleanup()?;
+
+        let result = ::futures::executor::block_on(users.wrap("d", Duration::hours(1), async {
+            Err::<u32, _>(Error::Failed)
+        }));
+
+        assert!(result.is_err());
+
+        let stats = cache.namespace_stats()?;
+        assert_eq!(2, stats.len());
+
+        let default = &stats[0];
+        assert_eq!(None, default.namespace);
+        assert_eq!(
+            (1, 1, 0),
+            (default.hits, default.misses, default.stale_hits)
+        );
+        assert_eq!(1, default.inserts);
+
+        let users = &stats[1];
+        assert_eq!(Some(json!(["users"])), users.namespace);
+        assert_eq!((1, 1, 1), (users.hits, users.misses, users.stale_hits));
+        assert_eq!((2, 1, 1), (users.inserts, users.deletes, users.expirations));
+        assert_eq!(1, users.loader_errors);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats_bounded() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_stats_bounded")?;
+        let cache = Cache::builder(db).max_stats_namespaces(1).load()?;
+        let users = cache.namespaced(&"users")?;
+        let admins = cache.namespaced(&"admins")?;
+        let guests = cache.namespaced(&"guests")?;
+
+        users.insert("a", Duration::hours(1), &1u32)?;
+        admins.insert("a", Duration::hours(1), &2u32)?;
+        guests.insert("a", Duration::hours(1), &3u32)?;
+        cache.insert("a", Duration::hours(1), &4u32)?;
+
+        let stats = cache.namespace_stats()?;
+        assert_eq!(3, stats.len());
+        assert_eq!(
+            (None, false, 1),
+            (
+                stats[0].namespace.clone(),
+                stats[0].overflow,
+                stats[0].inserts
+            )
+        );
+        assert_eq!(Some(json!(["users"])), stats[1].namespace);
+        assert_eq!(
+            (None, true, 2),
+            (
+                stats[2].namespace.clone(),
+                stats[2].overflow,
+                stats[2].inserts
+            )
+        );
+        assert_eq!(4, cache.stats().inserts);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ttl_utilization() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_ttl_utilization")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        cache.insert("a", Duration::hours(1), &2u32)?;
+        cache.insert("b", Duration::milliseconds(2), &3u32)?;
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        cache.get::<_, u32>("a")?;
+        cache.get::<_, u32>("b")?;
+        cache.get::<_, u32>("missing")?;
+        cache.insert("b", Duration::hours(1), &4u32)?;
+        cache.insert_forever("c", &5u32)?;
+        cache.insert_forever("c", &6u32)?;
+
+        let stats = cache.namespace_stats()?;
+        let default = &stats[0];
+
+        assert_eq!(2, default.access_age.count());
+        let age = default.access_age.quantile(0.5).ok_or("missing")?;
+        assert!(age > std::time::Duration::from_millis(5));
+
+        let buckets = default.ttl_utilization.buckets().collect::<Vec<_>>();
+        assert_eq!(11, buckets.len());
+        assert_eq!((0.1, 1), buckets[0]);
+        assert_eq!((f64::INFINITY, 1), buckets[10]);
+        assert_eq!(Some(0.1), default.ttl_utilization.quantile(0.5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_stats() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_cache_stats")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        cache.insert("a", Duration::hours(1), &1u32)?;
+        users.insert("b", Duration::hours(-1), &2u32)?;
+        cache.get::<_, u32>("a")?;
+        cache.get_many::<_, u32, _>(vec!["missing", "a"])?;
+        users.get::<_, u32>("b")?;
+
+        let value = ::futures::executor::block_on(users.wrap("c", Duration::hours(1), async {
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            Ok::<_, Error>(3u32)
+        }))?;
+
+        assert_eq!(3, value);
+
+        let stats = cache.stats();
+        assert_eq!((4, 1), (stats.gets, stats.wraps));
+        assert_eq!((2, 1, 2), (stats.hits, stats.stale_hits, stats.misses));
+        assert_eq!((3, 0, 0), (stats.inserts, stats.deletes, stats.errors));
+
+        assert_eq!(4, stats.get_latency.count());
+        assert_eq!(3, stats.insert_latency.count());
+        assert_eq!(1, stats.loader_latency.count());
+
+        let loader = stats.loader_latency.quantile(0.5).ok_or("missing")?;
+        assert!(loader > std::time::Duration::from_millis(2));
+        assert!(stats.loader_latency.total() >= std::time::Duration::from_millis(2));
+        assert_eq!(None, crate::Histogram::default().mean());
+
+        // wrap reads the entry again before calling its loader, get_many reads
+        // all of its entries at once, and missing entries aren't decoded.
+        let storage = cache.storage_stats();
+        assert_eq!(5, storage.read_latency.count());
+        assert_eq!(3, storage.decode_latency.count());
+        assert_eq!(3, storage.write_latency.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_slow_operations() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_slow_operations")?;
+
+        let cache = Cache::builder(db)
+            .slow_loader_threshold(std::time::Duration::from_millis(20))
+            .slow_storage_threshold(std::time::Duration::from_secs(0))
+            .load()?;
+
+        let users = cache.namespaced(&"users")?;
+        users.insert("a", Duration::hours(1), &1u32)?;
+        users.get::<_, u32>("a")?;
+
+        ::futures::executor::block_on(users.wrap("slow", Duration::hours(1), async {
+            std::thread::sleep(std::time::Duration::from_millis(25));
+            Ok::<_, Error>(2u32)
+        }))?;
+
+        ::futures::executor::block_on(
+            users.wrap("fast", Duration::hours(1), async { Ok::<_, Error>(3u32) }),
+        )?;
+
+        let stats = cache.namespace_stats()?;
+        let users = stats
+            .iter()
+            .find(|stats| stats.namespace == Some(json!(["users"])))
+            .ok_or("missing")?;
+
+        assert_eq!(1, users.slow_loads);
+        assert_eq!(2, users.loader_latency.count());
+        // at least the three writes, besides the reads.
+        assert!(users.slow_storage >= 3, "{}", users.slow_storage);
+
+        let stats = cache.stats();
+        assert_eq!(
+            (users.slow_loads, users.slow_storage),
+            (stats.slow_loads, stats.slow_storage)
+        );
+
+        // only the first of the warnings in an interval is logged.
+        let limit = super::WarningLimit::default();
+        assert_eq!(Some(0), limit.check());
+        assert_eq!(None, limit.check());
+        assert_eq!(None, limit.check());
+        limit.state.lock().0 = super::Instant::now().checked_sub(2 * super::SLOW_WARNING_INTERVAL);
+        assert_eq!(Some(2), limit.check());
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks() -> Result<(), Box<dyn error::Error>> {
+        use super::namespace::Policy;
+        use serde_cbor as cbor;
+        use serde_json::json;
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let record = |name: &'static str| {
+            let events = events.clone();
+
+            move |event: &super::HookEvent| {
+                let found = event.stored_at.is_some();
+                let tag = event.metadata.get("source").cloned();
+                events.lock().unwrap().push((
+                    name,
+                    event.ns.clone(),
+                    event.key.clone(),
+                    found,
+                    tag,
+                ));
+            }
+        };
+
+        let cache = Cache::builder(db("test_hooks")?)
+            .namespace_policy(&"users", Policy::new().max_entries(1))?
+            .on_insert(record("insert"))
+            .on_hit(record("hit"))
+            .on_miss(record("miss"))
+            .on_expire(record("expire"))
+            .on_evict(record("evict"))
+            .on_corrupt(record("corrupt"))
+            .load()?;
+
+        let mut metadata = super::Metadata::new();
+        metadata.insert(
+            String::from("source"),
+            cbor::Value::Text(String::from("db")),
+        );
+
+        cache.insert_with_metadata("a", Duration::hours(1), &1u32, &metadata)?;
+        cache.get::<_, u32>("a")?;
+        cache.get::<_, u32>("missing")?;
+        cache.insert("b", Duration::hours(-1), &2u32)?;
+        cache.get::<_, u32>("b")?;
+        cache.cleanup()?;
+
+        let users = cache.namespaced(&"users")?;
+        users.insert("c", Duration::hours(1), &3u32)?;
+        users.insert("d", Duration::hours(2), &4u32)?;
+        cache.cleanup()?;
+
+        let key = cache.key(&"a")?;
+        let mut corrupt = cache.inner.db.get(&key)?.ok_or("missing")?.to_vec();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        cache.inner.db.insert(&key, corrupt)?;
+        cache.get::<_, u32>("a")?;
+
+        let source = Some(cbor::Value::Text(String::from("db")));
+
+        assert_eq!(
+            vec![
+                ("insert", json!(null), json!("a"), true, source.clone()),
+                ("hit", json!(null), json!("a"), true, source),
+                ("miss", json!(null), json!("missing"), false, None),
+                ("insert", json!(null), json!("b"), true, None),
+                ("miss", json!(null), json!("b"), true, None),
+                ("expire", json!(null), json!("b"), true, None),
+                ("insert", json!(["users"]), json!("c"), true, None),
+                ("insert", json!(["users"]), json!("d"), true, None),
+                ("evict", json!(["users"]), json!("c"), true, None),
+                ("corrupt", json!(null), json!("a"), false, None),
+            ],
+            *events.lock().unwrap()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain() -> Result<(), Box<dyn error::Error>> {
+        use super::explain::Reason;
+        use serde_json::json;
+        use std::sync::Mutex;
+
+        let explanations = Arc::new(Mutex::new(Vec::new()));
+
+        let cache = Cache::builder(db("test_explain")?)
+            .explain({
+                let explanations = explanations.clone();
+                move |explanation| explanations.lock().unwrap().push(explanation.clone())
+            })
+            .load()?;
+
+        cache.insert("a", Duration::seconds(-32), &1u32)?;
+        cache.insert("b", Duration::hours(1), &2u32)?;
+        cache.get::<_, u32>("a")?;
+        cache.get::<_, u32>("b")?;
+        cache.bump_epoch()?;
+        cache.get_ref("b")?;
+        cache.get::<_, u32>("missing")?;
+
+        let value = ::futures::executor::block_on(
+            cache.wrap("c", Duration::hours(1), async { Ok::<_, Error>(3u32) }),
+        )?;
+        assert_eq!(3, value);
+
+        assert!(cache
+            .insert_if_absent("c", Duration::hours(1), &4u32)?
+            .is_some());
+
+        let explanations = explanations.lock().unwrap();
+        let keys = explanations
+            .iter()
+            .map(|e| (e.ns.clone(), e.key.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                (json!(null), json!("a")),
+                (json!(null), json!("b")),
+                (json!(null), json!("b")),
+                (json!(null), json!("missing")),
+                (json!(null), json!("c")),
+                (json!(null), json!("c")),
+                (json!(null), json!("c")),
+                (json!(null), json!("c")),
+            ],
+            keys
+        );
+
+        let reasons = explanations.iter().map(|e| &e.reason).collect::<Vec<_>>();
+        assert!(matches!(reasons[0], Reason::Expired { ago } if ago.num_seconds() >= 32));
+        assert!(reasons[0].to_string().starts_with("expired 3"));
+        assert!(matches!(
+            reasons[1],
+            Reason::Fresh {
+                expires_in: Some(..)
+            }
+        ));
+        assert_eq!(
+            &Reason::Invalidated {
+                epoch: 0,
+                current: 1
+            },
+            reasons[2]
+        );
+        assert_eq!(
+            "stored in epoch 0 before it was bumped to 1",
+            reasons[2].to_string()
+        );
+        assert_eq!(&Reason::Missing, reasons[3]);
+        assert_eq!(&Reason::Missing, reasons[4]);
+        // read again before loading, in case a concurrent call loaded it.
+        assert_eq!(&Reason::Missing, reasons[5]);
+        assert!(matches!(reasons[6], Reason::Loaded { .. }));
+        assert_eq!(&Reason::Rejected, reasons[7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_error() -> Result<(), Box<dyn error::Error>> {
+        use super::{CacheKey, ErrorKind, KeyEncoder, KeyError, KeyRedaction};
+        use serde::ser::Error as _;
+        use serde_json::json;
+
+        struct Unkeyable;
+
+        impl CacheKey for Unkeyable {
+            fn encode_key(&self, _: KeyEncoder<'_>) -> Result<(), KeyError> {
+                Err(KeyError::custom("unkeyable"))
+            }
+        }
+
+        struct Unserializable;
+
+        impl serde::Serialize for Unserializable {
+            fn serialize<S>(&self, _: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                Err(S::Error::custom("unserializable"))
+            }
+        }
+
+        let cache = Cache::builder(db("test_entry_error")?)
+            .key_redaction(KeyRedaction::NamespaceOnly)
+            .load()?
+            .namespaced(&"users")?;
+
+        let error = cache
+            .insert("alice", Duration::hours(1), &Unserializable)
+            .err()
+            .ok_or("missing error")?;
+
+        assert_eq!(ErrorKind::ValueSerialization, error.kind());
+
+        match &error {
+            Error::Entry(e) => {
+                assert_eq!(json!(["users"]), e.ns);
+                assert_eq!(Some(json!({ "redacted": true })), e.key);
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+
+        let message = error.to_string();
+        assert!(message.starts_with(r#"["users"]/{"redacted":true}: "#));
+        assert!(!message.contains("alice"));
+
+        let error = cache
+            .get::<_, u32>(Unkeyable)
+            .err()
+            .ok_or("missing error")?;
+
+        assert_eq!(ErrorKind::KeySerialization, error.kind());
+        assert!(matches!(error.root(), Error::Key(..)));
+        assert!(matches!(&error, Error::Entry(e) if e.key.is_none()));
+
+        cache.insert("text", Duration::hours(1), &"not a number")?;
+        let error = cache
+            .incr("text", 1, Duration::hours(1))
+            .err()
+            .ok_or("missing error")?;
+
+        assert_eq!(ErrorKind::ValueDeserialization, error.kind());
+        assert!(matches!(&error, Error::Entry(e) if e.key.is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_closed_error() -> Result<(), Box<dyn error::Error>> {
+        use super::ErrorKind;
+
+        let db = sled::Config::new().temporary(true).open()?;
+        let cache = Cache::load(db.open_tree("cache")?)?;
+        db.drop_tree("cache")?;
+
+        let error = cache.delete("a").err().ok_or("missing error")?;
+        assert_eq!(ErrorKind::Closed, error.kind());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_reads() -> Result<(), Box<dyn error::Error>> {
+        use super::ErrorKind;
+
+        let db = db("test_strict_reads")?;
+        let cache = Cache::load(db.clone())?;
+        cache.insert("a", Duration::hours(1), &"not a number")?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Missing));
+
+        let strict = Cache::builder(db).strict_reads(true).load()?;
+        assert!(matches!(strict.get::<_, String>("a")?, State::Fresh(..)));
+
+        let error = strict.get::<_, u32>("a").err().ok_or("missing error")?;
+        assert_eq!(ErrorKind::ValueDeserialization, error.kind());
+        assert!(error.to_string().starts_with(r#"null/"a": "#));
+
+        let result = strict.get_many::<_, u32, _>(vec!["missing", "a"]);
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_cache_like() -> Result<(), Box<dyn error::Error>> {
+        use super::mock::{MemoryCache, NoopCache};
+        use super::CacheLike;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        fn exercise<C>(cache: &C) -> Result<Vec<Option<u32>>, Error>
+        where
+            C: CacheLike,
+        {
+            let loads = AtomicUsize::new(0);
+            let users = cache.namespaced(&"users")?;
+
+            users.insert("a", Duration::hours(1), &1u32)?;
+            users.insert("b", Duration::seconds(-1), &2u32)?;
+            users.insert("d", Duration::hours(1), &"not a number")?;
+
+            fn assert_send<T: Send>(_: &T) {}
+            assert_send(&users.wrap("e", Duration::hours(1), async { Ok::<_, Error>(5u32) }));
+
+            let load = || {
+                ::futures::executor::block_on(users.wrap("c", Duration::hours(1), async {
+                    Ok::<_, Error>(3u32 + loads.fetch_add(1, Ordering::SeqCst) as u32)
+                }))
+            };
+
+            load()?;
+            let loaded = load()?;
+            users.delete("a")?;
+
+            Ok(vec![
+                users.get::<_, u32>("a")?.get(),
+                users.get::<_, u32>("b")?.get(),
+                cache.get::<_, u32>("c")?.get(),
+                users.get::<_, u32>("d")?.get(),
+                Some(loaded),
+            ])
+        }
+
+        let db = db("test_cache_like")?;
+        let cache = Cache::load(db)?;
+        let expected = vec![None, Some(2), None, None, Some(3)];
+        assert_eq!(expected, exercise(&cache)?);
+
+        let memory = MemoryCache::new();
+        assert_eq!(expected, exercise(&memory)?);
+        assert_eq!(3, memory.len());
+
+        assert_eq!(
+            vec![None, None, None, None, Some(4)],
+            exercise(&NoopCache::new())?
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_clock() -> Result<(), Box<dyn error::Error>> {
+        use super::testing::{assert_entry_count, assert_hit, assert_miss, TestCache};
+
+        let cache = TestCache::new();
+        let start = cache.clock().now();
+
+        cache.insert("a", Duration::minutes(5), &1u32)?;
+        cache.insert("b", Duration::minutes(15), &2u32)?;
+
+        let stored = cache.get::<_, u32>("a")?;
+        let stored = match stored {
+            State::Fresh(stored) => stored,
+            _ => panic!("expected a fresh entry"),
+        };
+        assert_eq!(Some(start), stored.stored_at());
+        assert_eq!(Some(start + Duration::minutes(5)), stored.expires_at());
+        assert_eq!(Some(Duration::minutes(5)), stored.ttl());
+        assert_eq!(Some(Duration::zero()), stored.age());
+
+        cache.clock().advance(Duration::minutes(10));
+        assert_eq!(Some(Duration::zero()), stored.ttl());
+        assert_eq!(Some(Duration::minutes(10)), stored.age());
+        assert_miss(&cache, "a");
+        assert_eq!(2u32, assert_hit::<_, u32>(&cache, "b"));
+
+        match cache.get_ref("b")? {
+            State::Fresh(stored) => assert_eq!(Some(Duration::minutes(5)), stored.value.ttl()),
+            _ => panic!("expected a fresh entry"),
+        }
+
+        assert_eq!(1, cache.cleanup()?);
+        assert_entry_count(&cache, 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_expired_key_iterator_clock() -> Result<(), Box<dyn error::Error>> {
+        use super::testing::TestCache;
+
+        let cache = TestCache::new();
+        cache.insert("a", Duration::minutes(5), &1u32)?;
+        let key = cache.key(&"a")?;
+
+        let mut expiry_iter = CacheExpiredKeyIterator::from(cache.cache().clone());
+        assert_eq!(None, expiry_iter.next().transpose()?);
+
+        cache.clock().advance(Duration::minutes(10));
+        let expired = expiry_iter.next().transpose()?.expect("expired key");
+        assert_eq!(
+            &(cache.clock().now() - Duration::minutes(5)),
+            expired.expired_at()
+        );
+        assert_eq!(ExpiredKey::from(None, &key)?, expired.zero_expiry());
+
+        // Entries expired by bumping the epoch expire when they're found.
+        let cache = TestCache::new();
+        cache.insert("b", Duration::hours(1), &2u32)?;
+        cache.bump_epoch()?;
+        let mut expiry_iter = CacheExpiredKeyIterator::from(cache.cache().clone());
+        let expired = expiry_iter.next().transpose()?.expect("expired key");
+        assert_eq!(&cache.clock().now(), expired.expired_at());
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_faults() -> Result<(), Box<dyn error::Error>> {
+        use super::faults::Faults;
+        use super::ErrorKind;
+
+        let db = db("test_faults")?;
+        let cache = Cache::load(db.clone())?;
+        cache.insert("a", Duration::hours(1), &1u32)?;
+
+        let failing = Cache::builder(db.clone())
+            .faults(Faults::new().fail_reads(1.0))
+            .load()?;
+        let error = failing.get::<_, u32>("a").err().ok_or("missing error")?;
+        assert_eq!(ErrorKind::Storage, error.kind());
+
+        let corrupting = Cache::builder(db.clone())
+            .faults(Faults::new().corrupt_reads(1.0))
+            .load()?;
+        assert!(matches!(corrupting.get::<_, u32>("a")?, State::Corrupt));
+        assert_eq!(Some(1), cache.get::<_, u32>("a")?.get());
+
+        let corrupting = Cache::builder(db.clone())
+            .faults(Faults::new().corrupt_writes(1.0))
+            .load()?;
+        corrupting.insert("b", Duration::hours(1), &2u32)?;
+        assert!(matches!(cache.get::<_, u32>("b")?, State::Corrupt));
+
+        // batches of writes fail as a whole.
+        let failing = Cache::builder(db.clone())
+            .faults(Faults::new().fail_writes(1.0))
+            .load()?;
+        let batch = vec![
+            ("c", Duration::hours(1), 3u32),
+            ("d", Duration::hours(1), 4u32),
+        ];
+        let error = failing.insert_many(batch).err().ok_or("missing error")?;
+        assert_eq!(ErrorKind::Storage, error.kind());
+        assert_eq!(1, failing.stats().errors);
+        assert!(matches!(cache.get::<_, u32>("c")?, State::Missing));
+
+        let outcomes = |seed| -> Result<Vec<bool>, Box<dyn error::Error>> {
+            let cache = Cache::builder(db.clone())
+                .faults(Faults::new().fail_reads(0.5).seed(seed))
+                .load()?;
+            Ok((0..64).map(|_| cache.get::<_, u32>("a").is_err()).collect())
+        };
+
+        let first = outcomes(42)?;
+        assert_eq!(first, outcomes(42)?);
+        assert!(first.iter().any(|failed| *failed));
+        assert!(first.iter().any(|failed| !*failed));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), Box<dyn error::Error>> {
+        use super::verify::Problem;
+
+        let db = db("test_verify")?;
+        let cache = Cache::builder(db.clone()).chunk_size(16).load()?;
+        #[cfg(feature = "deduplicate")]
+        let dedup = Cache::builder(db.clone()).deduplicate(true).load()?;
+        #[cfg(not(feature = "deduplicate"))]
+        let dedup = cache.clone();
+
+        cache.insert_tagged("a", Duration::hours(1), &1u32, &["users"])?;
+        cache.insert("b", Duration::hours(1), &"a value which is chunked")?;
+        cache.insert("c", Duration::hours(1), &3u32)?;
+        dedup.insert("d", Duration::hours(1), &"shared")?;
+        dedup.insert("e", Duration::hours(1), &"shared")?;
+
+        let report = cache.verify()?;
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(5, report.entries);
+
+        let a = cache.key(&"a")?;
+        db.remove(super::tag_key("users", &a))?;
+        db.insert(super::tag_key("users", &cache.key(&"x")?), &[])?;
+        db.insert(super::chunk_key(&a, u64::MAX, 0), &b"orphan"[..])?;
+        db.insert(&[super::KEY_VERSION][..], &b"garbage"[..])?;
+
+        let c = cache.key(&"c")?;
+        let mut value = db.get(&c)?.ok_or("missing entry")?.to_vec();
+        *value.last_mut().ok_or("empty entry")? ^= 0xff;
+        db.insert(&c, value)?;
+
+        let report = cache.verify()?;
+        let mut problems = report
+            .issues
+            .iter()
+            .map(|issue| issue.problem.to_string())
+            .collect::<Vec<_>>();
+        problems.sort();
+
+        assert_eq!(
+            vec![
+                Problem::ChecksumMismatch.to_string(),
+                Problem::DanglingTag.to_string(),
+                Problem::MissingTag {
+                    tag: String::from("users")
+                }
+                .to_string(),
+                Problem::OrphanedChunk.to_string(),
+                String::from("undecodable key: Entry is corrupt"),
+            ],
+            problems
+        );
+        assert_eq!(0, report.repaired());
+
+        let report = cache.verify_and_repair(super::verify::Exclusive::assert())?;
+        assert_eq!(5, report.repaired());
+        assert!(cache.verify()?.is_ok());
+
+        assert_eq!(Some(1), cache.get::<_, u32>("a")?.get());
+        assert!(matches!(cache.get::<_, u32>("c")?, State::Missing));
+        assert_eq!(1, cache.invalidate_tag("users")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_health() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_health")?;
+        let cache = Cache::builder(db.clone()).cleanup_on_load(false).load()?;
+        let records = db.len();
+
+        let health = cache.health();
+        assert!(health.is_healthy(), "{:?}", health.error);
+        assert!(health.write_latency.is_some());
+        assert!(health.read_latency.is_some());
+        assert!(health.flush_latency.is_some());
+        assert!(health.delete_latency.is_some());
+        assert_eq!(None, health.last_cleanup);
+
+        cache.cleanup()?;
+        assert!(cache.health().last_cleanup.is_some());
+
+        // probes don't leave anything behind.
+        assert_eq!(records, db.len());
+        assert!(cache.verify()?.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_raw_keys() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_raw_keys")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert(RawKey(b"ab"), Duration::hours(1), &1u32)?;
+        cache.insert(RawKey(vec![b'a', b'b']), Duration::hours(1), &2u32)?;
+        cache.insert("ab", Duration::hours(1), &3u32)?;
+
+        assert_eq!(Some(2), cache.get::<_, u32>(RawKey(&b"ab"[..]))?.get());
+        assert_eq!(Some(3), cache.get::<_, u32>("ab")?.get());
+
+        let keys = cache
+            .list_json()?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!([null, "ab"]), json!([null, [97, 98]])], keys);
+
+        let typed = cache.typed::<RawKey<Vec<u8>>, u32>();
+        assert_eq!(Some(2), typed.get(&RawKey(vec![b'a', b'b']))?.get());
+
+        // byte slices and vectors are stored like raw keys, and strings the
+        // same way whether they're borrowed or owned.
+        assert_eq!(Some(2), cache.get::<_, u32>(&b"ab"[..])?.get());
+        assert_eq!(Some(2), cache.get::<_, u32>(vec![b'a', b'b'])?.get());
+        assert_eq!(Some(2), cache.get::<_, u32>(&vec![b'a', b'b'])?.get());
+        assert_eq!(Some(3), cache.get::<_, u32>(String::from("ab"))?.get());
+        assert_eq!(Some(3), cache.get::<_, u32>(&String::from("ab"))?.get());
+
+        let typed = cache.typed::<Vec<u8>, u32>();
+        assert_eq!(Some(2), typed.get(&vec![b'a', b'b'])?.get());
+        Ok(())
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn test_uuid_keys() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_uuid_keys")?;
+        let cache = Cache::load(db)?;
+        let id = uuid::Uuid::from_bytes([0xab; 16]);
+
+        cache.insert(id, Duration::hours(1), &1u32)?;
+
+        assert_eq!(Some(1), cache.get::<_, u32>(&id)?.get());
+        assert_eq!(Some(1), cache.get::<_, u32>(RawKey(id.as_bytes()))?.get());
+
+        let keys = cache
+            .list_json()?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!([null, id.as_bytes()])], keys);
+        Ok(())
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_derive_cache_key() -> Result<(), Box<dyn error::Error>> {
+        use super::CacheKey;
+        use serde_json::json;
+
+        #[derive(CacheKey)]
+        #[cache_key(crate = "crate", rename = "User")]
+        struct Old<'a> {
+            name: &'a str,
+            id: u32,
+        }
+
+        #[derive(CacheKey)]
+        #[cache_key(crate = "crate", rename = "User")]
+        struct New<'a> {
+            id: u32,
+            #[cache_key(rename = "name")]
+            username: &'a str,
+            #[cache_key(default)]
+            admin: bool,
+            #[cache_key(skip)]
+            _hint: usize,
+        }
+
+        #[derive(CacheKey)]
+        #[cache_key(crate = "crate", version = 2)]
+        enum Query {
+            Search(String),
+        }
+
+        let db = db("test_derive_cache_key")?;
+        let cache = Cache::load(db)?;
+
+        cache.insert(
+            Old {
+                name: "alice",
+                id: 1,
+            },
+            Duration::hours(1),
+            &1u32,
+        )?;
+        cache.insert(Query::Search("cats".into()), Duration::hours(1), &2u32)?;
+
+        let new = New {
+            id: 1,
+            username: "alice",
+            admin: false,
+            _hint: 10,
+        };
+
+        assert_eq!(Some(1), cache.get::<_, u32>(new)?.get());
+
+        let admin = New {
+            id: 1,
+            username: "alice",
+            admin: true,
+            _hint: 10,
+        };
+
+        assert_eq!(None, cache.get::<_, u32>(admin)?.get());
+
+        let keys = cache
+            .list_json()?
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![
+                json!([null, ["User", 0, "id", 1, "name", "alice"]]),
+                json!([null, ["Query::Search", 2, "0", "cats"]]),
+            ],
+            keys
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn test_cached_attribute() -> Result<(), Box<dyn error::Error>> {
+        use super::cached;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Service {
+            cache: Cache,
+            calls: AtomicUsize,
+        }
+
+        impl Service {
+            #[cached(crate = "crate", cache = "self.cache", ttl = "5m", ns = "greeting")]
+            async fn greet(&self, key: &str, times: usize) -> Result<String, Error> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                Ok(key.repeat(times))
+            }
+        }
+
+        let db = db("test_cached_attribute")?;
+
+        let service = Service {
+            cache: Cache::load(db)?,
+            calls: AtomicUsize::new(0),
+        };
+
+        ::futures::executor::block_on(async {
+            assert_eq!("abab", service.greet("ab", 2).await?);
+            assert_eq!("abab", service.greet("ab", 2).await?);
+            assert_eq!("ababab", service.greet("ab", 3).await?);
+            Ok::<_, Error>(())
+        })?;
+
+        assert_eq!(2, service.calls.load(Ordering::SeqCst));
+
+        let greetings = service.cache.namespaced(&"greeting")?;
+        assert_eq!(2, greetings.len()?);
+        Ok(())
+    }
+
+    #[cfg(feature = "key-hashing")]
+    #[test]
+    fn test_max_key_len() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_max_key_len")?;
+        let cache = Cache::builder(db.clone()).max_key_len(64).load()?;
+
+        let long = "x".repeat(1024);
+        cache.insert(&long, Duration::hours(1), &1u32)?;
+        cache.insert("short", Duration::hours(1), &2u32)?;
+
+        assert_eq!(Some(1), cache.get::<_, u32>(&long)?.get());
+        assert!(db
+            .iter()
+            .keys()
+            .all(|key| key.is_ok_and(|key| key.len() < 128)));
+
+        let mut keys = cache.keys::<String>()?;
+        keys.sort();
+        assert_eq!(vec!["short".to_owned(), long.clone()], keys);
+
+        let entries = cache.list_json()?;
+        assert!(entries.iter().any(|entry| entry.key == json!([null, long])));
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_redaction() -> Result<(), Box<dyn error::Error>> {
+        use super::KeyRedaction;
+
+        let db = db("test_key_redaction")?;
+
+        let format = |redaction| -> Result<String, Box<dyn error::Error>> {
+            let cache = Cache::builder(db.clone())
+                .key_redaction(redaction)
+                .load()?
+                .namespaced(&"users")?;
+            let key = cache.key(&"alice")?;
+            let value = serde_cbor::to_vec(&"secret")?;
+            Ok(format!(
+                "{} {}",
+                cache.key_format(&key),
+                cache.key_format(&value)
+            ))
+        };
+
+        assert_eq!(
+            r#"[["users"],"alice"] "secret""#,
+            format(KeyRedaction::Full)?
+        );
+
+        #[cfg(feature = "key-hashing")]
+        {
+            let hashed = format(KeyRedaction::Hashed)?;
+            assert!(hashed.starts_with(r#"[["users"],{"hashed":""#));
+            assert!(!hashed.contains("alice") && !hashed.contains("secret"));
+        }
+
+        assert_eq!(
+            r#"[["users"],{"redacted":true}] {"redacted":true}"#,
+            format(KeyRedaction::NamespaceOnly)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_redaction_events() -> Result<(), Box<dyn error::Error>> {
+        use super::KeyRedaction;
+        use serde_json::json;
+
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let hook_events = events.clone();
+
+        let cache = Cache::builder(db("test_key_redaction_events")?)
+            .key_redaction(KeyRedaction::NamespaceOnly)
+            .audit_log(db("test_key_redaction_events_audit")?)
+            .on_insert(move |event| {
+                let key = (event.ns.clone(), event.key.clone());
+                hook_events.lock().unwrap().push(key);
+            })
+            .load()?
+            .namespaced(&"users")?;
+
+        cache.insert("alice", Duration::hours(1), &1u32)?;
+
+        let redacted = (json!(["users"]), json!({"redacted": true}));
+        assert_eq!(vec![redacted.clone()], *events.lock().unwrap());
+
+        let records = cache.audit_log(0)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(1, records.len());
+        assert_eq!(redacted, (records[0].ns.clone(), records[0].key.clone()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_namespace() -> Result<(), Box<dyn error::Error>> {
+        use super::MigrateOptions;
+
+        let db = db("test_rename_namespace")?;
+        let cache = Cache::load(db)?;
+
+        let accounts = cache.namespaced(&"accounts")?;
+        accounts.insert("alice", Duration::hours(1), &1u32)?;
+        accounts.insert_derived("summary", Duration::hours(1), &2u32, &["alice"])?;
+        accounts.insert_tagged("bob", Duration::hours(1), &3u32, &["people"])?;
+        accounts.insert("carol", Duration::hours(1), &4u32)?;
+        accounts
+            .namespaced(&"admins")?
+            .insert("dave", Duration::hours(1), &5u32)?;
+
+        let users = cache.namespaced(&"users")?;
+        users.insert("carol", Duration::hours(1), &6u32)?;
+
+        let mut reported = 0;
+
+        let progress = cache.rename_namespace(
+            &"accounts",
+            &"users",
+            MigrateOptions::new().limit(2).progress(|_| reported += 1),
+        )?;
+
+        assert_eq!(2, reported);
+        assert!(!progress.complete);
+
+        let progress = cache.rename_namespace(&"accounts", &"users", MigrateOptions::new())?;
+        assert!(progress.complete);
+        assert_eq!((2, 1), (progress.migrated, progress.skipped));
+
+        assert_eq!(Some(1), users.get::<_, u32>("alice")?.get());
+        assert_eq!(Some(6), users.get::<_, u32>("carol")?.get());
+        assert_eq!(Some(4), accounts.get::<_, u32>("carol")?.get());
+        assert_eq!(None, accounts.get::<_, u32>("alice")?.get());
+
+        let admins = users.namespaced(&"admins")?;
+        assert_eq!(Some(5), admins.get::<_, u32>("dave")?.get());
+
+        assert_eq!(1, cache.invalidate_tag("people")?);
+        assert_eq!(2, users.invalidate("alice")?);
+        assert!(matches!(
+            users.get::<_, u32>("summary")?,
+            State::Expired(..)
+        ));
+
+        // an entry which was modified since it was read is left alone.
+        let (from, to) = (users.ns_key()?, accounts.ns_key()?);
+        let key = users.key(&"alice")?;
+        let stale = cache.inner.db.get(&key)?.ok_or("missing")?;
+        users.insert("alice", Duration::hours(1), &7u32)?;
+
+        let new_key = super::rename_key(&key, &from, &to).ok_or("not renamed")?;
+        assert!(!cache.rename_entry(&key, Some(&new_key), &stale, &from, &to)?);
+        assert_eq!(Some(7), users.get::<_, u32>("alice")?.get());
+        assert_eq!(None, accounts.get::<_, u32>("alice")?.get());
+
+        // namespaces are nested inside of the namespace of the handle.
+        let progress = users.rename_namespace(&"admins", &"staff", MigrateOptions::new())?;
+        assert_eq!(1, progress.migrated);
+
+        let staff = users.namespaced(&"staff")?;
+        assert_eq!(Some(5), staff.get::<_, u32>("dave")?.get());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_namespace() -> Result<(), Box<dyn error::Error>> {
+        use serde_bytes::ByteBuf;
+
+        let db = db("test_copy_namespace")?;
+        let source = Cache::load(db)?;
+        let dest = Cache::builder(self::db("test_copy_namespace_dest")?)
+            .format(Format::Json)
+            .load()?;
+
+        let users = source.namespaced(&"users")?;
+        let expires_at = Utc::now() + Duration::hours(1);
+        users.insert_until("alice", expires_at, &1u32)?;
+        users.insert_tagged("bob", Duration::hours(1), &2u32, &["people"])?;
+        users.insert("carol", Duration::hours(1), &3u32)?;
+        users.insert("expired", Duration::hours(-1), &4u32)?;
+
+        let copied =
+            source.copy_namespace(&"users", &dest, &"people", |entry| entry.key[1] != "carol")?;
+
+        assert_eq!(2, copied);
+
+        let people = dest.namespaced(&"people")?;
+
+        match people.get::<_, u32>("alice")? {
+            State::Fresh(entry) => {
+                assert_eq!(1, entry.value);
+                assert_eq!(
+                    expires_at.timestamp_millis(),
+                    entry.expires_at().unwrap().timestamp_millis()
+                );
+            }
+            _ => panic!("expected a fresh entry"),
+        }
+
+        assert_eq!(None, people.get::<_, u32>("carol")?.get());
+        assert_eq!(None, people.get::<_, u32>("expired")?.get());
+        assert_eq!(1, dest.invalidate_tag("people")?);
+
+        // namespaces are nested inside of the namespace of each handle.
+        let org = dest.namespaced(&"org")?;
+        let copied = source.copy_namespace(&"users", &org, &"people", |_| true)?;
+        assert_eq!(3, copied);
+        assert_eq!(
+            Some(3),
+            org.namespaced(&"people")?.get::<_, u32>("carol")?.get()
+        );
+
+        // dependencies on entries outside of the namespace are only kept when
+        // copying within the same cache.
+        source.insert("global", Duration::hours(1), &5u32)?;
+        let deps = [
+            ByteBuf::from(source.key(&"global")?),
+            ByteBuf::from(users.key(&"alice")?),
+        ];
+        let options = super::InsertOptions {
+            deps: &deps,
+            ..super::InsertOptions::default()
+        };
+        users.inner_insert(&users.key(&"derived")?, Duration::hours(1), &6u32, options)?;
+
+        let derived = |entry: &super::JsonEntry| entry.key[1] == "derived";
+        assert_eq!(
+            1,
+            source.copy_namespace(&"users", &dest, &"people", derived)?
+        );
+        assert_eq!(
+            1,
+            source.copy_namespace(&"users", &source, &"copies", derived)?
+        );
+
+        let meta = |cache: &Cache, key: Vec<u8>| -> Result<_, Box<dyn error::Error>> {
+            let value = cache.inner.db.get(key)?.ok_or("missing")?;
+            Ok(super::decode_meta(&value)?.deps)
+        };
+
+        let copies = source.namespaced(&"copies")?;
+        assert_eq!(
+            vec![ByteBuf::from(people.key(&"alice")?)],
+            meta(&dest, people.key(&"derived")?)?
+        );
+        assert_eq!(
+            vec![deps[0].clone(), ByteBuf::from(copies.key(&"alice")?)],
+            meta(&source, copies.key(&"derived")?)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaced_generation() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let db = db("test_namespaced_generation")?;
+        let cache = Cache::load(db.clone())?;
+        let search = cache.namespaced_generation(&"search")?;
+        let nested = search.namespaced(&"nested")?;
+        let other = cache.namespaced_generation(&"other")?;
+
+        search.insert("a", Duration::hours(12), &1u32)?;
+        nested.insert("b", Duration::hours(12), &2u32)?;
+        other.insert("c", Duration::hours(12), &3u32)?;
+        assert_eq!(Some(0), search.generation());
+        assert_eq!(None, cache.generation());
+
+        assert_eq!(1, cache.bump_generation(&"search")?);
+        assert_eq!(Some(1), search.generation());
+        assert!(matches!(search.test("a")?, State::Missing));
+        assert!(matches!(nested.test("b")?, State::Missing));
+        assert!(matches!(other.test("c")?, State::Fresh(..)));
+
+        search.insert("a", Duration::hours(12), &4u32)?;
+        assert_eq!(Some(4), search.get::<_, u32>("a")?.get());
+        assert_eq!(4, cache.total_len()?);
+
+        // entries of older generations are removed by the cleanup on load.
+        let cache = Cache::load(db)?;
+        let search = cache.namespaced_generation(&"search")?;
+        assert_eq!(Some(1), search.generation());
+        assert_eq!(Some(4), search.get::<_, u32>("a")?.get());
+        assert_eq!(2, cache.total_len()?);
+
+        // generations can't be confused with namespaces which are integers.
+        let other = cache.namespaced_generation(&"other")?;
+        let plain = cache.namespaced(&"other")?.namespaced(&0u64)?;
+        assert!(matches!(plain.test("c")?, State::Missing));
+        assert_eq!(Some(3), other.get::<_, u32>("c")?.get());
+
+        let entry = other.list_json()?.into_iter().next().ok_or("missing")?;
+        assert_eq!(json!([["other", {"generation": 0}], "c"]), entry.key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_typed() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_list_typed")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+        let nested = users.namespaced(&"nested")?;
+
+        users.insert(1u32, Duration::hours(12), &"Alice")?;
+        users.insert(2u32, Duration::hours(12), &42u32)?;
+        users.insert(3u32, Duration::hours(12), &"Bob")?;
+        users.soft_delete(3u32)?;
+        nested.insert(4u32, Duration::hours(12), &"Carol")?;
+        cache.insert(5u32, Duration::hours(12), &"Dave")?;
+
+        let listing = cache.list::<_, String>(Some(&"users"))?;
+        assert_eq!(1, listing.entries.len());
+        assert_eq!(serde_json::json!([["users"], 1]), listing.entries[0].key);
+        assert_eq!("Alice", listing.entries[0].stored.value());
+        assert_eq!(1, listing.failed.len());
+        assert_eq!(
+            Some(serde_json::json!([["users"], 2])),
+            listing.failed[0].key
+        );
+
+        let listing = cache.list::<(), String>(None)?;
+        assert_eq!(1, listing.entries.len());
+        assert_eq!("Dave", listing.entries[0].stored.value());
+        assert!(listing.failed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_json_page() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_list_json_page")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        for n in 0..5u32 {
+            users.insert((n % 2, n), Duration::hours(12), &n)?;
+        }
+
+        users.insert((1u32, 5u32), Duration::seconds(-1), &5u32)?;
+        cache.insert("other", Duration::hours(12), &6u32)?;
+
+        let page = cache.list_json_page(None, 4)?;
+        assert_eq!(4, page.entries.len());
+        let page = cache.list_json_page(page.next.as_ref(), 4)?;
+        assert_eq!(3, page.entries.len());
+        assert!(page.next.is_none());
+
+        let filter = || ListFilter::new().ns(&"users").key_prefix(&(1u32,));
+        let page = cache.list_json_page_with(None, 1, filter())?;
+        assert_eq!(serde_json::json!([["users"], [1, 1]]), page.entries[0].key);
+        let cursor = page.next.unwrap().to_string().parse()?;
+        let page = cache.list_json_page_with(Some(&cursor), 10, filter())?;
+        assert_eq!(2, page.entries.len());
+        assert!(page.next.is_none());
+
+        let filter = ListFilter::new().default_ns();
+        assert_eq!(
+            1,
+            cache.list_json_page_with(None, 10, filter)?.entries.len()
+        );
+
+        let filter = ListFilter::new().expiry(ExpiryFilter::Expired);
+        let page = cache.list_json_page_with(None, 10, filter)?;
+        assert_eq!(1, page.entries.len());
+        assert_eq!(serde_json::json!([["users"], [1, 5]]), page.entries[0].key);
+
+        let filter = ListFilter::new().expiry(ExpiryFilter::Fresh);
+        assert_eq!(
+            6,
+            cache.list_json_page_with(None, 10, filter)?.entries.len()
+        );
+        assert!(matches!(
+            "zz".parse::<super::Cursor>(),
+            Err(Error::InvalidCursor(..))
+        ));
+        assert!(matches!(
+            cache.list_json_page(None, 0),
+            Err(Error::InvalidLimit)
+        ));
+
+        // pages which scan too many entries are returned with a cursor to
+        // resume from.
+        let many = cache.namespaced(&"many")?;
+        many.insert_many((0..=super::MAX_PAGE_SCAN).map(|n| (n, Duration::hours(12), n)))?;
+
+        let filter = || ListFilter::new().ns(&"many").expiry(ExpiryFilter::Expired);
+        let page = cache.list_json_page_with(None, 10, filter())?;
+        assert!(page.entries.is_empty());
+        let page = cache.list_json_page_with(page.next.as_ref(), 10, filter())?;
+        assert!(page.entries.is_empty());
+        assert!(page.next.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export() -> Result<(), Box<dyn error::Error>> {
+        use super::dump::{DumpFormat, ExportOptions};
+
+        let db = db("test_export")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+        let nested = users.namespaced(&"nested")?;
+
+        users.insert_tagged(1u32, Duration::hours(12), &"Alice", &["admin"])?;
+        users.insert_bytes(2u32, Duration::hours(12), b"raw")?;
+        users.insert(3u32, Duration::seconds(-1), &"expired")?;
+        nested.insert(4u32, Duration::hours(12), &"nested")?;
+        cache.insert(5u32, Duration::hours(12), &5u32)?;
+
+        let mut dump = Vec::new();
+        let options = ExportOptions::new().ns(&"users");
+        let report = cache.export(&mut dump, options)?;
+        assert_eq!(3, report.exported);
+        assert!(report.failed.is_empty());
+
+        let lines = String::from_utf8(dump)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+        assert_eq!(serde_json::json!({"futures-cache-dump": 2}), lines[0]);
+        assert_eq!(serde_json::json!([{"str": "users"}]), lines[1]["ns"]);
+        assert_eq!(serde_json::json!({"u64": 1}), lines[1]["key"]);
+        assert_eq!(serde_json::json!("Alice"), lines[1]["value"]);
+        assert_eq!(serde_json::json!(["admin"]), lines[1]["tags"]);
+        assert_eq!(serde_json::json!("726177"), lines[2]["raw"]);
+        assert_eq!(
+            serde_json::json!([{"str": "users"}, {"str": "nested"}]),
+            lines[3]["ns"]
+        );
+        assert_eq!(serde_json::json!("nested"), lines[3]["value"]);
+
+        let mut dump = Vec::new();
+        let options = ExportOptions::new().format(DumpFormat::Cbor).default_ns();
+        assert_eq!(1, cache.export(&mut dump, options)?.exported);
+
+        // values which can't be converted to JSON are reported.
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1u8], 1u32);
+        cache.insert(6u32, Duration::hours(12), &map)?;
+
+        let mut dump = Vec::new();
+        let report = cache.export(&mut dump, ExportOptions::new().default_ns())?;
+        assert_eq!(1, report.exported);
+        assert_eq!(1, report.failed.len());
+        assert_eq!(Some(serde_json::json!([null, 6])), report.failed[0].key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Result<(), Box<dyn error::Error>> {
+        use super::dump::{ConflictPolicy, DumpFormat, ExportOptions};
+
+        let db = db("test_import")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        users.insert_tagged(1u32, Duration::hours(12), &"Alice", &["admin"])?;
+        users.insert_bytes(2u32, Duration::hours(12), b"raw")?;
+        cache.insert(3u32, Duration::hours(12), &3u32)?;
+        users.insert_derived(("greeting", 1u32), Duration::hours(12), &"Hi", &[1u32])?;
+
+        let mut dump = Vec::new();
+        cache.export(&mut dump, ExportOptions::new().format(DumpFormat::Cbor))?;
+
+        let seeded = Cache::load(self::db("test_import_seeded")?)?;
+        seeded.insert(3u32, Duration::hours(12), &4u32)?;
+
+        let report = seeded.import(&dump[..], ConflictPolicy::Skip)?;
+        assert_eq!(
+            (3, 1, 0),
+            (report.imported, report.conflicts, report.failed)
+        );
+
+        let seeded_users = seeded.namespaced(&"users")?;
+        assert_eq!(
+            Some("Alice".to_owned()),
+            seeded_users.get::<_, String>(1u32)?.get()
+        );
+        assert_eq!(Some(b"raw".to_vec()), seeded_users.get_bytes(2u32)?.get());
+
+        assert_eq!(1, seeded.invalidate_tag("admin")?);
+        assert_eq!(Some(4), seeded.get::<_, u32>(3u32)?.get());
+
+        // the dump is older than the entries in the cache, except for the
+        // invalidated entry.
+        let report = seeded.import(&dump[..], ConflictPolicy::KeepNewer)?;
+        assert_eq!((1, 3), (report.imported, report.conflicts));
+        assert_eq!(Some(4), seeded.get::<_, u32>(3u32)?.get());
+
+        seeded.import(&dump[..], ConflictPolicy::Overwrite)?;
+        assert_eq!(Some(3), seeded.get::<_, u32>(3u32)?.get());
+
+        // dependencies are imported along with the entries.
+        let greeting = seeded_users.get::<_, String>(("greeting", 1u32))?;
+        assert_eq!(Some("Hi".to_owned()), greeting.get());
+        assert_eq!(2, seeded_users.invalidate(1u32)?);
+
+        let dump = concat!(
+            "{\"futures-cache-dump\":2}\n",
+            "{\"key\":{\"u64\":5},\"expires_at\":\"2000-01-01T00:00:00Z\",\"value\":1}\n",
+            "not json\n",
+        );
+
+        let report = seeded.import(dump.as_bytes(), ConflictPolicy::Skip)?;
+        assert_eq!((0, 1, 1), (report.imported, report.expired, report.failed));
+
+        let dump = "{\"futures-cache-dump\":1}\n";
+        let error = seeded
+            .import(dump.as_bytes(), ConflictPolicy::Skip)
+            .err()
+            .ok_or("missing error")?;
+        assert!(matches!(error.root(), Error::UnsupportedDumpVersion(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_from() -> Result<(), Box<dyn error::Error>> {
+        let blue = Cache::load(db("test_merge_from_blue")?)?;
+        let green = Cache::load(db("test_merge_from_green")?)?;
+        let blue_users = blue.namespaced(&"users")?;
+        let green_users = green.namespaced(&"users")?;
 
-            if entry.is_expired(now) {
-                self.inner.db.remove(key)?;
-            }
-        }
+        blue_users.insert_tagged("a", Duration::hours(2), &1u32, &["all"])?;
+        blue.insert("b", Duration::hours(1), &2u32)?;
+        blue.insert("c", Duration::hours(3), &3u32)?;
+        blue.insert("d", Duration::seconds(-1), &4u32)?;
+        green.insert("b", Duration::hours(2), &20u32)?;
+        green.insert("c", Duration::hours(2), &30u32)?;
 
+        let report = green.merge_from(&blue, MergePolicy::FreshestExpiry)?;
+        assert_eq!((2, 1, 0), (report.merged, report.kept, report.failed));
+        assert_eq!(Some(1), green_users.get::<_, u32>("a")?.get());
+        assert_eq!(Some(20), green.get::<_, u32>("b")?.get());
+        assert_eq!(Some(3), green.get::<_, u32>("c")?.get());
+        assert!(matches!(green.test("d")?, State::Missing));
+        assert_eq!(1, green.invalidate_tag("all")?);
+
+        let report = green.merge_from(&blue, MergePolicy::KeepExisting)?;
+        assert_eq!((1, 2), (report.merged, report.kept));
+        assert_eq!(Some(20), green.get::<_, u32>("b")?.get());
+
+        let policy = MergePolicy::Custom(Box::new(|existing, merged| {
+            existing.stored.value == 20 && merged.stored.value == 2
+        }));
+
+        let report = green.merge_from(&blue, policy)?;
+        assert_eq!((1, 2), (report.merged, report.kept));
+        assert_eq!(Some(2), green.get::<_, u32>("b")?.get());
+
+        blue.insert("c", Duration::hours(1), &33u32)?;
+        green.merge_from(&blue, MergePolicy::Overwrite)?;
+        assert_eq!(Some(33), green.get::<_, u32>("c")?.get());
         Ok(())
     }
 
-    /// Create a namespaced cache.
-    ///
-    /// The namespace must be unique to avoid conflicts.
-    ///
-    /// Each call to this functions will return its own queue for resolving futures.
-    pub fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
-    where
-        N: Serialize,
-    {
-        Ok(Self {
-            inner: Arc::new(Inner {
-                ns: Some(hashkey::to_key(ns)?.normalize()),
-                db: self.inner.db.clone(),
-                wakers: Default::default(),
-            }),
-        })
+    #[test]
+    #[cfg(feature = "postcard")]
+    fn test_merge_from_unconvertible() -> Result<(), Box<dyn error::Error>> {
+        let blue = Cache::builder(db("test_merge_from_unconvertible_blue")?)
+            .format(Format::Postcard)
+            .load()?;
+        let green = Cache::load(db("test_merge_from_unconvertible_green")?)?;
+
+        blue.insert("a", Duration::hours(1), &1u32)?;
+
+        // values which can't be converted are failures rather than kept.
+        let report = green.merge_from(&blue, MergePolicy::Overwrite)?;
+        assert_eq!((0, 0, 1), (report.merged, report.kept, report.failed));
+        assert!(matches!(green.test("a")?, State::Missing));
+        Ok(())
     }
 
-    /// Insert a value into the cache.
-    pub fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
-    where
-        K: Serialize,
-        T: Serialize,
-    {
-        let key = self.key(&key)?;
-        self.inner_insert(&key, age, value)
+    #[test]
+    fn test_diff() -> Result<(), Box<dyn error::Error>> {
+        use serde_json::json;
+
+        let a = Cache::load(db("test_diff_a")?)?;
+        let b = Cache::builder(db("test_diff_b")?)
+            .format(Format::Json)
+            .load()?;
+
+        let expires_at = Utc::now() + Duration::hours(1);
+
+        a.insert_until("same", expires_at, &1u32)?;
+        b.insert_until("same", expires_at, &1u32)?;
+        a.insert_until("value", expires_at, &1u32)?;
+        b.insert_until("value", expires_at, &2u32)?;
+        a.insert_until("expires", expires_at, &1u32)?;
+        b.insert_forever("expires", &1u32)?;
+        a.insert_forever("only-a", &1u32)?;
+        b.insert_forever("only-b", &1u32)?;
+        a.insert_forever("deleted", &1u32)?;
+        b.insert_forever("deleted", &1u32)?;
+        b.soft_delete("deleted")?;
+
+        let mut diff = a.diff(&b).collect::<Result<Vec<_>, _>>()?;
+        diff.sort_by_key(|d| format!("{:?}", d));
+
+        let expected = vec![
+            Difference::Changed {
+                key: json!([null, "expires"]),
+                value: false,
+                expires_at: true,
+            },
+            Difference::Changed {
+                key: json!([null, "value"]),
+                value: true,
+                expires_at: false,
+            },
+            Difference::OnlyInOther {
+                key: json!([null, "only-b"]),
+            },
+            Difference::OnlyInThis {
+                key: json!([null, "deleted"]),
+            },
+            Difference::OnlyInThis {
+                key: json!([null, "only-a"]),
+            },
+        ];
+
+        assert_eq!(expected, diff);
+        Ok(())
     }
 
-    /// Insert a value into the cache.
-    #[inline(always)]
-    fn inner_insert<T>(&self, key: &Vec<u8>, age: Duration, value: &T) -> Result<(), Error>
-    where
-        T: Serialize,
-    {
-        let expires_at = Utc::now() + age;
+    #[test]
+    fn test_cleanup_on_load() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_cleanup_on_load")?;
+        let cache = Cache::load(db.clone())?;
+        cache.insert("a", Duration::seconds(-1), &1u32)?;
 
-        let value = match cbor::to_vec(&StoredEntryRef { expires_at, value }) {
-            Ok(value) => value,
-            Err(e) => {
-                log::trace!("store:{} *errored*", KeyFormat(key));
-                return Err(e.into());
-            }
-        };
+        let cache = Cache::builder(db.clone()).cleanup_on_load(false).load()?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Expired(..)));
 
-        log::trace!("store:{}", KeyFormat(key));
-        self.inner.db.insert(key, value)?;
+        let cache = Cache::load(db)?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Missing));
         Ok(())
     }
 
-    /// Test an entry from the cache.
-    pub fn test<K>(&self, key: K) -> Result<State<()>, Error>
-    where
-        K: Serialize,
-    {
-        let key = self.key(&key)?;
-        self.inner_test(&key)
+    #[test]
+    fn test_read_only() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_read_only")?;
+        let cache = Cache::builder(db.clone()).format(Format::Json).load()?;
+        cache.insert("a", Duration::seconds(-1), &1u32)?;
+        cache.insert_sliding("b", Duration::hours(1), &2u32)?;
+        assert!(cache.touch("b", Duration::seconds(10))?);
+
+        // nothing is cleaned up, repaired, or extended.
+        let mut writes = db.watch_prefix(vec![]);
+        let cache = Cache::builder(db).read_only(true).load()?;
+        assert!(matches!(cache.get::<_, u32>("a")?, State::Expired(..)));
+        assert!(matches!(
+            cache.get::<_, u32>("b")?,
+            State::Fresh(e) if e.ttl().is_some_and(|ttl| ttl <= Duration::seconds(10))
+        ));
+        assert!(matches!(
+            cache.get_ref("b")?,
+            State::Fresh(e) if e.value.ttl().is_some_and(|ttl| ttl <= Duration::seconds(10))
+        ));
+        assert!(writes
+            .next_timeout(std::time::Duration::from_millis(10))
+            .is_err());
+        Ok(())
     }
 
-    /// Load an entry from the cache.
-    #[inline(always)]
-    fn inner_test(&self, key: &[u8]) -> Result<State<()>, Error> {
-        let value = match self.inner.db.get(&key)? {
-            Some(value) => value,
-            None => {
-                log::trace!("test:{} -> null (missing)", KeyFormat(key));
-                return Ok(State::Missing);
-            }
-        };
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_server() -> Result<(), Box<dyn error::Error>> {
+        use crate::server::Server;
+        use http_body_util::BodyExt as _;
+        use hyper::{Request, StatusCode};
 
-        let stored: PartialStoredEntry = match cbor::from_slice(&value) {
-            Ok(value) => value,
-            Err(e) => {
-                if log::log_enabled!(log::Level::Trace) {
-                    log::warn!(
-                        "{}: failed to deserialize: {}: {}",
-                        KeyFormat(key),
-                        e,
-                        KeyFormat(&value)
-                    );
-                } else {
-                    log::warn!("{}: failed to deserialize: {}", KeyFormat(key), e);
-                }
+        let db = db("test_server")?;
+        let cache = Cache::load(db)?;
+        let server = Server::new(cache.clone());
 
-                log::trace!("test:{} -> null (deserialize error)", KeyFormat(key));
-                return Ok(State::Missing);
-            }
+        let send = |server: &Server, request: Request<String>| {
+            ::futures::executor::block_on(async {
+                let response = server.handle(request).await;
+                let status = response.status();
+                let body = response.into_body().collect().await?.to_bytes();
+                Ok::<_, std::convert::Infallible>((status, body))
+            })
         };
 
-        if stored.is_expired(Utc::now()) {
-            log::trace!("test:{} -> null (expired)", KeyFormat(key));
-            return Ok(State::Expired(stored.into_stored_entry()));
+        let request =
+            Request::put("/entry?key=1&ns=%22users%22&ttl=60").body(String::from("\"alice\""))?;
+        assert_eq!(StatusCode::NO_CONTENT, send(&server, request)?.0);
+
+        let users = cache.namespaced(&"users")?;
+        assert_eq!(
+            Some(serde_json::json!("alice")),
+            users.get_value(1u32)?.get()
+        );
+
+        let (status, body) = send(
+            &server,
+            Request::get("/entry?key=1&ns=users").body(String::new())?,
+        )?;
+        let entry = serde_json::from_slice::<serde_json::Value>(&body)?;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!("alice"), entry["value"]);
+        assert_eq!(serde_json::json!("fresh"), entry["state"]);
+
+        let (status, body) = send(
+            &server,
+            Request::get("/entries?ns=users").body(String::new())?,
+        )?;
+        let page = serde_json::from_slice::<serde_json::Value>(&body)?;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!([["users"], 1]), page["entries"][0]["key"]);
+
+        let request = Request::delete("/entry?key=1&ns=users").body(String::new())?;
+        let read_only = server.clone().read_only(true);
+        assert_eq!(StatusCode::FORBIDDEN, send(&read_only, request)?.0);
+
+        let request = Request::delete("/entry?key=1&ns=users").body(String::new())?;
+        assert_eq!(StatusCode::NO_CONTENT, send(&server, request)?.0);
+
+        let request = Request::get("/entry?key=1&ns=users").body(String::new())?;
+        assert_eq!(StatusCode::NOT_FOUND, send(&server, request)?.0);
+
+        let (status, body) = send(&server, Request::get("/health").body(String::new())?)?;
+        let health = serde_json::from_slice::<serde_json::Value>(&body)?;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!(true), health["healthy"]);
+        assert!(health["flush_us"].is_u64());
+
+        // times to live which are out of range are rejected.
+        for ttl in ["ttl_ms=-9223372036854775808", "ttl=9223372036854775"] {
+            let request = Request::put(format!("/entry?key=2&{}", ttl)).body(String::from("1"))?;
+            assert_eq!(StatusCode::BAD_REQUEST, send(&server, request)?.0);
         }
 
-        log::trace!("test:{} -> *value*", KeyFormat(key));
-        Ok(State::Fresh(stored.into_stored_entry()))
+        let limited = server.clone().max_body_size(4);
+        let request = Request::put("/entry?key=2").body(String::from("\"alice\""))?;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, send(&limited, request)?.0);
+        assert!(matches!(cache.get_value(2u32)?, State::Missing));
+        Ok(())
     }
 
-    /// Load an entry from the cache.
-    pub fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
-    where
-        K: Serialize,
-        T: serde::de::DeserializeOwned,
-    {
-        let key = self.key(&key)?;
-        self.inner_get(&key)
-    }
+    #[cfg(all(feature = "client", feature = "server"))]
+    #[test]
+    fn test_client() -> Result<(), Box<dyn error::Error>> {
+        use crate::client::Client;
+        use crate::server::Server;
+        use crate::CacheLike;
 
-    /// Load an entry from the cache.
-    #[inline(always)]
-    fn inner_get<T>(&self, key: &[u8]) -> Result<State<T>, Error>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        let value = match self.inner.db.get(key)? {
-            Some(value) => value,
-            None => {
-                log::trace!("load:{} -> null (missing)", KeyFormat(key));
-                return Ok(State::Missing);
-            }
-        };
+        let db = db("test_client")?;
+        let cache = Cache::load(db)?;
 
-        let stored: StoredEntry<T> = match cbor::from_slice(&value) {
-            Ok(value) => value,
-            Err(e) => {
-                if log::log_enabled!(log::Level::Trace) {
-                    log::warn!(
-                        "{}: failed to deserialize: {}: {}",
-                        KeyFormat(key),
-                        e,
-                        KeyFormat(&value)
-                    );
-                } else {
-                    log::warn!("{}: failed to deserialize: {}", KeyFormat(key), e);
-                }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let (shutdown, signal) = futures_channel::oneshot::channel::<()>();
 
-                log::trace!("load:{} -> null (deserialize error)", KeyFormat(key));
-                return Ok(State::Missing);
-            }
-        };
+        let runtime = tokio::runtime::Runtime::new()?;
+        let server = Server::new(cache.clone());
+        let serve = runtime.spawn(server.serve_listener(listener, async {
+            let _ = signal.await;
+        }));
 
-        if stored.is_expired(Utc::now()) {
-            log::trace!("load:{} -> null (expired)", KeyFormat(key));
-            return Ok(State::Expired(stored));
+        let client = Client::builder(&format!("http://{}", addr))
+            .timeout(std::time::Duration::from_secs(5))
+            .build()?;
+        let users = client.namespaced(&"users")?;
+        assert!(matches!(users.get::<_, String>(1u32)?, State::Missing));
+
+        ::futures::executor::block_on(async {
+            let value = users
+                .wrap(1u32, Duration::hours(1), async {
+                    Ok::<_, Error>(String::from("alice"))
+                })
+                .await?;
+            assert_eq!("alice", value);
+
+            let value = users
+                .wrap(1u32, Duration::hours(1), async {
+                    Ok::<_, Error>(String::from("bob"))
+                })
+                .await?;
+            assert_eq!("alice", value);
+            Ok::<_, Error>(())
+        })?;
+
+        users.insert(2u32, Duration::seconds(-1), &"carol")?;
+        assert!(matches!(users.get::<_, String>(2u32)?, State::Expired(..)));
+
+        // times to live shorter than a second aren't truncated.
+        users.insert(3u32, Duration::milliseconds(500), &"dave")?;
+        assert!(matches!(users.get::<_, String>(3u32)?, State::Fresh(..)));
+
+        users.delete(1u32)?;
+        assert!(matches!(users.get::<_, String>(1u32)?, State::Missing));
+
+        fn insert_nested<C>(cache: &C) -> Result<(), Error>
+        where
+            C: CacheLike,
+        {
+            cache
+                .namespaced(&"admins")?
+                .insert(RawKey(b"erin"), Duration::hours(1), &"erin")
         }
 
-        log::trace!("load:{} -> *value*", KeyFormat(key));
-        Ok(State::Fresh(stored))
+        insert_nested(&users)?;
+
+        let users = cache.namespaced(&"users")?;
+        assert!(matches!(users.get::<_, String>(2u32)?, State::Expired(..)));
+
+        let admins = users.namespaced(&"admins")?;
+        assert!(matches!(
+            admins.get::<_, String>(RawKey(b"erin"))?,
+            State::Fresh(..)
+        ));
+
+        let _ = shutdown.send(());
+        runtime.block_on(serve)??;
+        Ok(())
     }
 
-    /// Get the waker associated with the given key.
-    fn waker(&self, key: &[u8]) -> Arc<Waker> {
-        let wakers = self.inner.wakers.read();
+    #[test]
+    fn test_invalidation_bus() -> Result<(), Box<dyn error::Error>> {
+        use crate::invalidation::{Invalidation, LocalBus};
+        use crate::AuditOp;
 
-        match wakers.get(key) {
-            Some(waker) => return waker.clone(),
-            None => drop(wakers),
+        let bus = LocalBus::new();
+        let a = Cache::builder(db("test_invalidation_bus_a")?)
+            .invalidation_bus(bus.clone())
+            .load()?;
+        let b = Cache::builder(db("test_invalidation_bus_b")?)
+            .invalidation_bus(bus)
+            .load()?;
+
+        let mut invalidations = b.invalidations()?;
+
+        a.insert("a", Duration::hours(1), &1u32)?;
+        a.delete_with_ns(None::<&()>, &"a")?;
+
+        let expected = [AuditOp::Insert, AuditOp::Delete];
+
+        for op in expected.iter().copied() {
+            let expected = Invalidation::Entry {
+                op,
+                ns: serde_json::Value::Null,
+                key: serde_json::json!("a"),
+            };
+
+            assert_eq!(Some(expected), invalidations.try_next());
         }
 
-        self.inner
-            .wakers
-            .write()
-            .entry(key.to_vec())
-            .or_default()
-            .clone()
-    }
+        assert_eq!(0, b.epoch());
+        a.bump_epoch()?;
+        assert_eq!(Some(Invalidation::Epoch(1)), invalidations.try_next());
+        assert_eq!(1, b.epoch());
 
-    /// Expires the given key
-    pub async fn expire<'a, E>(&'a self, key: ExpiredKey) -> Result<(), E>
-    where
-        E: From<Error>,
-    {
-        self.delete_with_ns(key.ns.as_ref(), &key.key)?;
+        let users = b.namespaced_generation(&"users")?;
+        assert_eq!(Some(0), users.generation());
+        a.bump_generation(&"users")?;
+        assert_eq!(Some(1), users.generation());
+
+        let cache = Cache::load(db("test_invalidation_bus_c")?)?;
+        assert!(matches!(
+            cache.invalidations(),
+            Err(Error::NoInvalidationBus)
+        ));
         Ok(())
     }
 
-    /// Wrap the result of the given future to load and store from cache.
-    pub async fn wrap<'a, K, F, T, E>(&'a self, key: K, age: Duration, future: F) -> Result<T, E>
-    where
-        K: Serialize,
-        F: Future<Output = Result<T, E>>,
-        T: Serialize + serde::de::DeserializeOwned,
-        E: From<Error>,
-    {
-        let key = self.key(&key)?;
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_bus() -> Result<(), Box<dyn error::Error>> {
+        use crate::invalidation::{Bus, Invalidation, UnixBus};
+        use futures_util::stream::StreamExt as _;
 
-        loop {
-            // There a slight race here. The answer might _just_ have been provided when we perform
-            // this check.
-            //
-            // If that happens, worst case we will end up re-computing the answer again.
-            if let State::Fresh(e) = self.inner_get(&key)? {
-                return Ok(e.value);
-            }
+        let dir = tempdir::TempDir::new("test_unix_bus")?;
+        let a = UnixBus::bind(dir.path())?;
+        let b = UnixBus::bind(dir.path())?;
+        let mut received = b.subscribe()?;
 
-            let waker = self.waker(&key);
+        a.publish(&Invalidation::Epoch(4))?;
+        let message = ::futures::executor::block_on(received.next());
+        assert_eq!(Some(Invalidation::Epoch(4)), message);
 
-            // only pending == 0 will be driving the future for a response.
-            if waker.pending.fetch_add(1, Ordering::AcqRel) > 0 {
-                let (tx, rx) = oneshot::channel();
-                waker.channels.push(tx);
+        // an empty datagram doesn't stop the bus from receiving.
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
 
-                let result = rx.await;
+        for entry in std::fs::read_dir(dir.path())? {
+            socket.send_to(&[], entry?.path())?;
+        }
 
-                // Ignore if sender is cancelled, just loop again.
-                match result {
-                    Ok(true) => return Err(E::from(Error::Failed)),
-                    Err(oneshot::Canceled) | Ok(false) => continue,
-                }
-            }
+        a.publish(&Invalidation::Epoch(5))?;
+        let message = ::futures::executor::block_on(received.next());
+        assert_eq!(Some(Invalidation::Epoch(5)), message);
 
-            // Check key again in case we got really unlucky and had two call do an interleaving
-            // pass for the previous check:
-            //
-            // T1 just went passed the first inner_get test above.
-            // T2 just finished the Waker::cleanup procedure and reduces pending to 0.
-            // T1 notices that it is the first pending thread (pending == 0) and ends up here.
-            if let State::Fresh(e) = self.inner_get(&key)? {
-                waker.cleanup(false);
-                return Ok(e.value);
-            }
+        // sockets are removed once the bus is dropped.
+        drop(a);
+        assert_eq!(1, std::fs::read_dir(dir.path())?.count());
+        Ok(())
+    }
 
-            // Guard in case it is cancelled.
-            let result = Guard::new(|| waker.cleanup(false)).wrap(future).await;
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() -> Result<(), Box<dyn error::Error>> {
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing_core::span::Current;
 
-            // Compute the answer by polling the underlying future and store it in the cache,
-            // then acquire the wakers lock and dispatch to all pending futures.
-            match result {
-                Ok(output) => {
-                    self.inner_insert(&key, age, &output)?;
-                    waker.cleanup(false);
-                    return Ok(output);
-                }
-                Err(e) => {
-                    waker.cleanup(true);
-                    return Err(e);
-                }
-            }
+        type Span = (&'static tracing::Metadata<'static>, Vec<(String, String)>);
+
+        /// Records the fields of every span by name.
+        #[derive(Default)]
+        struct Recorder {
+            spans: Mutex<Vec<Span>>,
+            entered: Mutex<Vec<Id>>,
         }
 
-        /// Create a stack guard that will run unless it is forgotten.
-        struct Guard<F>
-        where
-            F: FnMut(),
-        {
-            f: F,
+        struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0
+                    .push((field.name().to_owned(), format!("{:?}", value)));
+            }
         }
 
-        impl<F> Guard<F>
-        where
-            F: FnMut(),
-        {
-            /// Construct a new finalizer.
-            pub fn new(f: F) -> Self {
-                Self { f }
+        impl tracing::Subscriber for Recorder {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
             }
 
-            /// Wrap the given future with this cancellation guard.
-            pub async fn wrap<O>(self, future: O) -> O::Output
-            where
-                O: Future,
-            {
-                let result = future.await;
-                std::mem::forget(self);
-                result
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                let mut fields = Vec::new();
+                span.record(&mut Fields(&mut fields));
+                spans.push((span.metadata(), fields));
+                Id::from_u64(spans.len() as u64)
             }
-        }
 
-        impl<F> Drop for Guard<F>
-        where
-            F: FnMut(),
-        {
-            fn drop(&mut self) {
-                (self.f)();
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut spans = self.spans.lock().unwrap();
+                let index = span.into_u64() as usize - 1;
+                values.record(&mut Fields(&mut spans[index].1));
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, _: &tracing::Event<'_>) {}
+
+            fn enter(&self, span: &Id) {
+                self.entered.lock().unwrap().push(span.clone());
+            }
+
+            fn exit(&self, _: &Id) {
+                self.entered.lock().unwrap().pop();
+            }
+
+            fn current_span(&self) -> Current {
+                let spans = self.spans.lock().unwrap();
+
+                match self.entered.lock().unwrap().last() {
+                    Some(id) => Current::new(id.clone(), spans[id.into_u64() as usize - 1].0),
+                    None => Current::none(),
+                }
             }
         }
-    }
 
-    /// Helper to serialize the key with the default namespace.
-    fn key<T>(&self, key: &T) -> Result<Vec<u8>, Error>
-    where
-        T: Serialize,
-    {
-        self.key_with_ns(self.inner.ns.as_ref(), key)
+        let db = db("test_tracing")?;
+        let cache = Cache::load(db)?;
+        let users = cache.namespaced(&"users")?;
+
+        let recorder = Arc::new(Recorder::default());
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            users.insert("a", Duration::hours(1), &1u32)?;
+            users.get::<_, u32>("a")?;
+            users.get::<_, u32>("b")?;
+            Ok::<_, Error>(())
+        })?;
+
+        let spans = recorder.spans.lock().unwrap();
+        let field = |index: usize, name: &str| {
+            spans[index]
+                .1
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        assert_eq!(
+            vec!["insert", "get", "get"],
+            spans
+                .iter()
+                .map(|(meta, _)| meta.name())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(String::from("\"[\\\"users\\\"]\"")), field(0, "ns"));
+        assert_eq!(Some(String::from("\"hit\"")), field(1, "outcome"));
+        assert!(field(1, "size").is_some());
+        assert_eq!(Some(String::from("\"miss\"")), field(2, "outcome"));
+        Ok(())
     }
 
-    /// Helper to serialize the key with a specific namespace.
-    fn key_with_ns<T>(&self, ns: Option<&hashkey::Key>, key: &T) -> Result<Vec<u8>, Error>
-    where
-        T: Serialize,
-    {
-        let key = hashkey::to_key(key)?.normalize();
-        let key = Key(ns, key);
-        return Ok(cbor::to_vec(&key)?);
+    #[test]
+    fn test_len() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_len")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
+
+        assert!(other.is_empty()?);
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        other.insert("b", Duration::seconds(-1), &2u32)?;
+        other.insert("c", Duration::hours(12), &3u32)?;
 
-        #[derive(Serialize)]
-        struct Key<'a>(Option<&'a hashkey::Key>, hashkey::Key);
+        assert!(!other.is_empty()?);
+        assert_eq!(1, cache.len()?);
+        assert_eq!(2, other.len()?);
+        assert_eq!(3, cache.total_len()?);
+        assert_eq!(3, cache.approximate_len());
+
+        cache.delete_with_ns(None::<&()>, &"a")?;
+        assert_eq!(2, cache.approximate_len());
+        cache.cleanup()?;
+        assert_eq!(1, other.approximate_len());
+        Ok(())
     }
-}
 
-/// Helper formatter to convert cbor bytes to JSON or hex.
-struct KeyFormat<'a>(&'a [u8]);
+    #[test]
+    fn test_approximate_len() -> Result<(), Box<dyn error::Error>> {
+        use super::dump::{ConflictPolicy, ExportOptions};
+        use super::MigrateOptions;
 
-impl fmt::Display for KeyFormat<'_> {
-    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let value = match cbor::from_slice::<cbor::Value>(self.0) {
-            Ok(value) => value,
-            Err(_) => return self.0.encode_hex::<String>().fmt(fmt),
-        };
+        let db = db("test_approximate_len")?;
+        let cache = Cache::load(db.clone())?;
 
-        let value = match json::to_string(&value) {
-            Ok(value) => value,
-            Err(_) => return self.0.encode_hex::<String>().fmt(fmt),
+        let check = |step: &str| -> Result<(), Error> {
+            assert_eq!(cache.total_len()?, cache.approximate_len(), "{}", step);
+            Ok(())
         };
 
-        value.fmt(fmt)
-    }
-}
+        cache.insert_tagged("a", Duration::hours(1), &1u32, &["all"])?;
+        cache.insert_if_absent("b", Duration::hours(1), &2u32)?;
+        cache.incr("c", 1, Duration::hours(1))?;
+        cache.incr("c", 1, Duration::hours(1))?;
+        cache.push("d", Duration::hours(1), &1u32)?;
+        cache.push("d", Duration::hours(1), &2u32)?;
+        cache
+            .namespaced(&"users")?
+            .insert("e", Duration::hours(1), &5u32)?;
+        check("insert")?;
 
-#[derive(Debug, PartialEq, Eq)]
-/// Represents an expired key. The key is not introspectable except observing it's namespace
-/// and when it expired.
-pub struct ExpiredKey {
-    ns: Option<hashkey::Key>,
-    key: hashkey::Key,
-    expired_at: DateTime<Utc>,
-}
+        cache.rename_namespace(&"users", &"people", MigrateOptions::new())?;
+        check("rename")?;
 
-impl ExpiredKey {
-    /// Returns the namespace of the expired key
-    pub fn namespace<'a>(&'a self) -> Option<&'a hashkey::Key> {
-        self.ns.as_ref()
-    }
-    /// Returns the datetime (UTC) the key expired at
-    pub fn expired_at<'a>(&self) -> &DateTime<Utc> {
-        assert!(
-            self.expired_at < DateTime::<Utc>::from(std::time::SystemTime::now()),
-            "Expired key expired before current time (did your clock skew?)"
-        );
-        &self.expired_at
-    }
-    #[cfg(test)]
-    /// Convert a namespace and key into an ExpiredKey with DateTime set to the unix epoch
-    pub fn from(ns: Option<&[u8]>, key: &[u8]) -> Result<Self, Error> {
-        Ok(Self {
-            ns: ns.map(|ns| hashkey::to_key(&ns)).transpose()?,
-            key: hashkey::to_key(&key)?,
-            expired_at: DateTime::<Utc>::from(std::time::UNIX_EPOCH),
-        })
-    }
-    #[cfg(test)]
-    /// The DateTime of the expired_at timestamp is set to the unix epoch
-    pub fn zero_expiry(mut self) -> Self {
-        self.expired_at = DateTime::<Utc>::from(std::time::UNIX_EPOCH);
-        self
-    }
-}
+        cache.copy_namespace(&"people", &cache, &"copies", |_| true)?;
+        check("copy")?;
 
-/// This iterator will return all expired entries in cache. Can scan multiple caches. The Iterator will continue
-/// to scan and may return more entries after returning None.
-pub struct CacheExpiredKeyIterator {
-    caches: Vec<Cache>,
-    cache_idx: usize,
-    last_key: Option<hashkey::Key>,
-    max_scans: Option<usize>,
-}
+        let other = Cache::load(self::db("test_approximate_len_other")?)?;
+        other.insert("f", Duration::hours(1), &6u32)?;
+        cache.merge_from(&other, MergePolicy::Overwrite)?;
+        check("merge")?;
 
-impl CacheExpiredKeyIterator {
-    /// Construct an empty CacheExpiredKeyIterator. This instance will not return anything unless caches are added to it
-    pub fn empty() -> Self {
-        Self {
-            caches: Vec::new(),
-            cache_idx: 0,
-            last_key: None,
-            max_scans: None,
-        }
-    }
-    /// Construct an empty CacheExpiredKeyIterator with a limit on the number of keys to check for expiration each invocation
-    pub fn empty_with_max_scans(max_scans: Option<usize>) -> Self {
-        Self {
-            max_scans,
-            ..Self::empty()
-        }
-    }
-    /// Construct a CacheExpiredKeyIterator from an existing Cache and a limit on the number of scans to perform
-    pub fn from_cache(f: Cache, max_scans: Option<usize>) -> Self {
-        Self {
-            caches: vec![f],
-            ..Self::empty_with_max_scans(max_scans)
-        }
-    }
-    /// Add a cache to the list of caches to scan.
-    pub fn add_cache(&mut self, c: Cache) {
-        self.caches.push(c);
-    }
-}
+        let mut dump = Vec::new();
+        other.insert("g", Duration::hours(1), &7u32)?;
+        other.export(&mut dump, ExportOptions::new())?;
+        cache.import(&dump[..], ConflictPolicy::Overwrite)?;
+        check("import")?;
 
-impl From<Cache> for CacheExpiredKeyIterator {
-    fn from(f: Cache) -> Self {
-        Self::from_cache(f, None)
+        cache.take::<_, u32>("b")?;
+        cache.invalidate_tag("all")?;
+        cache.clear_namespace(Some(&"copies"))?;
+        check("delete")?;
+
+        db.insert(vec![0xff, 0xff], &b"garbage"[..])?;
+        cache.verify_and_repair(super::verify::Exclusive::assert())?;
+        check("repair")?;
+        Ok(())
     }
-}
 
-impl Iterator for CacheExpiredKeyIterator {
-    type Item = Result<ExpiredKey, Error>;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.caches.is_empty() {
-            return None;
-        }
-        let cache = self.caches[self.cache_idx].clone();
-        let mut scans = 0;
+    #[test]
+    fn test_approximate_len_saturates() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_approximate_len_saturates")?;
+        Cache::load(db.clone())?.insert("a", Duration::hours(1), &1u32)?;
+        Cache::load(db.clone())?.insert("b", Duration::seconds(-1), &2u32)?;
 
-        let last_key = match self.last_key.clone() {
-            Some(t) => t,
-            None => {
-                let first = cache.inner.db.first();
-                let first = match first {
-                    Err(e) => return Some(Err(Error::Sled(e))),
-                    Ok(v) => v,
-                };
-                match first {
-                    None => {
-                        log::trace!("cache empty, advancing to next cache");
-                        self.cache_idx += 1;
-                        self.cache_idx = self.cache_idx % self.caches.len();
-                        return None;
-                    }
-                    Some(t) => match hashkey::to_key(&t.0.to_vec()) {
-                        Err(e) => return Some(Err(Error::HashKey(e))),
-                        Ok(v) => v.normalize(),
-                    },
-                }
-            }
-        };
-        let mut last_key = match cache.key(&last_key) {
-            Err(e) => return Some(Err(e)),
-            Ok(v) => v,
-        };
-        log::trace!("last_key = {:?}", String::from_utf8_lossy(&last_key));
-        while scans < self.max_scans.unwrap_or(1000) {
-            // Retrieve key. If there is no more keys, go to the next cache and return None
-            let (new_last_key, value): (Vec<u8>, Vec<u8>) = match cache.inner.db.get_gt(&last_key) {
-                Ok(v) => match v {
-                    None => {
-                        if scans == 0 {
-                            log::trace!("key was empty on first scan, making exception, trying for the first key");
-                            match cache.inner.db.first() {
-                                Ok(Some((k, v))) => (k.to_vec(), v.to_vec()),
-                                Ok(None) => {
-                                    log::trace!("current key empty, probably a race, aborting");
-                                    self.cache_idx += 1;
-                                    self.cache_idx = self.cache_idx % self.caches.len();
-                                    self.last_key = None;
-                                    return None;
-                                }
-                                Err(e) => return Some(Err(Error::Sled(e))),
-                            }
-                        } else {
-                            log::trace!("scanned all keys in cache, resetting index and advancing to next cache");
-                            self.cache_idx += 1;
-                            // Clamp cache index to index count
-                            self.cache_idx = self.cache_idx % self.caches.len();
-                            log::trace!("next cache id: {}", self.cache_idx);
-                            self.last_key = None;
-                            return None;
-                        }
-                    }
-                    Some(v) => (v.0.to_vec(), v.1.to_vec()),
-                },
-                Err(e) => return Some(Err(Error::Sled(e))),
-            };
-            log::trace!("next_key = {:?}", String::from_utf8_lossy(&new_last_key));
-            if new_last_key == last_key {
-                log::trace!("lastkey = nextkey => returning");
-                return None;
-            }
-            last_key = new_last_key;
-            let value: PartialStoredEntry =
-                cbor::from_slice(&value).expect("could not decode stored entry");
-            if value.is_expired(DateTime::from(std::time::SystemTime::now())) {
-                log::trace!("key expired, returning");
-                return Some(Ok(ExpiredKey {
-                    ns: cache.inner.ns.clone(),
-                    key: hashkey::to_key(&last_key).expect("just deserialized, must be valid key"),
-                    expired_at: value.expires_at,
-                }));
-            }
-            log::trace!("key didn't expire, check next key");
-            // Increment scan counter
-            scans += 1;
-        }
-        None
+        // The count isn't synchronized with the database without a cleanup.
+        let cache = Cache::builder(db).cleanup_on_load(false).load()?;
+        assert_eq!(0, cache.approximate_len());
+
+        cache.delete("a")?;
+        assert_eq!(0, cache.approximate_len());
+
+        assert_eq!(1, cache.drain_expired::<u32>()?.count());
+        assert_eq!(0, cache.approximate_len());
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Cache, CacheExpiredKeyIterator, Duration, Error, ExpiredKey};
-    use std::{error, fs, sync::Arc, thread};
-    use tempdir::TempDir;
+    #[test]
+    fn test_iter_expired() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_iter_expired")?;
+        let cache = Cache::load(db)?;
 
-    fn db(name: &str) -> Result<sled::Tree, Box<dyn error::Error>> {
-        let path = TempDir::new(name)?;
-        let path = path.path();
+        cache.insert("a", Duration::seconds(-1), &1u32)?;
+        cache.insert_pinned("b", Duration::seconds(-1), &2u32)?;
+        cache.insert("c", Duration::hours(12), &3u32)?;
 
-        if !path.is_dir() {
-            fs::create_dir_all(path)?;
-        }
+        let expired = cache
+            .iter_expired::<serde_json::Value>()?
+            .map(|e| e.map(|(key, stored)| (key, stored.value)))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let db = sled::open(path)?;
-        Ok(db.open_tree("test")?)
+        assert_eq!(
+            vec![
+                (serde_json::json!("a"), serde_json::json!(1)),
+                (serde_json::json!("b"), serde_json::json!(2)),
+            ],
+            expired
+        );
+        assert!(matches!(cache.test("a")?, State::Expired(..)));
+        Ok(())
     }
 
     #[test]
-    fn test_expiry_iterator() -> Result<(), Box<dyn error::Error>> {
-        use std::sync::atomic::{AtomicUsize, Ordering};
+    fn test_epoch() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_epoch")?;
+        let cache = Cache::load(db.clone())?;
+        let other = cache.namespaced(&"other")?;
+
+        cache.insert("a", Duration::hours(12), &1u32)?;
+        other.insert_pinned("b", Duration::hours(12), &2u32)?;
+
+        assert_eq!(1, cache.bump_epoch()?);
+        assert_eq!(1, other.epoch());
+        assert!(matches!(cache.test("a")?, State::Expired(..)));
+        assert!(matches!(other.test("b")?, State::Expired(..)));
+
+        cache.insert("c", Duration::hours(12), &3u32)?;
+        assert!(matches!(cache.test("c")?, State::Fresh(..)));
 
-        let db = db("test_cached_expiry")?;
         let cache = Cache::load(db)?;
+        assert_eq!(1, cache.epoch());
+        assert_eq!(2, cache.total_len()?);
+        assert!(matches!(cache.test("c")?, State::Fresh(..)));
+        Ok(())
+    }
 
-        let count = Arc::new(AtomicUsize::default());
-        let c = count.clone();
+    #[test]
+    fn test_invalidate_tag() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_invalidate_tag")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
 
-        let op1 = cache.wrap("a", Duration::seconds(1), async move {
-            let _ = c.fetch_add(1, Ordering::SeqCst);
-            Ok::<_, Error>(String::from("foo"))
-        });
+        cache.insert_tagged("a", Duration::hours(12), &1u32, &["user:42"])?;
+        other.insert_tagged("b", Duration::hours(12), &2u32, &["user:42", "all"])?;
+        other.insert_tagged("c", Duration::hours(12), &3u32, &["all"])?;
+        // Replacing the entry drops its old tags.
+        other.insert_tagged("c", Duration::hours(12), &3u32, &["user:42"])?;
+        other.insert("c", Duration::hours(12), &3u32)?;
 
-        ::futures::executor::block_on(async move { op1.await })?;
+        assert_eq!(2, cache.invalidate_tag("user:42")?);
+        assert!(matches!(cache.test("a")?, State::Missing));
+        assert!(matches!(other.test("b")?, State::Missing));
+        assert!(matches!(other.test("c")?, State::Fresh(..)));
+        assert_eq!(0, cache.invalidate_tag("all")?);
+        assert_eq!(0, cache.invalidate_tag("user:42")?);
+        Ok(())
+    }
 
-        let mut expiry_iter: CacheExpiredKeyIterator = cache.into();
+    #[test]
+    fn test_invalidate_derived() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_invalidate_derived")?;
+        let cache = Cache::load(db)?;
 
-        assert_eq!(
-            expiry_iter.next().transpose()?,
-            None,
-            "No expiry after creation of entry"
-        );
+        cache.insert("b", Duration::hours(12), &1u32)?;
+        cache.insert("c", Duration::hours(12), &2u32)?;
+        cache.insert_derived("a", Duration::hours(12), &3u32, &["b", "c"])?;
+        cache.insert_derived("top", Duration::hours(12), &4u32, &["a"])?;
+        cache.insert_derived("cycle", Duration::hours(12), &5u32, &["cycle"])?;
 
-        std::thread::sleep(Duration::seconds(2).to_std()?);
+        assert_eq!(3, cache.invalidate("b")?);
+        assert!(matches!(cache.test("b")?, State::Expired(..)));
+        assert!(matches!(cache.test("a")?, State::Expired(..)));
+        assert!(matches!(cache.test("top")?, State::Expired(..)));
+        assert!(matches!(cache.test("c")?, State::Fresh(..)));
 
-        #[derive(serde::Serialize)]
-        struct Key<'a>(Option<&'a serde_hashkey::Key>, serde_hashkey::Key);
+        assert_eq!(1, cache.invalidate("cycle")?);
+        assert_eq!(0, cache.invalidate("missing")?);
+        Ok(())
+    }
 
-        let key = Key(None, serde_hashkey::to_key(&"a")?);
+    #[test]
+    fn test_clear_namespace() -> Result<(), Box<dyn error::Error>> {
+        let db = db("test_clear_namespace")?;
+        let cache = Cache::load(db)?;
+        let other = cache.namespaced(&"other")?;
 
-        assert_eq!(
-            expiry_iter.next().transpose()?.map(|x| x.zero_expiry()),
-            Some(ExpiredKey::from(None, &serde_cbor::to_vec(&key)?)?),
-            "Expired entry must be returned"
-        );
+        cache.insert("a", Duration::hours(12), &1u32)?;
+
+        for n in 0..2000u32 {
+            other.insert(n, Duration::hours(12), &n)?;
+        }
+
+        other.insert_tagged("tagged", Duration::hours(12), &0u32, &["tag"])?;
 
+        assert_eq!(2001, cache.clear_namespace(Some(&"other"))?);
+        assert!(other.is_empty()?);
+        // the tag is removed along with the entry.
+        assert!(cache.verify()?.is_ok());
+        assert_eq!(1, cache.approximate_len());
+        assert_eq!(1, cache.clear()?);
+        assert_eq!(0, cache.total_len()?);
         Ok(())
     }
 
@@ -1082,6 +16437,11 @@ mod tests {
             assert!(PollOnce::new(&mut op2).await.is_none());
             assert_eq!(2, waker.pending.load(Ordering::SeqCst));
 
+            let usage = cache.memory_usage();
+            assert_eq!(1, usage.wakers);
+            assert_eq!(2, usage.pending);
+            assert!(usage.bytes > 0);
+
             op1_tx.send(()).expect("send to op1");
             op2_tx.send(()).expect("send to op2");
 
@@ -1111,18 +16471,14 @@ mod tests {
             }
         }
 
-        impl<F> PollOnce<F> {
-            pin_utils::unsafe_pinned!(future: F);
-        }
-
         impl<F> Future for PollOnce<F>
         where
-            F: Future,
+            F: Future + Unpin,
         {
             type Output = Option<F::Output>;
 
-            fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-                match self.future().poll(cx) {
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+                match Pin::new(&mut self.future).poll(cx) {
                     Poll::Ready(output) => Poll::Ready(Some(output)),
                     Poll::Pending => Poll::Ready(None),
                 }
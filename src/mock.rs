@@ -0,0 +1,300 @@
+//! Stand-ins for a [Cache][crate::Cache], so that code using a cache can be
+//! unit tested without a database. Requires the `testing` feature.
+//!
+//! Code which is generic over [CacheLike] can be passed a
+//! [Cache][crate::Cache] in production, and a [MemoryCache] or a [NoopCache]
+//! in tests.
+//!
+//! ```rust
+//! use futures_cache::mock::MemoryCache;
+//! use futures_cache::{CacheLike, Duration, Error};
+//!
+//! async fn user_name<C>(cache: &C, id: u32) -> Result<String, Error>
+//! where
+//!     C: CacheLike,
+//! {
+//!     cache
+//!         .namespaced(&"users")?
+//!         .wrap(id, Duration::hours(1), async move { Ok::<_, Error>(format!("user {}", id)) })
+//!         .await
+//! }
+//!
+//! # fn main() -> Result<(), Error> {
+//! let cache = MemoryCache::new();
+//! let name = futures::executor::block_on(user_name(&cache, 1))?;
+//!
+//! assert_eq!("user 1", name);
+//! assert_eq!(1, cache.len());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{CacheKey, CacheLike, Clock, Duration, Error, State, StoredEntry};
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_cbor as cbor;
+use serde_hashkey as hashkey;
+use std::future::Future;
+use std::sync::Arc;
+
+/// A cache which never stores anything, so every read misses and every call
+/// to [wrap][CacheLike::wrap] runs its future.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopCache;
+
+impl NoopCache {
+    /// Construct a new cache which never stores anything.
+    pub fn new() -> Self {
+        NoopCache
+    }
+}
+
+impl CacheLike for NoopCache {
+    fn get<K, T>(&self, _: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: DeserializeOwned,
+    {
+        Ok(State::Missing)
+    }
+
+    fn insert<K, T>(&self, _: K, _: Duration, _: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        Ok(())
+    }
+
+    fn delete<K>(&self, _: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        Ok(())
+    }
+
+    fn namespaced<N>(&self, _: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        Ok(NoopCache)
+    }
+
+    async fn wrap<K, F, T, E>(&self, _: K, _: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey + Send,
+        F: Future<Output = Result<T, E>> + Send,
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Error> + Send,
+    {
+        future.await
+    }
+}
+
+/// A cache which holds its entries in memory.
+///
+/// Keys and namespaces are encoded the same way as by a
+/// [Cache][crate::Cache], and values are stored as CBOR, so values which don't
+/// round trip through a cache don't round trip through a memory cache either.
+/// Expired entries are kept until they're replaced or deleted, and concurrent
+/// calls to [wrap][CacheLike::wrap] for the same key aren't coalesced.
+///
+/// Clones and namespaced handles share their entries and their
+/// [clock][MemoryCache::clock].
+#[derive(Debug, Clone, Default)]
+pub struct MemoryCache {
+    entries: Arc<Mutex<HashMap<Vec<u8>, MemoryEntry>>>,
+    ns_key: Vec<u8>,
+    clock: Option<Clock>,
+}
+
+#[derive(Debug)]
+struct MemoryEntry {
+    value: Vec<u8>,
+    stored_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+impl MemoryCache {
+    /// Construct a new empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the current time from `clock` instead of the system clock, like
+    /// [Builder::clock][crate::Builder::clock] does for a cache.
+    ///
+    /// ```rust
+    /// use futures_cache::mock::MemoryCache;
+    /// use futures_cache::testing::MockClock;
+    /// use futures_cache::{CacheLike, Duration, State};
+    ///
+    /// # fn main() -> Result<(), futures_cache::Error> {
+    /// let clock = MockClock::new();
+    /// let cache = MemoryCache::new().clock({
+    ///     let clock = clock.clone();
+    ///     move || clock.now()
+    /// });
+    ///
+    /// cache.insert("a", Duration::minutes(5), &1u32)?;
+    /// clock.advance(Duration::minutes(10));
+    /// assert!(matches!(cache.get::<_, u32>("a")?, State::Expired(..)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn clock<F>(self, clock: F) -> Self
+    where
+        F: 'static + Send + Sync + Fn() -> DateTime<Utc>,
+    {
+        Self {
+            clock: Some(Clock::new(clock)),
+            ..self
+        }
+    }
+
+    /// The current time according to the clock.
+    fn now(&self) -> DateTime<Utc> {
+        crate::now(self.clock.as_ref())
+    }
+
+    /// The number of entries in every namespace, including expired ones.
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    /// Test if there are no entries in any namespace.
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+
+    /// Delete every entry in every namespace.
+    pub fn clear(&self) {
+        self.entries.lock().clear();
+    }
+
+    /// The key an entry is stored under.
+    fn key<K>(&self, key: &K) -> Result<Vec<u8>, Error>
+    where
+        K: CacheKey,
+    {
+        Ok(crate::join_key(&self.ns_key, &crate::key::encode(key)?))
+    }
+
+    /// Load the entry stored under the given key.
+    fn get_stored<T>(&self, key: &[u8]) -> Result<State<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let entries = self.entries.lock();
+
+        let entry = match entries.get(key) {
+            Some(entry) => entry,
+            None => return Ok(State::Missing),
+        };
+
+        // like a cache, treat values which can't be deserialized as missing.
+        let value = match cbor::from_slice(&entry.value) {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!("failed to deserialize: {}", e);
+                return Ok(State::Missing);
+            }
+        };
+
+        let stored = StoredEntry::detached(
+            value,
+            entry.stored_at,
+            Some(entry.expires_at),
+            self.clock.clone(),
+        );
+
+        if entry.expires_at < self.now() {
+            return Ok(State::Expired(stored));
+        }
+
+        Ok(State::Fresh(stored))
+    }
+
+    /// Store a value under the given key.
+    fn insert_stored<T>(&self, key: Vec<u8>, age: Duration, value: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let stored_at = self.now();
+
+        let expires_at = stored_at
+            .checked_add_signed(age)
+            .ok_or(Error::TtlOutOfRange(age))?;
+
+        let entry = MemoryEntry {
+            value: cbor::to_vec(value)?,
+            stored_at,
+            expires_at,
+        };
+
+        self.entries.lock().insert(key, entry);
+        Ok(())
+    }
+}
+
+impl CacheLike for MemoryCache {
+    fn get<K, T>(&self, key: K) -> Result<State<T>, Error>
+    where
+        K: CacheKey,
+        T: DeserializeOwned,
+    {
+        self.get_stored(&self.key(&key)?)
+    }
+
+    fn insert<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        self.insert_stored(self.key(&key)?, age, value)
+    }
+
+    fn delete<K>(&self, key: K) -> Result<(), Error>
+    where
+        K: CacheKey,
+    {
+        let key = self.key(&key)?;
+        self.entries.lock().remove(&key);
+        Ok(())
+    }
+
+    fn namespaced<N>(&self, ns: &N) -> Result<Self, Error>
+    where
+        N: Serialize,
+    {
+        let ns = hashkey::to_key(ns)?.normalize();
+        let mut ns_key = self.ns_key.clone();
+        ns_key.extend(crate::key::namespace_to_vec(Some(&ns))?);
+
+        Ok(Self {
+            entries: self.entries.clone(),
+            ns_key,
+            clock: self.clock.clone(),
+        })
+    }
+
+    async fn wrap<K, F, T, E>(&self, key: K, age: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey + Send,
+        F: Future<Output = Result<T, E>> + Send,
+        T: Serialize + DeserializeOwned + Send,
+        E: From<Error> + Send,
+    {
+        let key = self.key(&key)?;
+
+        if let State::Fresh(entry) = self.get_stored(&key)? {
+            return Ok(entry.into_value());
+        }
+
+        let value = future.await?;
+        self.insert_stored(key, age, &value)?;
+        Ok(value)
+    }
+}
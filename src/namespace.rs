@@ -0,0 +1,218 @@
+//! Namespaced handles with a default time to live, and policies applied to
+//! namespaces.
+//!
+//! A [Namespace] is constructed with
+//! [Cache::namespaced_with][crate::Cache::namespaced_with], or with
+//! [Cache::namespace][crate::Cache::namespace] to use the time to live of the
+//! [Policy] of the namespace. Values inserted through it expire after the time
+//! to live of the handle unless another one is given explicitly, so it doesn't
+//! have to be repeated at each call site. Every other method of [Cache] is
+//! available through the handle as well.
+//!
+//! ```rust
+//! use futures_cache::{Cache, Duration};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//! let users = cache.namespaced_with(&"users", Duration::hours(1))?;
+//!
+//! users.insert("alice", &"Alice")?;
+//! users.insert_with_ttl("bob", Duration::minutes(5), &"Bob")?;
+//!
+//! assert_eq!(Some("Alice".to_owned()), users.get::<_, String>("alice")?.get());
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::codec::Format;
+use crate::{Cache, CacheKey, Duration, Error};
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::ops::Deref;
+
+/// A namespaced cache with a default time to live.
+///
+/// See the [module level documentation][self].
+#[derive(Clone)]
+pub struct Namespace {
+    cache: Cache,
+    ttl: Duration,
+}
+
+impl Namespace {
+    pub(crate) fn new(cache: Cache, ttl: Duration) -> Self {
+        Self { cache, ttl }
+    }
+
+    /// The default time to live of values inserted through this handle.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// The namespaced cache this handle accesses.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Insert a value into the cache which expires after the default time to
+    /// live.
+    ///
+    /// See [Cache::insert].
+    pub fn insert<K, T>(&self, key: K, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        self.cache.insert(key, self.ttl, value)
+    }
+
+    /// Insert a value into the cache which expires after `age` instead of the
+    /// default time to live.
+    ///
+    /// See [Cache::insert].
+    pub fn insert_with_ttl<K, T>(&self, key: K, age: Duration, value: &T) -> Result<(), Error>
+    where
+        K: CacheKey,
+        T: Serialize,
+    {
+        self.cache.insert(key, age, value)
+    }
+
+    /// Wrap the result of the given future to load and store it in the cache,
+    /// where it expires after the default time to live.
+    ///
+    /// See [Cache::wrap].
+    pub async fn wrap<K, F, T, E>(&self, key: K, future: F) -> Result<T, E>
+    where
+        K: CacheKey,
+        F: Future<Output = Result<T, E>>,
+        T: 'static + Serialize + DeserializeOwned,
+        E: From<Error>,
+    {
+        self.cache.wrap(key, self.ttl, future).await
+    }
+
+    /// Wrap the result of the given future to load and store it in the cache,
+    /// where it expires after `age` instead of the default time to live.
+    ///
+    /// See [Cache::wrap].
+    pub async fn wrap_with_ttl<K, F, T, E>(&self, key: K, age: Duration, future: F) -> Result<T, E>
+    where
+        K: CacheKey,
+        F: Future<Output = Result<T, E>>,
+        T: 'static + Serialize + DeserializeOwned,
+        E: From<Error>,
+    {
+        self.cache.wrap(key, age, future).await
+    }
+}
+
+impl Deref for Namespace {
+    type Target = Cache;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cache
+    }
+}
+
+/// Configuration of a namespace, registered with
+/// [Builder::namespace_policy][crate::Builder::namespace_policy].
+///
+/// A policy applies to every handle of the namespace, and to namespaces nested
+/// inside of it. Anything which isn't configured by the policy is configured
+/// like the rest of the cache.
+///
+/// ```rust
+/// use futures_cache::namespace::Policy;
+/// use futures_cache::{Cache, Duration};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let db = sled::Config::new().temporary(true).open()?;
+///
+/// let cache = Cache::builder(db.open_tree("cache")?)
+///     .namespace_policy(
+///         &"users",
+///         Policy::new()
+///             .ttl(Duration::hours(1))
+///             .negative_ttl(Duration::minutes(1))
+///             .max_entries(10_000),
+///     )?
+///     .load()?;
+///
+/// let users = cache.namespace(&"users")?;
+/// users.insert(1u32, &Some("Alice"))?;
+/// users.insert(2u32, &None::<String>)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub(crate) ttl: Option<Duration>,
+    pub(crate) format: Option<Format>,
+    pub(crate) sliding: bool,
+    #[cfg(feature = "zstd")]
+    pub(crate) compression_threshold: Option<Option<usize>>,
+    pub(crate) max_entries: Option<usize>,
+    pub(crate) negative_ttl: Option<Duration>,
+}
+
+impl Policy {
+    /// Construct a policy which doesn't configure anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The default time to live of entries inserted through a handle
+    /// constructed with [Cache::namespace][crate::Cache::namespace].
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// The format used to serialize values of new entries, overriding
+    /// [Builder::format][crate::Builder::format].
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Give entries a sliding expiration, as if they were inserted with
+    /// [Cache::insert_sliding][crate::Cache::insert_sliding].
+    pub fn sliding(mut self, sliding: bool) -> Self {
+        self.sliding = sliding;
+        self
+    }
+
+    /// Compress values which are larger than `threshold` bytes once
+    /// serialized, or never compress them if `None`, overriding
+    /// [Builder::compression_threshold][crate::Builder::compression_threshold].
+    /// Requires the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    pub fn compression_threshold(mut self, threshold: Option<usize>) -> Self {
+        self.compression_threshold = Some(threshold);
+        self
+    }
+
+    /// The largest number of entries to keep in the namespace.
+    ///
+    /// The quota is enforced by [Cache::cleanup][crate::Cache::cleanup], which
+    /// evicts the entries which expire soonest until the namespace is within
+    /// its quota. Pinned entries are never evicted, and entries in nested
+    /// namespaces don't count towards the quota.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// The time to live of negative results, which are values that serialize
+    /// as nothing, like [None] or `()`, used instead of the time to live they
+    /// were inserted with.
+    ///
+    /// Negative results aren't cached at all if this is zero or less. By
+    /// default they're cached like any other value.
+    pub fn negative_ttl(mut self, ttl: Duration) -> Self {
+        self.negative_ttl = Some(ttl);
+        self
+    }
+}
@@ -0,0 +1,310 @@
+//! Access to parts of values addressed by
+//! [JSON pointers](https://www.rfc-editor.org/rfc/rfc6901).
+
+use crate::Error;
+use serde::de::{self, Deserialize, DeserializeSeed, IgnoredAny};
+use serde_cbor as cbor;
+use serde_json as json;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Parse a JSON pointer into its reference tokens.
+///
+/// Returns `None` if the pointer is malformed.
+pub(crate) fn parse(pointer: &str) -> Option<Vec<String>> {
+    if pointer.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let pointer = pointer.strip_prefix('/')?;
+
+    pointer
+        .split('/')
+        .map(|token| {
+            let mut out = String::with_capacity(token.len());
+            let mut chars = token.chars();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '~' => match chars.next()? {
+                        '0' => out.push('~'),
+                        '1' => out.push('/'),
+                        _ => return None,
+                    },
+                    c => out.push(c),
+                }
+            }
+
+            Some(out)
+        })
+        .collect()
+}
+
+/// Find the part of a dynamic value at the given path.
+pub(crate) fn pointer_mut<'a>(
+    mut value: &'a mut cbor::Value,
+    path: &[String],
+) -> Option<&'a mut cbor::Value> {
+    for token in path {
+        value = match value {
+            cbor::Value::Map(map) => map
+                .iter_mut()
+                .find(|(key, _)| matches(key, token))
+                .map(|(_, value)| value)?,
+            cbor::Value::Array(array) => array.get_mut(token.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+/// Assign a JSON value to a part of a dynamic value.
+///
+/// Only the parts which differ are converted, so that byte strings and keys
+/// which aren't strings are kept wherever the value is left unchanged.
+pub(crate) fn assign(target: &mut cbor::Value, value: json::Value) -> Result<(), Error> {
+    match (target, value) {
+        (cbor::Value::Map(map), json::Value::Object(object)) => {
+            map.retain(|key, _| object.keys().any(|token| matches(key, token)));
+
+            for (token, value) in object {
+                match map.iter_mut().find(|(key, _)| matches(key, &token)) {
+                    Some((_, part)) => assign(part, value)?,
+                    None => {
+                        map.insert(cbor::Value::Text(token), cbor::value::to_value(&value)?);
+                    }
+                }
+            }
+        }
+        (cbor::Value::Array(array), json::Value::Array(values)) => {
+            array.truncate(values.len());
+            let mut values = values.into_iter();
+
+            for (part, value) in array.iter_mut().zip(values.by_ref()) {
+                assign(part, value)?;
+            }
+
+            for value in values {
+                array.push(cbor::value::to_value(&value)?);
+            }
+        }
+        (target, value) => {
+            if json::to_value(&*target).ok().as_ref() != Some(&value) {
+                *target = cbor::value::to_value(&value)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Test if a map key matches a reference token.
+///
+/// Keys which aren't strings are compared by their string representation.
+fn matches(key: &cbor::Value, token: &str) -> bool {
+    match key {
+        cbor::Value::Text(key) => key == token,
+        cbor::Value::Bytes(key) => key == token.as_bytes(),
+        cbor::Value::Integer(key) => key.to_string() == token,
+        cbor::Value::Bool(key) => key.to_string() == token,
+        _ => false,
+    }
+}
+
+/// Deserializes the part of a value at the given path, skipping over
+/// everything else.
+///
+/// Deserializes to `None` if nothing exists at the path.
+pub(crate) struct PathSeed<'a, T> {
+    path: &'a [String],
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T> PathSeed<'a, T> {
+    pub(crate) fn new(path: &'a [String]) -> Self {
+        Self {
+            path,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, T> DeserializeSeed<'de> for PathSeed<'_, T>
+where
+    T: Deserialize<'de>,
+{
+    type Value = Option<T>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        match self.path.split_first() {
+            Some((token, rest)) => deserializer.deserialize_any(PathVisitor {
+                token,
+                rest,
+                _marker: PhantomData,
+            }),
+            None => T::deserialize(deserializer).map(Some),
+        }
+    }
+}
+
+/// Visits a map or a sequence, descending into the entry or element with the
+/// given token.
+struct PathVisitor<'a, T> {
+    token: &'a str,
+    rest: &'a [String],
+    _marker: PhantomData<T>,
+}
+
+impl<'de, T> de::Visitor<'de> for PathVisitor<'_, T>
+where
+    T: Deserialize<'de>,
+{
+    type Value = Option<T>;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "a map or a sequence containing `{}`", self.token)
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Option<T>, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let mut found = None;
+
+        while let Some(matches) = map.next_key_seed(TokenSeed(self.token))? {
+            if matches && found.is_none() {
+                found = map.next_value_seed(PathSeed::new(self.rest))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+
+        Ok(found)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Option<T>, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        let mut found = None;
+
+        if let Ok(index) = self.token.parse::<usize>() {
+            let mut skipped = 0;
+
+            while skipped < index && seq.next_element::<IgnoredAny>()?.is_some() {
+                skipped += 1;
+            }
+
+            if skipped == index {
+                found = seq.next_element_seed(PathSeed::new(self.rest))?.flatten();
+            }
+        }
+
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(found)
+    }
+
+    // Scalars don't contain anything.
+    fn visit_bool<E>(self, _: bool) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_bytes<E>(self, _: &[u8]) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_none<E>(self) -> Result<Option<T>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+/// Deserializes a map key, testing if it matches a reference token.
+///
+/// Keys which aren't strings are compared by their string representation.
+struct TokenSeed<'a>(&'a str);
+
+impl<'de> DeserializeSeed<'de> for TokenSeed<'_> {
+    type Value = bool;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<bool, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> de::Visitor<'de> for TokenSeed<'_> {
+    type Value = bool;
+
+    fn expecting(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.write_str("a map key")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<bool, E> {
+        Ok(self.0 == v.to_string())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<bool, E> {
+        Ok(self.0 == v.to_string())
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<bool, E> {
+        Ok(self.0 == v.to_string())
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<bool, E> {
+        Ok(self.0 == v)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<bool, E> {
+        Ok(self.0.as_bytes() == v)
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<bool, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<bool, A::Error>
+    where
+        A: de::SeqAccess<'de>,
+    {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+}
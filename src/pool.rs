@@ -0,0 +1,107 @@
+//! A pool of threads shared by all caches, which streams run their blocking
+//! reads on so that they never block the executor polling them.
+
+use crate::Error;
+use crossbeam::channel;
+use futures_channel::oneshot;
+use std::future::Future;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::thread;
+
+/// The number of threads in the pool.
+const THREADS: usize = 4;
+
+/// A job run by the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The queue of jobs, or why the pool couldn't be started.
+static JOBS: OnceLock<Result<channel::Sender<Job>, io::ErrorKind>> = OnceLock::new();
+
+/// Start the threads of the pool.
+///
+/// Fails if not a single thread could be spawned.
+fn start() -> Result<channel::Sender<Job>, io::ErrorKind> {
+    let (jobs, receiver) = channel::unbounded::<Job>();
+    let mut error = None;
+    let mut started = 0;
+
+    for n in 0..THREADS {
+        let receiver = receiver.clone();
+
+        let result = thread::Builder::new()
+            .name(format!("futures-cache-{}", n))
+            .spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            });
+
+        match result {
+            Ok(..) => started += 1,
+            Err(e) => error = Some(e.kind()),
+        }
+    }
+
+    match error {
+        Some(kind) if started == 0 => Err(kind),
+        _ => Ok(jobs),
+    }
+}
+
+/// Run the given function on the pool, returning a task which completes with
+/// its result.
+///
+/// The task fails with [Error::Panicked] if the function panics.
+pub(crate) fn spawn<F, T>(f: F) -> Task<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+
+    let job = Box::new(move || {
+        let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+
+    let state = match JOBS.get_or_init(start) {
+        Ok(jobs) if jobs.send(job).is_ok() => TaskState::Running(rx),
+        Ok(..) => TaskState::Failed(Some(Error::Panicked)),
+        Err(kind) => TaskState::Failed(Some(Error::Io(io::Error::from(*kind)))),
+    };
+
+    Task { state }
+}
+
+/// A function running on the pool, see [spawn].
+pub(crate) struct Task<T> {
+    state: TaskState<T>,
+}
+
+enum TaskState<T> {
+    Running(oneshot::Receiver<thread::Result<T>>),
+    /// The function couldn't be run.
+    Failed(Option<Error>),
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match &mut self.get_mut().state {
+            TaskState::Running(rx) => match Pin::new(rx).poll(cx) {
+                Poll::Ready(Ok(Ok(value))) => Poll::Ready(Ok(value)),
+                // the function panicked, or the job was dropped without being
+                // run.
+                Poll::Ready(Ok(Err(..)) | Err(oneshot::Canceled)) => {
+                    Poll::Ready(Err(Error::Panicked))
+                }
+                Poll::Pending => Poll::Pending,
+            },
+            TaskState::Failed(error) => Poll::Ready(Err(error.take().unwrap_or(Error::Panicked))),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Queries over the JSON view of cached entries.
+//!
+//! A [Query] is constructed with [Cache::query][crate::Cache::query] and
+//! matches entries whose values satisfy all of its filters. Values are
+//! converted to JSON like by [Cache::list_json][crate::Cache::list_json], so
+//! entries of any type can be inspected.
+//!
+//! ```rust
+//! use futures_cache::query::{eq, json_path};
+//! use futures_cache::{Cache, Duration};
+//! use serde_json::json;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//! let users = cache.namespaced(&"users")?;
+//! users.insert(1u32, Duration::hours(1), &json!({"name": "Alice", "active": true}))?;
+//! users.insert(2u32, Duration::hours(1), &json!({"name": "Bob", "active": false}))?;
+//!
+//! let active = cache
+//!     .query()
+//!     .ns(&"users")
+//!     .filter(json_path("$.active"), eq(true))
+//!     .limit(100)
+//!     .run()?;
+//!
+//! assert_eq!(1, active.len());
+//! assert_eq!(json!([["users"], 1]), active[0].key);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Cache, Error, JsonEntry};
+use serde::Serialize;
+use serde_hashkey as hashkey;
+use serde_json as json;
+use std::cmp::Ordering;
+
+/// A query over the entries of a cache.
+///
+/// See the [module level documentation][self].
+pub struct Query<'a> {
+    cache: &'a Cache,
+    ns: Option<Result<Option<hashkey::Key>, Error>>,
+    filters: Vec<(Path, Predicate)>,
+    limit: Option<usize>,
+}
+
+impl<'a> Query<'a> {
+    pub(crate) fn new(cache: &'a Cache) -> Self {
+        Self {
+            cache,
+            ns: None,
+            filters: Vec::new(),
+            limit: None,
+        }
+    }
+
+    /// Only match entries in the given namespace.
+    ///
+    /// Without this, entries in every namespace are matched.
+    pub fn ns<N>(mut self, ns: &N) -> Self
+    where
+        N: Serialize,
+    {
+        self.ns = Some(
+            hashkey::to_key(ns)
+                .map(|ns| Some(ns.normalize()))
+                .map_err(Error::from),
+        );
+        self
+    }
+
+    /// Only match entries in the default namespace.
+    pub fn default_ns(mut self) -> Self {
+        self.ns = Some(Ok(None));
+        self
+    }
+
+    /// Only match entries where the part of the value at `path` satisfies
+    /// `predicate`.
+    pub fn filter(mut self, path: Path, predicate: Predicate) -> Self {
+        self.filters.push((path, predicate));
+        self
+    }
+
+    /// Stop after matching `limit` entries.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Run the query, returning the matching entries in the order of their
+    /// keys.
+    ///
+    /// This scans every entry in the queried namespaces, converting their
+    /// values to JSON.
+    pub fn run(self) -> Result<Vec<JsonEntry>, Error> {
+        let mut filters = Vec::with_capacity(self.filters.len());
+
+        for (path, predicate) in self.filters {
+            filters.push((path.tokens?, predicate));
+        }
+
+        let iter = match self.ns {
+            Some(ns) => self
+                .cache
+                .inner
+                .db
+                .scan_prefix(self.cache.ns_prefix_with(ns?.as_ref())?),
+            None => self.cache.entries(),
+        };
+
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut out = Vec::new();
+
+        for result in iter {
+            if out.len() >= limit {
+                break;
+            }
+
+            let (key, value) = result?;
+
+            let entry = match self.cache.json_entry(&key, &value) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let matched = filters
+                .iter()
+                .all(|(tokens, predicate)| predicate.test(resolve(&entry.stored.value, tokens)));
+
+            if matched {
+                out.push(entry);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// A path to a part of a JSON value, constructed with [json_path].
+pub struct Path {
+    tokens: Result<Vec<Token>, Error>,
+}
+
+/// A step in a [Path].
+enum Token {
+    Field(String),
+    Index(usize),
+}
+
+/// Construct a path from a subset of
+/// [JSONPath](https://www.rfc-editor.org/rfc/rfc9535) syntax, like
+/// `$.user.emails[0]`.
+///
+/// Only the root `$`, member names following a `.`, and array indexes or
+/// quoted member names in brackets are supported. Malformed paths are
+/// reported as [Error::InvalidPath] when the query is run.
+pub fn json_path(path: &str) -> Path {
+    Path {
+        tokens: parse(path).ok_or_else(|| Error::InvalidPath(path.to_owned())),
+    }
+}
+
+/// Parse a JSON path into its steps.
+fn parse(path: &str) -> Option<Vec<Token>> {
+    let mut rest = path.strip_prefix('$')?;
+    let mut tokens = Vec::new();
+
+    while !rest.is_empty() {
+        if let Some(tail) = rest.strip_prefix('.') {
+            let end = tail.find(['.', '[']).unwrap_or(tail.len());
+
+            if end == 0 {
+                return None;
+            }
+
+            tokens.push(Token::Field(tail[..end].to_owned()));
+            rest = &tail[end..];
+        } else if let Some(tail) = rest.strip_prefix('[') {
+            let end = tail.find(']')?;
+            let inner = &tail[..end];
+
+            let token = match inner
+                .strip_prefix('\'')
+                .and_then(|inner| inner.strip_suffix('\''))
+            {
+                Some(name) => Token::Field(name.to_owned()),
+                None => Token::Index(inner.parse().ok()?),
+            };
+
+            tokens.push(token);
+            rest = &tail[end + 1..];
+        } else {
+            return None;
+        }
+    }
+
+    Some(tokens)
+}
+
+/// Find the part of a value at the given path.
+fn resolve<'a>(mut value: &'a json::Value, tokens: &[Token]) -> Option<&'a json::Value> {
+    for token in tokens {
+        value = match (token, value) {
+            (Token::Field(name), json::Value::Object(map)) => map.get(name)?,
+            (Token::Index(index), json::Value::Array(array)) => array.get(*index)?,
+            _ => return None,
+        };
+    }
+
+    Some(value)
+}
+
+/// A condition on a part of a value, constructed with functions like [eq].
+///
+/// Predicates other than [exists] never match values which don't have anything
+/// at the filtered path.
+pub struct Predicate {
+    kind: Kind,
+}
+
+enum Kind {
+    Exists,
+    Eq(json::Value),
+    Ne(json::Value),
+    Cmp(json::Value, fn(Ordering) -> bool),
+}
+
+impl Predicate {
+    /// Test if the predicate holds for the given part of a value.
+    fn test(&self, value: Option<&json::Value>) -> bool {
+        let value = match (&self.kind, value) {
+            (Kind::Exists, value) => return value.is_some(),
+            (_, Some(value)) => value,
+            (_, None) => return false,
+        };
+
+        match &self.kind {
+            Kind::Exists => true,
+            Kind::Eq(expected) => equals(value, expected),
+            Kind::Ne(expected) => !equals(value, expected),
+            Kind::Cmp(expected, test) => compare(value, expected).is_some_and(test),
+        }
+    }
+}
+
+/// Test if two values are equal, comparing numbers by value.
+fn equals(a: &json::Value, b: &json::Value) -> bool {
+    match (a, b) {
+        (json::Value::Number(..), json::Value::Number(..)) => {
+            compare(a, b) == Some(Ordering::Equal)
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Compare two numbers or two strings.
+fn compare(a: &json::Value, b: &json::Value) -> Option<Ordering> {
+    match (a, b) {
+        (json::Value::Number(a), json::Value::Number(b)) => {
+            match (a.as_i64(), b.as_i64(), a.as_u64(), b.as_u64()) {
+                (Some(a), Some(b), ..) => Some(a.cmp(&b)),
+                (.., Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+            }
+        }
+        (json::Value::String(a), json::Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Match values which are present.
+pub fn exists() -> Predicate {
+    Predicate { kind: Kind::Exists }
+}
+
+/// Match values equal to the given value.
+pub fn eq<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Eq(value.into()),
+    }
+}
+
+/// Match values which are present and not equal to the given value.
+pub fn ne<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Ne(value.into()),
+    }
+}
+
+/// Match numbers or strings less than the given value.
+pub fn lt<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Cmp(value.into(), Ordering::is_lt),
+    }
+}
+
+/// Match numbers or strings less than or equal to the given value.
+pub fn le<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Cmp(value.into(), Ordering::is_le),
+    }
+}
+
+/// Match numbers or strings greater than the given value.
+pub fn gt<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Cmp(value.into(), Ordering::is_gt),
+    }
+}
+
+/// Match numbers or strings greater than or equal to the given value.
+pub fn ge<V>(value: V) -> Predicate
+where
+    V: Into<json::Value>,
+{
+    Predicate {
+        kind: Kind::Cmp(value.into(), Ordering::is_ge),
+    }
+}
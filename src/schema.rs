@@ -0,0 +1,162 @@
+//! Versioned schemas of cached values.
+//!
+//! A [Schema] is registered for a type with
+//! [Builder::schema][crate::Builder::schema]. Entries record the schema version
+//! their value was stored with, and values stored with an older version are
+//! migrated to the current one when they're read, instead of failing to
+//! deserialize. Migrated values aren't written back, and values read through
+//! [EntryRef::value][crate::EntryRef::value] aren't migrated.
+//!
+//! ```rust
+//! use futures_cache::{schema::Schema, Cache, Duration};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct UserV1 {
+//!     name: String,
+//! }
+//!
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct User {
+//!     display_name: String,
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let tree = db.open_tree("cache")?;
+//!
+//! let cache = Cache::builder(tree.clone())
+//!     .schema(Schema::<UserV1>::new(1))
+//!     .load()?;
+//! cache.insert("user", Duration::hours(1), &UserV1 { name: "Alice".into() })?;
+//!
+//! let cache = Cache::builder(tree)
+//!     .schema(Schema::<User>::new(2).migration(1, |old: UserV1| User {
+//!         display_name: old.name,
+//!     }))
+//!     .load()?;
+//!
+//! let user = cache.get::<_, User>("user")?.get();
+//! assert_eq!(Some(User { display_name: "Alice".into() }), user);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::codec::Format;
+use crate::Error;
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::{Any, TypeId};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+/// Decodes a value stored with a specific schema version.
+type DecodeFn = Box<dyn Fn(&Format, &[u8]) -> Result<Box<dyn Any>, Error> + Send + Sync>;
+
+/// Converts a value to the next schema version.
+type StepFn = Box<dyn Fn(Box<dyn Any>) -> Option<Box<dyn Any>> + Send + Sync>;
+
+/// The schema of values of type `T`.
+///
+/// Entries which were stored before a schema was registered for their type
+/// have version `0`.
+pub struct Schema<T> {
+    inner: Migrations,
+    _marker: PhantomData<fn(T) -> T>,
+}
+
+impl<T> Schema<T>
+where
+    T: 'static + Serialize + DeserializeOwned,
+{
+    /// Construct a schema where values of type `T` have the given version.
+    pub fn new(version: u32) -> Self {
+        Self {
+            inner: Migrations {
+                type_id: TypeId::of::<T>(),
+                version,
+                decoders: BTreeMap::new(),
+                steps: BTreeMap::new(),
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// Register a migration of values stored with the given version, which are
+    /// deserialized as `Old` and converted to the value of the next version.
+    ///
+    /// Migrations are chained, so a value stored with version `1` of a schema
+    /// which is now at version `3` is converted by the migrations registered
+    /// for version `1` and `2` in turn. The migration from the version before
+    /// the current one must produce a `T`. Entries stored with a version which
+    /// can't be migrated are treated as missing.
+    pub fn migration<Old, New, F>(mut self, version: u32, migrate: F) -> Self
+    where
+        Old: 'static + DeserializeOwned,
+        New: 'static,
+        F: 'static + Send + Sync + Fn(Old) -> New,
+    {
+        self.inner.decoders.insert(
+            version,
+            Box::new(|format, bytes| Ok(Box::new(format.decode::<Old>(bytes)?))),
+        );
+
+        self.inner.steps.insert(
+            version,
+            Box::new(move |value| {
+                let value = value.downcast::<Old>().ok()?;
+                Some(Box::new(migrate(*value)))
+            }),
+        );
+
+        self
+    }
+
+    /// Erase the type of the schema.
+    pub(crate) fn into_migrations(self) -> Migrations {
+        self.inner
+    }
+}
+
+/// The type-erased migrations of a schema.
+pub(crate) struct Migrations {
+    /// The type the schema belongs to.
+    pub(crate) type_id: TypeId,
+    /// The current version of the schema.
+    pub(crate) version: u32,
+    decoders: BTreeMap<u32, DecodeFn>,
+    steps: BTreeMap<u32, StepFn>,
+}
+
+impl Migrations {
+    /// Migrate a value serialized in the given format from the given version
+    /// to the current one.
+    pub(crate) fn migrate<T>(&self, format: &Format, version: u32, bytes: &[u8]) -> Result<T, Error>
+    where
+        T: 'static,
+    {
+        let decode = self
+            .decoders
+            .get(&version)
+            .ok_or(Error::UnsupportedSchema(version))?;
+
+        let mut value = decode(format, bytes)?;
+
+        for from in version..self.version {
+            let step = self
+                .steps
+                .get(&from)
+                .ok_or(Error::UnsupportedSchema(version))?;
+            value = step(value).ok_or_else(wrong_type)?;
+        }
+
+        match value.downcast::<T>() {
+            Ok(value) => Ok(*value),
+            Err(..) => Err(wrong_type()),
+        }
+    }
+}
+
+/// Error raised when the migrations of a schema don't chain up.
+fn wrong_type() -> Error {
+    Error::Codec("schema migration produced a value of the wrong type".into())
+}
@@ -0,0 +1,532 @@
+//! An HTTP server which exposes a cache over the network, so that several
+//! processes can share one persistent cache. Requires the `server` feature.
+//!
+//! Keys and namespaces are passed as JSON in the query string, like
+//! `?ns="users"&key=[1,2]`. Anything which isn't valid JSON is used as a
+//! string, and a namespace of `null` is the default namespace, which is also
+//! used when the namespace is omitted. The server responds to:
+//!
+//! * `GET /entry?key=<KEY>[&ns=<NS>]` with the entry as JSON, including its
+//!   `state`, which is either `fresh` or `expired`. Missing entries respond
+//!   with `404 Not Found`.
+//! * `PUT /entry?key=<KEY>[&ns=<NS>][&ttl=<SECONDS>|&ttl_ms=<MILLISECONDS>]`
+//!   stores the JSON value in the body, which never expires unless a time to
+//!   live is given. Bodies larger than [Server::max_body_size] respond with
+//!   `413 Payload Too Large`.
+//! * `DELETE /entry?key=<KEY>[&ns=<NS>]` deletes the entry.
+//! * `GET /entries[?ns=<NS>][&prefix=<KEY>][&expiry=fresh|expired][&cursor=<CURSOR>][&limit=<N>]`
+//!   lists a page of entries like [Cache::list_json_page_with], as an object
+//!   with the `entries` and the `next` cursor to pass to get the next page.
+//! * `GET /health` probes the cache with [Cache::health], and responds with
+//!   `200 OK` if it's healthy and `503 Service Unavailable` if it isn't. The
+//!   latencies of the probe are reported in microseconds.
+//!
+//! Errors respond with an object holding the `error` message.
+//!
+//! Instead of `key`, the `/entry` endpoints accept a `raw_key`, which is the
+//! key as encoded by a [Cache] in hex. Instead of `ns`, they accept an
+//! `ns_path`, which is a JSON array of nested namespaces, outermost first.
+//! This is what a [client][crate::client] sends, so that its keys and
+//! namespaces are stored the same way as by a local cache.
+//!
+//! Namespaces are nested inside of the namespace of the served cache. Reads
+//! and writes run on a pool of threads shared by all caches, so that they
+//! never block the executor. Statistics are only kept separately for a
+//! [bounded][crate::Builder::max_stats_namespaces] number of namespaces, so
+//! clients can't grow the memory of the server by requesting new namespaces.
+//!
+//! ```rust,no_run
+//! use futures_cache::server::Server;
+//! use futures_cache::Cache;
+//!
+//! # async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::open("cache")?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//!
+//! Server::new(cache).serve(([127, 0, 0, 1], 8080).into()).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::key;
+use crate::{
+    pool, Cache, CacheKey, Cursor, Duration, Error, ErrorKind, ExpiryFilter, KeyEncoder, KeyError,
+    ListFilter, State,
+};
+use http_body_util::{BodyExt as _, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use serde::Deserialize;
+use serde_json as json;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+
+/// The number of entries listed by `GET /entries` unless a limit is given.
+const DEFAULT_LIMIT: usize = 100;
+
+/// The largest body of `PUT /entry` unless configured otherwise.
+const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// The body of a response of the server.
+pub type ResponseBody = Full<Bytes>;
+
+/// An HTTP server exposing a cache.
+///
+/// See the [module level documentation][self].
+#[derive(Clone)]
+pub struct Server {
+    cache: Cache,
+    read_only: bool,
+    max_limit: usize,
+    max_body_size: usize,
+}
+
+/// Query of the `/entry` endpoint.
+#[derive(Deserialize)]
+struct EntryQuery {
+    key: Option<String>,
+    raw_key: Option<String>,
+    ns: Option<String>,
+    ns_path: Option<String>,
+    ttl: Option<i64>,
+    ttl_ms: Option<i64>,
+}
+
+impl EntryQuery {
+    /// The key given in the query.
+    fn key(&self) -> Result<QueryKey, Failure> {
+        match (&self.key, &self.raw_key) {
+            (Some(key), None) => Ok(QueryKey::Json(parse_json(key))),
+            (None, Some(key)) => {
+                let key = hex::decode(key).map_err(|e| bad_request(e.to_string()))?;
+                let key = key::Encoded::new(key).map_err(|e| bad_request(e.to_string()))?;
+                Ok(QueryKey::Encoded(key))
+            }
+            _ => Err(bad_request("expected one of `key` or `raw_key`")),
+        }
+    }
+
+    /// The time to live given in the query, if any.
+    fn ttl(&self) -> Result<Option<Duration>, Failure> {
+        let millis = match (self.ttl, self.ttl_ms) {
+            (None, None) => return Ok(None),
+            (Some(ttl), None) => ttl
+                .checked_mul(1000)
+                .ok_or_else(|| bad_request("time to live out of range"))?,
+            (None, Some(ttl)) => ttl,
+            _ => return Err(bad_request("expected one of `ttl` or `ttl_ms`")),
+        };
+
+        Duration::try_milliseconds(millis)
+            .map(Some)
+            .ok_or_else(|| bad_request("time to live out of range"))
+    }
+}
+
+/// A key given in a query.
+enum QueryKey {
+    Json(json::Value),
+    Encoded(key::Encoded),
+}
+
+impl CacheKey for QueryKey {
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        match self {
+            QueryKey::Json(key) => key.encode_key(encoder),
+            QueryKey::Encoded(key) => key.encode_key(encoder),
+        }
+    }
+}
+
+/// Query of the `/entries` endpoint.
+#[derive(Deserialize)]
+struct ListQuery {
+    ns: Option<String>,
+    prefix: Option<String>,
+    expiry: Option<Expiry>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Expiry {
+    Fresh,
+    Expired,
+}
+
+/// A response which failed, with its status.
+struct Failure(StatusCode, String);
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Self {
+        let status = match (error.kind(), error.root()) {
+            (ErrorKind::KeySerialization, _)
+            | (_, Error::TtlOutOfRange(..))
+            | (_, Error::InvalidCursor(..))
+            | (_, Error::InvalidLimit)
+            | (_, Error::HashedKeys) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        Failure(status, error.to_string())
+    }
+}
+
+impl From<json::Error> for Failure {
+    fn from(error: json::Error) -> Self {
+        Failure(StatusCode::BAD_REQUEST, error.to_string())
+    }
+}
+
+impl Server {
+    /// Construct a server exposing the given cache.
+    pub fn new(cache: Cache) -> Self {
+        Self {
+            cache,
+            read_only: false,
+            max_limit: 1000,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+
+    /// Refuse requests which modify the cache with `403 Forbidden`. Defaults
+    /// to `false`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// The largest number of entries listed in one page, regardless of the
+    /// limit requested. Defaults to 1000.
+    pub fn max_limit(mut self, max_limit: usize) -> Self {
+        self.max_limit = max_limit;
+        self
+    }
+
+    /// The largest body of a request storing an entry, in bytes. Defaults to
+    /// 16 MiB.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Serve requests on the given address until the server fails.
+    ///
+    /// This has to be run in the context of a tokio runtime.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), Error> {
+        self.serve_with_shutdown(addr, futures_util::future::pending())
+            .await
+    }
+
+    /// Serve requests on the given address until the given future completes,
+    /// after which requests in progress are completed before returning.
+    ///
+    /// This has to be run in the context of a tokio runtime.
+    pub async fn serve_with_shutdown<F>(self, addr: SocketAddr, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+        let listener = TcpListener::bind(addr)?;
+        self.serve_listener(listener, signal).await
+    }
+
+    /// Serve requests on a listener which is already bound until the given
+    /// future completes, like [Server::serve_with_shutdown].
+    ///
+    /// This makes it possible to bind to port 0 and learn which port was
+    /// picked before serving.
+    pub async fn serve_listener<F>(self, listener: TcpListener, signal: F) -> Result<(), Error>
+    where
+        F: Future<Output = ()>,
+    {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let graceful = GracefulShutdown::new();
+        pin_utils::pin_mut!(signal);
+
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // errors like running out of file descriptors go away
+                        // once connections are closed.
+                        log::warn!("failed to accept connection: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                _ = &mut signal => break,
+            };
+
+            let server = self.clone();
+
+            let service = service_fn(move |request| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(request).await) }
+            });
+
+            let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+            let connection = graceful.watch(connection);
+
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    log::warn!("failed to serve connection: {}", e);
+                }
+            });
+        }
+
+        graceful.shutdown().await;
+        Ok(())
+    }
+
+    /// Respond to a single request.
+    ///
+    /// This is used by [Server::serve], and can be used to serve the cache as
+    /// part of another hyper service.
+    pub async fn handle<B>(&self, request: Request<B>) -> Response<ResponseBody>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let result = match (request.method(), request.uri().path()) {
+            (&Method::GET, "/entry") => match query(&request) {
+                Ok(query) => self.blocking(move |server| server.get(query)).await,
+                Err(e) => Err(e),
+            },
+            (&Method::PUT, "/entry") => self.put(request).await,
+            (&Method::DELETE, "/entry") => match self.check_writable().and(query(&request)) {
+                Ok(query) => self.blocking(move |server| server.delete(query)).await,
+                Err(e) => Err(e),
+            },
+            (&Method::GET, "/entries") => match query(&request) {
+                Ok(query) => self.blocking(move |server| server.list(query)).await,
+                Err(e) => Err(e),
+            },
+            (&Method::GET, "/health") => self.blocking(|server| Ok(server.health())).await,
+            (_, "/entry") | (_, "/entries") | (_, "/health") => Err(Failure(
+                StatusCode::METHOD_NOT_ALLOWED,
+                String::from("method not allowed"),
+            )),
+            _ => Err(Failure(StatusCode::NOT_FOUND, String::from("not found"))),
+        };
+
+        match result {
+            Ok(response) => response,
+            Err(Failure(status, error)) => {
+                let body = json::json!({ "error": error });
+                respond(status, &body)
+            }
+        }
+    }
+
+    /// Run a handler on the pool of threads shared by all caches, since it
+    /// reads or writes the database.
+    async fn blocking<F>(&self, handler: F) -> Result<Response<ResponseBody>, Failure>
+    where
+        F: 'static + Send + FnOnce(&Server) -> Result<Response<ResponseBody>, Failure>,
+    {
+        let server = self.clone();
+        pool::spawn(move || handler(&server)).await?
+    }
+
+    fn get(&self, query: EntryQuery) -> Result<Response<ResponseBody>, Failure> {
+        let cache = self.namespaced(query.ns.as_deref(), query.ns_path.as_deref())?;
+
+        let (state, entry) = match cache.get_value(query.key()?)? {
+            State::Fresh(entry) => ("fresh", entry),
+            State::Expired(entry) => ("expired", entry),
+            State::Missing => {
+                return Err(Failure(
+                    StatusCode::NOT_FOUND,
+                    String::from("entry is missing"),
+                ))
+            }
+            State::Corrupt => return Err(Error::Corrupt.into()),
+        };
+
+        let mut entry = json::to_value(&entry)?;
+
+        if let Some(entry) = entry.as_object_mut() {
+            entry.insert(String::from("state"), json::Value::from(state));
+        }
+
+        Ok(respond(StatusCode::OK, &entry))
+    }
+
+    async fn put<B>(&self, request: Request<B>) -> Result<Response<ResponseBody>, Failure>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        self.check_writable()?;
+
+        let query = query::<EntryQuery, _>(&request)?;
+        let key = query.key()?;
+        let ttl = query.ttl()?;
+
+        let body = Limited::new(request.into_body(), self.max_body_size)
+            .collect()
+            .await
+            .map_err(|e| match e.downcast::<LengthLimitError>() {
+                Ok(e) => Failure(StatusCode::PAYLOAD_TOO_LARGE, e.to_string()),
+                Err(e) => Failure(StatusCode::BAD_REQUEST, e.to_string()),
+            })?
+            .to_bytes();
+
+        let value = json::from_slice::<json::Value>(&body)?;
+
+        self.blocking(move |server| {
+            let cache = server.namespaced(query.ns.as_deref(), query.ns_path.as_deref())?;
+
+            match ttl {
+                Some(ttl) => cache.insert_value(key, ttl, &value)?,
+                None => cache.insert_forever(key, &value)?,
+            }
+
+            Ok(no_content())
+        })
+        .await
+    }
+
+    fn delete(&self, query: EntryQuery) -> Result<Response<ResponseBody>, Failure> {
+        let cache = self.namespaced(query.ns.as_deref(), query.ns_path.as_deref())?;
+        cache.delete(query.key()?)?;
+        Ok(no_content())
+    }
+
+    fn list(&self, query: ListQuery) -> Result<Response<ResponseBody>, Failure> {
+        let mut filter = ListFilter::new();
+
+        match query.ns.as_deref().map(parse_json) {
+            Some(json::Value::Null) => filter = filter.default_ns(),
+            Some(ns) => filter = filter.ns(&ns),
+            None => (),
+        }
+
+        if let Some(prefix) = &query.prefix {
+            filter = filter.key_prefix(&parse_json(prefix));
+        }
+
+        match query.expiry {
+            Some(Expiry::Fresh) => filter = filter.expiry(ExpiryFilter::Fresh),
+            Some(Expiry::Expired) => filter = filter.expiry(ExpiryFilter::Expired),
+            None => (),
+        }
+
+        let cursor = match &query.cursor {
+            Some(cursor) => Some(cursor.parse::<Cursor>()?),
+            None => None,
+        };
+
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(self.max_limit);
+        let page = self
+            .cache
+            .list_json_page_with(cursor.as_ref(), limit, filter)?;
+
+        let body = json::json!({
+            "entries": page.entries,
+            "next": page.next.map(|next| next.to_string()),
+        });
+
+        Ok(respond(StatusCode::OK, &body))
+    }
+
+    fn health(&self) -> Response<ResponseBody> {
+        let health = self.cache.health();
+        let micros = |latency: Option<std::time::Duration>| latency.map(|l| l.as_micros() as u64);
+
+        let status = if health.is_healthy() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        let body = json::json!({
+            "healthy": health.is_healthy(),
+            "error": health.error,
+            "write_us": micros(health.write_latency),
+            "flush_us": micros(health.flush_latency),
+            "read_us": micros(health.read_latency),
+            "delete_us": micros(health.delete_latency),
+            "last_cleanup": health.last_cleanup,
+            "errors": health.errors,
+            "loader_errors": health.loader_errors,
+        });
+
+        respond(status, &body)
+    }
+
+    /// Construct the handle for the namespace given in a query, either as
+    /// `ns` or as `ns_path`.
+    fn namespaced(&self, ns: Option<&str>, path: Option<&str>) -> Result<Cache, Failure> {
+        match (ns.map(parse_json), path) {
+            (Some(json::Value::Null), None) | (None, None) => Ok(self.cache.clone()),
+            (Some(ns), None) => Ok(self.cache.namespaced(&ns)?),
+            (None, Some(path)) => {
+                let mut cache = self.cache.clone();
+
+                for ns in json::from_str::<Vec<json::Value>>(path)? {
+                    cache = cache.namespaced(&ns)?;
+                }
+
+                Ok(cache)
+            }
+            _ => Err(bad_request("expected one of `ns` or `ns_path`")),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Failure> {
+        if self.read_only {
+            return Err(Failure(
+                StatusCode::FORBIDDEN,
+                String::from("cache is read-only"),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn bad_request<M>(message: M) -> Failure
+where
+    M: Into<String>,
+{
+    Failure(StatusCode::BAD_REQUEST, message.into())
+}
+
+/// Parse a key or namespace given as JSON, falling back to a string.
+fn parse_json(arg: &str) -> json::Value {
+    json::from_str(arg).unwrap_or_else(|_| json::Value::String(arg.to_owned()))
+}
+
+/// Deserialize the query string of a request.
+fn query<T, B>(request: &Request<B>) -> Result<T, Failure>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let query = request.uri().query().unwrap_or_default();
+    serde_urlencoded::from_str(query).map_err(|e| Failure(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+fn respond(status: StatusCode, body: &json::Value) -> Response<ResponseBody> {
+    let mut response = Response::new(Full::from(body.to_string()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    response
+}
+
+fn no_content() -> Response<ResponseBody> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    response
+}
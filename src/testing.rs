@@ -0,0 +1,223 @@
+//! Utilities for testing code which uses a cache.
+//!
+//! A [TestCache] is stored in a temporary directory which is removed once
+//! it's dropped, and reads the time from a [MockClock], so that tests can
+//! expire entries by advancing the clock instead of sleeping.
+//!
+//! ```rust
+//! use futures_cache::testing::{assert_entry_count, assert_hit, assert_miss, TestCache};
+//! use futures_cache::Duration;
+//!
+//! let cache = TestCache::new();
+//! cache.insert("a", Duration::minutes(5), &1u32).unwrap();
+//!
+//! let value: u32 = assert_hit(&cache, "a");
+//! assert_eq!(1, value);
+//! assert_entry_count(&cache, 1);
+//!
+//! cache.clock().advance(Duration::minutes(10));
+//! assert_miss(&cache, "a");
+//! ```
+
+use crate::{Builder, Cache, CacheKey, DateTime, Duration, State, Utc};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// A clock which only moves when it's told to.
+///
+/// Clones share the same time. Pass it to [Builder::clock] through
+/// [MockClock::install], or use a [TestCache] which does this already. A
+/// [MemoryCache][crate::mock::MemoryCache] reads the time from a mock clock
+/// passed to [MemoryCache::clock][crate::mock::MemoryCache::clock].
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    /// Construct a clock which starts at the current time.
+    pub fn new() -> Self {
+        Self::at(Utc::now())
+    }
+
+    /// Construct a clock which starts at the given time.
+    pub fn at(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// The current time of the clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        *self.now.lock()
+    }
+
+    /// Move the clock to the given time, which may be in the past.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock() = now;
+    }
+
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock() += duration;
+    }
+
+    /// Configure the builder to read the time from this clock.
+    pub fn install(&self, builder: Builder) -> Builder {
+        let clock = self.clone();
+        builder.clock(move || clock.now())
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A cache stored in a temporary directory, which reads the time from a
+/// [MockClock].
+///
+/// Dereferences to the [Cache], and the directory is removed once the test
+/// cache is dropped. See the [module level documentation][self].
+pub struct TestCache {
+    cache: Cache,
+    clock: MockClock,
+    db: sled::Db,
+}
+
+impl TestCache {
+    /// Construct an empty test cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temporary database can't be opened.
+    pub fn new() -> Self {
+        Self::with_builder(|builder| builder)
+    }
+
+    /// Construct an empty test cache, configured by `configure` in addition to
+    /// reading the time from its clock.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the temporary database can't be opened, or if the cache
+    /// can't be loaded with the given configuration.
+    pub fn with_builder<F>(configure: F) -> Self
+    where
+        F: FnOnce(Builder) -> Builder,
+    {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("failed to open temporary database");
+
+        let tree = db
+            .open_tree("cache")
+            .expect("failed to open temporary tree");
+
+        let clock = MockClock::new();
+
+        let cache = configure(clock.install(Cache::builder(tree)))
+            .load()
+            .expect("failed to load test cache");
+
+        Self { cache, clock, db }
+    }
+
+    /// The cache.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// The clock the cache reads the time from.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// The temporary database the cache is stored in.
+    pub fn db(&self) -> &sled::Db {
+        &self.db
+    }
+}
+
+impl Default for TestCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for TestCache {
+    type Target = Cache;
+
+    fn deref(&self) -> &Cache {
+        &self.cache
+    }
+}
+
+/// Assert that the entry with the given key is fresh, returning its value.
+///
+/// # Panics
+///
+/// Panics if the entry isn't fresh, or if it can't be read.
+#[track_caller]
+pub fn assert_hit<K, T>(cache: &Cache, key: K) -> T
+where
+    K: CacheKey + fmt::Debug,
+    T: 'static + DeserializeOwned,
+{
+    let name = format!("{:?}", key);
+
+    match cache.get::<_, T>(key) {
+        Ok(State::Fresh(entry)) => entry.into_value(),
+        Ok(state) => panic!("expected a hit for {}, but it's {}", name, describe(&state)),
+        Err(e) => panic!("expected a hit for {}, but it failed: {}", name, e),
+    }
+}
+
+/// Assert that the entry with the given key isn't fresh, regardless of the
+/// type of its value.
+///
+/// # Panics
+///
+/// Panics if the entry is fresh, or if it can't be read.
+#[track_caller]
+pub fn assert_miss<K>(cache: &Cache, key: K)
+where
+    K: CacheKey + fmt::Debug,
+{
+    let name = format!("{:?}", key);
+
+    match cache.test(key) {
+        Ok(State::Fresh(..)) => panic!("expected a miss for {}, but it's fresh", name),
+        Ok(..) => (),
+        Err(e) => panic!("expected a miss for {}, but it failed: {}", name, e),
+    }
+}
+
+/// Assert the number of entries in the namespace of the given handle,
+/// including expired ones which haven't been cleaned up.
+///
+/// # Panics
+///
+/// Panics if the number of entries differs, or if they can't be counted.
+#[track_caller]
+pub fn assert_entry_count(cache: &Cache, expected: usize) {
+    match cache.len() {
+        Ok(len) => assert_eq!(expected, len, "unexpected number of entries"),
+        Err(e) => panic!("failed to count entries: {}", e),
+    }
+}
+
+/// Describe a state which isn't fresh.
+fn describe<T>(state: &State<T>) -> &'static str {
+    match state {
+        State::Fresh(..) => "fresh",
+        State::Expired(..) => "expired",
+        State::Missing => "missing",
+        State::Corrupt => "corrupt",
+    }
+}
@@ -0,0 +1,130 @@
+//! Spans of operations on a cache, which are recorded with [tracing] if the
+//! `tracing` feature is enabled, and cost nothing otherwise.
+//!
+//! Spans are named after their operation, like `get`, and carry the fields:
+//!
+//! * `ns` - the namespace of the handle as JSON.
+//! * `outcome` - `hit`, `stale` or `miss` for reads.
+//! * `size` - the size of the stored entry in bytes.
+//! * `loader_us` - how long the future passed to `wrap` took in microseconds.
+//!
+//! [tracing]: https://docs.rs/tracing
+
+use crate::State;
+use std::future::Future;
+use std::time;
+
+/// The operation a span is created for.
+#[derive(Clone, Copy)]
+pub(crate) enum Op {
+    Get,
+    Insert,
+    Wrap,
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// A span which isn't recorded anywhere.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+/// Construct the span of an operation, where `ns` formats the namespace of
+/// the handle it's performed through.
+#[cfg(feature = "tracing")]
+pub(crate) fn span<F>(op: Op, ns: F) -> Span
+where
+    F: FnOnce() -> String,
+{
+    use tracing::field::Empty;
+
+    let span = match op {
+        Op::Get => tracing::debug_span!("get", ns = Empty, outcome = Empty, size = Empty),
+        Op::Insert => tracing::debug_span!("insert", ns = Empty, size = Empty),
+        Op::Wrap => tracing::debug_span!(
+            "wrap",
+            ns = Empty,
+            outcome = Empty,
+            size = Empty,
+            loader_us = Empty
+        ),
+    };
+
+    if !span.is_disabled() {
+        span.record("ns", ns().as_str());
+    }
+
+    span
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn span<F>(_: Op, _: F) -> Span
+where
+    F: FnOnce() -> String,
+{
+    Span
+}
+
+/// Run the given function in the span.
+#[cfg(feature = "tracing")]
+pub(crate) fn in_span<F, T>(span: &Span, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    span.in_scope(f)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn in_span<F, T>(_: &Span, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    f()
+}
+
+/// Poll the given future in the span.
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F>(span: Span, future: F) -> impl Future<Output = F::Output>
+where
+    F: Future,
+{
+    tracing::Instrument::instrument(future, span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F>(_: Span, future: F) -> impl Future<Output = F::Output>
+where
+    F: Future,
+{
+    future
+}
+
+/// Record the outcome of a read in the current span.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn outcome<T>(state: &State<T>) {
+    #[cfg(feature = "tracing")]
+    {
+        let outcome = match state {
+            State::Fresh(..) => "hit",
+            State::Expired(..) => "stale",
+            State::Missing | State::Corrupt => "miss",
+        };
+
+        Span::current().record("outcome", outcome);
+    }
+}
+
+/// Record the size of the stored entry in the current span.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn size(size: usize) {
+    #[cfg(feature = "tracing")]
+    Span::current().record("size", size);
+}
+
+/// Record how long the loader of `wrap` took in the current span.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn loader(duration: time::Duration) {
+    #[cfg(feature = "tracing")]
+    Span::current().record("loader_us", duration.as_micros() as u64);
+}
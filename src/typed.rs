@@ -0,0 +1,121 @@
+//! Handles to a cache with fixed key and value types.
+//!
+//! A [TypedCache] is constructed with [Cache::typed][crate::Cache::typed].
+//! Since the types of keys and values are part of the handle, they don't have
+//! to be spelled out at each call site, and a key can't accidentally be read
+//! as a different type than it was stored as.
+//!
+//! ```rust
+//! use futures_cache::{Cache, Duration};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//! let users = cache.namespaced(&"users")?.typed::<String, User>();
+//!
+//! users.insert("alice", Duration::hours(1), &User { name: "Alice".into() })?;
+//!
+//! let user = users.get("alice")?.get();
+//! assert_eq!(Some(User { name: "Alice".into() }), user);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Cache, CacheKey, Duration, Error, KeyEncoder, KeyError, State};
+use serde::{de::DeserializeOwned, Serialize};
+use std::borrow::Borrow;
+use std::future::Future;
+use std::marker::PhantomData;
+
+/// A handle to a cache with keys of type `K` and values of type `V`.
+///
+/// See the [module level documentation][self].
+pub struct TypedCache<K, V> {
+    cache: Cache,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedCache<K, V>
+where
+    K: CacheKey,
+    V: 'static + Serialize + DeserializeOwned,
+{
+    pub(crate) fn new(cache: Cache) -> Self {
+        Self {
+            cache,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The untyped cache this handle accesses.
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Load an entry from the cache.
+    ///
+    /// Like with a [HashMap][std::collections::HashMap], the key can be
+    /// passed as any borrowed form of `K`, like a `&str` for a [String] key.
+    /// The borrowed form has to be encoded the same way as `K`, which is the
+    /// case for the borrowed forms in the standard library.
+    ///
+    /// See [Cache::get].
+    pub fn get<Q>(&self, key: &Q) -> Result<State<V>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + CacheKey,
+    {
+        self.cache.get(Borrowed(key))
+    }
+
+    /// Insert a value into the cache.
+    ///
+    /// See [Cache::insert] and [TypedCache::get] for which keys can be used.
+    pub fn insert<Q>(&self, key: &Q, age: Duration, value: &V) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + CacheKey,
+    {
+        self.cache.insert(Borrowed(key), age, value)
+    }
+
+    /// Wrap the result of the given future to load and store it in the cache.
+    ///
+    /// See [Cache::wrap] and [TypedCache::get] for which keys can be used.
+    pub async fn wrap<Q, F, E>(&self, key: &Q, age: Duration, future: F) -> Result<V, E>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + CacheKey,
+        F: Future<Output = Result<V, E>>,
+        E: From<Error>,
+    {
+        self.cache.wrap(Borrowed(key), age, future).await
+    }
+}
+
+/// A borrowed key, which is encoded like the key it borrows.
+struct Borrowed<'a, Q: ?Sized>(&'a Q);
+
+impl<Q> CacheKey for Borrowed<'_, Q>
+where
+    Q: ?Sized + CacheKey,
+{
+    fn encode_key(&self, encoder: KeyEncoder<'_>) -> Result<(), KeyError> {
+        self.0.encode_key(encoder)
+    }
+}
+
+impl<K, V> Clone for TypedCache<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
@@ -0,0 +1,636 @@
+//! Checks of the integrity of a cache, to tell whether it can be trusted
+//! after an unclean shutdown.
+//!
+//! [Cache::verify][crate::Cache::verify] scans the whole database and reports
+//! every [Problem] it finds, without modifying anything.
+//! [Cache::verify_and_repair][crate::Cache::verify_and_repair] performs the
+//! same scan, and repairs what it finds:
+//!
+//! * Entries which can't be read, since their key can't be decoded, their
+//!   header is malformed, they fail their checksum or their value is missing
+//!   or corrupt, are removed.
+//! * Missing records of the tags, dependencies and indexes of entries are
+//!   restored, while records of entries which don't exist or no longer have
+//!   them are removed.
+//! * Chunks and retained versions which don't belong to any entry are
+//!   removed.
+//! * Reference counts of deduplicated values are corrected, and values which
+//!   aren't referenced are removed.
+//!
+//! Entries which are modified while the database is scanned might be reported
+//! as broken, so a cache should be verified while it isn't in use. Repairing
+//! such a report could remove entries or deduplicated values which are still
+//! in use, which is why repairs require the caller to assert with [Exclusive]
+//! that nothing else uses the cache.
+//!
+//! ```rust
+//! use futures_cache::{Cache, Duration};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let db = sled::Config::new().temporary(true).open()?;
+//! let cache = Cache::load(db.open_tree("cache")?)?;
+//! cache.insert_tagged("a", Duration::hours(1), &1u32, &["users"])?;
+//!
+//! let report = cache.verify()?;
+//! assert!(report.is_ok());
+//! assert_eq!(1, report.entries);
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+    blob_key, blob_ref_key, chunk_key, decode_counter, decode_meta, dep_key, index_key, key,
+    meta_key, namespace_json, split_array, split_chunk_key, split_entry, split_history_key,
+    split_key, tag_key, upgrade_key, Cache, Chunks, Error, PartialStoredEntry, BLOB_KEY,
+    BLOB_REF_KEY, CHECKSUM_LEN, CHUNK_KEY, DEP_KEY, HISTORY_KEY, INDEX_KEY, TAG_KEY,
+};
+use hashbrown::HashMap;
+use hex::ToHex as _;
+use std::fmt;
+use std::sync::atomic::Ordering;
+
+/// An assertion that nothing else reads or writes a cache while it's
+/// repaired, through this or any other handle to its database.
+///
+/// Repairs are performed in several passes over the database which aren't
+/// atomic, so an entry written concurrently could lose its chunks or its
+/// deduplicated value. See
+/// [Cache::verify_and_repair][crate::Cache::verify_and_repair].
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Exclusive;
+
+impl Exclusive {
+    /// Assert that nothing else uses the cache until the repair is done.
+    pub fn assert() -> Self {
+        Exclusive
+    }
+}
+
+/// The outcome of verifying a cache.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Report {
+    /// The number of entries which were checked.
+    pub entries: usize,
+    /// Every problem which was found.
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    /// Test if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The number of problems which were repaired.
+    pub fn repaired(&self) -> usize {
+        self.issues.iter().filter(|issue| issue.repaired).count()
+    }
+}
+
+/// A problem found while verifying a cache.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Issue {
+    /// The database key of the entry or of the internal record with the
+    /// problem.
+    pub key: Vec<u8>,
+    /// What the problem is.
+    pub problem: Problem,
+    /// Whether the problem was repaired.
+    pub repaired: bool,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}: {}", self.key.encode_hex::<String>(), self.problem)?;
+
+        if self.repaired {
+            write!(fmt, " (repaired)")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What's wrong with an entry or an internal record.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// The key of an entry can't be decoded.
+    UndecodableKey {
+        /// The error decoding the key.
+        error: String,
+    },
+    /// The header of an entry is malformed or of an unsupported version.
+    MalformedHeader {
+        /// The error decoding the header.
+        error: String,
+    },
+    /// An entry is truncated or fails its checksum.
+    ChecksumMismatch,
+    /// A chunk of a chunked entry is missing.
+    MissingChunk {
+        /// The index of the first missing chunk.
+        index: u32,
+    },
+    /// The chunks of a chunked entry don't match its length or checksum.
+    ChunkMismatch,
+    /// An item of a list is missing.
+    MissingItem {
+        /// The index of the first missing item.
+        index: u32,
+    },
+    /// The deduplicated value of an entry is missing.
+    MissingBlob,
+    /// The deduplicated value of an entry is truncated or fails its checksum.
+    CorruptBlob,
+    /// The record of a tag of an entry is missing.
+    MissingTag {
+        /// The missing tag.
+        tag: String,
+    },
+    /// The record of a dependency of an entry is missing.
+    MissingDependency,
+    /// The record of an index of an entry is missing.
+    MissingIndex {
+        /// The name of the index.
+        name: String,
+    },
+    /// A tag record refers to an entry which doesn't exist or doesn't have
+    /// the tag.
+    DanglingTag,
+    /// A dependency record refers to an entry which doesn't exist or doesn't
+    /// have the dependency.
+    DanglingDependency,
+    /// An index record refers to an entry which doesn't exist or isn't
+    /// indexed by it.
+    DanglingIndex,
+    /// A chunk doesn't belong to any entry.
+    OrphanedChunk,
+    /// A retained version belongs to an entry which doesn't exist.
+    OrphanedHistory,
+    /// The reference count of a deduplicated value is wrong.
+    BlobRefCount {
+        /// The number of entries referring to the value.
+        expected: u64,
+        /// The stored reference count.
+        actual: u64,
+    },
+    /// A deduplicated value isn't referenced by any entry.
+    UnreferencedBlob,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UndecodableKey { error } => write!(fmt, "undecodable key: {}", error),
+            Problem::MalformedHeader { error } => write!(fmt, "malformed header: {}", error),
+            Problem::ChecksumMismatch => write!(fmt, "checksum mismatch"),
+            Problem::MissingChunk { index } => write!(fmt, "missing chunk {}", index),
+            Problem::ChunkMismatch => write!(fmt, "chunks don't match the entry"),
+            Problem::MissingItem { index } => write!(fmt, "missing list item {}", index),
+            Problem::MissingBlob => write!(fmt, "missing deduplicated value"),
+            Problem::CorruptBlob => write!(fmt, "corrupt deduplicated value"),
+            Problem::MissingTag { tag } => write!(fmt, "missing record of tag {:?}", tag),
+            Problem::MissingDependency => write!(fmt, "missing record of dependency"),
+            Problem::MissingIndex { name } => write!(fmt, "missing record in index {:?}", name),
+            Problem::DanglingTag => write!(fmt, "dangling tag record"),
+            Problem::DanglingDependency => write!(fmt, "dangling dependency record"),
+            Problem::DanglingIndex => write!(fmt, "dangling index record"),
+            Problem::OrphanedChunk => write!(fmt, "orphaned chunk"),
+            Problem::OrphanedHistory => write!(fmt, "orphaned retained version"),
+            Problem::BlobRefCount { expected, actual } => write!(
+                fmt,
+                "deduplicated value has {} references, but counts {}",
+                expected, actual
+            ),
+            Problem::UnreferencedBlob => write!(fmt, "unreferenced deduplicated value"),
+        }
+    }
+}
+
+/// Verify the cache, repairing the problems found if `repair` is set.
+pub(crate) fn verify(cache: &Cache, repair: bool) -> Result<Report, Error> {
+    let mut verifier = Verifier {
+        cache,
+        repair,
+        report: Report::default(),
+        blob_refs: HashMap::new(),
+        removed: 0,
+    };
+
+    verifier.entries()?;
+    verifier.records(TAG_KEY, Problem::DanglingTag, tag_record)?;
+    verifier.records(DEP_KEY, Problem::DanglingDependency, dep_record)?;
+    verifier.records(INDEX_KEY, Problem::DanglingIndex, index_record)?;
+    verifier.chunks()?;
+    verifier.history()?;
+    verifier.blobs()?;
+
+    // the scan counted every entry, including ones written outside of the
+    // cache which were never counted in memory.
+    if repair {
+        let len = verifier.report.entries - verifier.removed;
+        cache.inner.len.store(len, Ordering::Relaxed);
+    }
+
+    Ok(verifier.report)
+}
+
+/// Tests if an entry has the record it was parsed from.
+type Has<'a> = Box<dyn Fn(&PartialStoredEntry) -> bool + 'a>;
+
+struct Verifier<'a> {
+    cache: &'a Cache,
+    repair: bool,
+    report: Report,
+    /// The number of entries referring to each deduplicated value.
+    blob_refs: HashMap<Vec<u8>, u64>,
+    /// The number of entries which were removed.
+    removed: usize,
+}
+
+impl Verifier<'_> {
+    /// Report a problem, repairing it with `fix` if repairs are enabled.
+    fn issue<F>(&mut self, key: &[u8], problem: Problem, fix: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Cache) -> Result<(), Error>,
+    {
+        log::warn!("{}: {}", self.cache.key_format(key), problem);
+
+        if self.repair {
+            fix(self.cache)?;
+        }
+
+        self.report.issues.push(Issue {
+            key: key.to_vec(),
+            problem,
+            repaired: self.repair,
+        });
+
+        Ok(())
+    }
+
+    /// Check every entry.
+    fn entries(&mut self) -> Result<(), Error> {
+        for result in self.cache.entries() {
+            let (key, value) = result?;
+            self.report.entries += 1;
+
+            let problem = match self.check_entry(&key, &value)? {
+                Ok(entry) => {
+                    self.check_records(&key, &entry)?;
+                    continue;
+                }
+                Err(problem) => problem,
+            };
+
+            self.issue(&key, problem, |cache| {
+                cache.remove(&key)?;
+                Ok(())
+            })?;
+
+            if self.repair {
+                self.removed += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that an entry can be read, returning its metadata.
+    fn check_entry(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Result<PartialStoredEntry, Problem>, Error> {
+        if let Err(e) = self.check_key(key) {
+            return Ok(Err(Problem::UndecodableKey {
+                error: e.to_string(),
+            }));
+        }
+
+        let entry = match split_entry(value) {
+            Ok((entry, _)) => entry,
+            Err(Error::Corrupt) => return Ok(Err(Problem::ChecksumMismatch)),
+            Err(e) => {
+                return Ok(Err(Problem::MalformedHeader {
+                    error: e.to_string(),
+                }))
+            }
+        };
+
+        if let Some(chunks) = &entry.chunks {
+            if let Some(problem) = self.check_chunks(key, chunks)? {
+                return Ok(Err(problem));
+            }
+        }
+
+        if let Some(list) = &entry.list {
+            for index in list.start..list.end {
+                if !self.contains(&chunk_key(key, list.id, index))? {
+                    return Ok(Err(Problem::MissingItem { index }));
+                }
+            }
+        }
+
+        if let Some(hash) = &entry.blob {
+            let blob = match self.cache.inner.db.get(blob_key(hash))? {
+                Some(blob) => blob,
+                None => return Ok(Err(Problem::MissingBlob)),
+            };
+
+            if blob.len() < 1 + CHECKSUM_LEN {
+                return Ok(Err(Problem::CorruptBlob));
+            }
+
+            let end = blob.len() - CHECKSUM_LEN;
+
+            if crc32fast::hash(&blob[..end]).to_be_bytes() != blob[end..] {
+                return Ok(Err(Problem::CorruptBlob));
+            }
+
+            *self.blob_refs.entry(hash.to_vec()).or_default() += 1;
+        }
+
+        Ok(Ok(entry))
+    }
+
+    /// Check that the key of an entry can be decoded.
+    fn check_key(&self, key: &[u8]) -> Result<(), Error> {
+        let key = upgrade_key(key)?;
+        let (ns, key) = split_key(&key)?;
+        namespace_json(ns)?;
+
+        // hashed keys can't be decoded.
+        if !self.cache.inner.config.hashes_keys() {
+            key::to_json(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check the chunks of a chunked entry.
+    fn check_chunks(&self, key: &[u8], chunks: &Chunks) -> Result<Option<Problem>, Error> {
+        let mut hasher = crc32fast::Hasher::new();
+        let mut len = 0;
+
+        for index in 0..chunks.count {
+            match self.cache.inner.db.get(chunk_key(key, chunks.id, index))? {
+                Some(chunk) => {
+                    hasher.update(&chunk);
+                    len += chunk.len() as u64;
+                }
+                None => return Ok(Some(Problem::MissingChunk { index })),
+            }
+        }
+
+        if len != chunks.len || hasher.finalize() != chunks.checksum {
+            return Ok(Some(Problem::ChunkMismatch));
+        }
+
+        Ok(None)
+    }
+
+    /// Check that the tags, dependencies and indexes of an entry are recorded.
+    fn check_records(&mut self, key: &[u8], entry: &PartialStoredEntry) -> Result<(), Error> {
+        let mut missing = Vec::new();
+
+        for tag in &entry.tags {
+            missing.push((tag_key(tag, key), Problem::MissingTag { tag: tag.clone() }));
+        }
+
+        for dep in &entry.deps {
+            missing.push((dep_key(dep, key), Problem::MissingDependency));
+        }
+
+        for (name, indexed) in &entry.indexes {
+            let problem = Problem::MissingIndex { name: name.clone() };
+            missing.push((index_key(name, indexed, key), problem));
+        }
+
+        for (record, problem) in missing {
+            if self.contains(&record)? {
+                continue;
+            }
+
+            self.issue(key, problem, |cache| {
+                cache.inner.db.insert(record, &[])?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the records with the given prefix refer to entries which
+    /// have them, where `parse` splits a record into the key of its entry
+    /// and a test of whether the entry has it.
+    fn records<P>(&mut self, name: &str, problem: Problem, parse: P) -> Result<(), Error>
+    where
+        P: Fn(&[u8]) -> Option<(&[u8], Has<'_>)>,
+    {
+        let prefix = meta_key(name);
+
+        for result in self.cache.inner.db.scan_prefix(&prefix).keys() {
+            let record = result?;
+
+            let valid = match parse(&record[prefix.len()..]) {
+                Some((key, has)) => match self.cache.inner.db.get(key)? {
+                    Some(value) => decode_meta(&value).is_ok_and(|entry| has(&entry)),
+                    None => false,
+                },
+                None => false,
+            };
+
+            if !valid {
+                self.issue(&record, problem.clone(), |cache| {
+                    cache.inner.db.remove(&record)?;
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that every chunk belongs to the entry stored under its key.
+    fn chunks(&mut self) -> Result<(), Error> {
+        for result in self.cache.inner.db.scan_prefix(meta_key(CHUNK_KEY)).keys() {
+            let chunk_key = result?;
+
+            let owned = match split_chunk_key(&chunk_key) {
+                Some((key, id)) => {
+                    self.cache.is_pending(id)?
+                        || match self.cache.inner.db.get(key)? {
+                            Some(value) => decode_meta(&value).ok().is_some_and(|entry| {
+                                entry.chunks.map(|chunks| chunks.id) == Some(id)
+                                    || entry.list.map(|list| list.id) == Some(id)
+                            }),
+                            None => false,
+                        }
+                }
+                None => false,
+            };
+
+            if !owned {
+                self.issue(&chunk_key, Problem::OrphanedChunk, |cache| {
+                    cache.inner.db.remove(&chunk_key)?;
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that every retained version belongs to an entry.
+    fn history(&mut self) -> Result<(), Error> {
+        for result in self
+            .cache
+            .inner
+            .db
+            .scan_prefix(meta_key(HISTORY_KEY))
+            .keys()
+        {
+            let history_key = result?;
+
+            let owned = match split_history_key(&history_key) {
+                Some(key) => self.contains(key)?,
+                None => false,
+            };
+
+            if !owned {
+                self.issue(&history_key, Problem::OrphanedHistory, |cache| {
+                    cache.inner.db.remove(&history_key)?;
+                    Ok(())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the reference counts of deduplicated values against the entries
+    /// referring to them.
+    fn blobs(&mut self) -> Result<(), Error> {
+        let ref_prefix = meta_key(BLOB_REF_KEY);
+
+        for result in self.cache.inner.db.scan_prefix(&ref_prefix) {
+            let (ref_key, value) = result?;
+            let hash = &ref_key[ref_prefix.len()..];
+            let expected = self.blob_refs.remove(hash).unwrap_or_default();
+            let actual = decode_counter(&value);
+
+            if expected == actual {
+                continue;
+            }
+
+            let problem = if expected == 0 {
+                Problem::UnreferencedBlob
+            } else {
+                Problem::BlobRefCount { expected, actual }
+            };
+
+            let hash = hash.to_vec();
+
+            self.issue(&ref_key, problem, |cache| {
+                if expected == 0 {
+                    cache.inner.db.remove(&ref_key)?;
+                    cache.inner.db.remove(blob_key(&hash))?;
+                } else {
+                    cache
+                        .inner
+                        .db
+                        .insert(&ref_key, &expected.to_be_bytes()[..])?;
+                }
+
+                Ok(())
+            })?;
+        }
+
+        // referenced values without a reference count.
+        for (hash, expected) in std::mem::take(&mut self.blob_refs) {
+            let problem = Problem::BlobRefCount {
+                expected,
+                actual: 0,
+            };
+
+            let ref_key = blob_ref_key(&hash);
+
+            self.issue(&ref_key, problem, |cache| {
+                cache
+                    .inner
+                    .db
+                    .insert(&ref_key, &expected.to_be_bytes()[..])?;
+                Ok(())
+            })?;
+        }
+
+        let blob_prefix = meta_key(BLOB_KEY);
+
+        for result in self.cache.inner.db.scan_prefix(&blob_prefix).keys() {
+            let blob_key = result?;
+            let hash = &blob_key[blob_prefix.len()..];
+
+            if self.contains(&blob_ref_key(hash))? {
+                continue;
+            }
+
+            self.issue(&blob_key, Problem::UnreferencedBlob, |cache| {
+                cache.inner.db.remove(&blob_key)?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Error> {
+        Ok(self.cache.inner.db.contains_key(key)?)
+    }
+}
+
+/// Split a length-prefixed field off the front of a record.
+fn split_field(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, rest) = split_array::<4>(bytes)?;
+    let len = u32::from_be_bytes(len) as usize;
+    Some((rest.get(..len)?, rest.get(len..)?))
+}
+
+/// Parse a tag record.
+fn tag_record(record: &[u8]) -> Option<(&[u8], Has<'_>)> {
+    let (tag, key) = split_field(record)?;
+    let tag = std::str::from_utf8(tag).ok()?;
+    Some((
+        key,
+        Box::new(move |entry| entry.tags.iter().any(|t| t == tag)),
+    ))
+}
+
+/// Parse a dependency record.
+fn dep_record(record: &[u8]) -> Option<(&[u8], Has<'_>)> {
+    let (dep, key) = split_field(record)?;
+    Some((
+        key,
+        Box::new(move |entry| entry.deps.iter().any(|d| d.as_slice() == dep)),
+    ))
+}
+
+/// Parse an index record.
+fn index_record(record: &[u8]) -> Option<(&[u8], Has<'_>)> {
+    let (name, rest) = split_field(record)?;
+    let (indexed, key) = split_field(rest)?;
+    let name = std::str::from_utf8(name).ok()?;
+
+    let has = move |entry: &PartialStoredEntry| {
+        entry
+            .indexes
+            .iter()
+            .any(|(n, i)| n == name && i.as_slice() == indexed)
+    };
+
+    Some((key, Box::new(has)))
+}